@@ -0,0 +1,244 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{error::ErrorUnauthorized, http::header, Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+
+use super::jwks::JwksCache;
+use super::user::{AuthenticatedUser, Claims};
+
+/// The only algorithm login-system signs access tokens with.
+const TOKEN_ALGORITHM: Algorithm = Algorithm::EdDSA;
+
+/// Requires a bearer token issued by login-system on every request it wraps,
+/// and makes the caller available to handlers as an `AuthenticatedUser`.
+#[derive(Clone)]
+pub struct JwtAuth {
+    jwks: Arc<JwksCache>,
+    issuer: String,
+}
+
+impl JwtAuth {
+    pub fn new(jwks: Arc<JwksCache>, issuer: impl Into<String>) -> Self {
+        JwtAuth {
+            jwks,
+            issuer: issuer.into(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for JwtAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = JwtAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(JwtAuthMiddleware {
+            service: Rc::new(service),
+            jwks: self.jwks.clone(),
+            issuer: self.issuer.clone(),
+        }))
+    }
+}
+
+pub struct JwtAuthMiddleware<S> {
+    service: Rc<S>,
+    jwks: Arc<JwksCache>,
+    issuer: String,
+}
+
+impl<S, B> Service<ServiceRequest> for JwtAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let jwks = self.jwks.clone();
+        let issuer = self.issuer.clone();
+
+        Box::pin(async move {
+            let token =
+                bearer_token(&req).ok_or_else(|| ErrorUnauthorized("Missing bearer token"))?;
+            let claims = validate_token(&token, &jwks, &issuer)
+                .await
+                .map_err(ErrorUnauthorized)?;
+
+            req.extensions_mut().insert(AuthenticatedUser::from(claims));
+            service.call(req).await
+        })
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::to_string)
+}
+
+async fn validate_token(token: &str, jwks: &JwksCache, issuer: &str) -> Result<Claims, String> {
+    let header = decode_header(token).map_err(|e| format!("Malformed token: {}", e))?;
+    // login-system only signs with Ed25519. The algorithm is fixed here rather
+    // than taken from the token, which is under the sender's control.
+    if header.alg != TOKEN_ALGORITHM {
+        return Err("Unsupported token algorithm".to_string());
+    }
+    let kid = header.kid.ok_or("Token has no key id")?;
+    let key = jwks.decoding_key(&kid).await?;
+
+    let mut validation = Validation::new(TOKEN_ALGORITHM);
+    validation.set_issuer(&[issuer]);
+
+    decode::<Claims>(token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|e| format!("Invalid token: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::jwks::tests::{test_jwks, TEST_KID, TEST_PRIVATE_KEY};
+    use actix_web::{test, web, App, HttpResponse};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    const ISSUER: &str = "login-system";
+
+    fn token(kid: &str, issuer: &str, expires_in: i64) -> String {
+        token_with_role(kid, issuer, expires_in, "student")
+    }
+
+    fn token_with_role(kid: &str, issuer: &str, expires_in: i64, role: &str) -> String {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.to_string());
+        let claims = serde_json::json!({
+            "sub": "42",
+            "role": role,
+            "iss": issuer,
+            "iat": now,
+            "exp": now + expires_in,
+        });
+        let key = EncodingKey::from_ed_pem(TEST_PRIVATE_KEY.as_bytes()).unwrap();
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    async fn whoami(user: AuthenticatedUser) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{}:{:?}", user.id, user.role))
+    }
+
+    async fn call_with(authorization: Option<String>) -> (actix_web::http::StatusCode, String) {
+        let jwks = Arc::new(JwksCache::with_keys(
+            "http://127.0.0.1:9/unused",
+            test_jwks(),
+        ));
+        let app = test::init_service(
+            App::new().service(
+                web::resource("/whoami")
+                    .wrap(JwtAuth::new(jwks, ISSUER))
+                    .route(web::get().to(whoami)),
+            ),
+        )
+        .await;
+
+        let mut req = test::TestRequest::get().uri("/whoami");
+        if let Some(value) = authorization {
+            req = req.insert_header((header::AUTHORIZATION, value));
+        }
+        match test::try_call_service(&app, req.to_request()).await {
+            Ok(resp) => {
+                let status = resp.status();
+                let body = test::read_body(resp).await;
+                (status, String::from_utf8_lossy(&body).to_string())
+            }
+            Err(e) => (e.as_response_error().status_code(), e.to_string()),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_valid_token_exposes_user() {
+        let (status, body) =
+            call_with(Some(format!("Bearer {}", token(TEST_KID, ISSUER, 60)))).await;
+        assert!(status.is_success(), "Unexpected status: {:?}", status);
+        assert_eq!(body, "42:Student");
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_role_is_rejected() {
+        let (status, _) = call_with(Some(format!(
+            "Bearer {}",
+            token_with_role(TEST_KID, ISSUER, 60, "superuser")
+        )))
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_token_is_rejected() {
+        let (status, _) = call_with(None).await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_token_is_rejected() {
+        let (status, _) =
+            call_with(Some(format!("Bearer {}", token(TEST_KID, ISSUER, -3600)))).await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_wrong_issuer_is_rejected() {
+        let (status, _) = call_with(Some(format!(
+            "Bearer {}",
+            token(TEST_KID, "someone-else", 60)
+        )))
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_other_algorithms_are_rejected() {
+        // Signed with the public key as an HMAC secret, which anyone can fetch.
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(TEST_KID.to_string());
+        let claims =
+            serde_json::json!({ "sub": "42", "role": "admin", "iss": ISSUER, "exp": i64::MAX });
+        let jwks = serde_json::to_vec(&test_jwks()).unwrap();
+        let forged = encode(&header, &claims, &EncodingKey::from_secret(&jwks)).unwrap();
+
+        let (status, _) = call_with(Some(format!("Bearer {}", forged))).await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_key_is_rejected() {
+        let (status, _) = call_with(Some(format!(
+            "Bearer {}",
+            token("rotated-away", ISSUER, 60)
+        )))
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+}
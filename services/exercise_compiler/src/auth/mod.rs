@@ -0,0 +1,3 @@
+pub mod jwks;
+pub mod middleware;
+pub mod user;
@@ -0,0 +1,133 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use serde_derive::Deserialize;
+
+/// Roles issued by login-system, from least to most privileged. Each role can
+/// do everything the roles before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Instructor,
+    Admin,
+}
+
+/// What a user is in a particular class, independent of their `Role`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClassRole {
+    Instructor,
+    Student,
+}
+
+/// A class the user belongs to, as listed in the access token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClassMembership {
+    pub id: i64,
+    pub role: ClassRole,
+}
+
+/// The subset of login-system access token claims this service cares about.
+#[derive(Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    /// Left out of tokens for users who aren't in any class.
+    #[serde(default)]
+    pub classes: Vec<ClassMembership>,
+    /// Set on impersonation tokens, e.g. an instructor viewing the service as
+    /// one of their students.
+    #[serde(default)]
+    pub act: Option<Actor>,
+}
+
+/// The user behind an impersonation token (RFC 8693's `act` claim).
+#[derive(Deserialize)]
+pub struct Actor {
+    pub sub: String,
+}
+
+/// The caller of a request that went through `JwtAuth`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: String,
+    pub role: Role,
+    pub classes: Vec<ClassMembership>,
+    /// Who is really calling, when the token is an impersonation token.
+    pub impersonator: Option<String>,
+}
+
+impl AuthenticatedUser {
+    /// Fails with 403 Forbidden unless the user is in the class. Admins may
+    /// act in every class, as an instructor would.
+    pub fn require_class(&self, class_id: i64) -> Result<ClassRole, Error> {
+        if self.role >= Role::Admin {
+            return Ok(ClassRole::Instructor);
+        }
+        self.classes
+            .iter()
+            .find(|class| class.id == class_id)
+            .map(|class| class.role)
+            .ok_or_else(|| ErrorForbidden("Not a member of this class"))
+    }
+}
+
+impl From<Claims> for AuthenticatedUser {
+    fn from(claims: Claims) -> Self {
+        AuthenticatedUser {
+            id: claims.sub,
+            role: claims.role,
+            classes: claims.classes,
+            impersonator: claims.act.map(|actor| actor.sub),
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| ErrorUnauthorized("Not authenticated")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: Role) -> AuthenticatedUser {
+        AuthenticatedUser {
+            id: "42".to_string(),
+            role,
+            classes: vec![ClassMembership {
+                id: 7,
+                role: ClassRole::Student,
+            }],
+            impersonator: None,
+        }
+    }
+
+    #[test]
+    fn test_require_class_checks_membership() {
+        assert_eq!(
+            user(Role::Student).require_class(7).unwrap(),
+            ClassRole::Student
+        );
+        assert!(user(Role::Instructor).require_class(8).is_err());
+        assert_eq!(
+            user(Role::Admin).require_class(8).unwrap(),
+            ClassRole::Instructor
+        );
+    }
+}
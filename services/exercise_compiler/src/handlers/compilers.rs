@@ -6,9 +6,16 @@ use tempfile::{Builder, NamedTempFile};
 use tokio::process::Command;
 use uuid::Uuid;
 
+use crate::auth::user::AuthenticatedUser;
+use crate::submissions::{Submission, SubmissionStore};
+
 #[derive(Serialize, Deserialize)]
 pub struct CompileRequest {
     code: String,
+    /// The class the code is run for, e.g. an exercise assigned to it. Only
+    /// members of the class may run code for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    class_id: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -231,9 +238,32 @@ async fn interpret_js_tmpfile(req: web::Json<CompileRequest>) -> HttpResponse {
 pub async fn run_code(
     req: web::Json<CompileRequest>,
     language: web::Path<Language>,
+    user: AuthenticatedUser,
+    submissions: web::Data<SubmissionStore>,
 ) -> HttpResponse {
-    println!("Received code: {}", req.code);
-    match language.language.as_str() {
+    if let Some(class_id) = req.class_id {
+        if let Err(e) = user.require_class(class_id) {
+            return e.error_response();
+        }
+    }
+    // The code itself stays out of the log; it's the user's work.
+    match &user.impersonator {
+        Some(impersonator) => log::info!(
+            "Running {} code from user {} ({:?}), impersonated by user {}",
+            language.language,
+            user.id,
+            user.role,
+            impersonator
+        ),
+        None => log::info!(
+            "Running {} code from user {} ({:?})",
+            language.language,
+            user.id,
+            user.role
+        ),
+    }
+    let submission = Submission::new(&language.language, &req.code, req.class_id);
+    let response = match language.language.as_str() {
         "cpp" => compile_c_tmpfile(req).await,
         "python" => interpret_python_tmpfile(req).await,
         //        "java" => compile_java(req).await,
@@ -241,8 +271,17 @@ pub async fn run_code(
         "rust" => compile_rust_tmpfile(req).await,
         "go" => compile_go_tmpfile(req).await,
         "haskell" => compile_haskell_tmpfile(req).await,
-        _ => HttpResponse::BadRequest().body("Language not supported"),
+        _ => return HttpResponse::BadRequest().body("Language not supported"),
+    };
+    // Runs by someone impersonating the user aren't the user's work.
+    if user.impersonator.is_some() {
+        return response;
+    }
+    // The run already happened; failing to keep a copy shouldn't fail it.
+    if let Err(e) = submissions.record(&user.id, &submission).await {
+        log::warn!("Error recording submission of user {}: {}", user.id, e);
     }
+    response
 }
 
 #[cfg(test)]
@@ -258,6 +297,7 @@ mod tests {
         .await;
         let request = CompileRequest {
             code: "print('Hello, world!')".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/python")
@@ -285,6 +325,7 @@ mod tests {
         .await;
         let request = CompileRequest {
             code: "print('Hello, world!'".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/python")
@@ -312,6 +353,7 @@ mod tests {
         let request = CompileRequest {
             code: "#include <stdio.h>\nint main() { printf(\"Hello, world!\"); return 0; }"
                 .to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/cpp")
@@ -339,6 +381,7 @@ mod tests {
         let request = CompileRequest {
             code: "#include <stdio.h>\nint main() { printf(\"Hello, world!\"); return 0;"
                 .to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/cpp")
@@ -366,6 +409,7 @@ mod tests {
         .await;
         let request = CompileRequest {
             code: "console.log('Hello, world!')".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/javascript")
@@ -393,6 +437,7 @@ mod tests {
         .await;
         let request = CompileRequest {
             code: "console.log('Hello, world!'".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/javascript")
@@ -419,6 +464,7 @@ mod tests {
                 .await;
         let request = CompileRequest {
             code: "fn main() { println!(\"Hello, world!\"); }".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/rust")
@@ -445,6 +491,7 @@ mod tests {
                 .await;
         let request = CompileRequest {
             code: "fn main() { println!(\"Hello, world!\") }".to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/rust")
@@ -472,6 +519,7 @@ mod tests {
         let request = CompileRequest {
             code: "package main\nimport \"fmt\"\nfunc main() { fmt.Println(\"Hello, world!\") }"
                 .to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/go")
@@ -499,6 +547,7 @@ mod tests {
         let request = CompileRequest {
             code: "package main\nimport \"fmt\"\nfunc main() { fmt.Println(\"Hello, world!\")"
                 .to_string(),
+            class_id: None,
         };
         let req = test::TestRequest::post()
             .uri("/run/go")
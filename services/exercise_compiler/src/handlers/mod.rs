@@ -1 +1,2 @@
 pub mod compilers;
+pub mod submissions;
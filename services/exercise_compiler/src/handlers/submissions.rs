@@ -0,0 +1,92 @@
+use actix_web::{web, HttpResponse};
+
+use crate::auth::user::AuthenticatedUser;
+use crate::submissions::SubmissionStore;
+
+/// Everything the caller has submitted, for their data export.
+pub async fn list_submissions(
+    store: web::Data<SubmissionStore>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    match store.list(&user.id).await {
+        Ok(submissions) => HttpResponse::Ok().json(submissions),
+        Err(e) => {
+            log::error!("Error reading submissions of user {}: {}", user.id, e);
+            HttpResponse::InternalServerError().body("Error reading submissions")
+        }
+    }
+}
+
+/// Erases everything the caller has submitted. login-system calls this on the
+/// user's behalf when they erase their account. Not with an impersonation
+/// token: only the user may erase their work.
+pub async fn erase_submissions(
+    store: web::Data<SubmissionStore>,
+    user: AuthenticatedUser,
+) -> HttpResponse {
+    if user.impersonator.is_some() {
+        return HttpResponse::Forbidden().body("Not allowed while impersonating a user");
+    }
+    match store.erase(&user.id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("Error erasing submissions of user {}: {}", user.id, e);
+            HttpResponse::InternalServerError().body("Error erasing submissions")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::user::Role;
+    use crate::submissions::Submission;
+    use actix_web::{test, App, HttpMessage};
+
+    #[actix_rt::test]
+    async fn test_users_export_and_erase_their_own_submissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = web::Data::new(SubmissionStore::new(dir.path()));
+        store
+            .record("42", &Submission::new("python", "print(1)", None))
+            .await
+            .unwrap();
+        store
+            .record("43", &Submission::new("python", "print(2)", None))
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new().app_data(store.clone()).service(
+                web::resource("/me/submissions")
+                    .route(web::get().to(list_submissions))
+                    .route(web::delete().to(erase_submissions)),
+            ),
+        )
+        .await;
+        let as_user = |req: test::TestRequest, impersonator: Option<&str>| {
+            let req = req.uri("/me/submissions").to_request();
+            req.extensions_mut().insert(AuthenticatedUser {
+                id: "42".to_string(),
+                role: Role::Student,
+                classes: Vec::new(),
+                impersonator: impersonator.map(str::to_string),
+            });
+            req
+        };
+
+        let submissions: Vec<Submission> =
+            test::call_and_read_body_json(&app, as_user(test::TestRequest::get(), None)).await;
+        assert_eq!(submissions.len(), 1);
+        assert_eq!(submissions[0].code, "print(1)");
+
+        let resp = test::call_service(&app, as_user(test::TestRequest::delete(), Some("7"))).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::FORBIDDEN);
+        assert_eq!(store.list("42").await.unwrap().len(), 1);
+
+        let resp = test::call_service(&app, as_user(test::TestRequest::delete(), None)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NO_CONTENT);
+        assert!(store.list("42").await.unwrap().is_empty());
+        assert_eq!(store.list("43").await.unwrap().len(), 1);
+    }
+}
@@ -1,20 +1,47 @@
+mod auth;
 mod handlers;
+mod submissions;
+use std::sync::Arc;
+
 use actix_cors::Cors;
 use actix_web::{http, web, App, HttpServer};
+use auth::{jwks::JwksCache, middleware::JwtAuth};
+use submissions::SubmissionStore;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let jwks_url = std::env::var("LOGIN_SYSTEM_JWKS_URL")
+        .unwrap_or_else(|_| "http://localhost:8000/.well-known/jwks.json".to_string());
+    let issuer =
+        std::env::var("LOGIN_SYSTEM_ISSUER").unwrap_or_else(|_| "login-system".to_string());
+    let jwks = Arc::new(JwksCache::new(jwks_url));
+    let submissions_dir =
+        std::env::var("SUBMISSIONS_DIR").unwrap_or_else(|_| "submissions".to_string());
+    let submissions = web::Data::new(SubmissionStore::new(submissions_dir));
+
+    HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin("http://localhost:3000") // Permitir origem do frontend
-            .allowed_methods(vec!["GET", "POST"]) // Métodos permitidos
+            .allowed_methods(vec!["GET", "POST", "DELETE"]) // Métodos permitidos
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
             .max_age(3600);
 
-        App::new().wrap(cors).service(
-            web::resource("/run/{language}").route(web::post().to(handlers::compilers::run_code)),
-        )
+        App::new()
+            .wrap(cors)
+            .app_data(submissions.clone())
+            .service(
+                web::resource("/run/{language}")
+                    .wrap(JwtAuth::new(jwks.clone(), issuer.clone()))
+                    .route(web::post().to(handlers::compilers::run_code)),
+            )
+            .service(
+                web::resource("/me/submissions")
+                    .wrap(JwtAuth::new(jwks.clone(), issuer.clone()))
+                    .route(web::get().to(handlers::submissions::list_submissions))
+                    .route(web::delete().to(handlers::submissions::erase_submissions)),
+            )
     })
     .bind("127.0.0.1:8080")?
     .run()
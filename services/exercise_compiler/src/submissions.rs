@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Code a user ran, kept so they can export it and have it erased.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Submission {
+    pub id: Uuid,
+    /// Unix timestamp.
+    pub submitted_at: u64,
+    pub language: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class_id: Option<i64>,
+    pub code: String,
+}
+
+impl Submission {
+    pub fn new(language: &str, code: &str, class_id: Option<i64>) -> Self {
+        Submission {
+            id: Uuid::new_v4(),
+            submitted_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            language: language.to_string(),
+            class_id,
+            code: code.to_string(),
+        }
+    }
+}
+
+/// Submissions as one JSON-lines file per user in `dir`, so everything about a
+/// user can be read or removed at once.
+pub struct SubmissionStore {
+    dir: PathBuf,
+    /// Appends from concurrent requests could interleave otherwise.
+    write_lock: Mutex<()>,
+}
+
+impl SubmissionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SubmissionStore {
+            dir: dir.into(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// User ids come from login-system tokens and are numeric; anything else
+    /// is refused rather than used as a file name.
+    fn path(&self, user_id: &str) -> std::io::Result<PathBuf> {
+        if user_id.is_empty() || !user_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid user id {:?}", user_id),
+            ));
+        }
+        Ok(self.dir.join(format!("{}.jsonl", user_id)))
+    }
+
+    pub async fn record(&self, user_id: &str, submission: &Submission) -> std::io::Result<()> {
+        let path = self.path(user_id)?;
+        let mut line = serde_json::to_string(submission)?;
+        line.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(line.as_bytes()).await
+    }
+
+    /// Oldest first.
+    pub async fn list(&self, user_id: &str) -> std::io::Result<Vec<Submission>> {
+        let path = self.path(user_id)?;
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(std::io::Error::from))
+            .collect()
+    }
+
+    /// Removes every submission of the user. Erasing a user without any is
+    /// not an error.
+    pub async fn erase(&self, user_id: &str) -> std::io::Result<()> {
+        let path = self.path(user_id)?;
+        let _guard = self.write_lock.lock().await;
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_submissions_are_kept_per_user_until_erased() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SubmissionStore::new(dir.path().join("submissions"));
+
+        store
+            .record("42", &Submission::new("python", "print(1)", Some(7)))
+            .await
+            .unwrap();
+        store
+            .record("42", &Submission::new("rust", "fn main() {}", None))
+            .await
+            .unwrap();
+        store
+            .record("43", &Submission::new("go", "package main", None))
+            .await
+            .unwrap();
+
+        let submissions = store.list("42").await.unwrap();
+        assert_eq!(submissions.len(), 2);
+        assert_eq!(submissions[0].class_id, Some(7));
+
+        store.erase("42").await.unwrap();
+        store.erase("42").await.unwrap();
+        assert!(store.list("42").await.unwrap().is_empty());
+        assert_eq!(store.list("43").await.unwrap().len(), 1);
+
+        assert!(store.list("../43").await.is_err());
+    }
+}
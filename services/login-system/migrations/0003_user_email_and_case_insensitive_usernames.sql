@@ -0,0 +1,9 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+
+ALTER TABLE users ADD COLUMN created_at INTEGER;
+
+DROP INDEX IF EXISTS users_username_idx;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_nocase_idx ON users (username COLLATE NOCASE);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_nocase_idx ON users (email COLLATE NOCASE);
@@ -0,0 +1,10 @@
+ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+
+-- Accounts created before verification existed keep working.
+UPDATE users SET email_verified_at = CAST(strftime('%s', 'now') AS INTEGER);
+
+-- Ids of verification and reset tokens that have been used, so each link works once.
+CREATE TABLE IF NOT EXISTS used_tokens (
+    jti TEXT PRIMARY KEY,
+    expires_at INTEGER NOT NULL
+);
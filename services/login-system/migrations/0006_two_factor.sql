@@ -0,0 +1,17 @@
+-- A row exists once enrollment has started. 2FA is on once `confirmed_at` is set.
+CREATE TABLE IF NOT EXISTS totp_credentials (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id),
+    secret TEXT NOT NULL,
+    confirmed_at INTEGER,
+    -- The last 30-second step a code was accepted for, so a code can't be replayed.
+    last_used_step INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    code_hash TEXT NOT NULL,
+    used_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_idx ON recovery_codes (user_id);
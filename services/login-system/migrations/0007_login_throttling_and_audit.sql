@@ -0,0 +1,18 @@
+-- Failed logins per key, e.g. `account:ada` or `ip:203.0.113.7`. Cleared by a
+-- successful login (accounts only) or an admin unlock.
+CREATE TABLE IF NOT EXISTS login_failures (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS audit_events (
+    id INTEGER PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    user_id INTEGER,
+    ip TEXT,
+    detail TEXT
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_created_at_idx ON audit_events (created_at);
@@ -0,0 +1,21 @@
+-- Sign-ins started with an OpenID Connect provider, until the browser comes
+-- back to the callback. Each row is used once and then deleted.
+CREATE TABLE IF NOT EXISTS oidc_login_states (
+    state TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    link_user_id INTEGER REFERENCES users(id),
+    expires_at INTEGER NOT NULL
+);
+
+-- Accounts at OpenID Connect providers, by the provider's stable subject id.
+CREATE TABLE IF NOT EXISTS external_identities (
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX IF NOT EXISTS external_identities_user_idx ON external_identities (user_id);
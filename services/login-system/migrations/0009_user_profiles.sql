@@ -0,0 +1,11 @@
+-- Optional details users can edit about themselves. A user without a row has
+-- an empty profile.
+CREATE TABLE IF NOT EXISTS user_profiles (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id),
+    display_name TEXT,
+    avatar_url TEXT,
+    preferred_language TEXT,
+    locale TEXT,
+    bio TEXT,
+    updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,29 @@
+-- Groups of students taught by one or more instructors. Anyone with the join
+-- code can enroll as a student.
+CREATE TABLE IF NOT EXISTS classes (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    join_code TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL
+);
+
+-- Who is in a class, and as what: 'instructor' or 'student'.
+CREATE TABLE IF NOT EXISTS class_members (
+    class_id INTEGER NOT NULL REFERENCES classes(id),
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    role TEXT NOT NULL,
+    joined_at INTEGER NOT NULL,
+    PRIMARY KEY (class_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS class_members_user_idx ON class_members (user_id);
+
+-- Invitations by email address, accepted by the user who verified that address.
+CREATE TABLE IF NOT EXISTS class_invites (
+    class_id INTEGER NOT NULL REFERENCES classes(id),
+    email TEXT NOT NULL COLLATE NOCASE,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (class_id, email)
+);
+
+CREATE INDEX IF NOT EXISTS class_invites_email_idx ON class_invites (email);
@@ -0,0 +1,9 @@
+-- Ed25519 keys access and one-time tokens are signed with, as PKCS#8 PEM.
+-- A key signs from activates_at until the next key activates, and is kept
+-- afterwards while tokens it signed can still be valid.
+CREATE TABLE IF NOT EXISTS signing_keys (
+    kid TEXT PRIMARY KEY,
+    private_key TEXT NOT NULL,
+    activates_at INTEGER NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1,15 @@
+-- Keys other services call with instead of a user's token. Only a hash of the
+-- key is stored, and the prefix so admins can tell keys apart. Revoked keys
+-- are kept, so the audit trail can still name them.
+CREATE TABLE IF NOT EXISTS api_keys (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    created_by INTEGER REFERENCES users(id),
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+);
@@ -0,0 +1,23 @@
+-- The client's user agent and whether the attempt succeeded, so failed logins
+-- can be told apart from successful ones without knowing every event name.
+ALTER TABLE audit_events ADD COLUMN user_agent TEXT;
+
+ALTER TABLE audit_events ADD COLUMN outcome TEXT NOT NULL DEFAULT 'success';
+
+UPDATE audit_events SET outcome = 'failure' WHERE event IN ('login_failed', 'account_locked');
+
+CREATE INDEX IF NOT EXISTS audit_events_user_idx ON audit_events (user_id);
+
+CREATE INDEX IF NOT EXISTS audit_events_event_idx ON audit_events (event);
+
+-- The log is append-only: events can't be changed or removed, not even along
+-- with the user they are about.
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
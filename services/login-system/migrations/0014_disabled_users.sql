@@ -0,0 +1,3 @@
+-- When an admin disabled the account, or NULL. Disabled users can't sign in
+-- or refresh their sessions until an admin enables the account again.
+ALTER TABLE users ADD COLUMN disabled_at INTEGER;
@@ -0,0 +1,15 @@
+-- One row per login, shared by every refresh token rotated from it. The
+-- address and user agent are the ones last seen refreshing. Logins from before
+-- this table existed have no row, so they don't show up in session lists, but
+-- signing out everywhere still revokes them.
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    family_id TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL,
+    ip TEXT,
+    user_agent TEXT
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_idx ON sessions (user_id);
@@ -0,0 +1,35 @@
+-- Schools sharing the service. Users and classes belong to exactly one, and
+-- admins only see their own. Everything from before this table existed
+-- belongs to the default organization, whose admins run the whole service.
+CREATE TABLE IF NOT EXISTS organizations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    slug TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    name TEXT NOT NULL,
+    -- The OpenID Connect provider the school's accounts are created with, or
+    -- NULL to allow registering with a password.
+    sso_provider TEXT,
+    password_min_length INTEGER NOT NULL DEFAULT 8,
+    created_at INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO organizations (id, slug, name, created_at)
+VALUES (1, 'default', 'Default', CAST(strftime('%s', 'now') AS INTEGER));
+
+-- New accounts with an address at one of these domains join the organization,
+-- and its members can't use addresses anywhere else. Each domain belongs to
+-- at most one organization. Members of the default organization use any
+-- domain nobody claimed.
+CREATE TABLE IF NOT EXISTS organization_domains (
+    domain TEXT PRIMARY KEY COLLATE NOCASE,
+    organization_id INTEGER NOT NULL REFERENCES organizations(id)
+);
+
+CREATE INDEX IF NOT EXISTS organization_domains_organization_idx
+    ON organization_domains (organization_id);
+
+-- SQLite can't add a REFERENCES column with a default, so these aren't
+-- declared as foreign keys.
+ALTER TABLE users ADD COLUMN organization_id INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE classes ADD COLUMN organization_id INTEGER NOT NULL DEFAULT 1;
+
+CREATE INDEX IF NOT EXISTS users_organization_idx ON users (organization_id);
@@ -0,0 +1,41 @@
+-- Ids of deleted users must never be handed out again: their access tokens
+-- stay valid until they expire, and the audit log keeps their events. Only a
+-- table created with AUTOINCREMENT guarantees that, so users is rebuilt, and
+-- its counter starts above every user id the audit log has seen. Foreign keys
+-- are checked at commit, when every user is back.
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TABLE users_copy AS SELECT * FROM users;
+
+DROP TABLE users;
+
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    email TEXT,
+    created_at INTEGER,
+    role TEXT NOT NULL DEFAULT 'student' CHECK (role IN ('student', 'instructor', 'admin')),
+    email_verified_at INTEGER,
+    disabled_at INTEGER,
+    organization_id INTEGER NOT NULL DEFAULT 1
+);
+
+INSERT INTO users (id, username, password, email, created_at, role, email_verified_at, disabled_at, organization_id)
+SELECT id, username, password, email, created_at, role, email_verified_at, disabled_at, organization_id
+FROM users_copy;
+
+DROP TABLE users_copy;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_nocase_idx ON users (username COLLATE NOCASE);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_nocase_idx ON users (email COLLATE NOCASE);
+
+CREATE INDEX IF NOT EXISTS users_organization_idx ON users (organization_id);
+
+INSERT INTO sqlite_sequence (name, seq)
+SELECT 'users', 0 WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'users');
+
+UPDATE sqlite_sequence
+SET seq = MAX(seq, (SELECT COALESCE(MAX(user_id), 0) FROM audit_events))
+WHERE name = 'users';
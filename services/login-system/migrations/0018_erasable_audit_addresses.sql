@@ -0,0 +1,17 @@
+-- Erasing an account clears the addresses and user agents of its audit
+-- events, since they identify the person. That is the only change the log
+-- allows, everything else about an event stays as it was recorded.
+DROP TRIGGER IF EXISTS audit_events_no_update;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+WHEN NEW.id IS NOT OLD.id
+    OR NEW.created_at IS NOT OLD.created_at
+    OR NEW.event IS NOT OLD.event
+    OR NEW.user_id IS NOT OLD.user_id
+    OR NEW.outcome IS NOT OLD.outcome
+    OR NEW.detail IS NOT OLD.detail
+    OR (NEW.ip IS NOT NULL AND NEW.ip IS NOT OLD.ip)
+    OR (NEW.user_agent IS NOT NULL AND NEW.user_agent IS NOT OLD.user_agent)
+BEGIN
+    SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
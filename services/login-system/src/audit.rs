@@ -0,0 +1,36 @@
+use actix_web::{http::header, HttpRequest};
+
+use crate::repository::{AuditEvent, AuditOutcome};
+
+/// Longer user agents are cut, so a client can't bloat the log.
+const USER_AGENT_MAX_CHARS: usize = 512;
+
+/// A successful event caused by `req`, with the client's address and user
+/// agent. Set `outcome` and `detail` with struct update syntax.
+pub fn request_event(req: &HttpRequest, event: &'static str, user_id: Option<i64>) -> AuditEvent {
+    AuditEvent {
+        event,
+        user_id,
+        ip: Some(client_ip(req)),
+        user_agent: user_agent(req),
+        outcome: AuditOutcome::Success,
+        detail: None,
+    }
+}
+
+/// Shuttle runs the service behind a proxy, so the client address comes from
+/// `X-Forwarded-For`. A client could fake it when reaching the service
+/// directly, which is why accounts are throttled on their own as well.
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+pub fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(USER_AGENT_MAX_CHARS).collect())
+}
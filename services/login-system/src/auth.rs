@@ -0,0 +1,275 @@
+use actix_web::{dev::Payload, http::header, web::Data, FromRequest, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::str::FromStr;
+
+use crate::error::ApiError;
+use crate::repository::{ApiKeyRepository, DEFAULT_ORGANIZATION_ID};
+use crate::tokens::{hash_token, TokenIssuer, API_KEY_PREFIX};
+
+/// User roles, from least to most privileged. Each role can do everything the
+/// roles before it can. Stored in `users.role` and copied into access tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Instructor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Instructor => "instructor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "student" => Ok(Role::Student),
+            "instructor" => Ok(Role::Instructor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role: {}", s)),
+        }
+    }
+}
+
+/// What a user is in a particular class, independent of their `Role`: an
+/// instructor may well be a student in someone else's class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClassRole {
+    Instructor,
+    Student,
+}
+
+impl ClassRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClassRole::Instructor => "instructor",
+            ClassRole::Student => "student",
+        }
+    }
+}
+
+impl FromStr for ClassRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "instructor" => Ok(ClassRole::Instructor),
+            "student" => Ok(ClassRole::Student),
+            _ => Err(format!("Unknown class role: {}", s)),
+        }
+    }
+}
+
+/// What an API key lets a service do. Each scope unlocks a group of the
+/// `/service` endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiScope {
+    /// Look up users by id or username.
+    #[serde(rename = "users:read")]
+    UsersRead,
+    /// Read class rosters.
+    #[serde(rename = "classes:read")]
+    ClassesRead,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::UsersRead => "users:read",
+            ApiScope::ClassesRead => "classes:read",
+        }
+    }
+}
+
+impl FromStr for ApiScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "users:read" => Ok(ApiScope::UsersRead),
+            "classes:read" => Ok(ApiScope::ClassesRead),
+            _ => Err(format!("Unknown scope: {}", s)),
+        }
+    }
+}
+
+/// The caller, taken from a valid `Authorization: Bearer` access token.
+/// Handlers that take this as an argument reject anonymous requests with 401.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: i64,
+    pub organization_id: i64,
+    pub role: Role,
+    /// Who is really calling, when the token is an impersonation token.
+    pub impersonator: Option<i64>,
+}
+
+impl AuthenticatedUser {
+    /// Fails with 403 Forbidden unless the user has at least `role`.
+    pub fn require_role(&self, role: Role) -> Result<(), ApiError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden("Insufficient role"))
+        }
+    }
+
+    /// Admins of the default organization run the service for every school.
+    pub fn is_platform_admin(&self) -> bool {
+        self.role == Role::Admin && self.organization_id == DEFAULT_ORGANIZATION_ID
+    }
+
+    /// Fails with 403 Forbidden unless the caller is a platform admin, e.g.
+    /// for settings that affect every organization.
+    pub fn require_platform_admin(&self) -> Result<(), ApiError> {
+        if self.is_platform_admin() {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(
+                "Only admins of the default organization can do this",
+            ))
+        }
+    }
+
+    /// Whether the caller may see the users and classes of an organization:
+    /// their own, or any for platform admins.
+    pub fn sees_organization(&self, organization_id: i64) -> bool {
+        self.organization_id == organization_id || self.is_platform_admin()
+    }
+
+    /// Fails with 403 Forbidden for impersonation tokens. For changes only the
+    /// user should make, like their password, profile or account.
+    pub fn forbid_impersonation(&self) -> Result<(), ApiError> {
+        match self.impersonator {
+            Some(_) => Err(ApiError::impersonation_forbidden()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+/// The token from the `Authorization: Bearer` header.
+pub fn bearer_token(req: &HttpRequest) -> Result<&str, ApiError> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::unauthorized("Missing bearer token"))
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, ApiError> {
+    let issuer = req.app_data::<Data<TokenIssuer>>().ok_or_else(|| {
+        ApiError::internal("Error authenticating", "TokenIssuer is not registered")
+    })?;
+
+    let token = bearer_token(req)?;
+
+    let claims = issuer
+        .verify(token)
+        .map_err(|_| ApiError::unauthorized("Invalid access token"))?;
+    let id = claims
+        .sub
+        .parse()
+        .map_err(|_| ApiError::unauthorized("Invalid access token"))?;
+    let impersonator = claims
+        .act
+        .map(|actor| actor.sub.parse())
+        .transpose()
+        .map_err(|_| ApiError::unauthorized("Invalid access token"))?;
+
+    Ok(AuthenticatedUser {
+        id,
+        organization_id: claims.org,
+        role: claims.role,
+        impersonator,
+    })
+}
+
+/// A service calling with an API key in the `Authorization: Bearer` header.
+/// Handlers that take this as an argument reject user tokens, unknown, expired
+/// and revoked keys with 401.
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    pub scopes: Vec<ApiScope>,
+}
+
+impl ApiClient {
+    /// Fails with 403 Forbidden unless the key was given `scope`.
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), ApiError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(ApiError::forbidden(format!(
+                "API key lacks the {} scope",
+                scope.as_str()
+            )))
+        }
+    }
+}
+
+/// Keys used within this many seconds of the last recorded use aren't written
+/// again, so a busy service doesn't turn every request into a write.
+const LAST_USED_PRECISION_SECS: i64 = 60;
+
+impl FromRequest for ApiClient {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let key = bearer_token(req).map(str::to_string);
+        let api_keys = req.app_data::<Data<dyn ApiKeyRepository>>().cloned();
+        Box::pin(async move {
+            let api_keys = api_keys.ok_or_else(|| {
+                ApiError::internal("Error authenticating", "ApiKeyRepository is not registered")
+            })?;
+            let key = key?;
+            if !key.starts_with(API_KEY_PREFIX) {
+                return Err(ApiError::unauthorized("Invalid API key"));
+            }
+
+            let now = chrono::Utc::now().timestamp();
+            let api_key = api_keys
+                .find_api_key(&hash_token(&key))
+                .await?
+                .filter(|api_key| api_key.is_usable(now))
+                .ok_or_else(|| ApiError::unauthorized("Invalid API key"))?;
+
+            if api_key
+                .last_used_at
+                .is_none_or(|at| at + LAST_USED_PRECISION_SECS <= now)
+            {
+                api_keys.touch_api_key(api_key.id, now).await?;
+            }
+
+            Ok(ApiClient {
+                scopes: api_key.scopes,
+            })
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use actix_web::HttpResponse;
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+use crate::error::{ApiError, FieldErrors};
+
+/// Registration shouldn't wait long on a service that is only advisory.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+const BREACHED_MESSAGE: &str =
+    "This password has appeared in a data breach, so attackers are likely to try it";
+
+/// The response to setting a password: 204 No Content, or 200 with
+/// `{"warnings": {"password": ["..."]}}` if the password was only let through
+/// with a warning.
+pub fn password_set_response(warnings: FieldErrors) -> HttpResponse {
+    if warnings.is_empty() {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::Ok().json(serde_json::json!({ "warnings": warnings }))
+    }
+}
+
+/// What happens to a password found in a breach.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreachPolicy {
+    /// Refused like any other invalid password.
+    Reject,
+    /// Accepted, with a warning in the response.
+    Warn,
+}
+
+impl FromStr for BreachPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(BreachPolicy::Reject),
+            "warn" => Ok(BreachPolicy::Warn),
+            other => Err(format!(
+                "Unknown breached password policy {:?}, expected reject or warn",
+                other
+            )),
+        }
+    }
+}
+
+/// Checks passwords against a Pwned Passwords style range API. Only the first
+/// five hex digits of the password's SHA-1 leave the service; the API answers
+/// with every hash suffix under that prefix and the match happens here.
+pub struct BreachedPasswords {
+    /// `None` turns the check off.
+    base_url: Option<Url>,
+    policy: BreachPolicy,
+    http: reqwest::Client,
+}
+
+impl BreachedPasswords {
+    pub fn new(base_url: Option<Url>, policy: BreachPolicy) -> Self {
+        BreachedPasswords {
+            base_url,
+            policy,
+            http: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn disabled() -> Self {
+        BreachedPasswords::new(None, BreachPolicy::Reject)
+    }
+
+    /// Warnings to return with the response, e.g. `{"password": ["..."]}`, or
+    /// a validation error when breached passwords are rejected.
+    pub async fn check(
+        &self,
+        password: &str,
+        field: &'static str,
+    ) -> Result<FieldErrors, ApiError> {
+        let mut warnings = FieldErrors::new();
+        if !self.is_breached(password).await {
+            return Ok(warnings);
+        }
+        warnings.insert(field, vec![BREACHED_MESSAGE.to_string()]);
+        match self.policy {
+            BreachPolicy::Reject => Err(ApiError::validation(warnings)),
+            BreachPolicy::Warn => Ok(warnings),
+        }
+    }
+
+    /// Fails open: if the API can't be reached, the password counts as not
+    /// breached rather than keeping everyone from signing up.
+    async fn is_breached(&self, password: &str) -> bool {
+        let Some(base_url) = &self.base_url else {
+            return false;
+        };
+        match self.lookup(base_url, password).await {
+            Ok(breached) => breached,
+            Err(e) => {
+                eprintln!("Breached password check failed: {:#}", e);
+                false
+            }
+        }
+    }
+
+    async fn lookup(&self, base_url: &Url, password: &str) -> anyhow::Result<bool> {
+        let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+
+        let mut url = base_url.clone();
+        let path = format!("{}/range/{}", url.path().trim_end_matches('/'), prefix);
+        url.set_path(&path);
+        let body = self
+            .http
+            .get(url)
+            // Pads the answer, so its size doesn't give the prefix away.
+            .header("Add-Padding", "true")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        for line in body.lines() {
+            let (candidate, count) = line
+                .trim()
+                .split_once(':')
+                .with_context(|| format!("Unexpected line {:?}", line))?;
+            // Padding lines have a count of 0.
+            if candidate.eq_ignore_ascii_case(suffix) && count.trim() != "0" {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// SHA-1 of "password1" is E38AD214943DAAD1D64C102FAEC29DE4AFE9DA3D.
+    async fn mock_range_api() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/range/E38AD"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                 214943DAAD1D64C102FAEC29DE4AFE9DA3D:2413945\r\n\
+                 FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF:0\r\n",
+            ))
+            .mount(&server)
+            .await;
+        // Every other prefix has a single hash, which is nobody's password.
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("0018A45C4D1DEF81644B54AB7F969B88D65:3\r\n"),
+            )
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[actix_web::test]
+    async fn test_breached_passwords_are_rejected_or_warned_about() {
+        let server = mock_range_api().await;
+        let url = Some(Url::parse(&server.uri()).unwrap());
+
+        let reject = BreachedPasswords::new(url.clone(), BreachPolicy::Reject);
+        let error = reject.check("password1", "password").await.unwrap_err();
+        assert!(error.fields().contains_key("password"));
+
+        let warn = BreachedPasswords::new(url, BreachPolicy::Warn);
+        let warnings = warn.check("password1", "password").await.unwrap();
+        assert_eq!(warnings["password"], [BREACHED_MESSAGE]);
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_passwords_pass_and_outages_fail_open() {
+        let server = mock_range_api().await;
+        let check = BreachedPasswords::new(
+            Some(Url::parse(&server.uri()).unwrap()),
+            BreachPolicy::Reject,
+        );
+        assert!(check
+            .check("correct horse battery staple", "password")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let unreachable = BreachedPasswords::new(
+            Some(Url::parse("http://127.0.0.1:9").unwrap()),
+            BreachPolicy::Reject,
+        );
+        assert!(unreachable.check("password1", "password").await.is_ok());
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::Context;
+use serde_json::Value;
+use url::Url;
+
+use crate::auth::AuthenticatedUser;
+use crate::tokens::TokenIssuer;
+
+/// Talks to exercise_compiler about a user's submissions, for their data
+/// export and account erasure. Requests are made as the user, with an access
+/// token issued just for the call, so the compiler needs no other credentials.
+///
+/// Without a configured URL (`EXERCISE_COMPILER_URL`) there is nothing to
+/// export or erase there.
+pub struct CompilerClient {
+    base_url: Option<Url>,
+    http: reqwest::Client,
+}
+
+impl CompilerClient {
+    pub fn new(base_url: Option<Url>) -> Self {
+        CompilerClient {
+            base_url,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn submissions_url(&self) -> Option<Url> {
+        let mut url = self.base_url.clone()?;
+        let path = format!("{}/me/submissions", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+        Some(url)
+    }
+
+    /// The user's submissions as the compiler returns them.
+    pub async fn submissions(
+        &self,
+        issuer: &TokenIssuer,
+        user: &AuthenticatedUser,
+    ) -> anyhow::Result<Value> {
+        let Some(url) = self.submissions_url() else {
+            return Ok(Value::Array(Vec::new()));
+        };
+        let token = issuer.access_token(user.id, user.organization_id, user.role, Vec::new())?;
+        let submissions = self
+            .http
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("exercise_compiler returned invalid submissions")?;
+        Ok(submissions)
+    }
+
+    pub async fn erase_submissions(
+        &self,
+        issuer: &TokenIssuer,
+        user: &AuthenticatedUser,
+    ) -> anyhow::Result<()> {
+        let Some(url) = self.submissions_url() else {
+            return Ok(());
+        };
+        let token = issuer.access_token(user.id, user.organization_id, user.role, Vec::new())?;
+        self.http
+            .delete(url)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::repository::DEFAULT_ORGANIZATION_ID;
+    use wiremock::matchers::{header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[actix_web::test]
+    async fn test_requests_are_made_as_the_user() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/compiler/me/submissions"))
+            .and(header_exists("authorization"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!([{ "language": "python" }])),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/compiler/me/submissions"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let client = CompilerClient::new(Some(
+            Url::parse(&format!("{}/compiler/", server.uri())).unwrap(),
+        ));
+        let issuer = TokenIssuer::for_tests();
+        let user = AuthenticatedUser {
+            id: 42,
+            organization_id: DEFAULT_ORGANIZATION_ID,
+            role: Role::Student,
+            impersonator: None,
+        };
+
+        let submissions = client.submissions(&issuer, &user).await.unwrap();
+        assert_eq!(submissions[0]["language"], "python");
+        let request = &server.received_requests().await.unwrap()[0];
+        let authorization = &request.headers[&"authorization".into()];
+        let token = authorization.as_str().trim_start_matches("Bearer ");
+        assert_eq!(issuer.verify(token).unwrap().sub, "42");
+
+        assert!(client.erase_submissions(&issuer, &user).await.is_err());
+
+        let unconfigured = CompilerClient::new(None);
+        assert_eq!(
+            unconfigured.submissions(&issuer, &user).await.unwrap(),
+            serde_json::json!([])
+        );
+        assert!(unconfigured.erase_submissions(&issuer, &user).await.is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::Context;
+use shuttle_secrets::SecretStore;
+use std::path::PathBuf;
+use std::sync::Arc;
+use url::Url;
+
+use crate::auth::Role;
+use crate::breach::{BreachPolicy, BreachedPasswords};
+use crate::mailer::{FileMailer, LogMailer, Mailer, SmtpMailer};
+use crate::password::PasswordHasher;
+
+/// Service configuration, read from the Shuttle secrets (`Secrets.toml`, or
+/// `Secrets.dev.toml` when running locally).
+pub struct Settings {
+    /// `libsql://...` for Turso, or `file:///path/to/db.sqlite` for a local database.
+    /// Defaults to `login-system.db` in the working directory.
+    pub database_url: Url,
+    pub database_auth_token: Option<String>,
+    pub key_rotation: KeyRotation,
+    /// Username promoted to admin at startup, so the first admin can be created
+    /// without touching the database.
+    pub bootstrap_admin: Option<String>,
+    /// Where links in emails point to, e.g. `https://login.example.com`.
+    pub public_base_url: Url,
+    pub mailer: MailerSettings,
+    pub two_factor_policy: TwoFactorPolicy,
+    pub password_hasher: PasswordHasher,
+    pub breached_passwords: BreachedPasswords,
+    pub oidc_providers: Vec<OidcProviderSettings>,
+    /// exercise_compiler, e.g. `http://localhost:8080`, so users' data exports
+    /// include their submissions and erasing an account erases them too.
+    pub exercise_compiler_url: Option<Url>,
+}
+
+/// An OpenID Connect provider users can sign in with, e.g. a university's
+/// Google Workspace or Microsoft Entra tenant.
+///
+/// `OIDC_PROVIDERS` lists the provider names, e.g. `google,microsoft`. Each one
+/// is configured with `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID` and, for
+/// confidential clients, `OIDC_<NAME>_CLIENT_SECRET`. The redirect URI to
+/// register with the provider is `<PUBLIC_BASE_URL>/oidc/<name>/callback`.
+#[derive(Clone)]
+pub struct OidcProviderSettings {
+    pub name: String,
+    /// The discovery document is read from `<issuer>/.well-known/openid-configuration`.
+    pub issuer: Url,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+}
+
+impl OidcProviderSettings {
+    fn from_secrets(secrets: &SecretStore) -> anyhow::Result<Vec<Self>> {
+        let names = secrets.get("OIDC_PROVIDERS").unwrap_or_default();
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                {
+                    anyhow::bail!(
+                        "OIDC provider name {:?} must be lowercase letters, digits or '_'",
+                        name
+                    );
+                }
+                let key = |suffix: &str| format!("OIDC_{}_{}", name.to_uppercase(), suffix);
+
+                Ok(OidcProviderSettings {
+                    name: name.to_string(),
+                    issuer: Url::parse(
+                        &secrets
+                            .get(&key("ISSUER"))
+                            .with_context(|| format!("{} secret is missing", key("ISSUER")))?,
+                    )
+                    .with_context(|| format!("{} is not a valid URL", key("ISSUER")))?,
+                    client_id: secrets
+                        .get(&key("CLIENT_ID"))
+                        .with_context(|| format!("{} secret is missing", key("CLIENT_ID")))?,
+                    client_secret: secrets.get(&key("CLIENT_SECRET")),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Selected with `MAILER`: `smtp`, `file` or `log` (the default).
+pub enum MailerSettings {
+    Smtp {
+        host: String,
+        credentials: Option<(String, String)>,
+        from: String,
+    },
+    File(PathBuf),
+    Log,
+}
+
+impl MailerSettings {
+    fn from_secrets(secrets: &SecretStore) -> anyhow::Result<Self> {
+        match secrets.get("MAILER").as_deref().unwrap_or("log") {
+            "smtp" => {
+                let credentials = match (secrets.get("SMTP_USERNAME"), secrets.get("SMTP_PASSWORD"))
+                {
+                    (Some(username), Some(password)) => Some((username, password)),
+                    _ => None,
+                };
+                Ok(MailerSettings::Smtp {
+                    host: secrets
+                        .get("SMTP_HOST")
+                        .context("SMTP_HOST secret is missing")?,
+                    credentials,
+                    from: secrets
+                        .get("MAIL_FROM")
+                        .context("MAIL_FROM secret is missing")?,
+                })
+            }
+            "file" => Ok(MailerSettings::File(
+                secrets
+                    .get("MAIL_OUTBOX_PATH")
+                    .unwrap_or_else(|| "outbox.txt".to_string())
+                    .into(),
+            )),
+            "log" => Ok(MailerSettings::Log),
+            other => anyhow::bail!("Unknown MAILER {:?}, expected smtp, file or log", other),
+        }
+    }
+
+    pub fn build(self) -> anyhow::Result<Arc<dyn Mailer>> {
+        Ok(match self {
+            MailerSettings::Smtp {
+                host,
+                credentials,
+                from,
+            } => Arc::new(SmtpMailer::new(&host, credentials, &from)?),
+            MailerSettings::File(path) => Arc::new(FileMailer::new(path)),
+            MailerSettings::Log => Arc::new(LogMailer),
+        })
+    }
+}
+
+impl Settings {
+    pub fn from_secrets(secrets: &SecretStore) -> anyhow::Result<Self> {
+        let database_url = match secrets.get("DATABASE_URL") {
+            Some(url) => Url::parse(&url).context("DATABASE_URL is not a valid URL")?,
+            None => {
+                let path = std::env::current_dir()?.join("login-system.db");
+                Url::from_file_path(&path)
+                    .map_err(|_| anyhow::anyhow!("Invalid database path: {}", path.display()))?
+            }
+        };
+
+        Ok(Settings {
+            database_url,
+            database_auth_token: secrets.get("DATABASE_AUTH_TOKEN"),
+            key_rotation: KeyRotation::from_secrets(secrets)?,
+            bootstrap_admin: secrets.get("BOOTSTRAP_ADMIN"),
+            public_base_url: Url::parse(
+                &secrets
+                    .get("PUBLIC_BASE_URL")
+                    .unwrap_or_else(|| "http://localhost:8000".to_string()),
+            )
+            .context("PUBLIC_BASE_URL is not a valid URL")?,
+            mailer: MailerSettings::from_secrets(secrets)?,
+            two_factor_policy: TwoFactorPolicy::parse(
+                &secrets.get("REQUIRE_2FA_ROLES").unwrap_or_default(),
+            )?,
+            password_hasher: password_hasher(secrets)?,
+            breached_passwords: breached_passwords(secrets)?,
+            oidc_providers: OidcProviderSettings::from_secrets(secrets)?,
+            exercise_compiler_url: secrets
+                .get("EXERCISE_COMPILER_URL")
+                .map(|url| Url::parse(&url))
+                .transpose()
+                .context("EXERCISE_COMPILER_URL is not a valid URL")?,
+        })
+    }
+}
+
+/// Argon2id cost for new password hashes: `ARGON2_MEMORY_KIB`,
+/// `ARGON2_ITERATIONS` and `ARGON2_PARALLELISM`. The defaults (19 MiB, 2, 1)
+/// are OWASP's recommended minimum. Raising them upgrades each user's hash the
+/// next time they log in.
+fn password_hasher(secrets: &SecretStore) -> anyhow::Result<PasswordHasher> {
+    let number = |key: &str, default: u32| match secrets.get(key) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("{} must be a positive number", key)),
+        None => Ok(default),
+    };
+    PasswordHasher::new(
+        number("ARGON2_MEMORY_KIB", 19 * 1024)?,
+        number("ARGON2_ITERATIONS", 2)?,
+        number("ARGON2_PARALLELISM", 1)?,
+    )
+}
+
+/// New passwords are looked up in `BREACHED_PASSWORD_API`, a Pwned Passwords
+/// style range API (`https://api.pwnedpasswords.com` by default, `none` to skip
+/// the check), and `BREACHED_PASSWORD_POLICY` says whether passwords found
+/// there are rejected (the default) or only warned about.
+fn breached_passwords(secrets: &SecretStore) -> anyhow::Result<BreachedPasswords> {
+    let base_url = match secrets.get("BREACHED_PASSWORD_API").as_deref() {
+        Some("none") => None,
+        Some(url) => Some(Url::parse(url).context("BREACHED_PASSWORD_API is not a valid URL")?),
+        None => Some(Url::parse("https://api.pwnedpasswords.com")?),
+    };
+    let policy: BreachPolicy = secrets
+        .get("BREACHED_PASSWORD_POLICY")
+        .as_deref()
+        .unwrap_or("reject")
+        .parse()
+        .map_err(anyhow::Error::msg)?;
+    Ok(BreachedPasswords::new(base_url, policy))
+}
+
+/// How the token signing keys, stored in the database, are rotated.
+///
+/// `JWT_KEY_ROTATION_DAYS` (30 by default) is how long each key signs.
+/// `JWT_SIGNING_KEY` and `JWT_KEY_ID` are optional and only used as the first
+/// key when the database has none yet, so a deployment that used to configure
+/// its key keeps accepting the tokens it already issued.
+pub struct KeyRotation {
+    pub interval_secs: i64,
+    /// PKCS#8 PEM and key id.
+    pub initial_key: Option<(String, String)>,
+}
+
+impl KeyRotation {
+    fn from_secrets(secrets: &SecretStore) -> anyhow::Result<Self> {
+        let days: i64 = match secrets.get("JWT_KEY_ROTATION_DAYS") {
+            Some(days) => days
+                .trim()
+                .parse()
+                .context("JWT_KEY_ROTATION_DAYS must be a number of days")?,
+            None => 30,
+        };
+        // The next key is published a day ahead, so a rotation needs more than that.
+        if days < 2 {
+            anyhow::bail!("JWT_KEY_ROTATION_DAYS must be at least 2");
+        }
+
+        Ok(KeyRotation {
+            interval_secs: days * 24 * 60 * 60,
+            initial_key: secrets.get("JWT_SIGNING_KEY").map(|pem| {
+                let kid = secrets
+                    .get("JWT_KEY_ID")
+                    .unwrap_or_else(|| "default".to_string());
+                (pem, kid)
+            }),
+        })
+    }
+}
+
+/// The externally visible address of the service, for building links in emails.
+pub struct PublicBaseUrl(pub Url);
+
+impl PublicBaseUrl {
+    pub fn join(&self, path: &str) -> String {
+        self.0.join(path).unwrap_or_else(|_| self.0.clone()).into()
+    }
+
+    /// `path` with the token as its `token` query parameter.
+    pub fn link(&self, path: &str, token: &str) -> String {
+        let mut url = self.0.join(path).unwrap_or_else(|_| self.0.clone());
+        url.query_pairs_mut().append_pair("token", token);
+        url.into()
+    }
+}
+
+/// Roles whose accounts can't sign in without 2FA, from `REQUIRE_2FA_ROLES`,
+/// e.g. `instructor,admin`. Empty by default, which leaves 2FA optional for everyone.
+#[derive(Default)]
+pub struct TwoFactorPolicy {
+    required_roles: Vec<Role>,
+}
+
+impl TwoFactorPolicy {
+    pub fn parse(roles: &str) -> anyhow::Result<Self> {
+        let required_roles = roles
+            .split(',')
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .map(|role| role.parse().map_err(anyhow::Error::msg))
+            .collect::<anyhow::Result<_>>()
+            .context("REQUIRE_2FA_ROLES must be a comma-separated list of roles")?;
+        Ok(TwoFactorPolicy { required_roles })
+    }
+
+    pub fn is_required(&self, role: Role) -> bool {
+        self.required_roles.contains(&role)
+    }
+}
@@ -0,0 +1,99 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::Payload,
+    http::header,
+    FromRequest, HttpRequest,
+};
+use std::future::{ready, Ready};
+
+use crate::error::ApiError;
+use crate::tokens::random_token;
+
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// A fresh token for a page, with the cookie that goes along with it. The page
+/// sends the token back in `X-CSRF-Token`; another site can make the browser
+/// send the request, but can neither read the token nor get the cookie sent.
+pub fn issue(secure: bool) -> (String, Cookie<'static>) {
+    let token = random_token();
+    let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .finish();
+    (token, cookie)
+}
+
+/// Guards an endpoint the pages post to. Browser requests, recognized by the
+/// `Origin` header every browser sends with a POST, or by the cookie, need a
+/// `X-CSRF-Token` matching the cookie. Other clients send neither, and can't
+/// be tricked into making requests, so they are let through.
+pub struct CsrfChecked;
+
+impl FromRequest for CsrfChecked {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(check(req))
+    }
+}
+
+fn check(req: &HttpRequest) -> Result<CsrfChecked, ApiError> {
+    let cookie = req.cookie(CSRF_COOKIE);
+    if cookie.is_none() && !req.headers().contains_key(header::ORIGIN) {
+        return Ok(CsrfChecked);
+    }
+
+    let token = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie, token) {
+        (Some(cookie), Some(token)) if !token.is_empty() && cookie.value() == token => {
+            Ok(CsrfChecked)
+        }
+        _ => Err(ApiError::forbidden(
+            "Missing or invalid CSRF token, please reload the page",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_api_clients_need_no_token() {
+        assert!(check(&TestRequest::post().to_http_request()).is_ok());
+    }
+
+    #[test]
+    fn test_browser_requests_need_matching_token() {
+        let origin = (header::ORIGIN, "https://evil.example");
+        assert!(check(
+            &TestRequest::post()
+                .insert_header(origin.clone())
+                .to_http_request()
+        )
+        .is_err());
+
+        let (token, cookie) = issue(true);
+        let req = TestRequest::post()
+            .insert_header(origin.clone())
+            .cookie(cookie.clone())
+            .insert_header((CSRF_HEADER, "forged"))
+            .to_http_request();
+        assert!(check(&req).is_err());
+
+        let req = TestRequest::post()
+            .insert_header(origin)
+            .cookie(cookie)
+            .insert_header((CSRF_HEADER, token))
+            .to_http_request();
+        assert!(check(&req).is_ok());
+    }
+}
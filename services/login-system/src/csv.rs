@@ -0,0 +1,135 @@
+use std::fmt;
+
+/// A record of a CSV file, with the line it starts on for error reports.
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub line: usize,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CsvError {
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+/// Reads comma-separated values as spreadsheets export them (RFC 4180):
+/// fields may be quoted, quoted fields may contain commas, line breaks and
+/// doubled quotes, and lines end in `\n` or `\r\n`. Blank lines are skipped.
+pub fn parse(text: &str) -> Result<Vec<Record>, CsvError> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+
+    loop {
+        let c = chars.next();
+        match c {
+            Some('"') if field.is_empty() => {
+                let quote_line = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(CsvError {
+                                line: quote_line,
+                                message: "Unterminated quoted field",
+                            })
+                        }
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                    return Err(CsvError {
+                        line,
+                        message: "Unexpected text after a quoted field",
+                    });
+                }
+            }
+            Some(',') => fields.push(std::mem::take(&mut field)),
+            Some('\r') if chars.peek() == Some(&'\n') => {}
+            Some('\n') | None => {
+                if !fields.is_empty() || !field.trim().is_empty() {
+                    fields.push(std::mem::take(&mut field));
+                    records.push(Record {
+                        line: record_line,
+                        fields: std::mem::take(&mut fields),
+                    });
+                }
+                field.clear();
+                if c.is_none() {
+                    return Ok(records);
+                }
+                line += 1;
+                record_line = line;
+            }
+            Some(c) => field.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(text: &str) -> Vec<Vec<String>> {
+        parse(text)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.fields)
+            .collect()
+    }
+
+    #[test]
+    fn test_plain_and_quoted_fields() {
+        assert_eq!(
+            fields("name,email\r\n\"Lovelace, Ada\",ada@example.com\n\nGrace,\n"),
+            vec![
+                vec!["name", "email"],
+                vec!["Lovelace, Ada", "ada@example.com"],
+                vec!["Grace", ""],
+            ]
+        );
+        assert_eq!(
+            fields("\u{feff}\"say \"\"hi\"\"\",\"two\nlines\""),
+            vec![vec!["say \"hi\"", "two\nlines"]]
+        );
+    }
+
+    #[test]
+    fn test_records_know_their_line() {
+        let records = parse("a\n\"b\nc\"\nd").unwrap();
+        let lines: Vec<usize> = records.iter().map(|record| record.line).collect();
+        assert_eq!(lines, [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_malformed_quotes_are_errors() {
+        assert_eq!(
+            parse("a\n\"open").unwrap_err(),
+            CsvError {
+                line: 2,
+                message: "Unterminated quoted field",
+            }
+        );
+        assert!(parse("\"a\"b,c").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use libsql_client::{args, Client, Config, ResultSet, Statement};
+use url::Url;
+
+/// Schema changes, applied in order. Never edit a migration that has shipped;
+/// add a new one instead.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "create_users",
+        include_str!("../migrations/0001_create_users.sql"),
+    ),
+    (
+        2,
+        "create_refresh_tokens",
+        include_str!("../migrations/0002_create_refresh_tokens.sql"),
+    ),
+    (
+        3,
+        "user_email_and_case_insensitive_usernames",
+        include_str!("../migrations/0003_user_email_and_case_insensitive_usernames.sql"),
+    ),
+    (
+        4,
+        "user_roles",
+        include_str!("../migrations/0004_user_roles.sql"),
+    ),
+    (
+        5,
+        "email_verification",
+        include_str!("../migrations/0005_email_verification.sql"),
+    ),
+    (
+        6,
+        "two_factor",
+        include_str!("../migrations/0006_two_factor.sql"),
+    ),
+    (
+        7,
+        "login_throttling_and_audit",
+        include_str!("../migrations/0007_login_throttling_and_audit.sql"),
+    ),
+    (8, "oidc", include_str!("../migrations/0008_oidc.sql")),
+    (
+        9,
+        "user_profiles",
+        include_str!("../migrations/0009_user_profiles.sql"),
+    ),
+    (
+        10,
+        "classes",
+        include_str!("../migrations/0010_classes.sql"),
+    ),
+    (
+        11,
+        "signing_keys",
+        include_str!("../migrations/0011_signing_keys.sql"),
+    ),
+    (
+        12,
+        "api_keys",
+        include_str!("../migrations/0012_api_keys.sql"),
+    ),
+    (
+        13,
+        "audit_log",
+        include_str!("../migrations/0013_audit_log.sql"),
+    ),
+    (
+        14,
+        "disabled_users",
+        include_str!("../migrations/0014_disabled_users.sql"),
+    ),
+    (
+        15,
+        "sessions",
+        include_str!("../migrations/0015_sessions.sql"),
+    ),
+    (
+        16,
+        "organizations",
+        include_str!("../migrations/0016_organizations.sql"),
+    ),
+    (
+        17,
+        "never_reuse_user_ids",
+        include_str!("../migrations/0017_never_reuse_user_ids.sql"),
+    ),
+    (
+        18,
+        "erasable_audit_addresses",
+        include_str!("../migrations/0018_erasable_audit_addresses.sql"),
+    ),
+];
+
+/// Connects to a remote libsql database (`libsql://`, `https://`) or a local
+/// SQLite file (`file:///path/to/db.sqlite`).
+pub async fn connect(url: Url, auth_token: Option<String>) -> anyhow::Result<Client> {
+    Client::from_config(Config { url, auth_token }).await
+}
+
+/// Applies every migration the database hasn't seen yet, each in its own transaction.
+pub async fn migrate(client: &Client) -> anyhow::Result<()> {
+    client
+        .execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
+            )",
+        )
+        .await?;
+
+    let applied = client
+        .execute("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")
+        .await?;
+    let current_version: i64 = match applied.rows.first() {
+        Some(row) => row.try_get(0)?,
+        None => 0,
+    };
+
+    for (version, name, sql) in MIGRATIONS {
+        if *version <= current_version {
+            continue;
+        }
+
+        let mut statements: Vec<Statement> = split_statements(sql)
+            .into_iter()
+            .map(Statement::new)
+            .collect();
+        statements.push(Statement::with_args(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?)",
+            args!(*version, *name, chrono::Utc::now().timestamp()),
+        ));
+        run_in_transaction(client, statements).await?;
+        log::info!("Applied migration {:04}_{}", version, name);
+    }
+
+    Ok(())
+}
+
+/// Splits on `;`, except inside the `BEGIN ... END` body of a trigger.
+fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+    for part in sql.split(';') {
+        statement.push_str(part);
+        let upper = statement.to_uppercase();
+        if upper.contains("CREATE TRIGGER") && !upper.trim_end().ends_with("END") {
+            statement.push(';');
+            continue;
+        }
+        if !statement.trim().is_empty() {
+            statements.push(statement.trim().to_string());
+        }
+        statement.clear();
+    }
+    statements
+}
+
+/// Runs the statements in one transaction, rolled back if any of them fails.
+/// `Client::batch` stops at the failing statement instead, leaving the earlier
+/// ones applied and the transaction open on the connection.
+pub async fn run_in_transaction(
+    client: &Client,
+    statements: impl IntoIterator<Item = Statement>,
+) -> anyhow::Result<Vec<ResultSet>> {
+    let transaction = client.transaction().await?;
+    let mut results = Vec::new();
+    for statement in statements {
+        match transaction.execute(statement).await {
+            Ok(rs) => results.push(rs),
+            Err(e) => {
+                if let Err(rollback) = transaction.rollback().await {
+                    log::error!("Error rolling back transaction: {}", rollback);
+                }
+                return Err(e);
+            }
+        }
+    }
+    transaction.commit().await?;
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_migrate_is_idempotent() {
+        let client = Client::in_memory().unwrap();
+        migrate(&client).await.unwrap();
+        migrate(&client).await.unwrap();
+
+        let rs = client
+            .execute("SELECT COUNT(*) FROM schema_migrations")
+            .await
+            .unwrap();
+        assert_eq!(
+            rs.rows[0].try_get::<i64>(0).unwrap(),
+            MIGRATIONS.len() as i64
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_failed_transaction_is_rolled_back() {
+        let client = Client::in_memory().unwrap();
+        client
+            .execute("CREATE TABLE t (a TEXT UNIQUE)")
+            .await
+            .unwrap();
+        let insert = |a: &str| Statement::with_args("INSERT INTO t (a) VALUES (?)", args!(a));
+
+        assert!(
+            run_in_transaction(&client, [insert("x"), insert("y"), insert("x")])
+                .await
+                .is_err()
+        );
+        // Nothing was kept, and the connection isn't left inside the transaction.
+        run_in_transaction(&client, [insert("z")]).await.unwrap();
+        let rs = client.execute("SELECT a FROM t").await.unwrap();
+        assert_eq!(rs.rows.len(), 1);
+        assert_eq!(rs.rows[0].try_get::<&str>(0).unwrap(), "z");
+    }
+
+    #[test]
+    fn test_trigger_bodies_are_not_split() {
+        let statements = split_statements(
+            "CREATE TABLE t (a);
+             CREATE TRIGGER t_no_delete BEFORE DELETE ON t
+             BEGIN
+                 SELECT RAISE(ABORT, 'no');
+             END;
+             ",
+        );
+        assert_eq!(statements.len(), 2);
+        assert!(statements[1].ends_with("END"));
+    }
+
+    #[test]
+    fn test_migration_versions_are_sequential() {
+        for (index, (version, _, _)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version, index as i64 + 1);
+        }
+    }
+}
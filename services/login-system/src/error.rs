@@ -0,0 +1,198 @@
+use actix_web::{http::header, http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::repository::RepositoryError;
+
+/// Field name -> messages, e.g. `{"password": ["Password is too short"]}`.
+pub type FieldErrors = BTreeMap<&'static str, Vec<String>>;
+
+/// An error returned by a handler. Every error is rendered as the same envelope,
+/// so clients only need one code path to display them:
+///
+/// ```json
+/// {"error": {"code": "validation_error", "message": "...", "fields": {"username": ["..."]}}}
+/// ```
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    fields: FieldErrors,
+    /// Sent as `Retry-After`, in seconds.
+    retry_after: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fields: &'a FieldErrors,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            fields: FieldErrors::new(),
+            retry_after: None,
+        }
+    }
+
+    pub fn validation(fields: FieldErrors) -> Self {
+        ApiError {
+            fields,
+            ..ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_error",
+                "Some fields are invalid",
+            )
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    /// Login is refused until the user follows the link in the verification email.
+    /// A separate code lets clients offer to resend it.
+    pub fn email_not_verified() -> Self {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "email_not_verified",
+            "Email address has not been verified",
+        )
+    }
+
+    /// An admin disabled the account. Like an unverified email, it is only
+    /// revealed once the user proved who they are.
+    pub fn account_disabled() -> Self {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "account_disabled",
+            "This account has been disabled",
+        )
+    }
+
+    /// The caller is using an impersonation token for something only the user
+    /// may do.
+    pub fn impersonation_forbidden() -> Self {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "impersonation_forbidden",
+            "Not allowed while impersonating a user",
+        )
+    }
+
+    /// The user's school creates accounts through its OpenID Connect provider,
+    /// so clients can send them there instead.
+    pub fn sso_required(provider: &str) -> Self {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "sso_required",
+            format!(
+                "Accounts at this school are created by signing in with {}",
+                provider
+            ),
+        )
+    }
+
+    /// Login is throttled after failed attempts; the client may retry after
+    /// `retry_after` seconds.
+    pub fn too_many_attempts(retry_after: i64) -> Self {
+        ApiError {
+            retry_after: Some(retry_after),
+            ..ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_attempts",
+                "Too many failed login attempts, try again later",
+            )
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// A uniqueness conflict on a single field, e.g. a taken username.
+    pub fn conflict(field: &'static str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        ApiError {
+            fields: FieldErrors::from([(field, vec![message.clone()])]),
+            ..ApiError::new(StatusCode::CONFLICT, "conflict", message)
+        }
+    }
+
+    #[cfg(test)]
+    pub fn fields(&self) -> &FieldErrors {
+        &self.fields
+    }
+
+    /// Another service failed. The cause is logged, not returned.
+    pub fn bad_gateway(message: impl Into<String>, cause: impl fmt::Display) -> Self {
+        let message = message.into();
+        eprintln!("{}: {:#}", message, cause);
+        ApiError::new(StatusCode::BAD_GATEWAY, "bad_gateway", message)
+    }
+
+    /// The cause is logged, not returned; clients only see `message`.
+    pub fn internal(message: impl Into<String>, cause: impl fmt::Display) -> Self {
+        let message = message.into();
+        eprintln!("{}: {}", message, cause);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if let Some(retry_after) = self.retry_after {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code,
+                message: &self.message,
+                fields: &self.fields,
+            },
+        })
+    }
+}
+
+impl From<RepositoryError> for ApiError {
+    fn from(e: RepositoryError) -> Self {
+        match e {
+            RepositoryError::Conflict(_) => {
+                ApiError::new(StatusCode::CONFLICT, "conflict", "Resource already exists")
+            }
+            RepositoryError::Database(e) => ApiError::internal("Database error", e),
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use actix_web::{get, post, web::Data, web::Json, web::Query, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::audit::request_event;
+use crate::breach::{password_set_response, BreachedPasswords};
+use crate::config::PublicBaseUrl;
+use crate::csrf::CsrfChecked;
+use crate::error::ApiError;
+use crate::handlers::organizations::find_organization;
+use crate::mailer::{Email, Mailer};
+use crate::pages;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditRepository, OneTimeTokenRepository, OrganizationRepository, RefreshTokenRepository, User,
+    UserRepository,
+};
+use crate::tokens::{OneTimeClaims, Purpose, TokenIssuer};
+use crate::validation::validate_password;
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetConfirmation {
+    token: String,
+    password: String,
+}
+
+/// Emails the user a link to `GET /verify-email`.
+pub async fn send_verification_email(
+    mailer: &dyn Mailer,
+    issuer: &TokenIssuer,
+    base_url: &PublicBaseUrl,
+    user: &User,
+) -> anyhow::Result<()> {
+    let Some(email) = user.email.as_deref() else {
+        return Ok(());
+    };
+    let token = issuer.one_time_token(user.id, Some(email), Purpose::VerifyEmail)?;
+
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nFollow this link to verify your email address:\n\n{}\n\nThe link expires in 24 hours.",
+                user.username,
+                base_url.link("/verify-email", &token)
+            ),
+        })
+        .await
+}
+
+async fn send_password_reset_email(
+    mailer: &dyn Mailer,
+    issuer: &TokenIssuer,
+    base_url: &PublicBaseUrl,
+    user: &User,
+) -> anyhow::Result<()> {
+    let Some(email) = user.email.as_deref() else {
+        return Ok(());
+    };
+    let token = issuer.one_time_token(user.id, Some(email), Purpose::ResetPassword)?;
+
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nFollow this link to choose a new password:\n\n{}\n\nThe link expires in 1 hour. If you didn't ask for it, you can ignore this email.",
+                user.username,
+                base_url.link("/password-reset", &token)
+            ),
+        })
+        .await
+}
+
+/// Tells a user an admin reset their password, with a link to choose a new
+/// one. Unlike a reset they asked for, there is no old password to go back to.
+pub async fn send_forced_password_reset_email(
+    mailer: &dyn Mailer,
+    issuer: &TokenIssuer,
+    base_url: &PublicBaseUrl,
+    user: &User,
+) -> anyhow::Result<()> {
+    let Some(email) = user.email.as_deref() else {
+        return Ok(());
+    };
+    let token = issuer.one_time_token(user.id, Some(email), Purpose::ResetPassword)?;
+
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Your password was reset".to_string(),
+            body: format!(
+                "Hi {},\n\nAn administrator reset the password of your account and signed you out everywhere. Follow this link to choose a new password:\n\n{}\n\nThe link expires in 1 hour. After that, use \"Forgot your password?\" on the login page.",
+                user.username,
+                base_url.link("/password-reset", &token)
+            ),
+        })
+        .await
+}
+
+/// Emails a user whose account was created for them, e.g. by a bulk import, a
+/// link to choose their password.
+pub async fn send_set_password_email(
+    mailer: &dyn Mailer,
+    issuer: &TokenIssuer,
+    base_url: &PublicBaseUrl,
+    user: &User,
+) -> anyhow::Result<()> {
+    let Some(email) = user.email.as_deref() else {
+        return Ok(());
+    };
+    let token = issuer.one_time_token(user.id, Some(email), Purpose::SetPassword)?;
+
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Your account is ready".to_string(),
+            body: format!(
+                "Hi {},\n\nAn account with the username {} was created for you. Follow this link to choose your password:\n\n{}\n\nThe link expires in 7 days. After that, use \"Forgot your password?\" on the login page.",
+                user.username,
+                user.username,
+                base_url.link("/password-reset", &token)
+            ),
+        })
+        .await
+}
+
+#[get("/verify-email")]
+async fn verify_email(
+    users: Data<dyn UserRepository>,
+    used_tokens: Data<dyn OneTimeTokenRepository>,
+    issuer: Data<TokenIssuer>,
+    query: Query<TokenQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (user, claims) = redeem(
+        &**users,
+        &**used_tokens,
+        &issuer,
+        &query.token,
+        Purpose::VerifyEmail,
+    )
+    .await?;
+    users.mark_email_verified(user.id).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(pages::message_page(
+            "Email Verified",
+            format!(
+                "{} is verified. You can now log in.",
+                claims.email.as_deref().unwrap_or_default()
+            ),
+        )))
+}
+
+/// Always answers 202, so it can't be used to find out which addresses have accounts.
+#[post("/verify-email/resend")]
+async fn resend_verification(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    base_url: Data<PublicBaseUrl>,
+    form: Json<EmailRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(user) = users.find_by_email(form.email.trim()).await? {
+        if user.email_verified_at.is_none() {
+            if let Err(e) = send_verification_email(&**mailer, &issuer, &base_url, &user).await {
+                eprintln!("Error sending verification email: {}", e);
+            }
+        }
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Always answers 202, so it can't be used to find out which addresses have accounts.
+#[post("/password-reset")]
+async fn request_password_reset(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    base_url: Data<PublicBaseUrl>,
+    form: Json<EmailRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(user) = users.find_by_email(form.email.trim()).await? {
+        if let Err(e) = send_password_reset_email(&**mailer, &issuer, &base_url, &user).await {
+            eprintln!("Error sending password reset email: {}", e);
+        }
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Sets the new password and signs the user out of every session.
+#[post("/password-reset/confirm")]
+#[allow(clippy::too_many_arguments)]
+async fn confirm_password_reset(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    organizations: Data<dyn OrganizationRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    used_tokens: Data<dyn OneTimeTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    issuer: Data<TokenIssuer>,
+    hasher: Data<PasswordHasher>,
+    breached_passwords: Data<BreachedPasswords>,
+    req: HttpRequest,
+    form: Json<PasswordResetConfirmation>,
+) -> Result<HttpResponse, ApiError> {
+    let confirmation: PasswordResetConfirmation = form.into_inner();
+
+    // Check the new password before using up the link, so a rejected password
+    // can be retried with the same email.
+    // Links for choosing the first password of an imported account land on
+    // the same page.
+    let purpose = if issuer
+        .verify_one_time(&confirmation.token, Purpose::SetPassword)
+        .is_ok()
+    {
+        Purpose::SetPassword
+    } else {
+        Purpose::ResetPassword
+    };
+    let claims = verify_link(&issuer, &confirmation.token, purpose)?;
+    let user = find_link_user(&**users, &claims).await?;
+    let min_chars = find_organization(&**organizations, user.organization_id)
+        .await?
+        .password_min_length as usize;
+    let password = validate_password(&confirmation.password, &user.username, min_chars)?;
+    let warnings = breached_passwords.check(&password, "password").await?;
+
+    let (user, _) = redeem(
+        &**users,
+        &**used_tokens,
+        &issuer,
+        &confirmation.token,
+        purpose,
+    )
+    .await?;
+
+    let password_hash = hasher.hash(password).await?;
+    users.set_password_hash(user.id, &password_hash).await?;
+    refresh_tokens.revoke_all_for_user(user.id).await?;
+    // Following the link proved the user controls the address.
+    users.mark_email_verified(user.id).await?;
+    let event = match purpose {
+        Purpose::SetPassword => "password_set",
+        _ => "password_reset",
+    };
+    audit
+        .record(request_event(&req, event, Some(user.id)))
+        .await?;
+
+    Ok(password_set_response(warnings))
+}
+
+fn verify_link(
+    issuer: &TokenIssuer,
+    token: &str,
+    purpose: Purpose,
+) -> Result<OneTimeClaims, ApiError> {
+    issuer
+        .verify_one_time(token, purpose)
+        .map_err(|_| invalid_link())
+}
+
+/// The link is only good for the address it was sent to; changing the email
+/// in the meantime invalidates it.
+async fn find_link_user(
+    users: &dyn UserRepository,
+    claims: &OneTimeClaims,
+) -> Result<User, ApiError> {
+    let user_id: i64 = claims.sub.parse().map_err(|_| invalid_link())?;
+    let user = users.find_by_id(user_id).await?.ok_or_else(invalid_link)?;
+
+    match user.email.as_deref() {
+        Some(email)
+            if claims
+                .email
+                .as_deref()
+                .is_some_and(|sent_to| email.eq_ignore_ascii_case(sent_to)) =>
+        {
+            Ok(user)
+        }
+        _ => Err(invalid_link()),
+    }
+}
+
+/// Verifies the token and marks it used.
+async fn redeem(
+    users: &dyn UserRepository,
+    used_tokens: &dyn OneTimeTokenRepository,
+    issuer: &TokenIssuer,
+    token: &str,
+    purpose: Purpose,
+) -> Result<(User, OneTimeClaims), ApiError> {
+    let claims = verify_link(issuer, token, purpose)?;
+    let user = find_link_user(users, &claims).await?;
+
+    if !used_tokens.consume(&claims.jti, claims.exp).await? {
+        return Err(ApiError::bad_request("This link has already been used"));
+    }
+    Ok((user, claims))
+}
+
+fn invalid_link() -> ApiError {
+    ApiError::bad_request("This link is invalid or has expired")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::mailer::FileMailer;
+    use crate::repository::libsql::LibsqlRepository;
+    use actix_web::{http::StatusCode, test, App};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    struct Outbox(PathBuf);
+
+    impl Outbox {
+        fn new() -> Self {
+            Outbox(
+                std::env::temp_dir().join(format!("outbox-{}.txt", crate::tokens::random_token())),
+            )
+        }
+
+        /// The token from the last link that was mailed.
+        fn last_token(&self) -> String {
+            let outbox = std::fs::read_to_string(&self.0).unwrap();
+            let (_, token) = outbox.rsplit_once("?token=").unwrap();
+            token.split_whitespace().next().unwrap().to_string()
+        }
+    }
+
+    impl Drop for Outbox {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    async fn setup() -> (Arc<LibsqlRepository>, User, Outbox) {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = repository.create_test_user("ada", Role::Student).await;
+        (repository, user, Outbox::new())
+    }
+
+    macro_rules! app {
+        ($repository:expr, $outbox:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OrganizationRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OneTimeTokenRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::from(
+                        Arc::new(FileMailer::new(&$outbox.0)) as Arc<dyn Mailer>
+                    ))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(BreachedPasswords::disabled()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        "http://localhost:8000".parse().unwrap(),
+                    )))
+                    .service(verify_email)
+                    .service(resend_verification)
+                    .service(request_password_reset)
+                    .service(confirm_password_reset),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn test_verification_link_works_once() {
+        let (repository, _, outbox) = setup().await;
+        let app = app!(repository, outbox);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/verify-email/resend")
+                .set_json(serde_json::json!({ "email": "ADA@example.com" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        let uri = format!("/verify-email?token={}", outbox.last_token());
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user = repository.find_by_username("ada").await.unwrap().unwrap();
+        assert!(user.email_verified_at.is_some());
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_reset_request_does_not_reveal_unknown_emails() {
+        let (repository, _, outbox) = setup().await;
+        let app = app!(repository, outbox);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/password-reset")
+                .set_json(serde_json::json!({ "email": "nobody@example.com" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(!outbox.0.exists());
+    }
+
+    #[actix_web::test]
+    async fn test_password_reset_changes_password_and_signs_out() {
+        let (repository, user, outbox) = setup().await;
+        repository
+            .store("refresh", user.id, "family", i64::MAX)
+            .await
+            .unwrap();
+        let app = app!(repository, outbox);
+
+        test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/password-reset")
+                .set_json(serde_json::json!({ "email": "ada@example.com" }))
+                .to_request(),
+        )
+        .await;
+        let token = outbox.last_token();
+
+        // A rejected password leaves the link usable.
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/password-reset/confirm")
+                .set_json(serde_json::json!({ "token": token, "password": "short" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let confirm = || {
+            test::TestRequest::post()
+                .uri("/password-reset/confirm")
+                .set_json(serde_json::json!({ "token": token, "password": "difference engine" }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, confirm()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let user = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert!(PasswordHasher::for_tests()
+            .verify("difference engine".to_string(), user.password_hash)
+            .await
+            .unwrap());
+        assert!(!repository.revoke("refresh").await.unwrap());
+
+        let resp = test::call_service(&app, confirm()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_reset_token_cannot_verify_email() {
+        let (repository, user, outbox) = setup().await;
+        let app = app!(repository, outbox);
+
+        let token = TokenIssuer::for_tests()
+            .one_time_token(user.id, Some("ada@example.com"), Purpose::ResetPassword)
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/verify-email?token={}", token))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_set_password_link_confirms_like_a_reset() {
+        let (repository, user, outbox) = setup().await;
+        let app = app!(repository, outbox);
+
+        send_set_password_email(
+            &FileMailer::new(&outbox.0),
+            &TokenIssuer::for_tests(),
+            &PublicBaseUrl("http://localhost:8000".parse().unwrap()),
+            &user,
+        )
+        .await
+        .unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/password-reset/confirm")
+                .set_json(serde_json::json!({
+                    "token": outbox.last_token(),
+                    "password": "difference engine",
+                }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let user = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert!(PasswordHasher::for_tests()
+            .verify("difference engine".to_string(), user.password_hash)
+            .await
+            .unwrap());
+        assert!(user.email_verified_at.is_some());
+    }
+}
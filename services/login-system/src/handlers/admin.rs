@@ -0,0 +1,987 @@
+use actix_web::{
+    delete, get, post, put, web::Data, web::Json, web::Path, web::Query, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, ClassRole, Role};
+use crate::config::PublicBaseUrl;
+use crate::error::ApiError;
+use crate::mailer::Mailer;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditEvent, AuditQuery, AuditRepository, ClassRepository, ExternalIdentityRepository,
+    LinkedIdentity, LoginAttemptRepository, Profile, ProfileRepository, PublicProfile,
+    RefreshTokenRepository, StoredAuditEvent, TwoFactorRepository, User, UserQuery, UserRepository,
+};
+use crate::throttle::account_key;
+use crate::tokens::{random_token, TokenIssuer};
+
+use super::account::send_forced_password_reset_email;
+use super::sessions::session_views;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+/// How much of the user's audit trail comes with their details.
+const RECENT_EVENTS: i64 = 20;
+
+/// `GET /admin/users?q=ada&role=student&disabled=true`.
+#[derive(Deserialize)]
+pub struct UserSearch {
+    /// Part of the username or email.
+    q: Option<String>,
+    role: Option<Role>,
+    disabled: Option<bool>,
+    /// An organization id. Only platform admins can pick one; everyone else
+    /// searches their own.
+    organization: Option<i64>,
+    /// `next_after` from the previous page.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// A user as admins see them.
+#[derive(Serialize)]
+struct AdminUser {
+    #[serde(flatten)]
+    account: PublicProfile,
+    disabled_at: Option<i64>,
+}
+
+impl From<User> for AdminUser {
+    fn from(user: User) -> Self {
+        AdminUser {
+            disabled_at: user.disabled_at,
+            account: user.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserPage {
+    users: Vec<AdminUser>,
+    /// Pass as `after` to get the next page. Missing on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_after: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct UserDetails {
+    #[serde(flatten)]
+    user: AdminUser,
+    profile: Profile,
+    classes: Vec<UserClass>,
+    two_factor_enabled: bool,
+    linked_identities: Vec<LinkedIdentity>,
+    /// Failed logins since the last successful one.
+    failed_logins: i64,
+    /// Newest first.
+    recent_events: Vec<StoredAuditEvent>,
+}
+
+#[derive(Serialize)]
+struct UserClass {
+    id: i64,
+    name: String,
+    role: ClassRole,
+}
+
+#[derive(Serialize)]
+struct PasswordResetSent {
+    email: String,
+}
+
+/// Ordered by id. Admins of a school only find its members.
+#[get("/admin/users")]
+async fn search_users(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    search: Query<UserSearch>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    let found = users
+        .search_users(&UserQuery {
+            text: search
+                .q
+                .as_deref()
+                .map(str::trim)
+                .filter(|q| !q.is_empty())
+                .map(str::to_string),
+            role: search.role,
+            disabled: search.disabled,
+            organization_id: if admin.is_platform_admin() {
+                search.organization
+            } else {
+                Some(admin.organization_id)
+            },
+            after_id: search.after,
+            limit,
+        })
+        .await?;
+    let next_after = match found.last() {
+        Some(last) if found.len() as i64 == limit => Some(last.id),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(UserPage {
+        users: found.into_iter().map(AdminUser::from).collect(),
+        next_after,
+    }))
+}
+
+#[get("/admin/users/{id}")]
+#[allow(clippy::too_many_arguments)]
+async fn user_details(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    profiles: Data<dyn ProfileRepository>,
+    classes: Data<dyn ClassRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    identities: Data<dyn ExternalIdentityRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user = find_user(&**users, &admin, path.into_inner()).await?;
+
+    let details = UserDetails {
+        profile: profiles.profile(user.id).await?,
+        classes: classes
+            .classes_of(user.id)
+            .await?
+            .into_iter()
+            .map(|(class, role)| UserClass {
+                id: class.id,
+                name: class.name,
+                role,
+            })
+            .collect(),
+        two_factor_enabled: two_factor
+            .totp_credential(user.id)
+            .await?
+            .is_some_and(|credential| credential.confirmed),
+        linked_identities: identities.identities_of(user.id).await?,
+        failed_logins: attempts
+            .failures(&account_key(&user.username))
+            .await?
+            .map_or(0, |failures| failures.failures),
+        recent_events: audit
+            .audit_events(&AuditQuery {
+                user_id: Some(user.id),
+                limit: RECENT_EVENTS,
+                ..AuditQuery::default()
+            })
+            .await?,
+        user: user.into(),
+    };
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Keeps the user from signing in and signs them out of every session. Access
+/// tokens they already have work until they expire.
+#[put("/admin/users/{id}/disabled")]
+async fn disable_user(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user_id = path.into_inner();
+    if user_id == admin.id {
+        return Err(ApiError::bad_request("Admins can't disable themselves"));
+    }
+    set_disabled(
+        &**users,
+        &**audit,
+        &req,
+        &admin,
+        user_id,
+        Some(chrono::Utc::now().timestamp()),
+    )
+    .await?;
+    refresh_tokens.revoke_all_for_user(user_id).await?;
+
+    let user = find_user(&**users, &admin, user_id).await?;
+    Ok(HttpResponse::Ok().json(AdminUser::from(user)))
+}
+
+#[delete("/admin/users/{id}/disabled")]
+async fn enable_user(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user_id = path.into_inner();
+    set_disabled(&**users, &**audit, &req, &admin, user_id, None).await?;
+
+    let user = find_user(&**users, &admin, user_id).await?;
+    Ok(HttpResponse::Ok().json(AdminUser::from(user)))
+}
+
+/// Replaces the password with a random one nobody knows, signs the user out
+/// everywhere and emails them a link to choose a new one, e.g. when the
+/// account looks compromised. Users without an email address would be locked
+/// out for good, so they are refused.
+#[post("/admin/users/{id}/password-reset")]
+#[allow(clippy::too_many_arguments)]
+async fn force_password_reset(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    base_url: Data<PublicBaseUrl>,
+    hasher: Data<PasswordHasher>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user = find_user(&**users, &admin, path.into_inner()).await?;
+    let Some(email) = user.email.clone() else {
+        return Err(ApiError::bad_request(
+            "The user has no email address to send a reset link to",
+        ));
+    };
+
+    let password_hash = hasher.hash(random_token()).await?;
+    users.set_password_hash(user.id, &password_hash).await?;
+    refresh_tokens.revoke_all_for_user(user.id).await?;
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("By admin {}", admin.id)),
+            ..request_event(&req, "password_reset_forced", Some(user.id))
+        })
+        .await?;
+    send_forced_password_reset_email(&**mailer, &issuer, &base_url, &user)
+        .await
+        .map_err(|e| ApiError::internal("Error sending password reset email", e))?;
+
+    Ok(HttpResponse::Ok().json(PasswordResetSent { email }))
+}
+
+/// The user's sessions that can still be refreshed, most recently used first.
+#[get("/admin/users/{id}/sessions")]
+async fn list_user_sessions(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user = find_user(&**users, &admin, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(session_views(&**refresh_tokens, user.id).await?))
+}
+
+#[delete("/admin/users/{id}/sessions/{session_id}")]
+async fn revoke_user_session(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<(i64, i64)>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let (user_id, session_id) = path.into_inner();
+    find_user(&**users, &admin, user_id).await?;
+    if !refresh_tokens.revoke_session(user_id, session_id).await? {
+        return Err(ApiError::not_found("Session not found"));
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Session {} by admin {}", session_id, admin.id)),
+            ..request_event(&req, "session_revoked", Some(user_id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Signs the user out everywhere. They can sign in again right away.
+#[delete("/admin/users/{id}/sessions")]
+async fn revoke_sessions(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user = find_user(&**users, &admin, path.into_inner()).await?;
+
+    refresh_tokens.revoke_all_for_user(user.id).await?;
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("By admin {}", admin.id)),
+            ..request_event(&req, "sessions_revoked", Some(user.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct RoleChange {
+    role: Role,
+}
+
+#[put("/admin/users/{id}/role")]
+async fn grant_role(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+    form: Json<RoleChange>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    change_role(
+        &**users,
+        &**audit,
+        &req,
+        &admin,
+        path.into_inner(),
+        form.role,
+    )
+    .await
+}
+
+/// Takes the user back to the default role.
+#[delete("/admin/users/{id}/role")]
+async fn revoke_role(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    change_role(
+        &**users,
+        &**audit,
+        &req,
+        &admin,
+        path.into_inner(),
+        Role::Student,
+    )
+    .await
+}
+
+/// Lifts a lockout after failed logins, e.g. once the user confirmed it was them.
+#[delete("/admin/users/{id}/lockout")]
+async fn unlock(
+    admin: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let user = find_user(&**users, &admin, path.into_inner()).await?;
+
+    attempts
+        .clear_failures(&account_key(&user.username))
+        .await?;
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Unlocked by admin {}", admin.id)),
+            ..request_event(&req, "account_unlocked", Some(user.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn change_role(
+    users: &dyn UserRepository,
+    audit: &dyn AuditRepository,
+    req: &HttpRequest,
+    admin: &AuthenticatedUser,
+    user_id: i64,
+    role: Role,
+) -> Result<HttpResponse, ApiError> {
+    // Otherwise the last admin could lock everyone out of these endpoints.
+    if user_id == admin.id && role < Role::Admin {
+        return Err(ApiError::bad_request("Admins can't demote themselves"));
+    }
+
+    find_user(users, admin, user_id).await?;
+    if !users.set_role(user_id, role).await? {
+        return Err(ApiError::not_found("User not found"));
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!(
+                "Changed to {} by admin {}",
+                role.as_str(),
+                admin.id
+            )),
+            ..request_event(req, "role_changed", Some(user_id))
+        })
+        .await?;
+    let user = find_user(users, admin, user_id).await?;
+
+    Ok(HttpResponse::Ok().json(PublicProfile::from(user)))
+}
+
+async fn set_disabled(
+    users: &dyn UserRepository,
+    audit: &dyn AuditRepository,
+    req: &HttpRequest,
+    admin: &AuthenticatedUser,
+    user_id: i64,
+    disabled_at: Option<i64>,
+) -> Result<(), ApiError> {
+    find_user(users, admin, user_id).await?;
+    if !users.set_disabled(user_id, disabled_at).await? {
+        return Err(ApiError::not_found("User not found"));
+    }
+    let event = match disabled_at {
+        Some(_) => "account_disabled",
+        None => "account_enabled",
+    };
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("By admin {}", admin.id)),
+            ..request_event(req, event, Some(user_id))
+        })
+        .await?;
+    Ok(())
+}
+
+/// Members of other organizations are as good as missing, unless the admin
+/// is a platform admin.
+async fn find_user(
+    users: &dyn UserRepository,
+    admin: &AuthenticatedUser,
+    id: i64,
+) -> Result<User, ApiError> {
+    users
+        .find_by_id(id)
+        .await?
+        .filter(|user| admin.sees_organization(user.organization_id))
+        .ok_or_else(|| ApiError::not_found("User not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::LogMailer;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{
+        NewUser, OrganizationRepository, OrganizationSettings, DEFAULT_ORGANIZATION_ID,
+    };
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_only_admins_can_grant_roles() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let student_id = repository
+            .create_test_user("student", Role::Student)
+            .await
+            .id;
+        let issuer = TokenIssuer::for_tests();
+        let admin_token = issuer
+            .access_token(admin_id, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+        let student_token = issuer
+            .access_token(
+                student_id,
+                DEFAULT_ORGANIZATION_ID,
+                Role::Student,
+                Vec::new(),
+            )
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(issuer))
+                .service(grant_role)
+                .service(revoke_role),
+        )
+        .await;
+
+        let grant = |token: &str, user_id: i64| {
+            test::TestRequest::put()
+                .uri(&format!("/admin/users/{}/role", user_id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "role": "instructor" }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, grant(&student_token, student_id)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, grant(&admin_token, student_id)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let profile: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(profile["role"], "instructor");
+        let events = repository
+            .audit_events(&AuditQuery {
+                event: Some("role_changed".to_string()),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].user_id, Some(student_id));
+
+        let resp = test::call_service(&app, grant(&admin_token, student_id + 100)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/admin/users/{}/role", student_id))
+            .set_json(serde_json::json!({ "role": "admin" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_cannot_demote_themselves() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let issuer = TokenIssuer::for_tests();
+        let admin_token = issuer
+            .access_token(admin_id, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(issuer))
+                .service(revoke_role),
+        )
+        .await;
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/users/{}/role", admin_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_unlock_clears_failed_logins() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let student_id = repository
+            .create_test_user("Student", Role::Student)
+            .await
+            .id;
+        let key = account_key("student");
+        for _ in 0..10 {
+            repository.record_failure(&key, 0, 0).await.unwrap();
+        }
+        let issuer = TokenIssuer::for_tests();
+        let admin_token = issuer
+            .access_token(admin_id, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn LoginAttemptRepository>
+                ))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(issuer))
+                .service(unlock),
+        )
+        .await;
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/users/{}/lockout", student_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.failures(&key).await.unwrap().is_none());
+    }
+
+    /// Every repository the user management endpoints need.
+    macro_rules! app {
+        ($repository:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ProfileRepository>))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn TwoFactorRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn ExternalIdentityRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn LoginAttemptRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        "http://localhost:8000".parse().unwrap(),
+                    )))
+                    .service(search_users)
+                    .service(user_details)
+                    .service(disable_user)
+                    .service(enable_user)
+                    .service(force_password_reset)
+                    .service(list_user_sessions)
+                    .service(revoke_user_session)
+                    .service(revoke_sessions),
+            )
+            .await
+        };
+    }
+
+    fn admin_request(request: test::TestRequest, admin_id: i64) -> test::TestRequest {
+        let token = TokenIssuer::for_tests()
+            .access_token(admin_id, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+        request.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    #[actix_web::test]
+    async fn test_users_are_searched_and_shown_to_admins() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let ada_id = repository.create_test_user("ada", Role::Student).await.id;
+        repository.create_test_user("adam", Role::Instructor).await;
+        repository.create_test_user("grace", Role::Student).await;
+        repository
+            .record_failure(&account_key("ada"), 0, 0)
+            .await
+            .unwrap();
+        let app = app!(repository);
+
+        let page: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::get(), admin_id)
+                .uri("/admin/users?q=ADA&limit=1")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(page["users"][0]["username"], "ada");
+        assert_eq!(page["next_after"], ada_id);
+        let page: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::get(), admin_id)
+                .uri(&format!(
+                    "/admin/users?q=ada&role=instructor&after={}",
+                    ada_id
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(page["users"][0]["username"], "adam");
+        assert!(page.get("next_after").is_none());
+
+        let details: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::get(), admin_id)
+                .uri(&format!("/admin/users/{}", ada_id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(details["username"], "ada");
+        assert_eq!(details["failed_logins"], 1);
+        assert_eq!(details["two_factor_enabled"], false);
+        assert!(details["password_hash"].is_null());
+
+        let token = TokenIssuer::for_tests()
+            .access_token(ada_id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/admin/users")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_school_admins_only_see_their_school() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let platform_admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let ada_id = repository.create_test_user("ada", Role::Student).await.id;
+        let north = repository
+            .create_organization(&OrganizationSettings {
+                slug: "north".to_string(),
+                name: "North High".to_string(),
+                email_domains: vec!["north.edu".to_string()],
+                sso_provider: None,
+                password_min_length: 8,
+            })
+            .await
+            .unwrap();
+        let mut north_ids = Vec::new();
+        for username in ["north_admin", "alan"] {
+            let user = repository
+                .create(NewUser {
+                    username: username.to_string(),
+                    email: Some(format!("{}@north.edu", username)),
+                    password_hash: "hash".to_string(),
+                    organization_id: north.id,
+                })
+                .await
+                .unwrap();
+            north_ids.push(user.id);
+        }
+        repository
+            .set_role(north_ids[0], Role::Admin)
+            .await
+            .unwrap();
+        let app = app!(repository);
+        let north_token = TokenIssuer::for_tests()
+            .access_token(north_ids[0], north.id, Role::Admin, Vec::new())
+            .unwrap();
+        let as_north_admin = |request: test::TestRequest| {
+            request
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", north_token)))
+                .to_request()
+        };
+
+        // Asking for another organization doesn't widen a school admin's view.
+        let page: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            as_north_admin(test::TestRequest::get().uri(&format!(
+                "/admin/users?organization={}",
+                DEFAULT_ORGANIZATION_ID
+            ))),
+        )
+        .await;
+        let usernames: Vec<&str> = page["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["username"].as_str().unwrap())
+            .collect();
+        assert_eq!(usernames, ["north_admin", "alan"]);
+
+        for request in [
+            test::TestRequest::get().uri(&format!("/admin/users/{}", ada_id)),
+            test::TestRequest::put().uri(&format!("/admin/users/{}/disabled", ada_id)),
+        ] {
+            let resp = test::call_service(&app, as_north_admin(request)).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        let resp = test::call_service(
+            &app,
+            as_north_admin(test::TestRequest::get().uri(&format!("/admin/users/{}", north_ids[1]))),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Platform admins see everyone, or one organization if they ask.
+        let page: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::get(), platform_admin_id)
+                .uri(&format!("/admin/users?organization={}", north.id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(page["users"].as_array().unwrap().len(), 2);
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::get(), platform_admin_id)
+                .uri(&format!("/admin/users/{}", north_ids[1]))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_disabling_signs_the_user_out_until_enabled() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let ada_id = repository.create_test_user("ada", Role::Student).await.id;
+        repository
+            .store("hash-1", ada_id, "family", i64::MAX)
+            .await
+            .unwrap();
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::put(), admin_id)
+                .uri(&format!("/admin/users/{}/disabled", admin_id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let user: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::put(), admin_id)
+                .uri(&format!("/admin/users/{}/disabled", ada_id))
+                .to_request(),
+        )
+        .await;
+        assert!(user["disabled_at"].is_i64());
+        assert!(!repository.revoke("hash-1").await.unwrap());
+
+        let user: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::delete(), admin_id)
+                .uri(&format!("/admin/users/{}/disabled", ada_id))
+                .to_request(),
+        )
+        .await;
+        assert!(user["disabled_at"].is_null());
+
+        let events = repository
+            .audit_events(&AuditQuery {
+                user_id: Some(ada_id),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        let names: Vec<_> = events.iter().map(|event| event.event.as_str()).collect();
+        assert_eq!(names, ["account_enabled", "account_disabled"]);
+    }
+
+    #[actix_web::test]
+    async fn test_forced_password_reset_replaces_the_password() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let ada = repository.create_test_user("ada", Role::Student).await;
+        let unreachable = repository
+            .create(NewUser {
+                username: "babbage".to_string(),
+                email: None,
+                password_hash: "hash".to_string(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        repository
+            .store("hash-1", ada.id, "family", i64::MAX)
+            .await
+            .unwrap();
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::post(), admin_id)
+                .uri(&format!("/admin/users/{}/password-reset", unreachable.id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let sent: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::post(), admin_id)
+                .uri(&format!("/admin/users/{}/password-reset", ada.id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(sent["email"], "ada@example.com");
+        let user = repository.find_by_id(ada.id).await.unwrap().unwrap();
+        assert_ne!(user.password_hash, ada.password_hash);
+        assert!(!repository.revoke("hash-1").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_admins_list_and_revoke_sessions() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin_id = repository.create_test_user("admin", Role::Admin).await.id;
+        let ada_id = repository.create_test_user("ada", Role::Student).await.id;
+        repository
+            .store("hash-1", ada_id, "family", i64::MAX)
+            .await
+            .unwrap();
+
+        repository
+            .store("hash-2", ada_id, "other", i64::MAX)
+            .await
+            .unwrap();
+        for family in ["family", "other"] {
+            repository
+                .record_session(family, ada_id, Some("10.0.0.1"), None)
+                .await
+                .unwrap();
+        }
+        let app = app!(repository);
+
+        let sessions: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            admin_request(test::TestRequest::get(), admin_id)
+                .uri(&format!("/admin/users/{}/sessions", ada_id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(sessions.as_array().unwrap().len(), 2);
+        let session_id = sessions[0]["id"].as_i64().unwrap();
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::delete(), admin_id)
+                .uri(&format!(
+                    "/admin/users/{}/sessions/{}",
+                    admin_id, session_id
+                ))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::delete(), admin_id)
+                .uri(&format!("/admin/users/{}/sessions/{}", ada_id, session_id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(repository.active_sessions(ada_id).await.unwrap().len(), 1);
+
+        let resp = test::call_service(
+            &app,
+            admin_request(test::TestRequest::delete(), admin_id)
+                .uri(&format!("/admin/users/{}/sessions", ada_id))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.active_sessions(ada_id).await.unwrap().is_empty());
+        let events = repository
+            .audit_events(&AuditQuery {
+                event: Some("sessions_revoked".to_string()),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events[0].user_id, Some(ada_id));
+    }
+}
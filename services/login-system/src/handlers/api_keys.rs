@@ -0,0 +1,217 @@
+use actix_web::{delete, get, post, web::Data, web::Json, web::Path, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::{ApiScope, AuthenticatedUser};
+use crate::error::{ApiError, FieldErrors};
+use crate::repository::{ApiKey, ApiKeyRepository, AuditEvent, AuditRepository, NewApiKey};
+use crate::tokens::{self, hash_token};
+use crate::validation::validate_api_key_name;
+
+/// Characters of the key kept in the clear, prefix included.
+const DISPLAYED_PREFIX_CHARS: usize = 12;
+const MAX_EXPIRY_DAYS: i64 = 366;
+
+#[derive(Deserialize)]
+pub struct NewApiKeyRequest {
+    name: String,
+    scopes: Vec<ApiScope>,
+    /// Keys without an expiry last until they are revoked.
+    expires_in_days: Option<i64>,
+}
+
+/// The key is only ever shown in this response.
+#[derive(Serialize)]
+struct CreatedApiKey {
+    key: String,
+    #[serde(flatten)]
+    api_key: ApiKey,
+}
+
+/// Services read users and classes of every organization, so only platform
+/// admins manage their keys.
+#[post("/admin/api-keys")]
+async fn create_api_key(
+    admin: AuthenticatedUser,
+    api_keys: Data<dyn ApiKeyRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    form: Json<NewApiKeyRequest>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_platform_admin()?;
+    let form = form.into_inner();
+    let name = validate_api_key_name(&form.name)?;
+
+    let mut errors = FieldErrors::new();
+    if form.scopes.is_empty() {
+        errors.insert("scopes", vec!["At least one scope is required".to_string()]);
+    }
+    if let Some(days) = form.expires_in_days {
+        if !(1..=MAX_EXPIRY_DAYS).contains(&days) {
+            errors.insert(
+                "expires_in_days",
+                vec![format!(
+                    "Expiry must be between 1 and {} days",
+                    MAX_EXPIRY_DAYS
+                )],
+            );
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+
+    let mut scopes = form.scopes;
+    scopes.sort_by_key(ApiScope::as_str);
+    scopes.dedup();
+
+    let key = tokens::api_key();
+    let now = chrono::Utc::now().timestamp();
+    let api_key = api_keys
+        .create_api_key(NewApiKey {
+            name,
+            prefix: key[..DISPLAYED_PREFIX_CHARS].to_string(),
+            key_hash: hash_token(&key),
+            scopes,
+            created_by: admin.id,
+            expires_at: form.expires_in_days.map(|days| now + days * 24 * 60 * 60),
+        })
+        .await?;
+
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Key {} ({})", api_key.id, api_key.name)),
+            ..request_event(&req, "api_key_created", Some(admin.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::Created().json(CreatedApiKey { key, api_key }))
+}
+
+#[get("/admin/api-keys")]
+async fn list_api_keys(
+    admin: AuthenticatedUser,
+    api_keys: Data<dyn ApiKeyRepository>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_platform_admin()?;
+    Ok(HttpResponse::Ok().json(api_keys.api_keys().await?))
+}
+
+/// Takes effect on the key's next request.
+#[delete("/admin/api-keys/{id}")]
+async fn revoke_api_key(
+    admin: AuthenticatedUser,
+    api_keys: Data<dyn ApiKeyRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_platform_admin()?;
+    let id = path.into_inner();
+    if !api_keys.revoke_api_key(id).await? {
+        return Err(ApiError::not_found("API key not found"));
+    }
+
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Key {}", id)),
+            ..request_event(&req, "api_key_revoked", Some(admin.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::handlers::service::find_user;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{NewUser, UserRepository, DEFAULT_ORGANIZATION_ID};
+    use crate::tokens::TokenIssuer;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_created_key_authenticates_until_revoked() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let admin = repository
+            .create(NewUser {
+                username: "admin".to_string(),
+                email: None,
+                password_hash: "hash".to_string(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        repository.set_role(admin.id, Role::Admin).await.unwrap();
+        let issuer = TokenIssuer::for_tests();
+        let admin_token = issuer
+            .access_token(admin.id, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn ApiKeyRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(issuer))
+                .service(create_api_key)
+                .service(list_api_keys)
+                .service(revoke_api_key)
+                .service(find_user),
+        )
+        .await;
+        let as_admin = |req: test::TestRequest| {
+            req.insert_header((header::AUTHORIZATION, format!("Bearer {}", admin_token)))
+        };
+        let lookup = |key: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/service/users/{}", admin.id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", key)))
+                .to_request()
+        };
+
+        let req = as_admin(test::TestRequest::post().uri("/admin/api-keys"))
+            .set_json(json!({ "name": "grader", "scopes": [], "expires_in_days": 0 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = as_admin(test::TestRequest::post().uri("/admin/api-keys"))
+            .set_json(json!({ "name": "grader", "scopes": ["users:read"], "expires_in_days": 30 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: Value = test::read_body_json(resp).await;
+        let key = created["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with(created["prefix"].as_str().unwrap()));
+
+        let resp = test::call_service(&app, lookup(&key)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user: Value = test::read_body_json(resp).await;
+        assert_eq!(user["username"], "admin");
+
+        // The key never shows up again, but its last use does.
+        let req = as_admin(test::TestRequest::get().uri("/admin/api-keys")).to_request();
+        let keys: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(keys[0].get("key").is_none());
+        assert!(keys[0]["last_used_at"].is_i64());
+
+        // User tokens aren't API keys.
+        let resp = test::call_service(&app, lookup(&admin_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = as_admin(
+            test::TestRequest::delete().uri(&format!("/admin/api-keys/{}", created["id"])),
+        )
+        .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = test::call_service(&app, lookup(&key)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
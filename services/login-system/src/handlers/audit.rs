@@ -0,0 +1,244 @@
+use actix_web::{get, http::header, web::Bytes, web::Data, web::Query, HttpRequest, HttpResponse};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, Role};
+use crate::error::ApiError;
+use crate::repository::{AuditEvent, AuditOutcome, AuditQuery, AuditRepository, StoredAuditEvent};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+/// The export reads the log in pages this big.
+const EXPORT_PAGE_SIZE: i64 = 1000;
+
+/// `GET /admin/audit-events?event=login_failed&user_id=7&since=1700000000`.
+/// Times are Unix timestamps.
+#[derive(Deserialize)]
+pub struct AuditFilters {
+    event: Option<String>,
+    user_id: Option<i64>,
+    ip: Option<String>,
+    outcome: Option<AuditOutcome>,
+    since: Option<i64>,
+    until: Option<i64>,
+    /// `next_before` from the previous page.
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl AuditFilters {
+    /// Admins of a school only see events about its members.
+    fn query(&self, admin: &AuthenticatedUser, limit: i64) -> AuditQuery {
+        AuditQuery {
+            event: self.event.clone(),
+            user_id: self.user_id,
+            ip: self.ip.clone(),
+            outcome: self.outcome,
+            since: self.since,
+            until: self.until,
+            organization_id: (!admin.is_platform_admin()).then_some(admin.organization_id),
+            before_id: self.before,
+            limit,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditPage {
+    events: Vec<StoredAuditEvent>,
+    /// Pass as `before` to get the next page. Missing on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_before: Option<i64>,
+}
+
+/// Newest first.
+#[get("/admin/audit-events")]
+async fn list_audit_events(
+    admin: AuthenticatedUser,
+    audit: Data<dyn AuditRepository>,
+    filters: Query<AuditFilters>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let limit = filters.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::bad_request(format!(
+            "limit must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    let events = audit.audit_events(&filters.query(&admin, limit)).await?;
+    let next_before = match events.last() {
+        Some(last) if events.len() as i64 == limit => Some(last.id),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(AuditPage {
+        events,
+        next_before,
+    }))
+}
+
+/// Every matching event as JSON lines, newest first. Takes the same filters as
+/// the listing, except for `limit`. The response is streamed a page at a time,
+/// so a large log is never held in memory as a whole.
+#[get("/admin/audit-events/export")]
+async fn export_audit_events(
+    admin: AuthenticatedUser,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    filters: Query<AuditFilters>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let query = filters.query(&admin, EXPORT_PAGE_SIZE);
+
+    // Exports leave the building, so they are audited too. Recorded before the
+    // first page, since a client that hangs up halfway still has what it got.
+    audit
+        .record(AuditEvent {
+            detail: Some(req.query_string().to_string()).filter(|filters| !filters.is_empty()),
+            ..request_event(&req, "audit_log_exported", Some(admin.id))
+        })
+        .await?;
+
+    let pages = stream::unfold(Some(query), move |query| {
+        let audit = audit.clone();
+        async move {
+            let mut query = query?;
+            match export_page(&**audit, &mut query).await {
+                Ok((page, more)) => Some((Ok(page), more.then_some(query))),
+                Err(e) => {
+                    // The status is already sent, so all that's left is to cut the response short.
+                    eprintln!("Error exporting audit events: {}", e);
+                    Some((Err(e), None))
+                }
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit-events.jsonl\"",
+        ))
+        .streaming(pages))
+}
+
+/// One page of the export as JSON lines, and whether there may be more.
+/// Moves `query` on to the next page.
+async fn export_page(
+    audit: &dyn AuditRepository,
+    query: &mut AuditQuery,
+) -> anyhow::Result<(Bytes, bool)> {
+    let events = audit.audit_events(query).await?;
+    let mut page = Vec::new();
+    for event in &events {
+        serde_json::to_writer(&mut page, event)?;
+        page.push(b'\n');
+    }
+    let more = match events.last() {
+        Some(last) if events.len() as i64 == EXPORT_PAGE_SIZE => {
+            query.before_id = Some(last.id);
+            true
+        }
+        _ => false,
+    };
+    Ok((Bytes::from(page), more))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::DEFAULT_ORGANIZATION_ID;
+    use crate::tokens::TokenIssuer;
+    use actix_web::{body::BodySize, body::MessageBody, http::StatusCode, test, App};
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_audit_log_is_paged_and_exported() {
+        let audit: Arc<dyn AuditRepository> = Arc::new(LibsqlRepository::in_memory().await);
+        for user_id in [1, 2, 1, 1] {
+            audit
+                .record(AuditEvent {
+                    event: "login_failed",
+                    user_id: Some(user_id),
+                    ip: None,
+                    user_agent: None,
+                    outcome: AuditOutcome::Failure,
+                    detail: None,
+                })
+                .await
+                .unwrap();
+        }
+        let issuer = TokenIssuer::for_tests();
+        let admin_token = issuer
+            .access_token(9, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+        let student_token = issuer
+            .access_token(1, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(audit))
+                .app_data(Data::new(issuer))
+                .service(export_audit_events)
+                .service(list_audit_events),
+        )
+        .await;
+        let get = |uri: &str, token: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, get("/admin/audit-events", &student_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            get("/admin/audit-events?user_id=1&limit=2", &admin_token),
+        )
+        .await;
+        assert_eq!(page["events"].as_array().unwrap().len(), 2);
+        assert_eq!(page["next_before"], 3);
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            get(
+                "/admin/audit-events?user_id=1&limit=2&before=3",
+                &admin_token,
+            ),
+        )
+        .await;
+        assert_eq!(page["events"][0]["id"], 1);
+        assert!(page.get("next_before").is_none());
+
+        let resp = test::call_service(
+            &app,
+            get("/admin/audit-events/export?outcome=failure", &admin_token),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.response().body().size(), BodySize::Stream);
+        let body = test::read_body(resp).await;
+        let lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["outcome"], "failure");
+
+        // The export itself shows up in the log.
+        let page: Value = test::call_and_read_body_json(
+            &app,
+            get("/admin/audit-events?event=audit_log_exported", &admin_token),
+        )
+        .await;
+        assert_eq!(page["events"][0]["user_id"], 9);
+    }
+}
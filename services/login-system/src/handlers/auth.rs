@@ -0,0 +1,583 @@
+use actix_web::{post, web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{client_ip, user_agent};
+use crate::config::TwoFactorPolicy;
+use crate::csrf::CsrfChecked;
+use crate::error::{ApiError, FieldErrors};
+use crate::handlers::two_factor::{check_totp_code, redeem_recovery_code};
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditRepository, ClassMembership, ClassRepository, LoginAttemptRepository,
+    OneTimeTokenRepository, RefreshTokenRepository, TwoFactorRepository, User, UserRepository,
+};
+use crate::throttle::LoginThrottle;
+use crate::tokens::{
+    hash_token, random_token, Purpose, TokenIssuer, ACCESS_TOKEN_TTL_SECS, REFRESH_TOKEN_TTL_SECS,
+};
+use crate::validation::{normalize_password, normalize_username};
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+/// Finishes a login that `/login` answered with a challenge. Takes either a
+/// code from the authenticator or one of the recovery codes.
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    mfa_token: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    refresh_token: String,
+}
+
+/// Returned by `/login` instead of tokens when the password alone isn't enough.
+#[derive(Serialize)]
+struct TwoFactorChallenge {
+    /// `totp` to send a code to `/login/2fa`, or `totp_enrollment` to set up
+    /// 2FA through `/2fa/setup` first, using `mfa_token` as the bearer token.
+    challenge: &'static str,
+    mfa_token: String,
+    expires_in: i64,
+}
+
+/// Answers with tokens, or with a `TwoFactorChallenge` if the account has 2FA
+/// or its role requires it.
+#[post("/login")]
+#[allow(clippy::too_many_arguments)]
+async fn login(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    classes: Data<dyn ClassRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    policy: Data<TwoFactorPolicy>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    issuer: Data<TokenIssuer>,
+    hasher: Data<PasswordHasher>,
+    req: HttpRequest,
+    form: Json<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let credentials: LoginRequest = form.into_inner();
+    let username = normalize_username(&credentials.username);
+
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &username);
+    throttle.check().await?;
+
+    let password = normalize_password(&credentials.password);
+    let Some(user) = users.find_by_username(&username).await? else {
+        hasher.verify_dummy(password).await?;
+        throttle.record_failure(None, "unknown_user").await?;
+        return Err(invalid_credentials());
+    };
+
+    if !hasher
+        .verify(password.clone(), user.password_hash.clone())
+        .await?
+    {
+        throttle
+            .record_failure(Some(user.id), "wrong_password")
+            .await?;
+        return Err(invalid_credentials());
+    }
+    // The password is only ever known here, so this is where old hashes are
+    // upgraded to the current algorithm and cost.
+    if hasher.needs_rehash(&user.password_hash) {
+        let password_hash = hasher.hash(password).await?;
+        users.set_password_hash(user.id, &password_hash).await?;
+    }
+    // Checked after the password, so it doesn't reveal which accounts exist.
+    if let Some(challenge) = login_challenge(&**two_factor, &policy, &issuer, &user).await? {
+        return Ok(challenge);
+    }
+
+    // Only a complete login clears the failures; clearing them after the
+    // password alone would let 2FA codes be guessed without backing off.
+    throttle.record_success(user.id, "password").await?;
+    issue_tokens(
+        &**refresh_tokens,
+        &**classes,
+        &issuer,
+        &req,
+        &user,
+        &random_token(),
+    )
+    .await
+}
+
+/// Each `mfa_token` allows a single attempt, so guessing codes means going
+/// through the password check every time.
+#[post("/login/2fa")]
+#[allow(clippy::too_many_arguments)]
+async fn login_two_factor(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    classes: Data<dyn ClassRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    used_tokens: Data<dyn OneTimeTokenRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    issuer: Data<TokenIssuer>,
+    hasher: Data<PasswordHasher>,
+    req: HttpRequest,
+    form: Json<TwoFactorLoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let request: TwoFactorLoginRequest = form.into_inner();
+    if request.code.is_none() && request.recovery_code.is_none() {
+        return Err(ApiError::validation(FieldErrors::from([(
+            "code",
+            vec!["A code or a recovery code is required".to_string()],
+        )])));
+    }
+
+    let claims = issuer
+        .verify_one_time(&request.mfa_token, Purpose::TwoFactorLogin)
+        .map_err(|_| invalid_mfa_token())?;
+    if !used_tokens.consume(&claims.jti, claims.exp).await? {
+        return Err(invalid_mfa_token());
+    }
+    let user_id: i64 = claims.sub.parse().map_err(|_| invalid_mfa_token())?;
+    let user = users
+        .find_by_id(user_id)
+        .await?
+        .ok_or_else(invalid_mfa_token)?;
+    // The account may have been disabled since the password was checked.
+    if user.disabled_at.is_some() {
+        return Err(ApiError::account_disabled());
+    }
+
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &user.username);
+    throttle.check().await?;
+
+    let accepted = match (&request.code, &request.recovery_code) {
+        (Some(code), _) => check_totp_code(&**two_factor, user.id, code).await?,
+        (None, Some(recovery_code)) => {
+            redeem_recovery_code(&**two_factor, &hasher, user.id, recovery_code).await?
+        }
+        (None, None) => false,
+    };
+    if !accepted {
+        throttle
+            .record_failure(Some(user.id), "wrong_two_factor_code")
+            .await?;
+        return Err(ApiError::unauthorized("Invalid two-factor code"));
+    }
+
+    let method = if request.code.is_some() {
+        "two_factor"
+    } else {
+        "recovery_code"
+    };
+    throttle.record_success(user.id, method).await?;
+    issue_tokens(
+        &**refresh_tokens,
+        &**classes,
+        &issuer,
+        &req,
+        &user,
+        &random_token(),
+    )
+    .await
+}
+
+/// Exchanges a refresh token for a new access token and a new refresh token.
+/// The presented token is revoked; presenting it again revokes its whole family,
+/// since that means it was stolen and used by someone else.
+#[post("/refresh")]
+async fn refresh(
+    users: Data<dyn UserRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    classes: Data<dyn ClassRepository>,
+    issuer: Data<TokenIssuer>,
+    req: HttpRequest,
+    form: Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let token_hash = hash_token(&form.refresh_token);
+
+    let token = refresh_tokens
+        .find(&token_hash)
+        .await?
+        .ok_or_else(invalid_refresh_token)?;
+
+    if token.expires_at <= chrono::Utc::now().timestamp() {
+        return Err(invalid_refresh_token());
+    }
+
+    // `revoke` only succeeds for a token that is still active, which covers both
+    // replays of an old token and two refreshes racing with the same one.
+    if !refresh_tokens.revoke(&token_hash).await? {
+        refresh_tokens.revoke_family(&token.family_id).await?;
+        return Err(invalid_refresh_token());
+    }
+
+    // Re-read the user so role and class changes are picked up on the next refresh.
+    let user = users
+        .find_by_id(token.user_id)
+        .await?
+        .ok_or_else(invalid_refresh_token)?;
+    if user.disabled_at.is_some() {
+        return Err(ApiError::account_disabled());
+    }
+
+    issue_tokens(
+        &**refresh_tokens,
+        &**classes,
+        &issuer,
+        &req,
+        &user,
+        &token.family_id,
+    )
+    .await
+}
+
+/// Revokes the refresh token and every token rotated from the same login.
+#[post("/logout")]
+async fn logout(
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    form: Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let token_hash = hash_token(&form.refresh_token);
+
+    if let Some(token) = refresh_tokens.find(&token_hash).await? {
+        refresh_tokens.revoke_family(&token.family_id).await?;
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// What stands between a user who proved who they are and their tokens: a
+/// disabled account or an unverified email is an error, and 2FA answers with a
+/// `TwoFactorChallenge`.
+pub(crate) async fn login_challenge(
+    two_factor: &dyn TwoFactorRepository,
+    policy: &TwoFactorPolicy,
+    issuer: &TokenIssuer,
+    user: &User,
+) -> Result<Option<HttpResponse>, ApiError> {
+    if user.disabled_at.is_some() {
+        return Err(ApiError::account_disabled());
+    }
+    if user.email_verified_at.is_none() {
+        return Err(ApiError::email_not_verified());
+    }
+
+    let totp_enabled = two_factor
+        .totp_credential(user.id)
+        .await?
+        .is_some_and(|credential| credential.confirmed);
+    if totp_enabled {
+        return two_factor_challenge(issuer, user.id, Purpose::TwoFactorLogin, "totp").map(Some);
+    }
+    if policy.is_required(user.role) {
+        return two_factor_challenge(
+            issuer,
+            user.id,
+            Purpose::TwoFactorEnrollment,
+            "totp_enrollment",
+        )
+        .map(Some);
+    }
+    Ok(None)
+}
+
+/// Starts a session with a new `family_id`, or continues it on refresh. Either
+/// way, the session remembers `req`'s client.
+pub(crate) async fn issue_tokens(
+    refresh_tokens: &dyn RefreshTokenRepository,
+    classes: &dyn ClassRepository,
+    issuer: &TokenIssuer,
+    req: &HttpRequest,
+    user: &User,
+    family_id: &str,
+) -> Result<HttpResponse, ApiError> {
+    let memberships = classes
+        .classes_of(user.id)
+        .await?
+        .into_iter()
+        .map(|(class, role)| ClassMembership { id: class.id, role })
+        .collect();
+    let access_token = issuer
+        .access_token(user.id, user.organization_id, user.role, memberships)
+        .map_err(|e| ApiError::internal("Error signing token", e))?;
+
+    let refresh_token = random_token();
+    let expires_at = chrono::Utc::now().timestamp() + REFRESH_TOKEN_TTL_SECS;
+    refresh_tokens
+        .store(&hash_token(&refresh_token), user.id, family_id, expires_at)
+        .await?;
+    refresh_tokens
+        .record_session(
+            family_id,
+            user.id,
+            Some(&client_ip(req)),
+            user_agent(req).as_deref(),
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_TTL_SECS,
+        refresh_token,
+    }))
+}
+
+fn two_factor_challenge(
+    issuer: &TokenIssuer,
+    user_id: i64,
+    purpose: Purpose,
+    challenge: &'static str,
+) -> Result<HttpResponse, ApiError> {
+    let mfa_token = issuer
+        .one_time_token(user_id, None, purpose)
+        .map_err(|e| ApiError::internal("Error signing token", e))?;
+
+    Ok(HttpResponse::Ok().json(TwoFactorChallenge {
+        challenge,
+        mfa_token,
+        expires_in: purpose.ttl_secs(),
+    }))
+}
+
+fn invalid_credentials() -> ApiError {
+    ApiError::unauthorized("Invalid username or password")
+}
+
+fn invalid_refresh_token() -> ApiError {
+    ApiError::unauthorized("Invalid refresh token")
+}
+
+fn invalid_mfa_token() -> ApiError {
+    ApiError::unauthorized("Invalid or expired two-factor login, please sign in again")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::repository::libsql::{LibsqlRepository, TEST_PASSWORD};
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    macro_rules! app {
+        ($repository:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn TwoFactorRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn LoginAttemptRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::new(TwoFactorPolicy::default()))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .service(login)
+                    .service(refresh)
+                    .service(logout),
+            )
+            .await
+        };
+    }
+
+    /// A verified user whose password was hashed with bcrypt.
+    async fn create_ada(repository: &LibsqlRepository) -> User {
+        let user = repository.create_test_user("ada", Role::Student).await;
+        repository.mark_email_verified(user.id).await.unwrap();
+        user
+    }
+
+    fn attempt(password: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({ "username": "ada", "password": password }))
+    }
+
+    fn refresh_with(refresh_token: &serde_json::Value) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/refresh")
+            .set_json(serde_json::json!({ "refresh_token": refresh_token }))
+    }
+
+    #[actix_web::test]
+    async fn test_failed_logins_back_off() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        create_ada(&repository).await;
+        let app = app!(repository);
+
+        for _ in 0..3 {
+            let resp = test::call_service(&app, attempt("wrong password").to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Past the free attempts, the delay grows with each failure.
+        let now = chrono::Utc::now().timestamp();
+        for _ in 0..5 {
+            repository
+                .record_failure(&crate::throttle::account_key("ada"), now, 0)
+                .await
+                .unwrap();
+        }
+
+        // Even the right password has to wait.
+        let resp = test::call_service(&app, attempt(TEST_PASSWORD).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[actix_web::test]
+    async fn test_login_upgrades_bcrypt_hashes() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = create_ada(&repository).await;
+        let app = app!(repository);
+
+        let resp = test::call_service(&app, attempt("wrong password").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let unchanged = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert_eq!(unchanged.password_hash, user.password_hash);
+
+        let resp = test::call_service(&app, attempt(TEST_PASSWORD).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let upgraded = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert!(upgraded.password_hash.starts_with("$argon2id$"));
+
+        let resp = test::call_service(&app, attempt(TEST_PASSWORD).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_disabled_accounts_cannot_sign_in_or_refresh() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = create_ada(&repository).await;
+        let app = app!(repository);
+
+        let tokens: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        repository.set_disabled(user.id, Some(1)).await.unwrap();
+
+        let resp = test::call_service(&app, attempt(TEST_PASSWORD).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "account_disabled");
+
+        let resp =
+            test::call_service(&app, refresh_with(&tokens["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_refresh_rotates_the_token() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        create_ada(&repository).await;
+        let app = app!(repository);
+
+        let first: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        let resp =
+            test::call_service(&app, refresh_with(&first["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let second: serde_json::Value = test::read_body_json(resp).await;
+        assert_ne!(second["refresh_token"], first["refresh_token"]);
+
+        let resp =
+            test::call_service(&app, refresh_with(&second["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_reusing_a_refresh_token_revokes_its_family() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        create_ada(&repository).await;
+        let app = app!(repository);
+
+        let stolen: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        let other_login: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        let rotated: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            refresh_with(&stolen["refresh_token"]).to_request(),
+        )
+        .await;
+
+        let resp =
+            test::call_service(&app, refresh_with(&stolen["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // The token it was rotated into goes too, whoever holds it.
+        let resp =
+            test::call_service(&app, refresh_with(&rotated["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(
+            &app,
+            refresh_with(&other_login["refresh_token"]).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_logout_revokes_the_login() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        create_ada(&repository).await;
+        let app = app!(repository);
+
+        let first: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        let other_login: serde_json::Value =
+            test::call_and_read_body_json(&app, attempt(TEST_PASSWORD).to_request()).await;
+        let rotated: serde_json::Value =
+            test::call_and_read_body_json(&app, refresh_with(&first["refresh_token"]).to_request())
+                .await;
+
+        let req = test::TestRequest::post()
+            .uri("/logout")
+            .set_json(serde_json::json!({ "refresh_token": rotated["refresh_token"] }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp =
+            test::call_service(&app, refresh_with(&rotated["refresh_token"]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(
+            &app,
+            refresh_with(&other_login["refresh_token"]).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Logging out again, or with a token that never existed, is not an error.
+        for refresh_token in [rotated["refresh_token"].clone(), "unknown".into()] {
+            let req = test::TestRequest::post()
+                .uri("/logout")
+                .set_json(serde_json::json!({ "refresh_token": refresh_token }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+    }
+}
@@ -0,0 +1,707 @@
+use actix_web::{delete, get, post, web::Data, web::Json, web::Path, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{AuthenticatedUser, ClassRole, Role};
+use crate::config::PublicBaseUrl;
+use crate::error::{ApiError, FieldErrors};
+use crate::handlers::organizations::{email_domain_error, find_organization};
+use crate::mailer::{Email, Mailer};
+use crate::repository::{
+    Class, ClassRepository, OrganizationRepository, RepositoryError, UserRepository,
+};
+use crate::tokens::random_code;
+use crate::validation::{validate_class_name, validate_email};
+
+const JOIN_CODE_LENGTH: usize = 8;
+/// A new code that happens to be taken is drawn again this many times.
+const JOIN_CODE_ATTEMPTS: usize = 3;
+
+#[derive(Deserialize)]
+pub struct NewClass {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct JoinRequest {
+    join_code: String,
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct InstructorRequest {
+    username: String,
+}
+
+/// A class as the caller sees it. Only instructors get the join code.
+#[derive(Serialize)]
+struct ClassView {
+    id: i64,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<ClassRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    join_code: Option<String>,
+}
+
+impl ClassView {
+    fn new(class: Class, role: Option<ClassRole>) -> Self {
+        ClassView {
+            id: class.id,
+            name: class.name,
+            role,
+            join_code: (role == Some(ClassRole::Instructor)).then_some(class.join_code),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JoinCode {
+    join_code: String,
+}
+
+/// Instructors create classes and become their first instructor.
+#[post("/classes")]
+async fn create_class(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    form: Json<NewClass>,
+) -> Result<HttpResponse, ApiError> {
+    caller.require_role(Role::Instructor)?;
+    let name = validate_class_name(&form.name)?;
+
+    for _ in 0..JOIN_CODE_ATTEMPTS {
+        match classes
+            .create_class(&name, &new_join_code(), caller.id)
+            .await
+        {
+            Ok(class) => {
+                return Ok(HttpResponse::Created()
+                    .json(ClassView::new(class, Some(ClassRole::Instructor))))
+            }
+            Err(RepositoryError::Conflict(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(ApiError::internal(
+        "Error creating class",
+        "No free join code",
+    ))
+}
+
+/// The classes the caller teaches or attends.
+#[get("/classes")]
+async fn list_classes(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let views: Vec<ClassView> = classes
+        .classes_of(caller.id)
+        .await?
+        .into_iter()
+        .map(|(class, role)| ClassView::new(class, Some(role)))
+        .collect();
+    Ok(HttpResponse::Ok().json(views))
+}
+
+/// Enrolls the caller as a student. Members who join again keep their role.
+#[post("/classes/join")]
+async fn join_class(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    form: Json<JoinRequest>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let join_code = form.join_code.trim().to_uppercase();
+    // Codes of other schools' classes don't work here.
+    let class = classes
+        .find_class_by_join_code(&join_code)
+        .await?
+        .filter(|class| class.organization_id == caller.organization_id)
+        .ok_or_else(|| ApiError::not_found("Unknown join code"))?;
+
+    let role = enroll(&**classes, class.id, caller.id).await?;
+    Ok(HttpResponse::Ok().json(ClassView::new(class, Some(role))))
+}
+
+/// Draws a new join code, so the old one stops working.
+#[post("/classes/{id}/join-code")]
+async fn regenerate_join_code(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let class = require_instructor(&**classes, &caller, path.into_inner()).await?;
+
+    for _ in 0..JOIN_CODE_ATTEMPTS {
+        let join_code = new_join_code();
+        match classes.set_join_code(class.id, &join_code).await {
+            Ok(_) => return Ok(HttpResponse::Ok().json(JoinCode { join_code })),
+            Err(RepositoryError::Conflict(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(ApiError::internal(
+        "Error changing join code",
+        "No free join code",
+    ))
+}
+
+/// The roster, for the class's instructors and admins.
+#[get("/classes/{id}/members")]
+async fn list_members(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let class = require_instructor(&**classes, &caller, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(classes.roster(class.id).await?))
+}
+
+/// Adds a co-instructor. They need the instructor role already; teaching one
+/// class doesn't make anyone an instructor elsewhere.
+#[post("/classes/{id}/instructors")]
+async fn add_instructor(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    users: Data<dyn UserRepository>,
+    path: Path<i64>,
+    form: Json<InstructorRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let class = require_instructor(&**classes, &caller, path.into_inner()).await?;
+    let user = users
+        .find_by_username(form.username.trim())
+        .await?
+        .filter(|user| user.organization_id == class.organization_id)
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    if user.role < Role::Instructor {
+        return Err(ApiError::bad_request(
+            "Only users with the instructor role can teach a class",
+        ));
+    }
+
+    classes
+        .add_member(class.id, user.id, ClassRole::Instructor)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Instructors remove members; anyone may leave. The last instructor can't go,
+/// so every class stays manageable by someone other than an admin.
+#[delete("/classes/{id}/members/{user_id}")]
+async fn remove_member(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    path: Path<(i64, i64)>,
+) -> Result<HttpResponse, ApiError> {
+    let (class_id, user_id) = path.into_inner();
+    if user_id != caller.id {
+        require_instructor(&**classes, &caller, class_id).await?;
+    }
+
+    let role = classes
+        .member_role(class_id, user_id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Not a member of this class"))?;
+    if role == ClassRole::Instructor {
+        let instructors = classes
+            .roster(class_id)
+            .await?
+            .iter()
+            .filter(|member| member.role == ClassRole::Instructor)
+            .count();
+        if instructors <= 1 {
+            return Err(ApiError::bad_request(
+                "A class needs at least one instructor",
+            ));
+        }
+    }
+
+    classes.remove_member(class_id, user_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Emails an invite. It can only be accepted by a user who verified that
+/// address, whether they have an account yet or not, so the address has to
+/// be one the class's organization accepts.
+#[post("/classes/{id}/invites")]
+async fn invite(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    organizations: Data<dyn OrganizationRepository>,
+    mailer: Data<dyn Mailer>,
+    base_url: Data<PublicBaseUrl>,
+    path: Path<i64>,
+    form: Json<InviteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let class = require_instructor(&**classes, &caller, path.into_inner()).await?;
+    let email = validate_email(&form.email)?;
+    let organization = find_organization(&**organizations, class.organization_id).await?;
+    if let Some(message) = email_domain_error(&**organizations, &organization, &email).await? {
+        return Err(ApiError::validation(FieldErrors::from([(
+            "email",
+            vec![message],
+        )])));
+    }
+
+    classes.invite(class.id, &email).await?;
+    let sent = mailer
+        .send(Email {
+            to: email,
+            subject: format!("You're invited to {}", class.name),
+            body: format!(
+                "Hi,\n\nYou have been invited to join the class {}.\n\nSign in with this email address to accept:\n\n{}\n\nNo account yet? Create one with this address first:\n\n{}",
+                class.name,
+                base_url.join("/login"),
+                base_url.join("/register")
+            ),
+        })
+        .await;
+    if let Err(e) = sent {
+        eprintln!("Error sending class invite: {}", e);
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Classes inviting the caller's verified address.
+#[get("/classes/invites")]
+async fn list_invites(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    users: Data<dyn UserRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let email = verified_email(&**users, &caller).await?;
+    let views: Vec<ClassView> = classes
+        .invites_for(&email)
+        .await?
+        .into_iter()
+        .filter(|class| class.organization_id == caller.organization_id)
+        .map(|class| ClassView::new(class, None))
+        .collect();
+    Ok(HttpResponse::Ok().json(views))
+}
+
+#[post("/classes/invites/{id}/accept")]
+async fn accept_invite(
+    caller: AuthenticatedUser,
+    classes: Data<dyn ClassRepository>,
+    users: Data<dyn UserRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let class_id = path.into_inner();
+    let email = verified_email(&**users, &caller).await?;
+    // The invite stays for whoever has the address in the class's school.
+    let class = classes
+        .find_class(class_id)
+        .await?
+        .filter(|class| class.organization_id == caller.organization_id)
+        .ok_or_else(|| ApiError::not_found("No invite to this class"))?;
+    if !classes.take_invite(class_id, &email).await? {
+        return Err(ApiError::not_found("No invite to this class"));
+    }
+
+    let role = enroll(&**classes, class.id, caller.id).await?;
+    Ok(HttpResponse::Ok().json(ClassView::new(class, Some(role))))
+}
+
+/// Fails with 403 unless the caller teaches the class or is an admin.
+async fn require_instructor(
+    classes: &dyn ClassRepository,
+    caller: &AuthenticatedUser,
+    class_id: i64,
+) -> Result<Class, ApiError> {
+    let class = classes
+        .find_class(class_id)
+        .await?
+        .ok_or_else(class_not_found)?;
+    if teaches(classes, caller, &class).await? {
+        Ok(class)
+    } else {
+        Err(ApiError::forbidden(
+            "Only the class's instructors can do that",
+        ))
+    }
+}
+
+/// Whether the caller may manage the class: its instructors and the admins of
+/// its organization can.
+pub(crate) async fn teaches(
+    classes: &dyn ClassRepository,
+    caller: &AuthenticatedUser,
+    class: &Class,
+) -> Result<bool, ApiError> {
+    Ok(
+        (caller.role >= Role::Admin && caller.sees_organization(class.organization_id))
+            || classes.member_role(class.id, caller.id).await? == Some(ClassRole::Instructor),
+    )
+}
+
+/// Adds the user as a student, unless they already are a member.
+async fn enroll(
+    classes: &dyn ClassRepository,
+    class_id: i64,
+    user_id: i64,
+) -> Result<ClassRole, ApiError> {
+    if let Some(role) = classes.member_role(class_id, user_id).await? {
+        return Ok(role);
+    }
+    classes
+        .add_member(class_id, user_id, ClassRole::Student)
+        .await?;
+    Ok(ClassRole::Student)
+}
+
+async fn verified_email(
+    users: &dyn UserRepository,
+    caller: &AuthenticatedUser,
+) -> Result<String, ApiError> {
+    let user = users
+        .find_by_id(caller.id)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Invalid access token"))?;
+    match user.email {
+        Some(email) if user.email_verified_at.is_some() => Ok(email),
+        _ => Err(ApiError::email_not_verified()),
+    }
+}
+
+fn new_join_code() -> String {
+    random_code(JOIN_CODE_LENGTH)
+}
+
+fn class_not_found() -> ApiError {
+    ApiError::not_found("Class not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::auth::issue_tokens;
+    use crate::mailer::LogMailer;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{ClassMembership, DEFAULT_ORGANIZATION_ID};
+    use crate::tokens::TokenIssuer;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    /// A verified test user and an access token for them.
+    async fn signed_in(repository: &LibsqlRepository, username: &str, role: Role) -> (i64, String) {
+        let user = repository.create_test_user(username, role).await;
+        repository.mark_email_verified(user.id).await.unwrap();
+        let token = TokenIssuer::for_tests()
+            .access_token(user.id, DEFAULT_ORGANIZATION_ID, role, Vec::new())
+            .unwrap();
+        (user.id, token)
+    }
+
+    macro_rules! app {
+        ($repository:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OrganizationRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        "http://localhost:8000".parse().unwrap(),
+                    )))
+                    .service(create_class)
+                    .service(list_classes)
+                    .service(join_class)
+                    .service(regenerate_join_code)
+                    .service(list_members)
+                    .service(add_instructor)
+                    .service(remove_member)
+                    .service(invite)
+                    .service(list_invites)
+                    .service(accept_invite),
+            )
+            .await
+        };
+    }
+
+    fn post(uri: &str, token: &str, body: serde_json::Value) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .set_json(body)
+    }
+
+    fn get(uri: &str, token: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    #[actix_web::test]
+    async fn test_students_join_with_the_code() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (_, grace) = signed_in(&repository, "grace", Role::Instructor).await;
+        let (ada_id, ada) = signed_in(&repository, "ada", Role::Student).await;
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            post("/classes", &ada, serde_json::json!({ "name": "Compilers" })).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let class: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/classes",
+                &grace,
+                serde_json::json!({ "name": "Compilers" }),
+            )
+            .to_request(),
+        )
+        .await;
+        let class_id = class["id"].as_i64().unwrap();
+        let join_code = class["join_code"].as_str().unwrap().to_lowercase();
+
+        let joined: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/classes/join",
+                &ada,
+                serde_json::json!({ "join_code": join_code }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(joined["role"], "student");
+        assert!(joined.get("join_code").is_none());
+
+        let uri = format!("/classes/{}/members", class_id);
+        let resp = test::call_service(&app, get(&uri, &ada).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let roster: serde_json::Value =
+            test::call_and_read_body_json(&app, get(&uri, &grace).to_request()).await;
+        assert_eq!(roster[1]["user_id"], ada_id);
+
+        // The old code stops working once a new one is drawn.
+        let resp = test::call_service(
+            &app,
+            post(
+                &format!("/classes/{}/join-code", class_id),
+                &grace,
+                serde_json::json!({}),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(
+            &app,
+            post(
+                "/classes/join",
+                &ada,
+                serde_json::json!({ "join_code": join_code }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_impersonators_cannot_join_classes() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (grace_id, grace) = signed_in(&repository, "grace", Role::Instructor).await;
+        let (ada_id, _) = signed_in(&repository, "ada", Role::Student).await;
+        let app = app!(repository);
+        let class: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/classes",
+                &grace,
+                serde_json::json!({ "name": "Compilers" }),
+            )
+            .to_request(),
+        )
+        .await;
+
+        let act = TokenIssuer::for_tests()
+            .impersonation_token(
+                ada_id,
+                DEFAULT_ORGANIZATION_ID,
+                Role::Student,
+                Vec::new(),
+                grace_id,
+            )
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            post(
+                "/classes/join",
+                &act,
+                serde_json::json!({ "join_code": class["join_code"] }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "impersonation_forbidden");
+        assert!(repository.classes_of(ada_id).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_invites_go_to_the_verified_address() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (_, grace) = signed_in(&repository, "grace", Role::Instructor).await;
+        let (_, ada) = signed_in(&repository, "ada", Role::Student).await;
+        let (_, alan) = signed_in(&repository, "alan", Role::Student).await;
+        let app = app!(repository);
+
+        let class: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/classes",
+                &grace,
+                serde_json::json!({ "name": "Compilers" }),
+            )
+            .to_request(),
+        )
+        .await;
+        let class_id = class["id"].as_i64().unwrap();
+        let resp = test::call_service(
+            &app,
+            post(
+                &format!("/classes/{}/invites", class_id),
+                &grace,
+                serde_json::json!({ "email": "Ada@Example.com" }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        let invites: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/classes/invites", &ada).to_request()).await;
+        assert_eq!(invites[0]["id"], class_id);
+
+        let accept = format!("/classes/invites/{}/accept", class_id);
+        let resp = test::call_service(
+            &app,
+            post(&accept, &alan, serde_json::json!({})).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            post(&accept, &ada, serde_json::json!({})).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(
+            &app,
+            post(&accept, &ada, serde_json::json!({})).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let classes: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/classes", &ada).to_request()).await;
+        assert_eq!(classes[0]["name"], "Compilers");
+    }
+
+    #[actix_web::test]
+    async fn test_last_instructor_cannot_leave() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (grace_id, grace) = signed_in(&repository, "grace", Role::Instructor).await;
+        let (_, ada) = signed_in(&repository, "ada", Role::Student).await;
+        let app = app!(repository);
+
+        let class: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            post(
+                "/classes",
+                &grace,
+                serde_json::json!({ "name": "Compilers" }),
+            )
+            .to_request(),
+        )
+        .await;
+        let class_id = class["id"].as_i64().unwrap();
+        let leave = || {
+            test::TestRequest::delete()
+                .uri(&format!("/classes/{}/members/{}", class_id, grace_id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", grace)))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, leave()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Students can't be made instructors of a class.
+        let resp = test::call_service(
+            &app,
+            post(
+                &format!("/classes/{}/instructors", class_id),
+                &grace,
+                serde_json::json!({ "username": "ada" }),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri(&format!("/classes/{}/members/{}", class_id, grace_id))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", ada)))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_tokens_list_class_memberships() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (grace_id, _) = signed_in(&repository, "grace", Role::Instructor).await;
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace_id)
+            .await
+            .unwrap();
+
+        let grace = repository.find_by_id(grace_id).await.unwrap().unwrap();
+
+        let issuer = TokenIssuer::for_tests();
+        let resp = issue_tokens(
+            &*repository,
+            &*repository,
+            &issuer,
+            &test::TestRequest::default().to_http_request(),
+            &grace,
+            "family",
+        )
+        .await
+        .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(&actix_web::body::to_bytes(resp.into_body()).await.unwrap())
+                .unwrap();
+
+        let claims = issuer
+            .verify(body["access_token"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            claims.classes,
+            vec![ClassMembership {
+                id: class.id,
+                role: ClassRole::Instructor,
+            }]
+        );
+    }
+}
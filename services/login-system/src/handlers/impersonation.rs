@@ -0,0 +1,196 @@
+use actix_web::{post, web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, ClassRole, Role};
+use crate::csrf::CsrfChecked;
+use crate::error::ApiError;
+use crate::repository::{
+    AuditEvent, AuditRepository, ClassMembership, ClassRepository, PublicProfile, UserRepository,
+};
+use crate::tokens::{TokenIssuer, IMPERSONATION_TTL_SECS};
+
+#[derive(Deserialize)]
+pub struct ImpersonationRequest {
+    user_id: i64,
+}
+
+#[derive(Serialize)]
+struct ImpersonationResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    /// Who the token is for.
+    user: PublicProfile,
+}
+
+/// An access token to see the service as a student does, e.g. to find out
+/// why an exercise won't let them submit. Instructors may impersonate the
+/// students of their classes, admins every student of their organization.
+///
+/// The token names the caller in its `act` claim and can't be refreshed.
+/// It can't change the student's password, profile or account, and starting
+/// it is audited.
+#[post("/impersonation")]
+#[allow(clippy::too_many_arguments)]
+async fn impersonate(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    classes: Data<dyn ClassRepository>,
+    audit: Data<dyn AuditRepository>,
+    issuer: Data<TokenIssuer>,
+    req: HttpRequest,
+    form: Json<ImpersonationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    // No impersonating someone else from behind a student's token.
+    caller.forbid_impersonation()?;
+    caller.require_role(Role::Instructor)?;
+
+    let user = users
+        .find_by_id(form.user_id)
+        .await?
+        .filter(|user| caller.sees_organization(user.organization_id))
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    if user.role != Role::Student {
+        return Err(ApiError::forbidden("Only students can be impersonated"));
+    }
+    if user.disabled_at.is_some() {
+        return Err(ApiError::account_disabled());
+    }
+
+    let memberships = classes.classes_of(user.id).await?;
+    if caller.role < Role::Admin {
+        let taught: Vec<i64> = classes
+            .classes_of(caller.id)
+            .await?
+            .into_iter()
+            .filter(|(_, role)| *role == ClassRole::Instructor)
+            .map(|(class, _)| class.id)
+            .collect();
+        let in_class = memberships
+            .iter()
+            .any(|(class, role)| *role == ClassRole::Student && taught.contains(&class.id));
+        if !in_class {
+            return Err(ApiError::forbidden(
+                "Instructors can only impersonate students in their classes",
+            ));
+        }
+    }
+
+    let access_token = issuer
+        .impersonation_token(
+            user.id,
+            user.organization_id,
+            user.role,
+            memberships
+                .into_iter()
+                .map(|(class, role)| ClassMembership { id: class.id, role })
+                .collect(),
+            caller.id,
+        )
+        .map_err(|e| ApiError::internal("Error signing token", e))?;
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("By {} {}", caller.role, caller.id)),
+            ..request_event(&req, "impersonation_started", Some(user.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ImpersonationResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: IMPERSONATION_TTL_SECS,
+        user: user.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{AuditQuery, DEFAULT_ORGANIZATION_ID};
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_instructors_impersonate_their_students() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let grace = repository
+            .create_test_user("grace", Role::Instructor)
+            .await
+            .id;
+        let ada = repository.create_test_user("ada", Role::Student).await.id;
+        let bob = repository.create_test_user("bob", Role::Student).await.id;
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace)
+            .await
+            .unwrap();
+        repository
+            .add_member(class.id, ada, ClassRole::Student)
+            .await
+            .unwrap();
+        let issuer = TokenIssuer::for_tests();
+        let grace_token = issuer
+            .access_token(grace, DEFAULT_ORGANIZATION_ID, Role::Instructor, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn ClassRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(TokenIssuer::for_tests()))
+                .service(impersonate),
+        )
+        .await;
+        let request = |token: &str, user_id: i64| {
+            test::TestRequest::post()
+                .uri("/impersonation")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(serde_json::json!({ "user_id": user_id }))
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, request(&grace_token, ada)).await;
+        assert_eq!(body["user"]["username"], "ada");
+        let claims = issuer
+            .verify(body["access_token"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(claims.sub, ada.to_string());
+        assert_eq!(claims.act.unwrap().sub, grace.to_string());
+        assert_eq!(claims.classes[0].id, class.id);
+
+        // Not in one of Grace's classes.
+        let resp = test::call_service(&app, request(&grace_token, bob)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        // No impersonating from an impersonation token.
+        let resp =
+            test::call_service(&app, request(body["access_token"].as_str().unwrap(), ada)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let admin_token = issuer
+            .access_token(99, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+        let resp = test::call_service(&app, request(&admin_token, bob)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, request(&admin_token, grace)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let events = repository
+            .audit_events(&AuditQuery {
+                event: Some("impersonation_started".to_string()),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].user_id, Some(ada));
+        assert_eq!(
+            events[1].detail.as_deref(),
+            Some(format!("By instructor {}", grace).as_str())
+        );
+    }
+}
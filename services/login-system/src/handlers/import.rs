@@ -0,0 +1,484 @@
+use actix_web::{post, web::Data, web::Query, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, Role};
+use crate::config::PublicBaseUrl;
+use crate::csv;
+use crate::error::{ApiError, FieldErrors};
+use crate::handlers::account::{send_set_password_email, send_verification_email};
+use crate::handlers::classes::teaches;
+use crate::handlers::organizations::{email_domain_error, find_organization};
+use crate::mailer::Mailer;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditEvent, AuditRepository, ClassRepository, ImportedUser, NewUser, OrganizationRepository,
+    RepositoryError, UserRepository,
+};
+use crate::tokens::{random_code, random_token, TokenIssuer};
+use crate::validation::validate_student;
+
+/// Several classes' worth, and few enough to check and hash in one request.
+const MAX_IMPORT_ROWS: usize = 1000;
+const REQUIRED_COLUMNS: &[&str] = &["username", "email"];
+const OPTIONAL_COLUMNS: &[&str] = &["name", "class"];
+/// 60 random bits, which is plenty for a password that is meant to be changed.
+const TEMPORARY_PASSWORD_LENGTH: usize = 12;
+
+/// How imported students get into their accounts.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Credentials {
+    /// Each student is emailed a link to choose a password.
+    #[default]
+    Invite,
+    /// The report lists a password for each student, to be handed out. Students
+    /// are still asked to verify their address before they can log in.
+    TemporaryPassword,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    credentials: Credentials,
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    created: usize,
+    rows: Vec<RowReport>,
+}
+
+#[derive(Serialize)]
+struct RowReport {
+    /// The line of the file the row starts on, counting the header as line 1.
+    line: usize,
+    username: String,
+    #[serde(skip_serializing_if = "FieldErrors::is_empty")]
+    errors: FieldErrors,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<i64>,
+    /// Only ever shown in this report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temporary_password: Option<String>,
+}
+
+/// Creates student accounts from a CSV file with a header row naming the
+/// columns `username` and `email`, and optionally `name` and `class` (a class
+/// id the caller teaches, to enroll the student in). Every row is checked
+/// first; if any is invalid, nothing is created and the report says what to
+/// fix on which line. Otherwise all accounts are created at once, in the
+/// caller's organization.
+#[post("/students/import")]
+#[allow(clippy::too_many_arguments)]
+async fn import_students(
+    caller: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    organizations: Data<dyn OrganizationRepository>,
+    classes: Data<dyn ClassRepository>,
+    audit: Data<dyn AuditRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    hasher: Data<PasswordHasher>,
+    base_url: Data<PublicBaseUrl>,
+    req: HttpRequest,
+    query: Query<ImportQuery>,
+    body: String,
+) -> Result<HttpResponse, ApiError> {
+    caller.require_role(Role::Instructor)?;
+    let credentials = query.credentials;
+
+    let mut records = csv::parse(&body)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?
+        .into_iter();
+    let header = records
+        .next()
+        .ok_or_else(|| ApiError::bad_request("The file is empty"))?;
+    let columns = column_indexes(&header.fields)?;
+    let records: Vec<csv::Record> = records.collect();
+    if records.is_empty() {
+        return Err(ApiError::bad_request("The file has no students"));
+    }
+    if records.len() > MAX_IMPORT_ROWS {
+        return Err(ApiError::bad_request(format!(
+            "At most {} students can be imported at once",
+            MAX_IMPORT_ROWS
+        )));
+    }
+    let organization = find_organization(&**organizations, caller.organization_id).await?;
+
+    let mut rows = Vec::with_capacity(records.len());
+    let mut imported = Vec::with_capacity(records.len());
+    let mut seen_usernames = HashSet::new();
+    let mut seen_emails = HashSet::new();
+    let mut allowed_classes = HashMap::new();
+    for record in &records {
+        let field = |column: &str| {
+            columns
+                .get(column)
+                .and_then(|&index| record.fields.get(index))
+                .map(String::as_str)
+                .unwrap_or_default()
+        };
+
+        let mut row = RowReport {
+            line: record.line,
+            username: field("username").trim().to_string(),
+            errors: FieldErrors::new(),
+            user_id: None,
+            temporary_password: None,
+        };
+        let student = match validate_student(field("username"), field("email"), field("name")) {
+            Ok(student) => Some(student),
+            Err(errors) => {
+                row.errors = errors;
+                None
+            }
+        };
+        let class_id =
+            match row_class(&**classes, &caller, &mut allowed_classes, field("class")).await? {
+                Ok(class_id) => class_id,
+                Err(message) => {
+                    row.errors.insert("class", vec![message]);
+                    None
+                }
+            };
+
+        if let Some(student) = student {
+            if !seen_usernames.insert(student.username.to_lowercase()) {
+                add_error(
+                    &mut row.errors,
+                    "username",
+                    "Appears more than once in the file",
+                );
+            } else if users.find_by_username(&student.username).await?.is_some() {
+                add_error(&mut row.errors, "username", "Username is already taken");
+            }
+            if !seen_emails.insert(student.email.to_lowercase()) {
+                add_error(
+                    &mut row.errors,
+                    "email",
+                    "Appears more than once in the file",
+                );
+            } else if users.find_by_email(&student.email).await?.is_some() {
+                add_error(
+                    &mut row.errors,
+                    "email",
+                    "An account with this email address already exists",
+                );
+            } else if let Some(message) =
+                email_domain_error(&**organizations, &organization, &student.email).await?
+            {
+                add_error(&mut row.errors, "email", &message);
+            }
+
+            row.username = student.username.clone();
+            imported.push(ImportedUser {
+                user: NewUser {
+                    username: student.username,
+                    email: Some(student.email),
+                    password_hash: String::new(),
+                    organization_id: organization.id,
+                },
+                display_name: student.display_name,
+                class_id,
+            });
+        }
+        rows.push(row);
+    }
+
+    if rows.iter().any(|row| !row.errors.is_empty()) {
+        return Ok(HttpResponse::UnprocessableEntity().json(ImportReport { created: 0, rows }));
+    }
+
+    let passwords = hash_passwords(&hasher, credentials, imported.len()).await?;
+    for (user, (_, password_hash)) in imported.iter_mut().zip(&passwords) {
+        user.user.password_hash = password_hash.clone();
+    }
+    let created = users.import(imported).await.map_err(|e| match e {
+        RepositoryError::Conflict(_) => ApiError::conflict(
+            "username",
+            "A username or email address was taken during the import, nothing was created",
+        ),
+        e => e.into(),
+    })?;
+
+    for ((row, user), (password, _)) in rows.iter_mut().zip(&created).zip(passwords) {
+        row.user_id = Some(user.id);
+        let sent = match credentials {
+            Credentials::Invite => {
+                send_set_password_email(&**mailer, &issuer, &base_url, user).await
+            }
+            Credentials::TemporaryPassword => {
+                row.temporary_password = Some(password);
+                send_verification_email(&**mailer, &issuer, &base_url, user).await
+            }
+        };
+        if let Err(e) = sent {
+            eprintln!("Error sending email to imported user {}: {}", user.id, e);
+        }
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("{} accounts", created.len())),
+            ..request_event(&req, "students_imported", Some(caller.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::Created().json(ImportReport {
+        created: created.len(),
+        rows,
+    }))
+}
+
+/// Maps each known column to its position. Column names are case-insensitive.
+fn column_indexes(header: &[String]) -> Result<HashMap<String, usize>, ApiError> {
+    let mut columns = HashMap::new();
+    for (index, name) in header.iter().enumerate() {
+        let name = name.trim().to_lowercase();
+        if !REQUIRED_COLUMNS.contains(&name.as_str()) && !OPTIONAL_COLUMNS.contains(&name.as_str())
+        {
+            return Err(ApiError::bad_request(format!(
+                "Unknown column \"{}\", expected {}",
+                name,
+                [REQUIRED_COLUMNS, OPTIONAL_COLUMNS].concat().join(", ")
+            )));
+        }
+        if columns.insert(name.clone(), index).is_some() {
+            return Err(ApiError::bad_request(format!(
+                "Column \"{}\" appears twice",
+                name
+            )));
+        }
+    }
+
+    if let Some(missing) = REQUIRED_COLUMNS
+        .iter()
+        .find(|column| !columns.contains_key(**column))
+    {
+        return Err(ApiError::bad_request(format!(
+            "Missing column \"{}\"",
+            missing
+        )));
+    }
+    Ok(columns)
+}
+
+/// The class a row enrolls the student in, if any, or why it can't. Answers
+/// are remembered, since most rows name the same few classes.
+async fn row_class(
+    classes: &dyn ClassRepository,
+    caller: &AuthenticatedUser,
+    allowed: &mut HashMap<i64, bool>,
+    class: &str,
+) -> Result<Result<Option<i64>, String>, ApiError> {
+    let class = class.trim();
+    if class.is_empty() {
+        return Ok(Ok(None));
+    }
+    let Ok(class_id) = class.parse::<i64>() else {
+        return Ok(Err("Class must be a class id".to_string()));
+    };
+
+    let is_allowed = match allowed.get(&class_id) {
+        Some(&is_allowed) => is_allowed,
+        None => {
+            let is_allowed = match classes.find_class(class_id).await? {
+                Some(class) => teaches(classes, caller, &class).await?,
+                None => false,
+            };
+            allowed.insert(class_id, is_allowed);
+            is_allowed
+        }
+    };
+    if is_allowed {
+        Ok(Ok(Some(class_id)))
+    } else {
+        Ok(Err(format!("No class {} that you teach", class_id)))
+    }
+}
+
+/// Returns each account's password, empty for invited students, and its hash.
+/// One at a time, so a large import doesn't take every blocking thread and
+/// Argon2's memory with it.
+async fn hash_passwords(
+    hasher: &PasswordHasher,
+    credentials: Credentials,
+    count: usize,
+) -> Result<Vec<(String, String)>, ApiError> {
+    match credentials {
+        // Nobody knows the password, so one hash can serve every account.
+        Credentials::Invite => {
+            let password_hash = hasher.hash(random_token()).await?;
+            Ok(vec![(String::new(), password_hash); count])
+        }
+        Credentials::TemporaryPassword => {
+            let mut passwords = Vec::with_capacity(count);
+            for _ in 0..count {
+                let password = random_code(TEMPORARY_PASSWORD_LENGTH);
+                let password_hash = hasher.hash(password.clone()).await?;
+                passwords.push((password, password_hash));
+            }
+            Ok(passwords)
+        }
+    }
+}
+
+fn add_error(errors: &mut FieldErrors, field: &'static str, message: &str) {
+    errors.entry(field).or_default().push(message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::LogMailer;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::DEFAULT_ORGANIZATION_ID;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    async fn setup() -> (Arc<LibsqlRepository>, String, i64) {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let grace = repository
+            .create(NewUser {
+                username: "grace".to_string(),
+                email: Some("grace@example.com".to_string()),
+                password_hash: "hash".to_string(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        repository
+            .set_role(grace.id, Role::Instructor)
+            .await
+            .unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace.id)
+            .await
+            .unwrap();
+        let token = TokenIssuer::for_tests()
+            .access_token(
+                grace.id,
+                DEFAULT_ORGANIZATION_ID,
+                Role::Instructor,
+                Vec::new(),
+            )
+            .unwrap();
+        (repository, token, class.id)
+    }
+
+    macro_rules! app {
+        ($repository:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OrganizationRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        "http://localhost:8000".parse().unwrap(),
+                    )))
+                    .service(import_students),
+            )
+            .await
+        };
+    }
+
+    fn upload(uri: &str, token: &str, csv: String) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .insert_header((header::CONTENT_TYPE, "text/csv"))
+            .set_payload(csv)
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_rows_are_reported_and_nothing_is_created() {
+        let (repository, token, class_id) = setup().await;
+        let app = app!(repository);
+
+        let csv = format!(
+            "Name,Email,Username,Class\n\
+             Ada Lovelace,ada@example.com,ada,{class_id}\n\
+             Alan Turing,not-an-email,alan,{class_id}\n\
+             Ada Again,ADA@example.com,ada2,{class_id}\n\
+             Grace Hopper,hopper@example.com,Grace,\n\
+             Edsger Dijkstra,edsger@example.com,edsger,999\n"
+        );
+        let resp =
+            test::call_service(&app, upload("/students/import", &token, csv).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let report: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(report["created"], 0);
+        let rows = report["rows"].as_array().unwrap();
+        assert!(rows[0].get("errors").is_none());
+        assert_eq!(rows[1]["line"], 3);
+        assert!(rows[1]["errors"]["email"].is_array());
+        assert!(rows[2]["errors"]["email"].is_array());
+        assert!(rows[3]["errors"]["username"].is_array());
+        assert!(rows[4]["errors"]["class"].is_array());
+        assert!(repository.find_by_username("ada").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_import_with_temporary_passwords() {
+        let (repository, token, class_id) = setup().await;
+        let app = app!(repository);
+
+        let csv = format!(
+            "username,email,class\r\n\
+             ada,ada@example.com,{class_id}\r\n\
+             alan,alan@example.com,\r\n"
+        );
+        let resp = test::call_service(
+            &app,
+            upload(
+                "/students/import?credentials=temporary_password",
+                &token,
+                csv,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let report: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(report["created"], 2);
+        let password = report["rows"][0]["temporary_password"].as_str().unwrap();
+        let ada = repository.find_by_username("ada").await.unwrap().unwrap();
+        assert_eq!(report["rows"][0]["user_id"], ada.id);
+        assert!(PasswordHasher::for_tests()
+            .verify(password.to_string(), ada.password_hash)
+            .await
+            .unwrap());
+        assert_eq!(repository.roster(class_id).await.unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_students_cannot_import() {
+        let (repository, _, _) = setup().await;
+        let app = app!(repository);
+        let token = TokenIssuer::for_tests()
+            .access_token(1, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+
+        let resp = test::call_service(
+            &app,
+            upload(
+                "/students/import",
+                &token,
+                "username,email\nada,ada@example.com\n".to_string(),
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
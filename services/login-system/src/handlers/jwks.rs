@@ -0,0 +1,87 @@
+use actix_web::{get, http::header, web::Data, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+
+use crate::tokens::TokenIssuer;
+
+/// A public key in JWK form (RFC 8037).
+#[derive(Serialize)]
+struct Jwk {
+    kty: &'static str,
+    crv: &'static str,
+    x: String,
+    kid: String,
+    alg: &'static str,
+    #[serde(rename = "use")]
+    use_: &'static str,
+}
+
+#[derive(Serialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// The public keys tokens are signed with: the current one, the ones before it
+/// that still verify tokens in circulation and the next one once it is published.
+/// Services that verify access tokens fetch this and refetch on an unknown `kid`.
+#[get("/.well-known/jwks.json")]
+async fn jwks(issuer: Data<TokenIssuer>) -> HttpResponse {
+    let keys = issuer.public_keys(|key| Jwk {
+        kty: "OKP",
+        crv: "Ed25519",
+        x: URL_SAFE_NO_PAD.encode(key.public_key()),
+        kid: key.kid.clone(),
+        alg: "EdDSA",
+        use_: "sig",
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=3600"))
+        .json(JwkSet { keys })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::DEFAULT_ORGANIZATION_ID;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn test_jwks_publishes_the_signing_key() {
+        let issuer = Data::new(TokenIssuer::for_tests());
+        let app = test::init_service(App::new().app_data(issuer.clone()).service(jwks)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/.well-known/jwks.json")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+
+        let key = &body["keys"][0];
+        assert_eq!(key["kid"], "test-key");
+        assert_eq!(key["kty"], "OKP");
+        assert_eq!(key["crv"], "Ed25519");
+        assert_eq!(key["use"], "sig");
+
+        // The published key verifies what the issuer signs.
+        let x = URL_SAFE_NO_PAD.decode(key["x"].as_str().unwrap()).unwrap();
+        let token = issuer
+            .access_token(
+                42,
+                DEFAULT_ORGANIZATION_ID,
+                crate::auth::Role::Student,
+                Vec::new(),
+            )
+            .unwrap();
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::EdDSA);
+        validation.set_issuer(&[crate::tokens::ISSUER]);
+        let claims = jsonwebtoken::decode::<crate::tokens::Claims>(
+            &token,
+            &jsonwebtoken::DecodingKey::from_ed_der(&x),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.sub, "42");
+    }
+}
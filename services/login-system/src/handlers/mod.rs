@@ -0,0 +1,18 @@
+pub mod account;
+pub mod admin;
+pub mod api_keys;
+pub mod audit;
+pub mod auth;
+pub mod classes;
+pub mod impersonation;
+pub mod import;
+pub mod jwks;
+pub mod oidc;
+pub mod organizations;
+pub mod pages;
+pub mod privacy;
+pub mod profile;
+pub mod register;
+pub mod service;
+pub mod sessions;
+pub mod two_factor;
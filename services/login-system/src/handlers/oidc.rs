@@ -0,0 +1,819 @@
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    get,
+    http::header,
+    post,
+    web::Data,
+    web::Path,
+    web::Query,
+    HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::AuthenticatedUser;
+use crate::config::{PublicBaseUrl, TwoFactorPolicy};
+use crate::error::ApiError;
+use crate::handlers::account::send_verification_email;
+use crate::handlers::auth::{issue_tokens, login_challenge};
+use crate::handlers::organizations::organization_for_email;
+use crate::mailer::Mailer;
+use crate::oidc::{IdTokenClaims, OidcProvider, OidcProviders};
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditEvent, AuditRepository, ClassRepository, ExternalIdentityRepository, NewUser,
+    OidcLoginState, OidcStateRepository, OrganizationRepository, RefreshTokenRepository,
+    RepositoryError, TwoFactorRepository, User, UserRepository,
+};
+use crate::tokens::{random_token, TokenIssuer};
+
+/// How long the user has to finish signing in at the provider.
+const LOGIN_STATE_TTL_SECS: i64 = 10 * 60;
+/// Holds the state of the sign-in started in this browser. The callback only
+/// accepts that state, so a sign-in or link started by someone else can't be
+/// finished by sending their callback URL to this browser.
+const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    state: String,
+    #[serde(default)]
+    code: Option<String>,
+    /// Set instead of `code` when the user declined or the provider failed.
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuthorizationUrl {
+    authorization_url: String,
+}
+
+/// Redirects the browser to the provider to sign in. The provider sends it
+/// back to `/oidc/{provider}/callback`.
+#[get("/oidc/{provider}/login")]
+async fn oidc_login(
+    providers: Data<OidcProviders>,
+    states: Data<dyn OidcStateRepository>,
+    base_url: Data<PublicBaseUrl>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let provider = find_provider(&providers, &name)?;
+    let (url, cookie) = start(provider, &**states, &base_url, &name, None).await?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .cookie(cookie)
+        .finish())
+}
+
+/// Links the provider to the signed-in user's account. Answers with the URL
+/// to send the browser to, since a redirect can't carry the access token.
+#[post("/oidc/{provider}/link")]
+async fn oidc_link(
+    caller: AuthenticatedUser,
+    providers: Data<OidcProviders>,
+    states: Data<dyn OidcStateRepository>,
+    base_url: Data<PublicBaseUrl>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let name = path.into_inner();
+    let provider = find_provider(&providers, &name)?;
+    let (authorization_url, cookie) =
+        start(provider, &**states, &base_url, &name, Some(caller.id)).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(AuthorizationUrl { authorization_url }))
+}
+
+/// Where the provider sends the browser back to. Signs the user in with the
+/// same responses as `/login`, or finishes linking the account.
+///
+/// A provider account that isn't linked yet gets a new local account, unless
+/// its email belongs to an existing one: emails aren't linked automatically,
+/// since that would hand the account to whoever controls the email at the
+/// provider. The owner has to sign in and link the provider instead. New
+/// accounts join the organization that claimed the email's domain, if it
+/// allows this provider.
+#[get("/oidc/{provider}/callback")]
+#[allow(clippy::too_many_arguments)]
+async fn oidc_callback(
+    providers: Data<OidcProviders>,
+    states: Data<dyn OidcStateRepository>,
+    identities: Data<dyn ExternalIdentityRepository>,
+    users: Data<dyn UserRepository>,
+    // One extractor, since actix allows no more than 16.
+    (organizations, hasher): (Data<dyn OrganizationRepository>, Data<PasswordHasher>),
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    classes: Data<dyn ClassRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    policy: Data<TwoFactorPolicy>,
+    audit: Data<dyn AuditRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    base_url: Data<PublicBaseUrl>,
+    req: HttpRequest,
+    path: Path<String>,
+    query: Query<CallbackQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let provider = find_provider(&providers, &name)?;
+    let query = query.into_inner();
+
+    // Checked before the state is taken, so a callback URL sent to another
+    // browser can't use up the state either.
+    let started_here = req
+        .cookie(STATE_COOKIE)
+        .is_some_and(|cookie| cookie.value() == query.state);
+    if !started_here {
+        return Err(ApiError::bad_request(
+            "Sign-in was started in another browser, please start over",
+        ));
+    }
+    // Taken before anything else, so a state is never accepted twice.
+    let state = states
+        .take(&query.state)
+        .await?
+        .filter(|state| state.provider == name)
+        .filter(|state| state.expires_at > chrono::Utc::now().timestamp())
+        .ok_or_else(|| ApiError::bad_request("Invalid or expired sign-in, please start over"))?;
+    if let Some(error) = query.error {
+        return Err(ApiError::bad_request(format!(
+            "Sign-in with {} failed: {}",
+            name, error
+        )));
+    }
+    let code = query
+        .code
+        .ok_or_else(|| ApiError::bad_request("Missing authorization code"))?;
+
+    let claims = provider
+        .authenticate(
+            &code,
+            &redirect_uri(&base_url, &name),
+            &state.code_verifier,
+            &state.nonce,
+        )
+        .await
+        .map_err(|e| {
+            eprintln!("Error completing sign-in with {}: {}", name, e);
+            ApiError::unauthorized(format!("Sign-in with {} failed", name))
+        })?;
+
+    if let Some(user_id) = state.link_user_id {
+        return link(&**identities, &name, &claims.sub, user_id).await;
+    }
+
+    let user = match identities.find_user_id(&name, &claims.sub).await? {
+        Some(user_id) => users
+            .find_by_id(user_id)
+            .await?
+            .ok_or_else(|| ApiError::unauthorized(format!("Sign-in with {} failed", name)))?,
+        None => {
+            let user = create_user(
+                &**users,
+                &**identities,
+                &**organizations,
+                &hasher,
+                &name,
+                &claims,
+            )
+            .await?;
+            audit
+                .record(AuditEvent {
+                    detail: Some(format!("oidc:{}", name)),
+                    ..request_event(&req, "user_registered", Some(user.id))
+                })
+                .await?;
+            if user.email_verified_at.is_none() {
+                if let Err(e) = send_verification_email(&**mailer, &issuer, &base_url, &user).await
+                {
+                    eprintln!("Error sending verification email: {}", e);
+                }
+            }
+            user
+        }
+    };
+
+    if let Some(challenge) = login_challenge(&**two_factor, &policy, &issuer, &user).await? {
+        return Ok(challenge);
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("oidc:{}", name)),
+            ..request_event(&req, "login_succeeded", Some(user.id))
+        })
+        .await?;
+    issue_tokens(
+        &**refresh_tokens,
+        &**classes,
+        &issuer,
+        &req,
+        &user,
+        &random_token(),
+    )
+    .await
+}
+
+fn find_provider<'a>(
+    providers: &'a OidcProviders,
+    name: &str,
+) -> Result<&'a OidcProvider, ApiError> {
+    providers
+        .get(name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown sign-in provider {}", name)))
+}
+
+fn redirect_uri(base_url: &PublicBaseUrl, name: &str) -> String {
+    base_url.join(&format!("/oidc/{}/callback", name))
+}
+
+/// Stores a new state with its PKCE verifier and nonce, and returns the
+/// provider's authorization URL for it, with the cookie binding the state to
+/// this browser.
+async fn start(
+    provider: &OidcProvider,
+    states: &dyn OidcStateRepository,
+    base_url: &PublicBaseUrl,
+    name: &str,
+    link_user_id: Option<i64>,
+) -> Result<(String, Cookie<'static>), ApiError> {
+    let state = OidcLoginState {
+        state: random_token(),
+        provider: name.to_string(),
+        code_verifier: random_token(),
+        nonce: random_token(),
+        link_user_id,
+        expires_at: chrono::Utc::now().timestamp() + LOGIN_STATE_TTL_SECS,
+    };
+    let url = provider
+        .authorization_url(
+            &redirect_uri(base_url, name),
+            &state.state,
+            &state.nonce,
+            &state.code_verifier,
+        )
+        .await
+        .map_err(|e| ApiError::internal(format!("Error contacting {}", name), e))?;
+
+    // Lax, since the provider sends the browser back with a cross-site redirect.
+    let cookie = Cookie::build(STATE_COOKIE, state.state.clone())
+        .path("/oidc")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(base_url.0.scheme() == "https")
+        .max_age(Duration::seconds(LOGIN_STATE_TTL_SECS))
+        .finish();
+
+    states.save(state).await?;
+    Ok((url, cookie))
+}
+
+async fn link(
+    identities: &dyn ExternalIdentityRepository,
+    name: &str,
+    subject: &str,
+    user_id: i64,
+) -> Result<HttpResponse, ApiError> {
+    match identities.find_user_id(name, subject).await? {
+        Some(linked) if linked == user_id => return Ok(HttpResponse::NoContent().finish()),
+        Some(_) => {
+            return Err(ApiError::conflict(
+                "provider",
+                format!("This {} account is linked to another user", name),
+            ))
+        }
+        None => {}
+    }
+
+    match identities.link(name, subject, user_id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(RepositoryError::Conflict(_)) => Err(ApiError::conflict(
+            "provider",
+            format!("This {} account is linked to another user", name),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Creates the local account for a provider account signing in for the
+/// first time. The username is derived from the provider's, with a number
+/// appended if it is taken. The password is random and never shown. The
+/// account and its link to the provider are created together.
+async fn create_user(
+    users: &dyn UserRepository,
+    identities: &dyn ExternalIdentityRepository,
+    organizations: &dyn OrganizationRepository,
+    hasher: &PasswordHasher,
+    name: &str,
+    claims: &IdTokenClaims,
+) -> Result<User, ApiError> {
+    let email = claims
+        .email
+        .as_deref()
+        .ok_or_else(|| ApiError::bad_request(format!("{} did not share an email address", name)))?;
+    if users.find_by_email(email).await?.is_some() {
+        return Err(ApiError::conflict(
+            "email",
+            format!(
+                "An account with this email already exists, sign in and link {} to it first",
+                name
+            ),
+        ));
+    }
+    let organization = organization_for_email(organizations, email).await?;
+    if let Some(provider) = organization.sso_provider.as_deref() {
+        if provider != name {
+            return Err(ApiError::sso_required(provider));
+        }
+    }
+
+    let password_hash = hasher.hash(random_token()).await?;
+    let base = username_base(claims.preferred_username.as_deref().unwrap_or(email));
+
+    for attempt in 1..=100 {
+        let username = match attempt {
+            1 => base.clone(),
+            n => format!("{}{}", base, n),
+        };
+        let new_user = NewUser {
+            username,
+            email: Some(email.to_string()),
+            password_hash: password_hash.clone(),
+            organization_id: organization.id,
+        };
+        match identities
+            .create_linked_user(new_user, claims.email_verified, name, &claims.sub)
+            .await
+        {
+            Ok(user) => return Ok(user),
+            Err(RepositoryError::Conflict(message)) if message.contains("users.email") => {
+                return Err(ApiError::conflict("email", "Email already registered"));
+            }
+            Err(RepositoryError::Conflict(message)) if message.contains("external_identities") => {
+                return Err(ApiError::conflict(
+                    "provider",
+                    format!("This {} account is linked to another user", name),
+                ));
+            }
+            Err(RepositoryError::Conflict(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(ApiError::conflict("username", "Username already taken"))
+}
+
+/// A valid username from the provider's username or the email's local part:
+/// only characters registration allows, starting with a letter or number,
+/// with room left for a numeric suffix.
+fn username_base(preferred: &str) -> String {
+    let local_part = preferred.split('@').next().unwrap_or_default();
+    let username: String = local_part
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .skip_while(|c| !c.is_alphanumeric())
+        .take(28)
+        .collect();
+
+    if username.chars().count() < 3 {
+        format!("user{}", username)
+    } else {
+        username
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OidcProviderSettings;
+    use crate::mailer::LogMailer;
+    use crate::oidc::pkce_challenge;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{OrganizationSettings, DEFAULT_ORGANIZATION_ID};
+    use crate::tokens::TEST_PRIVATE_KEY;
+    use actix_web::{dev::ServiceResponse, http::StatusCode, test, App};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    /// Stands in for the provider's sign-in page: what the browser would be
+    /// sent back with for each authorization code.
+    #[derive(Clone, Default)]
+    struct Logins(Arc<Mutex<HashMap<String, PendingLogin>>>);
+
+    #[derive(Clone)]
+    struct PendingLogin {
+        code_challenge: String,
+        claims: serde_json::Value,
+    }
+
+    /// The token endpoint: checks the PKCE verifier against the challenge from
+    /// the authorization request and answers with a signed ID token.
+    struct TokenEndpoint {
+        issuer: String,
+        logins: Logins,
+    }
+
+    impl Respond for TokenEndpoint {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let form: HashMap<String, String> = url::form_urlencoded::parse(&request.body)
+                .into_owned()
+                .collect();
+            let Some(login) = self.logins.0.lock().unwrap().remove(&form["code"]) else {
+                return ResponseTemplate::new(400)
+                    .set_body_json(serde_json::json!({"error": "invalid_grant"}));
+            };
+            if pkce_challenge(&form["code_verifier"]) != login.code_challenge {
+                return ResponseTemplate::new(400)
+                    .set_body_json(serde_json::json!({"error": "invalid_grant"}));
+            }
+
+            let now = chrono::Utc::now().timestamp();
+            let mut claims = serde_json::json!({
+                "iss": self.issuer,
+                "aud": "platform",
+                "iat": now,
+                "exp": now + 300,
+            });
+            for (key, value) in login.claims.as_object().unwrap() {
+                claims[key] = value.clone();
+            }
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some("mock".to_string());
+            let key = EncodingKey::from_ed_pem(TEST_PRIVATE_KEY.as_bytes()).unwrap();
+            let id_token = encode(&header, &claims, &key).unwrap();
+
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "unused",
+                "token_type": "Bearer",
+                "id_token": id_token,
+            }))
+        }
+    }
+
+    async fn mock_provider() -> (MockServer, Logins) {
+        let server = MockServer::start().await;
+        let logins = Logins::default();
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": server.uri(),
+                "authorization_endpoint": format!("{}/authorize", server.uri()),
+                "token_endpoint": format!("{}/token", server.uri()),
+                "jwks_uri": format!("{}/jwks", server.uri()),
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "keys": [{
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": "mock",
+                    "x": "vWSQpKX-CxrYcTAHG9al-9QXOZH8cvJQOa0JE9cBHL0",
+                }]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(TokenEndpoint {
+                issuer: server.uri(),
+                logins: logins.clone(),
+            })
+            .mount(&server)
+            .await;
+
+        (server, logins)
+    }
+
+    fn providers(server: &MockServer) -> Data<OidcProviders> {
+        Data::new(OidcProviders::new(vec![OidcProviderSettings {
+            name: "school".to_string(),
+            issuer: Url::parse(&server.uri()).unwrap(),
+            client_id: "platform".to_string(),
+            client_secret: Some("secret".to_string()),
+        }]))
+    }
+
+    macro_rules! app {
+        ($repository:expr, $server:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(providers(&$server))
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OrganizationRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn TwoFactorRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OidcStateRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn ExternalIdentityRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::new(TwoFactorPolicy::default()))
+                    .app_data(Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        Url::parse("http://localhost:8000").unwrap(),
+                    )))
+                    .service(oidc_login)
+                    .service(oidc_link)
+                    .service(oidc_callback),
+            )
+            .await
+        };
+    }
+
+    /// Follows the redirect to the provider and "signs in" there as `claims`,
+    /// giving the callback URL the provider would send the browser back to.
+    macro_rules! sign_in_at_provider {
+        ($app:expr, $logins:expr, $claims:expr) => {{
+            let resp = test::call_service(
+                &$app,
+                test::TestRequest::get()
+                    .uri("/oidc/school/login")
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::FOUND);
+            let location = resp.headers().get(header::LOCATION).unwrap();
+            callback_for(
+                &$logins,
+                location.to_str().unwrap(),
+                state_cookie(&resp),
+                $claims,
+            )
+        }};
+    }
+
+    /// Where the provider sends the browser back to, and the cookie the
+    /// browser sends along.
+    struct Callback {
+        uri: String,
+        cookie: Cookie<'static>,
+    }
+
+    impl Callback {
+        fn request(&self) -> test::TestRequest {
+            test::TestRequest::get()
+                .uri(&self.uri)
+                .cookie(self.cookie.clone())
+        }
+    }
+
+    fn state_cookie<B>(resp: &ServiceResponse<B>) -> Cookie<'static> {
+        resp.response()
+            .cookies()
+            .find(|cookie| cookie.name() == STATE_COOKIE)
+            .unwrap()
+            .into_owned()
+    }
+
+    fn callback_for(
+        logins: &Logins,
+        authorization_url: &str,
+        cookie: Cookie<'static>,
+        mut claims: serde_json::Value,
+    ) -> Callback {
+        let url = Url::parse(authorization_url).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(
+            params["redirect_uri"],
+            "http://localhost:8000/oidc/school/callback"
+        );
+        if claims.get("nonce").is_none() {
+            claims["nonce"] = params["nonce"].clone().into();
+        }
+
+        let code = random_token();
+        logins.0.lock().unwrap().insert(
+            code.clone(),
+            PendingLogin {
+                code_challenge: params["code_challenge"].clone(),
+                claims,
+            },
+        );
+        assert_eq!(cookie.value(), params["state"]);
+        Callback {
+            uri: format!(
+                "/oidc/school/callback?code={}&state={}",
+                code, params["state"]
+            ),
+            cookie,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_first_sign_in_creates_and_links_user() {
+        let (server, logins) = mock_provider().await;
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let school = repository
+            .create_organization(&OrganizationSettings {
+                slug: "school".to_string(),
+                name: "School".to_string(),
+                email_domains: vec!["school.edu".to_string()],
+                sso_provider: Some("school".to_string()),
+                password_min_length: 8,
+            })
+            .await
+            .unwrap();
+        let app = app!(repository, server);
+        let claims = serde_json::json!({
+            "sub": "s-123",
+            "email": "ada@school.edu",
+            "email_verified": true,
+            "preferred_username": "ada lovelace",
+        });
+
+        let callback = sign_in_at_provider!(app, logins, claims.clone());
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let access_token = TokenIssuer::for_tests()
+            .verify(body["access_token"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(access_token.org, school.id);
+
+        let user = repository
+            .find_by_email("ada@school.edu")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.username, "adalovelace");
+        assert_eq!(user.organization_id, school.id);
+        assert!(user.email_verified_at.is_some());
+
+        // Signing in again finds the same account.
+        let callback = sign_in_at_provider!(app, logins, claims);
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            repository.find_user_id("school", "s-123").await.unwrap(),
+            Some(user.id)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_state_cannot_be_replayed() {
+        let (server, logins) = mock_provider().await;
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let app = app!(repository, server);
+        let claims = serde_json::json!({ "sub": "s-1", "email": "grace@school.edu", "email_verified": true });
+
+        let callback = sign_in_at_provider!(app, logins, claims);
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_link_started_in_another_browser_is_rejected() {
+        let (server, logins) = mock_provider().await;
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = repository
+            .create(NewUser {
+                username: "mallory".to_string(),
+                email: Some("mallory@school.edu".to_string()),
+                password_hash: bcrypt::hash("not the victim", 4).unwrap(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        let app = app!(repository, server);
+        let access_token = TokenIssuer::for_tests()
+            .access_token(user.id, DEFAULT_ORGANIZATION_ID, user.role, Vec::new())
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/oidc/school/link")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", access_token)))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = state_cookie(&resp);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        // Another user's browser is sent to the link, and signs in at the provider.
+        let claims = serde_json::json!({ "sub": "victim", "email": "ada@school.edu", "email_verified": true });
+        let callback = callback_for(
+            &logins,
+            body["authorization_url"].as_str().unwrap(),
+            cookie,
+            claims,
+        );
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&callback.uri).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            repository.find_user_id("school", "victim").await.unwrap(),
+            None
+        );
+
+        // The state is still there for the browser that started the link.
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn test_id_token_for_another_login_is_rejected() {
+        let (server, logins) = mock_provider().await;
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let app = app!(repository, server);
+        let claims =
+            serde_json::json!({ "sub": "s-1", "email": "grace@school.edu", "nonce": "other" });
+
+        let callback = sign_in_at_provider!(app, logins, claims);
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(repository
+            .find_by_email("grace@school.edu")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_existing_email_must_be_linked_first() {
+        let (server, logins) = mock_provider().await;
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = repository
+            .create(NewUser {
+                username: "ada".to_string(),
+                email: Some("ada@school.edu".to_string()),
+                password_hash: bcrypt::hash("analytical engine", 4).unwrap(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        repository.mark_email_verified(user.id).await.unwrap();
+        let app = app!(repository, server);
+        let claims = serde_json::json!({ "sub": "s-123", "email": "ada@school.edu", "email_verified": true });
+
+        let callback = sign_in_at_provider!(app, logins, claims.clone());
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Once the owner links the provider, signing in with it works.
+        let access_token = TokenIssuer::for_tests()
+            .access_token(user.id, DEFAULT_ORGANIZATION_ID, user.role, Vec::new())
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/oidc/school/link")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", access_token)))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = state_cookie(&resp);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let callback = callback_for(
+            &logins,
+            body["authorization_url"].as_str().unwrap(),
+            cookie,
+            claims.clone(),
+        );
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let callback = sign_in_at_provider!(app, logins, claims);
+        let resp = test::call_service(&app, callback.request().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_username_base_is_a_valid_username() {
+        assert_eq!(username_base("ada.lovelace@school.edu"), "ada.lovelace");
+        assert_eq!(username_base("_x"), "userx");
+        assert_eq!(username_base(&"a".repeat(40)).len(), 28);
+    }
+}
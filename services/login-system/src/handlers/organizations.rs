@@ -0,0 +1,360 @@
+use actix_web::{get, post, put, web::Data, web::Json, web::Path, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, Role};
+use crate::error::{ApiError, FieldErrors};
+use crate::oidc::OidcProviders;
+use crate::repository::{
+    AuditEvent, AuditRepository, Organization, OrganizationRepository, OrganizationSettings,
+    RepositoryError, DEFAULT_ORGANIZATION_ID,
+};
+use crate::validation::{validate_organization, PASSWORD_MIN_CHARS};
+
+#[derive(Deserialize)]
+pub struct OrganizationRequest {
+    slug: String,
+    name: String,
+    /// E.g. `["school.edu"]`. Empty lets members use any domain nobody claimed.
+    #[serde(default)]
+    email_domains: Vec<String>,
+    /// The name of a configured OpenID Connect provider.
+    #[serde(default)]
+    sso_provider: Option<String>,
+    #[serde(default)]
+    password_min_length: Option<i64>,
+}
+
+impl OrganizationRequest {
+    fn settings(self, providers: &OidcProviders) -> Result<OrganizationSettings, ApiError> {
+        validate_organization(
+            OrganizationSettings {
+                slug: self.slug,
+                name: self.name,
+                email_domains: self.email_domains,
+                sso_provider: self.sso_provider,
+                password_min_length: self
+                    .password_min_length
+                    .unwrap_or(PASSWORD_MIN_CHARS as i64),
+            },
+            &providers.names(),
+        )
+    }
+}
+
+/// Every organization for platform admins, the admin's own for everyone else.
+#[get("/admin/organizations")]
+async fn list_organizations(
+    admin: AuthenticatedUser,
+    organizations: Data<dyn OrganizationRepository>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let listed: Vec<Organization> = organizations
+        .organizations()
+        .await?
+        .into_iter()
+        .filter(|organization| admin.sees_organization(organization.id))
+        .collect();
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+/// Onboards a school. Its first admin is someone who registers with one of its
+/// domains and is then granted the admin role by a platform admin.
+#[post("/admin/organizations")]
+async fn create_organization(
+    admin: AuthenticatedUser,
+    organizations: Data<dyn OrganizationRepository>,
+    audit: Data<dyn AuditRepository>,
+    providers: Data<OidcProviders>,
+    req: HttpRequest,
+    form: Json<OrganizationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_platform_admin()?;
+    let settings = form.into_inner().settings(&providers)?;
+
+    let organization = organizations
+        .create_organization(&settings)
+        .await
+        .map_err(settings_conflict)?;
+    audit
+        .record(AuditEvent {
+            detail: Some(format!(
+                "Organization {} ({})",
+                organization.id, organization.slug
+            )),
+            ..request_event(&req, "organization_created", Some(admin.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::Created().json(organization))
+}
+
+#[get("/admin/organizations/{id}")]
+async fn get_organization(
+    admin: AuthenticatedUser,
+    organizations: Data<dyn OrganizationRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_role(Role::Admin)?;
+    let id = path.into_inner();
+    let organization = organizations
+        .find_organization(id)
+        .await?
+        .filter(|organization| admin.sees_organization(organization.id))
+        .ok_or_else(organization_not_found)?;
+    Ok(HttpResponse::Ok().json(organization))
+}
+
+/// Replaces the settings. Domains taken away don't move anyone: members keep
+/// their organization and address, and new accounts at those domains join the
+/// default organization.
+#[put("/admin/organizations/{id}")]
+async fn update_organization(
+    admin: AuthenticatedUser,
+    organizations: Data<dyn OrganizationRepository>,
+    audit: Data<dyn AuditRepository>,
+    providers: Data<OidcProviders>,
+    req: HttpRequest,
+    path: Path<i64>,
+    form: Json<OrganizationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    admin.require_platform_admin()?;
+    let id = path.into_inner();
+    let settings = form.into_inner().settings(&providers)?;
+    // The default organization takes every address nobody claimed.
+    if id == DEFAULT_ORGANIZATION_ID && !settings.email_domains.is_empty() {
+        return Err(ApiError::validation(FieldErrors::from([(
+            "email_domains",
+            vec!["The default organization can't claim domains".to_string()],
+        )])));
+    }
+
+    if !organizations
+        .update_organization(id, &settings)
+        .await
+        .map_err(settings_conflict)?
+    {
+        return Err(organization_not_found());
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Organization {} ({})", id, settings.slug)),
+            ..request_event(&req, "organization_updated", Some(admin.id))
+        })
+        .await?;
+
+    let organization = find_organization(&**organizations, id).await?;
+    Ok(HttpResponse::Ok().json(organization))
+}
+
+/// The organization a user belongs to. Every user's exists, since
+/// organizations are never deleted.
+pub(crate) async fn find_organization(
+    organizations: &dyn OrganizationRepository,
+    id: i64,
+) -> Result<Organization, ApiError> {
+    organizations
+        .find_organization(id)
+        .await?
+        .ok_or_else(|| ApiError::internal("Error loading organization", id))
+}
+
+/// The organization a new account with the address joins: the one that
+/// claimed its domain, or the default one.
+pub(crate) async fn organization_for_email(
+    organizations: &dyn OrganizationRepository,
+    email: &str,
+) -> Result<Organization, ApiError> {
+    let domain = email.rsplit_once('@').map_or("", |(_, domain)| domain);
+    match organizations.find_organization_by_domain(domain).await? {
+        Some(organization) => Ok(organization),
+        None => find_organization(organizations, DEFAULT_ORGANIZATION_ID).await,
+    }
+}
+
+/// Why members of `organization` can't use the address, if they can't. A
+/// school's members use its domains, and nobody else does.
+pub(crate) async fn email_domain_error(
+    organizations: &dyn OrganizationRepository,
+    organization: &Organization,
+    email: &str,
+) -> Result<Option<String>, ApiError> {
+    if organization_for_email(organizations, email).await?.id == organization.id {
+        return Ok(None);
+    }
+    Ok(Some(if organization.email_domains.is_empty() {
+        "Addresses at this domain belong to another school".to_string()
+    } else {
+        format!(
+            "Email address must be at {}",
+            organization.email_domains.join(" or ")
+        )
+    }))
+}
+
+fn settings_conflict(e: RepositoryError) -> ApiError {
+    match e {
+        RepositoryError::Conflict(message) if message.contains("organizations.slug") => {
+            ApiError::conflict("slug", "Slug is already taken")
+        }
+        RepositoryError::Conflict(_) => ApiError::conflict(
+            "email_domains",
+            "A domain already belongs to another organization",
+        ),
+        e => e.into(),
+    }
+}
+
+fn organization_not_found() -> ApiError {
+    ApiError::not_found("Organization not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::tokens::TokenIssuer;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_platform_admins_manage_organizations() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let issuer = TokenIssuer::for_tests();
+        let platform_admin = issuer
+            .access_token(1, DEFAULT_ORGANIZATION_ID, Role::Admin, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(OidcProviders::new(Vec::new())))
+                .app_data(Data::new(TokenIssuer::for_tests()))
+                .service(list_organizations)
+                .service(create_organization)
+                .service(get_organization)
+                .service(update_organization),
+        )
+        .await;
+        let request = |request: test::TestRequest, token: &str| {
+            request
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+        let north = json!({
+            "slug": "north",
+            "name": "North High",
+            "email_domains": ["North.edu"],
+            "password_min_length": 12
+        });
+
+        let created: Value = test::call_and_read_body_json(
+            &app,
+            request(
+                test::TestRequest::post()
+                    .uri("/admin/organizations")
+                    .set_json(&north),
+                &platform_admin,
+            ),
+        )
+        .await;
+        assert_eq!(created["email_domains"], json!(["north.edu"]));
+        let north_id = created["id"].as_i64().unwrap();
+        assert_eq!(
+            organization_for_email(&*repository, "ada@NORTH.edu")
+                .await
+                .unwrap()
+                .id,
+            north_id
+        );
+
+        // Neither the slug nor the domain can be taken twice.
+        let resp = test::call_service(
+            &app,
+            request(
+                test::TestRequest::post()
+                    .uri("/admin/organizations")
+                    .set_json(json!({ "slug": "north-2", "name": "North", "email_domains": ["north.edu"] })),
+                &platform_admin,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // School admins see their own organization and can't create others.
+        let north_admin = issuer
+            .access_token(2, north_id, Role::Admin, Vec::new())
+            .unwrap();
+        let listed: Value = test::call_and_read_body_json(
+            &app,
+            request(
+                test::TestRequest::get().uri("/admin/organizations"),
+                &north_admin,
+            ),
+        )
+        .await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["slug"], "north");
+        let resp = test::call_service(
+            &app,
+            request(
+                test::TestRequest::get()
+                    .uri(&format!("/admin/organizations/{}", DEFAULT_ORGANIZATION_ID)),
+                &north_admin,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(
+            &app,
+            request(
+                test::TestRequest::put()
+                    .uri(&format!("/admin/organizations/{}", north_id))
+                    .set_json(&north),
+                &north_admin,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let updated: Value = test::call_and_read_body_json(
+            &app,
+            request(
+                test::TestRequest::put()
+                    .uri(&format!("/admin/organizations/{}", north_id))
+                    .set_json(json!({ "slug": "north", "name": "North High", "email_domains": ["north.org"] })),
+                &platform_admin,
+            ),
+        )
+        .await;
+        assert_eq!(updated["email_domains"], json!(["north.org"]));
+        assert_eq!(updated["password_min_length"], 8);
+        let default = organization_for_email(&*repository, "ada@north.edu")
+            .await
+            .unwrap();
+        assert_eq!(default.id, DEFAULT_ORGANIZATION_ID);
+        assert_eq!(
+            email_domain_error(&*repository, &default, "grace@north.org")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("Addresses at this domain belong to another school")
+        );
+
+        let resp = test::call_service(
+            &app,
+            request(
+                test::TestRequest::put()
+                    .uri(&format!("/admin/organizations/{}", DEFAULT_ORGANIZATION_ID))
+                    .set_json(json!({ "slug": "default", "name": "Default", "email_domains": ["example.com"] })),
+                &platform_admin,
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
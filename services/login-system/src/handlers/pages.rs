@@ -0,0 +1,102 @@
+use actix_web::{get, web::Data, web::Query, HttpResponse};
+use serde::Deserialize;
+
+use crate::config::PublicBaseUrl;
+use crate::csrf;
+use crate::oidc::OidcProviders;
+use crate::pages;
+
+#[derive(Deserialize)]
+pub struct ResetQuery {
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[get("/register")]
+async fn register_page(base_url: Data<PublicBaseUrl>) -> HttpResponse {
+    page(&base_url, pages::register_page)
+}
+
+#[get("/login")]
+async fn login_page(base_url: Data<PublicBaseUrl>, providers: Data<OidcProviders>) -> HttpResponse {
+    page(&base_url, |csrf_token| {
+        pages::login_page(csrf_token, providers.names())
+    })
+}
+
+/// The reset link in the email points here, with the token in the query.
+#[get("/password-reset")]
+async fn password_reset_page(
+    base_url: Data<PublicBaseUrl>,
+    query: Query<ResetQuery>,
+) -> HttpResponse {
+    let reset_token = query.into_inner().token;
+    page(&base_url, |csrf_token| {
+        pages::password_reset_page(csrf_token, reset_token)
+    })
+}
+
+#[get("/profile")]
+async fn profile_page(base_url: Data<PublicBaseUrl>) -> HttpResponse {
+    page(&base_url, pages::profile_page)
+}
+
+#[get("/admin")]
+async fn admin_page(base_url: Data<PublicBaseUrl>) -> HttpResponse {
+    page(&base_url, pages::admin_page)
+}
+
+/// Every page gets a fresh CSRF token, set as a cookie and embedded in the page.
+fn page(base_url: &PublicBaseUrl, render: impl FnOnce(String) -> String) -> HttpResponse {
+    let (csrf_token, cookie) = csrf::issue(base_url.0.scheme() == "https");
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .cookie(cookie)
+        .body(render(csrf_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use url::Url;
+
+    #[actix_web::test]
+    async fn test_pages_set_the_csrf_cookie() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(PublicBaseUrl(
+                    Url::parse("https://login.example.com").unwrap(),
+                )))
+                .app_data(Data::new(OidcProviders::new(Vec::new())))
+                .service(register_page)
+                .service(login_page)
+                .service(password_reset_page)
+                .service(profile_page)
+                .service(admin_page),
+        )
+        .await;
+
+        for uri in [
+            "/register",
+            "/login",
+            "/password-reset?token=abc",
+            "/profile",
+            "/admin",
+        ] {
+            let resp =
+                test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let cookie = resp
+                .response()
+                .cookies()
+                .find(|cookie| cookie.name() == csrf::CSRF_COOKIE)
+                .unwrap();
+            assert_eq!(cookie.secure(), Some(true));
+            let csrf_token = cookie.value().to_string();
+            let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+            assert!(body.contains(&csrf_token));
+        }
+    }
+}
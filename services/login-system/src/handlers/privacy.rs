@@ -0,0 +1,573 @@
+use actix_web::{get, http::header, post, web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::audit::request_event;
+use crate::auth::{AuthenticatedUser, ClassRole};
+use crate::compiler::CompilerClient;
+use crate::config::PublicBaseUrl;
+use crate::csrf::CsrfChecked;
+use crate::error::ApiError;
+use crate::mailer::{Email, Mailer};
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditEvent, AuditQuery, AuditRepository, ClassRepository, ExternalIdentityRepository,
+    LinkedIdentity, LoginAttemptRepository, OneTimeTokenRepository, Profile, ProfileRepository,
+    PublicProfile, StoredAuditEvent, User, UserRepository,
+};
+use crate::throttle::LoginThrottle;
+use crate::tokens::{Purpose, TokenIssuer};
+
+use super::profile::{check_current_password, find_caller};
+
+/// The audit log is read in pages this big.
+const AUDIT_PAGE_SIZE: i64 = 1000;
+
+/// `account.json` in the export.
+#[derive(Serialize)]
+struct AccountExport {
+    exported_at: i64,
+    account: PublicProfile,
+    profile: Profile,
+    classes: Vec<ExportedClass>,
+    linked_identities: Vec<LinkedIdentity>,
+    /// Sign-ins, password changes and the like, newest first.
+    audit_events: Vec<StoredAuditEvent>,
+}
+
+#[derive(Serialize)]
+struct ExportedClass {
+    id: i64,
+    name: String,
+    role: ClassRole,
+}
+
+/// Without a password, the confirmation is emailed instead. That's how users
+/// who only sign in through a provider, and never had a password, confirm.
+#[derive(Deserialize)]
+pub struct ErasureRequest {
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErasurePending {
+    confirmation_token: String,
+    expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ErasureConfirmation {
+    confirmation_token: String,
+}
+
+/// Everything kept about the caller, here and in exercise_compiler, as a zip
+/// archive with `account.json` and `submissions.json`.
+#[get("/me/export")]
+#[allow(clippy::too_many_arguments)]
+async fn export_data(
+    caller: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    profiles: Data<dyn ProfileRepository>,
+    classes: Data<dyn ClassRepository>,
+    identities: Data<dyn ExternalIdentityRepository>,
+    audit: Data<dyn AuditRepository>,
+    compiler: Data<CompilerClient>,
+    issuer: Data<TokenIssuer>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let user = find_caller(&**users, &caller).await?;
+    let submissions = compiler
+        .submissions(&issuer, &caller)
+        .await
+        .map_err(|e| ApiError::bad_gateway("Could not fetch submissions", e))?;
+
+    let mut query = AuditQuery {
+        user_id: Some(user.id),
+        limit: AUDIT_PAGE_SIZE,
+        ..AuditQuery::default()
+    };
+    let mut audit_events = Vec::new();
+    loop {
+        let page = audit.audit_events(&query).await?;
+        let full = page.len() as i64 == AUDIT_PAGE_SIZE;
+        query.before_id = page.last().map(|event| event.id);
+        audit_events.extend(page);
+        if !full {
+            break;
+        }
+    }
+
+    let account = AccountExport {
+        exported_at: chrono::Utc::now().timestamp(),
+        profile: profiles.profile(user.id).await?,
+        classes: classes
+            .classes_of(user.id)
+            .await?
+            .into_iter()
+            .map(|(class, role)| ExportedClass {
+                id: class.id,
+                name: class.name,
+                role,
+            })
+            .collect(),
+        linked_identities: identities.identities_of(user.id).await?,
+        audit_events,
+        account: user.into(),
+    };
+    let archive = export_archive(&account, &submissions)
+        .map_err(|e| ApiError::internal("Error building the export", e))?;
+
+    audit
+        .record(request_event(&req, "data_exported", Some(caller.id)))
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"my-data.zip\"",
+        ))
+        .body(archive))
+}
+
+fn export_archive(
+    account: &AccountExport,
+    submissions: &serde_json::Value,
+) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("account.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(account)?)?;
+    zip.start_file("submissions.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(submissions)?)?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// First step of erasing the account: checks the password and returns a token
+/// to confirm with, so the account can't be erased by a single stray request.
+/// Without a password, emails a link with the token to the verified address
+/// and answers with 202.
+#[post("/me/erasure")]
+#[allow(clippy::too_many_arguments)]
+async fn request_erasure(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    hasher: Data<PasswordHasher>,
+    base_url: Data<PublicBaseUrl>,
+    req: HttpRequest,
+    form: Json<ErasureRequest>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let user = find_caller(&**users, &caller).await?;
+    let Some(password) = form
+        .password
+        .as_deref()
+        .filter(|password| !password.is_empty())
+    else {
+        send_erasure_email(&**mailer, &issuer, &base_url, &user).await?;
+        audit
+            .record(AuditEvent {
+                detail: Some("email".to_string()),
+                ..request_event(&req, "erasure_requested", Some(user.id))
+            })
+            .await?;
+        return Ok(HttpResponse::Accepted().finish());
+    };
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &user.username);
+    check_current_password(&throttle, &hasher, &user, password, "password").await?;
+
+    let confirmation_token = issuer
+        .one_time_token(user.id, None, Purpose::ConfirmErasure)
+        .map_err(|e| ApiError::internal("Error issuing token", e))?;
+    audit
+        .record(request_event(&req, "erasure_requested", Some(user.id)))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ErasurePending {
+        confirmation_token,
+        expires_in: Purpose::ConfirmErasure.ttl_secs(),
+    }))
+}
+
+/// Emails the link to `/profile` that confirms the erasure. Only a verified
+/// address will do, since the email stands in for the password.
+async fn send_erasure_email(
+    mailer: &dyn Mailer,
+    issuer: &TokenIssuer,
+    base_url: &PublicBaseUrl,
+    user: &User,
+) -> Result<(), ApiError> {
+    let email = user
+        .email
+        .as_deref()
+        .filter(|_| user.email_verified_at.is_some())
+        .ok_or_else(|| {
+            ApiError::bad_request("Enter your password, or verify your email address first")
+        })?;
+    let token = issuer
+        .one_time_token(user.id, Some(email), Purpose::ConfirmErasure)
+        .map_err(|e| ApiError::internal("Error issuing token", e))?;
+
+    mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Confirm deleting your account".to_string(),
+            body: format!(
+                "Hi {},\n\nFollow this link while signed in to delete your account for good:\n\n{}\n\nThe link expires in 10 minutes. If you didn't ask for it, you can ignore this email.",
+                user.username,
+                base_url.link("/profile", &token)
+            ),
+        })
+        .await
+        .map_err(|e| ApiError::internal("Error sending email", e))
+}
+
+/// Erases the account here and the user's submissions in exercise_compiler.
+/// The audit log keeps its events, under an id no longer tied to a username
+/// and without the addresses and user agents they came from.
+#[post("/me/erasure/confirm")]
+#[allow(clippy::too_many_arguments)]
+async fn confirm_erasure(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    used_tokens: Data<dyn OneTimeTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    compiler: Data<CompilerClient>,
+    issuer: Data<TokenIssuer>,
+    req: HttpRequest,
+    form: Json<ErasureConfirmation>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let invalid = || ApiError::bad_request("This confirmation is invalid or has expired");
+    let claims = issuer
+        .verify_one_time(&form.confirmation_token, Purpose::ConfirmErasure)
+        .map_err(|_| invalid())?;
+    if claims.sub != caller.id.to_string() {
+        return Err(invalid());
+    }
+    // An emailed confirmation only counts while the address is still the user's.
+    if let Some(email) = claims.email.as_deref() {
+        let user = find_caller(&**users, &caller).await?;
+        if user.email.as_deref() != Some(email) {
+            return Err(invalid());
+        }
+    }
+    if !used_tokens.consume(&claims.jti, claims.exp).await? {
+        return Err(ApiError::bad_request(
+            "This confirmation has already been used",
+        ));
+    }
+
+    erase_account(&**users, &compiler, &issuer, &caller).await?;
+    audit
+        .record(AuditEvent {
+            ip: None,
+            user_agent: None,
+            ..request_event(&req, "account_erased", Some(caller.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Submissions go first: if exercise_compiler can't be reached, the account
+/// stays and the user can try again, rather than leaving submissions behind
+/// that nobody can ask about any more.
+async fn erase_account(
+    users: &dyn UserRepository,
+    compiler: &CompilerClient,
+    issuer: &TokenIssuer,
+    caller: &AuthenticatedUser,
+) -> Result<(), ApiError> {
+    compiler
+        .erase_submissions(issuer, caller)
+        .await
+        .map_err(|e| ApiError::bad_gateway("Could not erase submissions", e))?;
+    if !users.delete(caller.id).await? {
+        return Err(ApiError::not_found("User not found"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::mailer::LogMailer;
+    use crate::repository::libsql::{LibsqlRepository, TEST_PASSWORD};
+    use crate::repository::{AuditQuery, DEFAULT_ORGANIZATION_ID};
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+    use std::io::Read;
+    use std::sync::Arc;
+    use url::Url;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    macro_rules! app {
+        ($repository:expr, $compiler:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ProfileRepository>))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn ExternalIdentityRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OneTimeTokenRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn LoginAttemptRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>))
+                    .app_data(Data::new($compiler))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(PublicBaseUrl(
+                        "http://localhost:8000".parse().unwrap(),
+                    )))
+                    .service(export_data)
+                    .service(request_erasure)
+                    .service(confirm_erasure),
+            )
+            .await
+        };
+    }
+
+    async fn create_user(repository: &LibsqlRepository, username: &str) -> (i64, String) {
+        let user = repository.create_test_user(username, Role::Student).await;
+        let token = TokenIssuer::for_tests()
+            .access_token(user.id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+        (user.id, format!("Bearer {}", token))
+    }
+
+    async fn mock_compiler() -> (MockServer, CompilerClient) {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/me/submissions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "language": "python", "code": "print(1)" }
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/me/submissions"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let client = CompilerClient::new(Some(Url::parse(&server.uri()).unwrap()));
+        (server, client)
+    }
+
+    #[actix_web::test]
+    async fn test_export_bundles_account_and_submissions() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (ada, token) = create_user(&repository, "ada").await;
+        let class = repository
+            .create_class("Compilers", "ABCD2345", ada)
+            .await
+            .unwrap();
+        repository
+            .link("school", "ada@school.example", ada)
+            .await
+            .unwrap();
+        let (_server, compiler) = mock_compiler().await;
+        let app = app!(repository, compiler);
+
+        let req = test::TestRequest::get()
+            .uri("/me/export")
+            .insert_header((header::AUTHORIZATION, token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(body.to_vec())).unwrap();
+        let mut read_json = |name: &str| {
+            let mut text = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            serde_json::from_str::<Value>(&text).unwrap()
+        };
+        let account = read_json("account.json");
+        assert_eq!(account["account"]["username"], "ada");
+        assert_eq!(account["classes"][0]["id"], class.id);
+        assert_eq!(account["linked_identities"][0]["provider"], "school");
+        let submissions = read_json("submissions.json");
+        assert_eq!(submissions[0]["code"], "print(1)");
+
+        let exported = repository
+            .audit_events(&AuditQuery {
+                event: Some("data_exported".to_string()),
+                limit: 1,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(exported[0].user_id, Some(ada));
+    }
+
+    #[actix_web::test]
+    async fn test_erasure_needs_the_password_and_a_confirmation() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (ada, token) = create_user(&repository, "ada").await;
+        let (_, other_token) = create_user(&repository, "grace").await;
+        let (server, compiler) = mock_compiler().await;
+        let app = app!(repository, compiler);
+        let post = |uri: &str, token: &str, body: Value| {
+            test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, token.to_string()))
+                .set_json(body)
+                .to_request()
+        };
+
+        let resp = test::call_service(
+            &app,
+            post("/me/erasure", &token, json!({ "password": "wrong" })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let pending: Value = test::call_and_read_body_json(
+            &app,
+            post("/me/erasure", &token, json!({ "password": TEST_PASSWORD })),
+        )
+        .await;
+        let confirmation = json!({ "confirmation_token": pending["confirmation_token"] });
+
+        // Someone else's confirmation erases nothing.
+        let resp = test::call_service(
+            &app,
+            post("/me/erasure/confirm", &other_token, confirmation.clone()),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        let resp = test::call_service(
+            &app,
+            post("/me/erasure/confirm", &token, confirmation.clone()),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.find_by_id(ada).await.unwrap().is_none());
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests[0].method, wiremock::http::Method::Delete);
+
+        let resp =
+            test::call_service(&app, post("/me/erasure/confirm", &token, confirmation)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_erasure_without_a_password_is_confirmed_by_email() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (ada, token) = create_user(&repository, "ada").await;
+        let (server, compiler) = mock_compiler().await;
+        let app = app!(repository, compiler);
+        let post = |uri: &str, body: Value| {
+            test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, token.clone()))
+                .insert_header((header::USER_AGENT, "Firefox"))
+                .set_json(body)
+                .to_request()
+        };
+
+        // Only a verified address can stand in for the password.
+        let resp = test::call_service(&app, post("/me/erasure", json!({}))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        repository.mark_email_verified(ada).await.unwrap();
+        let resp = test::call_service(&app, post("/me/erasure", json!({}))).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+        let issuer = TokenIssuer::for_tests();
+        let stale = issuer
+            .one_time_token(ada, Some("old@example.com"), Purpose::ConfirmErasure)
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            post(
+                "/me/erasure/confirm",
+                json!({ "confirmation_token": stale }),
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let emailed = issuer
+            .one_time_token(ada, Some("ada@example.com"), Purpose::ConfirmErasure)
+            .unwrap();
+        let resp = test::call_service(
+            &app,
+            post(
+                "/me/erasure/confirm",
+                json!({ "confirmation_token": emailed }),
+            ),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.find_by_id(ada).await.unwrap().is_none());
+        assert!(!server.received_requests().await.unwrap().is_empty());
+
+        let events = repository
+            .audit_events(&AuditQuery {
+                user_id: Some(ada),
+                limit: 10,
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert!(events
+            .iter()
+            .any(|event| event.event == "erasure_requested"));
+        assert!(events
+            .iter()
+            .all(|event| event.ip.is_none() && event.user_agent.is_none()));
+    }
+
+    #[actix_web::test]
+    async fn test_account_stays_when_submissions_cannot_be_erased() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (ada, token) = create_user(&repository, "ada").await;
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+        let app = app!(
+            repository,
+            CompilerClient::new(Some(Url::parse(&server.uri()).unwrap()))
+        );
+
+        let confirmation_token = TokenIssuer::for_tests()
+            .one_time_token(ada, None, Purpose::ConfirmErasure)
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/me/erasure/confirm")
+            .insert_header((header::AUTHORIZATION, token))
+            .set_json(json!({ "confirmation_token": confirmation_token }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(repository.find_by_id(ada).await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,372 @@
+use actix_web::{get, patch, post, web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::audit::request_event;
+use crate::auth::AuthenticatedUser;
+use crate::breach::{password_set_response, BreachedPasswords};
+use crate::csrf::CsrfChecked;
+use crate::error::{ApiError, FieldErrors};
+use crate::handlers::organizations::find_organization;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditRepository, LoginAttemptRepository, OrganizationRepository, Profile, ProfileRepository,
+    PublicProfile, RefreshTokenRepository, User, UserRepository,
+};
+use crate::throttle::LoginThrottle;
+use crate::validation::{normalize_password, validate_password, validate_profile};
+
+/// The signed-in user's account and profile, as one object.
+#[derive(Serialize)]
+struct Me {
+    #[serde(flatten)]
+    user: PublicProfile,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+/// Fields left out stay as they are; `null` or an empty string clears them.
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(default, deserialize_with = "present")]
+    display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    preferred_language: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    bio: Option<Option<String>>,
+}
+
+/// Tells a field sent as `null` (`Some(None)`) apart from a missing one (`None`).
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    password: String,
+}
+
+#[get("/me")]
+async fn get_me(
+    caller: AuthenticatedUser,
+    users: Data<dyn UserRepository>,
+    profiles: Data<dyn ProfileRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let user = find_caller(&**users, &caller).await?;
+    let profile = profiles.profile(user.id).await?;
+
+    Ok(HttpResponse::Ok().json(Me {
+        user: user.into(),
+        profile,
+    }))
+}
+
+#[patch("/me")]
+async fn update_me(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    profiles: Data<dyn ProfileRepository>,
+    form: Json<ProfileUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let user = find_caller(&**users, &caller).await?;
+    let update = form.into_inner();
+
+    let mut profile = profiles.profile(user.id).await?;
+    for (field, value) in [
+        (&mut profile.display_name, update.display_name),
+        (&mut profile.avatar_url, update.avatar_url),
+        (&mut profile.preferred_language, update.preferred_language),
+        (&mut profile.locale, update.locale),
+        (&mut profile.bio, update.bio),
+    ] {
+        if let Some(value) = value {
+            *field = value;
+        }
+    }
+    let profile = validate_profile(profile)?;
+    profiles.save_profile(user.id, &profile).await?;
+
+    Ok(HttpResponse::Ok().json(Me {
+        user: user.into(),
+        profile,
+    }))
+}
+
+/// Signs the user out everywhere else, since a password change is often the
+/// reaction to someone else knowing the old one.
+#[post("/me/password")]
+#[allow(clippy::too_many_arguments)]
+async fn change_password(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    organizations: Data<dyn OrganizationRepository>,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    hasher: Data<PasswordHasher>,
+    breached_passwords: Data<BreachedPasswords>,
+    req: HttpRequest,
+    form: Json<PasswordChange>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let user = find_caller(&**users, &caller).await?;
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &user.username);
+    check_current_password(
+        &throttle,
+        &hasher,
+        &user,
+        &form.current_password,
+        "current_password",
+    )
+    .await?;
+
+    let min_chars = find_organization(&**organizations, user.organization_id)
+        .await?
+        .password_min_length as usize;
+    let password = validate_password(&form.password, &user.username, min_chars)?;
+    let warnings = breached_passwords.check(&password, "password").await?;
+    let password_hash = hasher.hash(password).await?;
+    users.set_password_hash(user.id, &password_hash).await?;
+    refresh_tokens.revoke_all_for_user(user.id).await?;
+    audit
+        .record(request_event(&req, "password_changed", Some(user.id)))
+        .await?;
+
+    Ok(password_set_response(warnings))
+}
+
+pub(super) async fn find_caller(
+    users: &dyn UserRepository,
+    caller: &AuthenticatedUser,
+) -> Result<User, ApiError> {
+    users
+        .find_by_id(caller.id)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Invalid access token"))
+}
+
+/// Counts wrong passwords like failed logins, so an access token can't be used
+/// to guess the password at full speed.
+pub(super) async fn check_current_password(
+    throttle: &LoginThrottle<'_>,
+    hasher: &PasswordHasher,
+    user: &User,
+    password: &str,
+    field: &'static str,
+) -> Result<(), ApiError> {
+    throttle.check().await?;
+    if hasher
+        .verify(normalize_password(password), user.password_hash.clone())
+        .await?
+    {
+        return Ok(());
+    }
+
+    throttle
+        .record_failure(Some(user.id), "wrong_current_password")
+        .await?;
+    Err(ApiError::validation(FieldErrors::from([(
+        field,
+        vec!["Password is incorrect".to_string()],
+    )])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::repository::libsql::{LibsqlRepository, TEST_PASSWORD};
+    use crate::repository::DEFAULT_ORGANIZATION_ID;
+    use crate::tokens::TokenIssuer;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    macro_rules! app {
+        ($repository:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OrganizationRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ProfileRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn LoginAttemptRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .app_data(Data::new(BreachedPasswords::disabled()))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .service(get_me)
+                    .service(update_me)
+                    .service(change_password),
+            )
+            .await
+        };
+    }
+
+    async fn setup() -> (Arc<LibsqlRepository>, User, (header::HeaderName, String)) {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user = repository.create_test_user("ada", Role::Student).await;
+        let token = TokenIssuer::for_tests()
+            .access_token(user.id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+        (
+            repository,
+            user,
+            (header::AUTHORIZATION, format!("Bearer {}", token)),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_patch_updates_only_given_fields() {
+        let (repository, _, bearer) = setup().await;
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::patch()
+                .uri("/me")
+                .insert_header(bearer.clone())
+                .set_json(serde_json::json!({ "display_name": "Ada", "locale": "en-GB" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::patch()
+                .uri("/me")
+                .insert_header(bearer.clone())
+                .set_json(serde_json::json!({ "locale": null, "bio": "Poet of science" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/me")
+                .insert_header(bearer)
+                .to_request(),
+        )
+        .await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["username"], "ada");
+        assert_eq!(body["display_name"], "Ada");
+        assert_eq!(body["locale"], serde_json::Value::Null);
+        assert_eq!(body["bio"], "Poet of science");
+        assert!(body.get("password_hash").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_profile_is_rejected() {
+        let (repository, user, bearer) = setup().await;
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::patch()
+                .uri("/me")
+                .insert_header(bearer)
+                .set_json(serde_json::json!({ "avatar_url": "http://example.com/a.png" }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            repository.profile(user.id).await.unwrap(),
+            Profile::default()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_impersonators_can_look_but_not_change() {
+        let (repository, user, _) = setup().await;
+        let token = TokenIssuer::for_tests()
+            .impersonation_token(
+                user.id,
+                DEFAULT_ORGANIZATION_ID,
+                Role::Student,
+                Vec::new(),
+                7,
+            )
+            .unwrap();
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", token));
+        let app = app!(repository);
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/me")
+                .insert_header(bearer.clone())
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for req in [
+            test::TestRequest::patch()
+                .uri("/me")
+                .set_json(serde_json::json!({ "display_name": "Not Ada" })),
+            test::TestRequest::post().uri("/me/password").set_json(
+                serde_json::json!({ "current_password": TEST_PASSWORD, "password": "difference engine" }),
+            ),
+        ] {
+            let resp =
+                test::call_service(&app, req.insert_header(bearer.clone()).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["error"]["code"], "impersonation_forbidden");
+        }
+        assert!(repository.find_by_id(user.id).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_password_change_requires_current_password() {
+        let (repository, user, bearer) = setup().await;
+        repository
+            .store("session", user.id, "family", i64::MAX)
+            .await
+            .unwrap();
+        let app = app!(repository);
+
+        let change = |current: &str| {
+            test::TestRequest::post()
+                .uri("/me/password")
+                .insert_header(bearer.clone())
+                .set_json(serde_json::json!({
+                    "current_password": current,
+                    "password": "difference engine",
+                }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, change("wrong password")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["error"]["fields"]["current_password"].is_array());
+
+        let resp = test::call_service(&app, change(TEST_PASSWORD)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let user = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert!(PasswordHasher::for_tests()
+            .verify("difference engine".to_string(), user.password_hash)
+            .await
+            .unwrap());
+        assert!(!repository.revoke("session").await.unwrap());
+    }
+}
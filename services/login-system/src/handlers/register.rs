@@ -0,0 +1,369 @@
+use actix_web::{post, web::Data, web::Json, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::request_event;
+use crate::breach::BreachedPasswords;
+use crate::config::PublicBaseUrl;
+use crate::csrf::CsrfChecked;
+use crate::error::{ApiError, FieldErrors};
+use crate::handlers::account::send_verification_email;
+use crate::handlers::organizations::organization_for_email;
+use crate::mailer::Mailer;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditRepository, NewUser, OrganizationRepository, PublicProfile, RepositoryError,
+    UserRepository,
+};
+use crate::tokens::TokenIssuer;
+use crate::validation::validate_registration;
+
+#[derive(Deserialize)]
+pub struct UserRegistration {
+    username: String,
+    password: String,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Registered {
+    #[serde(flatten)]
+    user: PublicProfile,
+    /// E.g. that the password showed up in a breach, if such passwords are
+    /// allowed.
+    #[serde(skip_serializing_if = "FieldErrors::is_empty")]
+    warnings: FieldErrors,
+}
+
+/// New accounts join the organization that claimed their email's domain, and
+/// follow its password policy.
+#[post("/register")]
+#[allow(clippy::too_many_arguments)]
+async fn register_user(
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    organizations: Data<dyn OrganizationRepository>,
+    audit: Data<dyn AuditRepository>,
+    mailer: Data<dyn Mailer>,
+    issuer: Data<TokenIssuer>,
+    base_url: Data<PublicBaseUrl>,
+    hasher: Data<PasswordHasher>,
+    breached_passwords: Data<BreachedPasswords>,
+    req: HttpRequest,
+    form: Json<UserRegistration>,
+) -> Result<HttpResponse, ApiError> {
+    let registration_data: UserRegistration = form.into_inner();
+    let organization = organization_for_email(
+        &**organizations,
+        registration_data
+            .email
+            .as_deref()
+            .unwrap_or_default()
+            .trim(),
+    )
+    .await?;
+    if let Some(provider) = &organization.sso_provider {
+        return Err(ApiError::sso_required(provider));
+    }
+    let registration = validate_registration(
+        &registration_data.username,
+        &registration_data.password,
+        registration_data.email.as_deref(),
+        organization.password_min_length as usize,
+    )?;
+
+    let warnings = breached_passwords
+        .check(&registration.password, "password")
+        .await?;
+    let hash_pwd: String = hasher.hash(registration.password).await?;
+
+    let new_user = NewUser {
+        username: registration.username,
+        email: Some(registration.email),
+        password_hash: hash_pwd,
+        organization_id: organization.id,
+    };
+
+    match users.create(new_user).await {
+        Ok(user) => {
+            audit
+                .record(request_event(&req, "user_registered", Some(user.id)))
+                .await?;
+            // The account exists either way; the user can ask for another email.
+            if let Err(e) = send_verification_email(&**mailer, &issuer, &base_url, &user).await {
+                eprintln!("Error sending verification email: {}", e);
+            }
+            Ok(HttpResponse::Created().json(Registered {
+                user: user.into(),
+                warnings,
+            }))
+        }
+        Err(RepositoryError::Conflict(message)) if message.contains("users.email") => {
+            Err(ApiError::conflict("email", "Email already registered"))
+        }
+        Err(RepositoryError::Conflict(_)) => {
+            Err(ApiError::conflict("username", "Username already taken"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::LogMailer;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::OrganizationSettings;
+    use actix_web::{http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    fn register(body: serde_json::Value) -> test::TestRequest {
+        test::TestRequest::post().uri("/register").set_json(body)
+    }
+
+    fn mail_data() -> (Data<dyn Mailer>, Data<TokenIssuer>, Data<PublicBaseUrl>) {
+        (
+            Data::from(Arc::new(LogMailer) as Arc<dyn Mailer>),
+            Data::new(TokenIssuer::for_tests()),
+            Data::new(PublicBaseUrl("http://localhost:8000".parse().unwrap())),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_register_returns_public_profile() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (mailer, issuer, base_url) = mail_data();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository as Arc<dyn AuditRepository>))
+                .app_data(mailer)
+                .app_data(issuer)
+                .app_data(base_url)
+                .app_data(Data::new(PasswordHasher::for_tests()))
+                .app_data(Data::new(BreachedPasswords::disabled()))
+                .service(register_user),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            register(serde_json::json!({
+                "username": "ada",
+                "email": "ada@example.com",
+                "password": "analytical engine"
+            }))
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let profile: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(profile["username"], "ada");
+        assert_eq!(profile["email_verified"], false);
+        assert!(profile["id"].is_i64());
+        assert!(profile.get("password").is_none());
+        assert!(profile.get("warnings").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_registration_follows_the_organization_of_the_email() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let north = repository
+            .create_organization(&OrganizationSettings {
+                slug: "north".to_string(),
+                name: "North High".to_string(),
+                email_domains: vec!["north.edu".to_string()],
+                sso_provider: None,
+                password_min_length: 20,
+            })
+            .await
+            .unwrap();
+        repository
+            .create_organization(&OrganizationSettings {
+                slug: "south".to_string(),
+                name: "South High".to_string(),
+                email_domains: vec!["south.edu".to_string()],
+                sso_provider: Some("google".to_string()),
+                password_min_length: 8,
+            })
+            .await
+            .unwrap();
+        let (mailer, issuer, base_url) = mail_data();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(mailer)
+                .app_data(issuer)
+                .app_data(base_url)
+                .app_data(Data::new(PasswordHasher::for_tests()))
+                .app_data(Data::new(BreachedPasswords::disabled()))
+                .service(register_user),
+        )
+        .await;
+        let request = |email: &str, password: &str| {
+            register(serde_json::json!({
+                "username": "ada",
+                "email": email,
+                "password": password
+            }))
+            .to_request()
+        };
+
+        // North asks for longer passwords than everyone else.
+        let resp = test::call_service(&app, request("ada@north.edu", "analytical engine")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // South creates its accounts through its sign-in provider.
+        let resp = test::call_service(&app, request("ada@south.edu", "analytical engine")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "sso_required");
+
+        let profile: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            request("ada@north.edu", "the analytical engine weaves"),
+        )
+        .await;
+        assert_eq!(profile["organization_id"], north.id);
+    }
+
+    #[actix_web::test]
+    async fn test_register_duplicate_username_is_conflict() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (mailer, issuer, base_url) = mail_data();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository as Arc<dyn AuditRepository>))
+                .app_data(mailer)
+                .app_data(issuer)
+                .app_data(base_url)
+                .app_data(Data::new(PasswordHasher::for_tests()))
+                .app_data(Data::new(BreachedPasswords::disabled()))
+                .service(register_user),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            register(serde_json::json!({
+                "username": "ada",
+                "email": "ada@example.com",
+                "password": "analytical engine"
+            }))
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = test::call_service(
+            &app,
+            register(serde_json::json!({
+                "username": "Ada",
+                "email": "ada@example.org",
+                "password": "analytical engine"
+            }))
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn test_register_invalid_input_returns_error_envelope() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (mailer, issuer, base_url) = mail_data();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository as Arc<dyn AuditRepository>))
+                .app_data(mailer)
+                .app_data(issuer)
+                .app_data(base_url)
+                .app_data(Data::new(PasswordHasher::for_tests()))
+                .app_data(Data::new(BreachedPasswords::disabled()))
+                .service(register_user),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            register(serde_json::json!({ "username": "", "password": "password" })).to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "validation_error");
+        assert!(body["error"]["fields"]["username"].is_array());
+        assert!(body["error"]["fields"]["email"].is_array());
+        assert!(body["error"]["fields"]["password"].is_array());
+    }
+
+    #[actix_web::test]
+    async fn test_breached_password_can_come_with_a_warning() {
+        use crate::breach::BreachPolicy;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // SHA-1 of "analytical engine" is 13653F3BACCF72B9471D3FB3E082E42726E60E5B.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/range/13653"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("F3BACCF72B9471D3FB3E082E42726E60E5B:12\r\n"),
+            )
+            .mount(&server)
+            .await;
+
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let (mailer, issuer, base_url) = mail_data();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn OrganizationRepository>
+                ))
+                .app_data(Data::from(repository as Arc<dyn AuditRepository>))
+                .app_data(mailer)
+                .app_data(issuer)
+                .app_data(base_url)
+                .app_data(Data::new(PasswordHasher::for_tests()))
+                .app_data(Data::new(BreachedPasswords::new(
+                    Some(server.uri().parse().unwrap()),
+                    BreachPolicy::Warn,
+                )))
+                .service(register_user),
+        )
+        .await;
+
+        let resp = test::call_service(
+            &app,
+            register(serde_json::json!({
+                "username": "ada",
+                "email": "ada@example.com",
+                "password": "analytical engine"
+            }))
+            .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["username"], "ada");
+        assert!(body["warnings"]["password"][0].is_string());
+    }
+}
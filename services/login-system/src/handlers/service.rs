@@ -0,0 +1,136 @@
+use actix_web::{get, web::Data, web::Path, web::Query, HttpResponse};
+use serde::Deserialize;
+
+use crate::auth::{ApiClient, ApiScope};
+use crate::error::ApiError;
+use crate::repository::{ClassRepository, PublicProfile, UserRepository};
+
+#[derive(Deserialize)]
+pub struct UserLookup {
+    username: String,
+}
+
+/// The `/service` endpoints are for other services. They take an API key with
+/// the matching scope instead of a user's token.
+#[get("/service/users/{id}")]
+async fn find_user(
+    client: ApiClient,
+    users: Data<dyn UserRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    client.require_scope(ApiScope::UsersRead)?;
+    let user = users
+        .find_by_id(path.into_inner())
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    Ok(HttpResponse::Ok().json(PublicProfile::from(user)))
+}
+
+/// `GET /service/users?username=ada`
+#[get("/service/users")]
+async fn find_user_by_username(
+    client: ApiClient,
+    users: Data<dyn UserRepository>,
+    query: Query<UserLookup>,
+) -> Result<HttpResponse, ApiError> {
+    client.require_scope(ApiScope::UsersRead)?;
+    let user = users
+        .find_by_username(query.username.trim())
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))?;
+    Ok(HttpResponse::Ok().json(PublicProfile::from(user)))
+}
+
+#[get("/service/classes/{id}/members")]
+async fn class_roster(
+    client: ApiClient,
+    classes: Data<dyn ClassRepository>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    client.require_scope(ApiScope::ClassesRead)?;
+    let class = classes
+        .find_class(path.into_inner())
+        .await?
+        .ok_or_else(|| ApiError::not_found("Class not found"))?;
+    Ok(HttpResponse::Ok().json(classes.roster(class.id).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{ApiKeyRepository, NewApiKey, NewUser, DEFAULT_ORGANIZATION_ID};
+    use crate::tokens::{self, hash_token};
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_keys_only_reach_their_scopes() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let grace = repository
+            .create(NewUser {
+                username: "grace".to_string(),
+                email: None,
+                password_hash: "hash".to_string(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace.id)
+            .await
+            .unwrap();
+        let key = tokens::api_key();
+        repository
+            .create_api_key(NewApiKey {
+                name: "grader".to_string(),
+                prefix: key[..12].to_string(),
+                key_hash: hash_token(&key),
+                scopes: vec![ApiScope::ClassesRead],
+                created_by: grace.id,
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+        let expired = tokens::api_key();
+        repository
+            .create_api_key(NewApiKey {
+                name: "old".to_string(),
+                prefix: expired[..12].to_string(),
+                key_hash: hash_token(&expired),
+                scopes: vec![ApiScope::ClassesRead],
+                created_by: grace.id,
+                expires_at: Some(1),
+            })
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository.clone() as Arc<dyn UserRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn ClassRepository>))
+                .app_data(Data::from(repository.clone() as Arc<dyn ApiKeyRepository>))
+                .service(find_user_by_username)
+                .service(class_roster),
+        )
+        .await;
+        let get = |uri: &str, key: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", key)))
+                .to_request()
+        };
+
+        let roster = format!("/service/classes/{}/members", class.id);
+        let resp = test::call_service(&app, get(&roster, &key)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let members: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(members[0]["username"], "grace");
+
+        let resp = test::call_service(&app, get("/service/users?username=grace", &key)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, get(&roster, &expired)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,239 @@
+use actix_web::{delete, get, web::Data, web::Path, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use crate::audit::request_event;
+use crate::auth::AuthenticatedUser;
+use crate::csrf::CsrfChecked;
+use crate::error::ApiError;
+use crate::repository::{AuditEvent, AuditRepository, RefreshTokenRepository, Session};
+
+/// Browsers by a token in their user agent. Order matters: Edge and Opera
+/// also claim to be Chrome, and Chrome claims to be Safari.
+const BROWSERS: &[(&str, &str)] = &[
+    ("Edg/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+];
+
+/// Android and ChromeOS user agents mention Linux too.
+const SYSTEMS: &[(&str, &str)] = &[
+    ("Windows", "Windows"),
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iOS"),
+    ("Mac OS X", "macOS"),
+    ("CrOS", "ChromeOS"),
+    ("Linux", "Linux"),
+];
+
+/// A session with a readable description of the device it was used from.
+#[derive(Serialize)]
+pub(super) struct SessionView {
+    #[serde(flatten)]
+    session: Session,
+    /// E.g. `Firefox on Linux`, when the user agent is recognized.
+    device: Option<String>,
+}
+
+impl From<Session> for SessionView {
+    fn from(session: Session) -> Self {
+        SessionView {
+            device: session.user_agent.as_deref().and_then(describe_device),
+            session,
+        }
+    }
+}
+
+pub(super) async fn session_views(
+    refresh_tokens: &dyn RefreshTokenRepository,
+    user_id: i64,
+) -> Result<Vec<SessionView>, ApiError> {
+    Ok(refresh_tokens
+        .active_sessions(user_id)
+        .await?
+        .into_iter()
+        .map(SessionView::from)
+        .collect())
+}
+
+fn describe_device(user_agent: &str) -> Option<String> {
+    let find = |names: &[(&str, &'static str)]| {
+        names
+            .iter()
+            .find(|(token, _)| user_agent.contains(token))
+            .map(|&(_, name)| name)
+    };
+    match (find(BROWSERS), find(SYSTEMS)) {
+        (Some(browser), Some(system)) => Some(format!("{} on {}", browser, system)),
+        (Some(name), None) | (None, Some(name)) => Some(name.to_string()),
+        (None, None) => None,
+    }
+}
+
+/// The caller's sessions that can still be refreshed, most recently used first.
+#[get("/me/sessions")]
+async fn list_sessions(
+    caller: AuthenticatedUser,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(session_views(&**refresh_tokens, caller.id).await?))
+}
+
+/// Signs one session out, e.g. a lab machine the user forgot to log out of.
+/// Its access token works until it expires.
+#[delete("/me/sessions/{id}")]
+async fn revoke_session(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    let session_id = path.into_inner();
+    if !refresh_tokens.revoke_session(caller.id, session_id).await? {
+        return Err(ApiError::not_found("Session not found"));
+    }
+    audit
+        .record(AuditEvent {
+            detail: Some(format!("Session {}", session_id)),
+            ..request_event(&req, "session_revoked", Some(caller.id))
+        })
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Signs the caller out everywhere, this session included.
+#[delete("/me/sessions")]
+async fn revoke_all_sessions(
+    caller: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    refresh_tokens: Data<dyn RefreshTokenRepository>,
+    audit: Data<dyn AuditRepository>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    caller.forbid_impersonation()?;
+    refresh_tokens.revoke_all_for_user(caller.id).await?;
+    audit
+        .record(request_event(&req, "sessions_revoked", Some(caller.id)))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::repository::libsql::LibsqlRepository;
+    use crate::repository::{NewUser, UserRepository, DEFAULT_ORGANIZATION_ID};
+    use crate::tokens::TokenIssuer;
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_devices_are_described_from_the_user_agent() {
+        assert_eq!(
+            describe_device(
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0"
+            )
+            .as_deref(),
+            Some("Firefox on Linux")
+        );
+        assert_eq!(
+            describe_device(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36 Edg/120.0.0.0"
+            )
+            .as_deref(),
+            Some("Edge on Windows")
+        );
+        assert_eq!(describe_device("curl/8.4.0"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_users_list_and_revoke_their_sessions() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let ada = repository
+            .create(NewUser {
+                username: "ada".to_string(),
+                email: None,
+                password_hash: "hash".to_string(),
+                organization_id: DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        for (hash, family) in [("hash-1", "lab"), ("hash-2", "home")] {
+            repository
+                .store(hash, ada.id, family, i64::MAX)
+                .await
+                .unwrap();
+            repository
+                .record_session(family, ada.id, None, Some("Firefox/120.0"))
+                .await
+                .unwrap();
+        }
+        let token = TokenIssuer::for_tests()
+            .access_token(ada.id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(
+                    repository.clone() as Arc<dyn RefreshTokenRepository>
+                ))
+                .app_data(Data::from(repository.clone() as Arc<dyn AuditRepository>))
+                .app_data(Data::new(TokenIssuer::for_tests()))
+                .service(list_sessions)
+                .service(revoke_session)
+                .service(revoke_all_sessions),
+        )
+        .await;
+        let request = |request: test::TestRequest| {
+            request
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let sessions: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            request(test::TestRequest::get().uri("/me/sessions")),
+        )
+        .await;
+        assert_eq!(sessions.as_array().unwrap().len(), 2);
+        assert_eq!(sessions[0]["device"], "Firefox");
+        let id = sessions[0]["id"].as_i64().unwrap();
+
+        let resp = test::call_service(
+            &app,
+            request(test::TestRequest::delete().uri(&format!("/me/sessions/{}", id))),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(
+            &app,
+            request(test::TestRequest::delete().uri(&format!("/me/sessions/{}", id))),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(repository.active_sessions(ada.id).await.unwrap().len(), 1);
+
+        let resp = test::call_service(
+            &app,
+            request(test::TestRequest::delete().uri(&format!("/me/sessions/{}", id + 100))),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(
+            &app,
+            request(test::TestRequest::delete().uri("/me/sessions")),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.active_sessions(ada.id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,578 @@
+use actix_web::{
+    delete, dev::Payload, post, web::Data, web::Json, FromRequest, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+
+use crate::auth::{bearer_token, AuthenticatedUser};
+use crate::config::TwoFactorPolicy;
+use crate::csrf::CsrfChecked;
+use crate::error::ApiError;
+use crate::password::PasswordHasher;
+use crate::repository::{
+    AuditRepository, LoginAttemptRepository, TwoFactorRepository, UserRepository,
+};
+use crate::throttle::LoginThrottle;
+use crate::tokens::{Purpose, TokenIssuer};
+use crate::totp;
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    /// For typing into an authenticator app by hand.
+    secret: String,
+    /// For rendering as a QR code.
+    provisioning_uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// The caller of an enrollment endpoint: either a signed-in user, or a user
+/// whose role requires 2FA and who got an enrollment token from `/login`
+/// instead of an access token.
+pub struct EnrollingUser {
+    pub id: i64,
+}
+
+impl FromRequest for EnrollingUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate_enrollment(req))
+    }
+}
+
+fn authenticate_enrollment(req: &HttpRequest) -> Result<EnrollingUser, ApiError> {
+    let issuer = req.app_data::<Data<TokenIssuer>>().ok_or_else(|| {
+        ApiError::internal("Error authenticating", "TokenIssuer is not registered")
+    })?;
+    let token = bearer_token(req)?;
+
+    let sub = match issuer.verify(token) {
+        Ok(claims) if claims.act.is_some() => return Err(ApiError::impersonation_forbidden()),
+        Ok(claims) => claims.sub,
+        Err(_) => {
+            issuer
+                .verify_one_time(token, Purpose::TwoFactorEnrollment)
+                .map_err(|_| ApiError::unauthorized("Invalid access token"))?
+                .sub
+        }
+    };
+    let id = sub
+        .parse()
+        .map_err(|_| ApiError::unauthorized("Invalid access token"))?;
+
+    Ok(EnrollingUser { id })
+}
+
+/// Starts enrollment with a new secret. Until it is confirmed with a code, 2FA
+/// stays off and calling this again replaces the secret.
+#[post("/2fa/setup")]
+async fn setup(
+    caller: EnrollingUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let user = users
+        .find_by_id(caller.id)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("Invalid access token"))?;
+
+    let secret = totp::generate_secret();
+    if !two_factor.begin_totp_enrollment(user.id, &secret).await? {
+        return Err(already_enabled());
+    }
+
+    let provisioning_uri = totp::provisioning_uri(&secret, &user.username)
+        .map_err(|e| ApiError::internal("Error creating provisioning URI", e))?;
+    Ok(HttpResponse::Ok().json(Enrollment {
+        secret,
+        provisioning_uri,
+    }))
+}
+
+/// Turns 2FA on once the user proves their authenticator works. The recovery
+/// codes are only ever shown in this response.
+#[post("/2fa/confirm")]
+async fn confirm(
+    caller: EnrollingUser,
+    _csrf: CsrfChecked,
+    two_factor: Data<dyn TwoFactorRepository>,
+    hasher: Data<PasswordHasher>,
+    form: Json<CodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let credential = match two_factor.totp_credential(caller.id).await? {
+        Some(credential) if credential.confirmed => return Err(already_enabled()),
+        Some(credential) => credential,
+        None => {
+            return Err(ApiError::bad_request(
+                "Two-factor setup has not been started",
+            ))
+        }
+    };
+
+    let step = totp::verify_code(&credential.secret, &form.code, now())
+        .map_err(|e| ApiError::internal("Error checking code", e))?
+        .ok_or_else(invalid_code)?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    if !two_factor
+        .confirm_totp(
+            caller.id,
+            step,
+            &hash_recovery_codes(&hasher, &recovery_codes).await?,
+        )
+        .await?
+    {
+        return Err(already_enabled());
+    }
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+/// Replaces every recovery code, e.g. after most of them were used.
+#[post("/2fa/recovery-codes")]
+#[allow(clippy::too_many_arguments)]
+async fn regenerate_recovery_codes(
+    user: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    hasher: Data<PasswordHasher>,
+    req: HttpRequest,
+    form: Json<CodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    user.forbid_impersonation()?;
+    let username = find_username(&**users, user.id).await?;
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &username);
+    check_code_throttled(&throttle, &**two_factor, user.id, &form.code).await?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    two_factor
+        .replace_recovery_codes(
+            user.id,
+            &hash_recovery_codes(&hasher, &recovery_codes).await?,
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+#[delete("/2fa")]
+#[allow(clippy::too_many_arguments)]
+async fn disable(
+    user: AuthenticatedUser,
+    _csrf: CsrfChecked,
+    users: Data<dyn UserRepository>,
+    two_factor: Data<dyn TwoFactorRepository>,
+    attempts: Data<dyn LoginAttemptRepository>,
+    audit: Data<dyn AuditRepository>,
+    policy: Data<TwoFactorPolicy>,
+    req: HttpRequest,
+    form: Json<CodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    user.forbid_impersonation()?;
+    if policy.is_required(user.role) {
+        return Err(ApiError::forbidden(format!(
+            "Two-factor authentication is required for the {} role",
+            user.role
+        )));
+    }
+    let username = find_username(&**users, user.id).await?;
+    let throttle = LoginThrottle::new(&**attempts, &**audit, &req, &username);
+    check_code_throttled(&throttle, &**two_factor, user.id, &form.code).await?;
+
+    two_factor.disable_totp(user.id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Checks a code against the user's confirmed secret and uses it up.
+pub async fn check_totp_code(
+    two_factor: &dyn TwoFactorRepository,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let Some(credential) = two_factor.totp_credential(user_id).await? else {
+        return Ok(false);
+    };
+    if !credential.confirmed {
+        return Ok(false);
+    }
+
+    let step = totp::verify_code(&credential.secret, code, now())
+        .map_err(|e| ApiError::internal("Error checking code", e))?;
+    match step {
+        Some(step) => Ok(two_factor.record_totp_step(user_id, step).await?),
+        None => Ok(false),
+    }
+}
+
+/// Counts wrong codes like failed logins, so an access token can't be used to
+/// guess codes at full speed.
+async fn check_code_throttled(
+    throttle: &LoginThrottle<'_>,
+    two_factor: &dyn TwoFactorRepository,
+    user_id: i64,
+    code: &str,
+) -> Result<(), ApiError> {
+    throttle.check().await?;
+    if check_totp_code(two_factor, user_id, code).await? {
+        return Ok(());
+    }
+
+    throttle
+        .record_failure(Some(user_id), "wrong_two_factor_code")
+        .await?;
+    Err(invalid_code())
+}
+
+/// Checks a recovery code and marks it used. Codes hashed with bcrypt before
+/// Argon2id was the default still work.
+pub async fn redeem_recovery_code(
+    two_factor: &dyn TwoFactorRepository,
+    hasher: &PasswordHasher,
+    user_id: i64,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let code = totp::normalize_recovery_code(code);
+
+    for recovery_code in two_factor.unused_recovery_codes(user_id).await? {
+        if hasher.verify(code.clone(), recovery_code.code_hash).await? {
+            return Ok(two_factor.use_recovery_code(recovery_code.id).await?);
+        }
+    }
+    Ok(false)
+}
+
+/// Hashed like passwords, off the workers that serve requests.
+async fn hash_recovery_codes(
+    hasher: &PasswordHasher,
+    codes: &[String],
+) -> Result<Vec<String>, ApiError> {
+    let mut hashes = Vec::with_capacity(codes.len());
+    for code in codes {
+        hashes.push(hasher.hash(totp::normalize_recovery_code(code)).await?);
+    }
+    Ok(hashes)
+}
+
+async fn find_username(users: &dyn UserRepository, id: i64) -> Result<String, ApiError> {
+    users
+        .find_by_id(id)
+        .await?
+        .map(|user| user.username)
+        .ok_or_else(|| ApiError::unauthorized("Invalid access token"))
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+fn already_enabled() -> ApiError {
+    ApiError::bad_request("Two-factor authentication is already enabled")
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::unauthorized("Invalid two-factor code")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::handlers::auth::{login, login_two_factor};
+    use crate::repository::libsql::{LibsqlRepository, TEST_PASSWORD};
+    use crate::repository::{
+        AuditRepository, ClassRepository, LoginAttemptRepository, OneTimeTokenRepository,
+        RefreshTokenRepository, DEFAULT_ORGANIZATION_ID,
+    };
+    use actix_web::{http::header, http::StatusCode, test, App};
+    use std::sync::Arc;
+
+    async fn create_user(repository: &LibsqlRepository, username: &str, role: Role) -> i64 {
+        let user = repository.create_test_user(username, role).await;
+        repository.mark_email_verified(user.id).await.unwrap();
+        user.id
+    }
+
+    macro_rules! app {
+        ($repository:expr, $policy:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(Data::from($repository.clone() as Arc<dyn UserRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn RefreshTokenRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn ClassRepository>))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn OneTimeTokenRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn TwoFactorRepository>
+                    ))
+                    .app_data(Data::from(
+                        $repository.clone() as Arc<dyn LoginAttemptRepository>
+                    ))
+                    .app_data(Data::from($repository.clone() as Arc<dyn AuditRepository>))
+                    .app_data(Data::new(TwoFactorPolicy::parse($policy).unwrap()))
+                    .app_data(Data::new(TokenIssuer::for_tests()))
+                    .app_data(Data::new(PasswordHasher::for_tests()))
+                    .service(login)
+                    .service(login_two_factor)
+                    .service(setup)
+                    .service(confirm)
+                    .service(regenerate_recovery_codes)
+                    .service(disable),
+            )
+            .await
+        };
+    }
+
+    fn login_request(username: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/login")
+            .set_json(serde_json::json!({ "username": username, "password": TEST_PASSWORD }))
+    }
+
+    fn bearer(token: &str) -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    #[actix_web::test]
+    async fn test_required_role_enrolls_then_signs_in_with_second_factor() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        create_user(&repository, "ada", Role::Instructor).await;
+        let app = app!(repository, "instructor,admin");
+
+        let challenge: serde_json::Value =
+            test::call_and_read_body_json(&app, login_request("ada").to_request()).await;
+        assert_eq!(challenge["challenge"], "totp_enrollment");
+        assert!(challenge.get("access_token").is_none());
+        let enrollment_token = challenge["mfa_token"].as_str().unwrap();
+
+        let enrollment: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::post()
+                .uri("/2fa/setup")
+                .insert_header(bearer(enrollment_token))
+                .to_request(),
+        )
+        .await;
+        let secret = enrollment["secret"].as_str().unwrap();
+        assert!(enrollment["provisioning_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/"));
+
+        let now = chrono::Utc::now().timestamp();
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/2fa/confirm")
+                .insert_header(bearer(enrollment_token))
+                .set_json(serde_json::json!({ "code": totp::code_at(secret, now) }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let recovery_codes = body["recovery_codes"].as_array().unwrap();
+        assert_eq!(recovery_codes.len(), totp::RECOVERY_CODE_COUNT);
+
+        let second_factor = |code: serde_json::Value| async {
+            let challenge: serde_json::Value =
+                test::call_and_read_body_json(&app, login_request("ada").to_request()).await;
+            assert_eq!(challenge["challenge"], "totp");
+
+            let mut body = code;
+            body["mfa_token"] = challenge["mfa_token"].clone();
+            test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .uri("/login/2fa")
+                    .set_json(body)
+                    .to_request(),
+            )
+            .await
+            .status()
+        };
+
+        // The code used to confirm can't be replayed, but the next one works.
+        assert_eq!(
+            second_factor(serde_json::json!({ "code": totp::code_at(secret, now) })).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            second_factor(serde_json::json!({ "code": totp::code_at(secret, now + 30) })).await,
+            StatusCode::OK
+        );
+
+        let recovery_code = recovery_codes[0].as_str().unwrap().to_uppercase();
+        assert_eq!(
+            second_factor(serde_json::json!({ "recovery_code": recovery_code })).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            second_factor(serde_json::json!({ "recovery_code": recovery_code })).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_mfa_token_allows_a_single_attempt() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user_id = create_user(&repository, "ada", Role::Student).await;
+        let secret = totp::generate_secret();
+        repository
+            .begin_totp_enrollment(user_id, &secret)
+            .await
+            .unwrap();
+        repository.confirm_totp(user_id, 0, &[]).await.unwrap();
+        let app = app!(repository, "");
+
+        let challenge: serde_json::Value =
+            test::call_and_read_body_json(&app, login_request("ada").to_request()).await;
+        let attempt = |code: String| {
+            test::TestRequest::post()
+                .uri("/login/2fa")
+                .set_json(serde_json::json!({ "mfa_token": challenge["mfa_token"], "code": code }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, attempt("000000".to_string())).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let code = totp::code_at(&secret, chrono::Utc::now().timestamp());
+        let resp = test::call_service(&app, attempt(code)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_policy_prevents_disabling() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let student = create_user(&repository, "ada", Role::Student).await;
+        let instructor = create_user(&repository, "grace", Role::Instructor).await;
+        let secret = totp::generate_secret();
+        for user_id in [student, instructor] {
+            repository
+                .begin_totp_enrollment(user_id, &secret)
+                .await
+                .unwrap();
+            repository.confirm_totp(user_id, 0, &[]).await.unwrap();
+        }
+        let app = app!(repository, "instructor");
+
+        let issuer = TokenIssuer::for_tests();
+        let disable_request = |user_id: i64, role: Role| {
+            test::TestRequest::delete()
+                .uri("/2fa")
+                .insert_header(bearer(
+                    &issuer
+                        .access_token(user_id, DEFAULT_ORGANIZATION_ID, role, Vec::new())
+                        .unwrap(),
+                ))
+                .set_json(serde_json::json!({
+                    "code": totp::code_at(&secret, chrono::Utc::now().timestamp())
+                }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, disable_request(instructor, Role::Instructor)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = test::call_service(&app, disable_request(student, Role::Student)).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(repository.totp_credential(student).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_wrong_codes_are_throttled_and_pages_need_a_csrf_token() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user_id = create_user(&repository, "ada", Role::Student).await;
+        let secret = totp::generate_secret();
+        repository
+            .begin_totp_enrollment(user_id, &secret)
+            .await
+            .unwrap();
+        repository.confirm_totp(user_id, 0, &[]).await.unwrap();
+        let app = app!(repository, "");
+        let token = TokenIssuer::for_tests()
+            .access_token(user_id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+        let request = |uri: &str, code: &str| {
+            test::TestRequest::post()
+                .uri(uri)
+                .insert_header(bearer(&token))
+                .set_json(serde_json::json!({ "code": code }))
+        };
+
+        // A page posting without the token is turned away before the code counts.
+        let resp = test::call_service(
+            &app,
+            request("/2fa/recovery-codes", "000000")
+                .insert_header((header::ORIGIN, "https://evil.example"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        for _ in 0..4 {
+            let resp =
+                test::call_service(&app, request("/2fa/recovery-codes", "000000").to_request())
+                    .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Even the right code has to wait now.
+        let code = totp::code_at(&secret, chrono::Utc::now().timestamp() + 30);
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::delete()
+                .uri("/2fa")
+                .insert_header(bearer(&token))
+                .set_json(serde_json::json!({ "code": code }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(repository.totp_credential(user_id).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_enrollment_from_a_page_needs_a_csrf_token() {
+        let repository = Arc::new(LibsqlRepository::in_memory().await);
+        let user_id = create_user(&repository, "ada", Role::Student).await;
+        let app = app!(repository, "");
+        let token = TokenIssuer::for_tests()
+            .access_token(user_id, DEFAULT_ORGANIZATION_ID, Role::Student, Vec::new())
+            .unwrap();
+
+        for (uri, body) in [
+            ("/2fa/setup", serde_json::json!({})),
+            ("/2fa/confirm", serde_json::json!({ "code": "000000" })),
+        ] {
+            let resp = test::call_service(
+                &app,
+                test::TestRequest::post()
+                    .uri(uri)
+                    .insert_header(bearer(&token))
+                    .insert_header((header::ORIGIN, "https://evil.example"))
+                    .set_json(body)
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(repository.totp_credential(user_id).await.unwrap().is_none());
+    }
+}
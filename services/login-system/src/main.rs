@@ -1,85 +1,202 @@
-use actix_web::{post, get, web::Data, web::Json, web::ServiceConfig, HttpResponse, HttpServer, App};
-use bcrypt::{hash, DEFAULT_COST};
+mod audit;
+mod auth;
+mod breach;
+mod compiler;
+mod config;
+mod csrf;
+mod csv;
+mod db;
+mod error;
+mod handlers;
+mod keys;
+mod mailer;
+mod oidc;
+mod pages;
+mod password;
+mod repository;
+mod throttle;
+mod tokens;
+mod totp;
+mod validation;
+
+use actix_web::{web, web::Data, web::ServiceConfig};
 use actix_cors::Cors;
-use leptos::*;
-use libsql_client::{Client, Config};
-use serde::Deserialize;
+use auth::Role;
+use compiler::CompilerClient;
+use config::{PublicBaseUrl, Settings};
+use error::ApiError;
+use mailer::Mailer;
+use oidc::OidcProviders;
+use repository::libsql::LibsqlRepository;
+use repository::{
+    ApiKeyRepository, AuditRepository, ClassRepository, ExternalIdentityRepository,
+    LoginAttemptRepository, OidcStateRepository, OneTimeTokenRepository, OrganizationRepository,
+    ProfileRepository, RefreshTokenRepository, SigningKeyRepository, TwoFactorRepository,
+    UserRepository,
+};
 use shuttle_actix_web::ShuttleActixWeb;
+use shuttle_secrets::SecretStore;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokens::TokenIssuer;
 
-#[derive(Deserialize)]
-struct UserRegistration {
-    id: u32,
-    username: String,
-    password: String,
-}
+#[shuttle_runtime::main]
+async fn main(
+    #[shuttle_secrets::Secrets] secrets: SecretStore,
+) -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
+    let settings = Settings::from_secrets(&secrets)?;
 
-#[post("/register")]
-async fn register_user(
-    client: Data<Arc<Mutex<Client>>>,
-    form: Json<UserRegistration>,
-) -> HttpResponse {
-    let registration_data: UserRegistration = form.into_inner();
-    let hash_pwd: String = match hash(registration_data.password, DEFAULT_COST) {
-        Ok(h) => h,
-        Err(_) => return HttpResponse::InternalServerError().body("Error hashing password"),
-    };
+    let client = db::connect(settings.database_url, settings.database_auth_token).await?;
+    db::migrate(&client).await?;
 
-    let sql_cmd: String = format!(
-        "INSERT INTO users (id, username, password) VALUES ({}, '{}', '{}')",
-        registration_data.id, registration_data.username, hash_pwd
-    );
+    let mailer: Data<dyn Mailer> = Data::from(settings.mailer.build()?);
+    let base_url = Data::new(PublicBaseUrl(settings.public_base_url));
+    let two_factor_policy = Data::new(settings.two_factor_policy);
+    let password_hasher = Data::new(settings.password_hasher);
+    let breached_passwords = Data::new(settings.breached_passwords);
+    let oidc_providers = Data::new(OidcProviders::new(settings.oidc_providers));
+    let compiler = Data::new(CompilerClient::new(settings.exercise_compiler_url));
 
-    let client: tokio::sync::MutexGuard<'_, Client> = client.lock().await;
-    match client.execute(sql_cmd.as_str()).await {
-        Ok(_) => HttpResponse::Ok().body("User registered"),
-        Err(_) => HttpResponse::InternalServerError().body("Error registering user"),
-    }
-}
+    let repository = Arc::new(LibsqlRepository::new(client));
 
-#[get("/register")]
-async fn register_page() -> HttpResponse {
-    let html_content: &str = r##"
-        <!DOCTYPE html>
-        <html lang="en">
-        <head>
-            <meta charset="UTF-8">
-            <title>Register User</title>
-            <script src="https://unpkg.com/htmx.org"></script>
-        </head>
-        <body>
-            <h1>User Registration</h1>
-            <form hx-post="http://localhost:8000/register" hx-encoding="application/json" hx-target="#response" hx-trigger="submit">
-                <input type="text" name="username" placeholder="Username" required>
-                <input type="password" name="password" placeholder="Password" required>
-                <button type="submit">Register</button>
-            </form>
-            <div id="response"></div>
-        </body>
-        </html>
-    "##;
+    let signing_keys = repository.clone() as Arc<dyn SigningKeyRepository>;
+    let now = chrono::Utc::now().timestamp();
+    let token_issuer = Data::new(TokenIssuer::new(
+        keys::rotate(&*signing_keys, &settings.key_rotation, now).await?,
+    )?);
+    keys::spawn_rotation(signing_keys, token_issuer.clone(), settings.key_rotation);
 
-    HttpResponse::Ok().content_type("text/html").body(html_content)
-}
-
-#[shuttle_runtime::main]
-async fn main() -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
-    let client = Client::from_config(Config {
-        url: url::Url::parse("libsql://login-system-guibarbosacrvg.turso.io").unwrap(),
-        auth_token: None,
-    })
-    .await
-    .unwrap();
+    if let Some(username) = &settings.bootstrap_admin {
+        bootstrap_admin(&*repository, username).await?;
+    }
 
-    let shared_client = Arc::new(Mutex::new(client));
+    let users: Data<dyn UserRepository> =
+        Data::from(repository.clone() as Arc<dyn UserRepository>);
+    let organizations: Data<dyn OrganizationRepository> =
+        Data::from(repository.clone() as Arc<dyn OrganizationRepository>);
+    let profiles: Data<dyn ProfileRepository> =
+        Data::from(repository.clone() as Arc<dyn ProfileRepository>);
+    let refresh_tokens: Data<dyn RefreshTokenRepository> =
+        Data::from(repository.clone() as Arc<dyn RefreshTokenRepository>);
+    let used_tokens: Data<dyn OneTimeTokenRepository> =
+        Data::from(repository.clone() as Arc<dyn OneTimeTokenRepository>);
+    let two_factor: Data<dyn TwoFactorRepository> =
+        Data::from(repository.clone() as Arc<dyn TwoFactorRepository>);
+    let login_attempts: Data<dyn LoginAttemptRepository> =
+        Data::from(repository.clone() as Arc<dyn LoginAttemptRepository>);
+    let audit: Data<dyn AuditRepository> =
+        Data::from(repository.clone() as Arc<dyn AuditRepository>);
+    let oidc_states: Data<dyn OidcStateRepository> =
+        Data::from(repository.clone() as Arc<dyn OidcStateRepository>);
+    let external_identities: Data<dyn ExternalIdentityRepository> =
+        Data::from(repository.clone() as Arc<dyn ExternalIdentityRepository>);
+    let classes: Data<dyn ClassRepository> =
+        Data::from(repository.clone() as Arc<dyn ClassRepository>);
+    let api_keys: Data<dyn ApiKeyRepository> =
+        Data::from(repository as Arc<dyn ApiKeyRepository>);
 
     let config = move |cfg: &mut ServiceConfig| {
         let cors = Cors::permissive();
-        cfg.app_data(Data::new(shared_client.clone()))
-           .service(register_user)
-           .service(register_page);
+        // Malformed bodies get the same error envelope as everything else.
+        let json_config = web::JsonConfig::default()
+            .error_handler(|err, _| ApiError::bad_request(err.to_string()).into());
+        cfg.app_data(json_config)
+           .app_data(users.clone())
+           .app_data(organizations.clone())
+           .app_data(profiles.clone())
+           .app_data(refresh_tokens.clone())
+           .app_data(used_tokens.clone())
+           .app_data(two_factor.clone())
+           .app_data(two_factor_policy.clone())
+           .app_data(password_hasher.clone())
+           .app_data(breached_passwords.clone())
+           .app_data(login_attempts.clone())
+           .app_data(audit.clone())
+           .app_data(oidc_states.clone())
+           .app_data(external_identities.clone())
+           .app_data(classes.clone())
+           .app_data(api_keys.clone())
+           .app_data(oidc_providers.clone())
+           .app_data(compiler.clone())
+           .app_data(token_issuer.clone())
+           .app_data(mailer.clone())
+           .app_data(base_url.clone())
+           .service(handlers::jwks::jwks)
+           .service(handlers::register::register_user)
+           .service(handlers::auth::login)
+           .service(handlers::auth::login_two_factor)
+           .service(handlers::auth::refresh)
+           .service(handlers::auth::logout)
+           .service(handlers::oidc::oidc_login)
+           .service(handlers::oidc::oidc_link)
+           .service(handlers::oidc::oidc_callback)
+           .service(handlers::account::verify_email)
+           .service(handlers::account::resend_verification)
+           .service(handlers::account::request_password_reset)
+           .service(handlers::account::confirm_password_reset)
+           .service(handlers::profile::get_me)
+           .service(handlers::profile::update_me)
+           .service(handlers::profile::change_password)
+           .service(handlers::sessions::list_sessions)
+           .service(handlers::sessions::revoke_session)
+           .service(handlers::sessions::revoke_all_sessions)
+           .service(handlers::privacy::export_data)
+           .service(handlers::privacy::request_erasure)
+           .service(handlers::privacy::confirm_erasure)
+           .service(handlers::two_factor::setup)
+           .service(handlers::two_factor::confirm)
+           .service(handlers::two_factor::regenerate_recovery_codes)
+           .service(handlers::two_factor::disable)
+           .service(handlers::classes::create_class)
+           .service(handlers::classes::list_classes)
+           .service(handlers::classes::join_class)
+           .service(handlers::classes::list_invites)
+           .service(handlers::classes::accept_invite)
+           .service(handlers::classes::regenerate_join_code)
+           .service(handlers::classes::list_members)
+           .service(handlers::classes::add_instructor)
+           .service(handlers::classes::remove_member)
+           .service(handlers::classes::invite)
+           .service(handlers::import::import_students)
+           .service(handlers::impersonation::impersonate)
+           .service(handlers::admin::grant_role)
+           .service(handlers::admin::revoke_role)
+           .service(handlers::admin::unlock)
+           .service(handlers::admin::search_users)
+           .service(handlers::admin::user_details)
+           .service(handlers::admin::disable_user)
+           .service(handlers::admin::enable_user)
+           .service(handlers::admin::force_password_reset)
+           .service(handlers::admin::list_user_sessions)
+           .service(handlers::admin::revoke_user_session)
+           .service(handlers::admin::revoke_sessions)
+           .service(handlers::organizations::list_organizations)
+           .service(handlers::organizations::create_organization)
+           .service(handlers::organizations::get_organization)
+           .service(handlers::organizations::update_organization)
+           .service(handlers::audit::export_audit_events)
+           .service(handlers::audit::list_audit_events)
+           .service(handlers::api_keys::create_api_key)
+           .service(handlers::api_keys::list_api_keys)
+           .service(handlers::api_keys::revoke_api_key)
+           .service(handlers::service::find_user)
+           .service(handlers::service::find_user_by_username)
+           .service(handlers::service::class_roster)
+           .service(handlers::pages::register_page)
+           .service(handlers::pages::login_page)
+           .service(handlers::pages::password_reset_page)
+           .service(handlers::pages::profile_page)
+           .service(handlers::pages::admin_page);
     };
 
-    ShuttleActixWeb::new(config) // Wrap the configuration in ActixWebService
+    Ok(config.into())
+}
+
+async fn bootstrap_admin(users: &dyn UserRepository, username: &str) -> anyhow::Result<()> {
+    match users.find_by_username(username).await? {
+        Some(user) => {
+            users.set_role(user.id, Role::Admin).await?;
+        }
+        None => log::warn!("BOOTSTRAP_ADMIN user {} does not exist yet", username),
+    }
+    Ok(())
 }
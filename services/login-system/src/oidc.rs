@@ -0,0 +1,399 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::config::OidcProviderSettings;
+
+/// Only asymmetric algorithms; an HMAC-signed ID token would be verified with
+/// the client secret, which the provider isn't supposed to sign with here.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+/// Unknown key ids trigger a fetch of the provider's keys, but never more often
+/// than this, so a stream of forged tokens can't be turned into a stream of
+/// requests to the provider.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The configured providers, by name.
+pub struct OidcProviders(HashMap<String, OidcProvider>);
+
+impl OidcProviders {
+    pub fn new(settings: Vec<OidcProviderSettings>) -> Self {
+        OidcProviders(
+            settings
+                .into_iter()
+                .map(|settings| (settings.name.clone(), OidcProvider::new(settings)))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OidcProvider> {
+        self.0.get(name)
+    }
+
+    /// In a stable order, for listing them on the login page.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.0.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// The parts of the discovery document the authorization code flow needs.
+#[derive(Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// What we use from a verified ID token.
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    /// Stable id of the user at the provider. Unlike the email, it never changes.
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+/// Some providers send `email_verified` as the string `"true"`.
+fn lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+/// A client for one provider. The discovery document and signing keys are
+/// fetched on first use and cached; keys are fetched again when a token is
+/// signed with a key we haven't seen, which is how providers rotate them, but
+/// at most once per `MIN_REFRESH_INTERVAL`.
+pub struct OidcProvider {
+    settings: OidcProviderSettings,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    keys: RwLock<CachedKeys>,
+}
+
+#[derive(Default)]
+struct CachedKeys {
+    keys: Option<JwkSet>,
+    fetched_at: Option<Instant>,
+}
+
+impl CachedKeys {
+    fn find(&self, kid: Option<&str>) -> Option<anyhow::Result<DecodingKey>> {
+        find_key(self.keys.as_ref()?, kid)
+    }
+
+    fn fetched_recently(&self) -> bool {
+        matches!(self.fetched_at, Some(at) if at.elapsed() < MIN_REFRESH_INTERVAL)
+    }
+}
+
+impl OidcProvider {
+    pub fn new(settings: OidcProviderSettings) -> Self {
+        OidcProvider {
+            settings,
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+            keys: RwLock::new(CachedKeys::default()),
+        }
+    }
+
+    async fn metadata(&self) -> anyhow::Result<ProviderMetadata> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let mut discovery_url = self.settings.issuer.clone();
+        let path = format!(
+            "{}/.well-known/openid-configuration",
+            discovery_url.path().trim_end_matches('/')
+        );
+        discovery_url.set_path(&path);
+        let metadata: ProviderMetadata = self
+            .http
+            .get(discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // The document has to be about the configured issuer (OpenID Connect
+        // Discovery 4.3), or its endpoints and keys could be anyone's. `Url`
+        // adds a trailing slash to a bare host, so that one doesn't count.
+        if metadata.issuer.trim_end_matches('/')
+            != self.settings.issuer.as_str().trim_end_matches('/')
+        {
+            anyhow::bail!(
+                "Discovery document is for issuer {}, not {}",
+                metadata.issuer,
+                self.settings.issuer
+            );
+        }
+
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Where to send the browser to sign in.
+    pub async fn authorization_url(
+        &self,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> anyhow::Result<String> {
+        let metadata = self.metadata().await?;
+        let mut url = Url::parse(&metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.settings.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", "openid email profile")
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &pkce_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    /// Exchanges the authorization code for an ID token and verifies it.
+    pub async fn authenticate(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> anyhow::Result<IdTokenClaims> {
+        let metadata = self.metadata().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &self.settings.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.settings.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.verify_id_token(&metadata, &response.id_token, nonce)
+            .await
+    }
+
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> anyhow::Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            anyhow::bail!("ID token uses unsupported algorithm {:?}", header.alg);
+        }
+        let key = self.decoding_key(metadata, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.settings.client_id]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)?.claims;
+
+        // Ties the token to the login that started in this browser, so a token
+        // captured elsewhere can't be replayed into a new login.
+        if claims.nonce.as_deref() != Some(nonce) {
+            anyhow::bail!("ID token nonce does not match");
+        }
+        Ok(claims)
+    }
+
+    async fn decoding_key(
+        &self,
+        metadata: &ProviderMetadata,
+        kid: Option<&str>,
+    ) -> anyhow::Result<DecodingKey> {
+        let unknown_key = || anyhow::anyhow!("No signing key {:?}", kid);
+        {
+            let cached = self.keys.read().await;
+            if let Some(key) = cached.find(kid) {
+                return key;
+            }
+            if cached.fetched_recently() {
+                return Err(unknown_key());
+            }
+        }
+
+        let mut cached = self.keys.write().await;
+        // Another request may have fetched the keys while we were waiting for the lock.
+        if cached.fetched_recently() {
+            return cached.find(kid).unwrap_or_else(|| Err(unknown_key()));
+        }
+        // Count failed fetches too, so an unreachable provider isn't asked again on every login.
+        cached.fetched_at = Some(Instant::now());
+
+        let keys: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let key = find_key(&keys, kid).unwrap_or_else(|| Err(unknown_key()));
+        cached.keys = Some(keys);
+        key
+    }
+}
+
+/// Without a `kid`, only a provider with a single key can be trusted to mean
+/// that one.
+fn find_key(keys: &JwkSet, kid: Option<&str>) -> Option<anyhow::Result<DecodingKey>> {
+    let jwk = match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }?;
+    Some(DecodingKey::from_jwk(jwk).map_err(anyhow::Error::from))
+}
+
+/// The S256 PKCE challenge sent with the authorization request. The verifier
+/// itself is only sent with the code, so an intercepted code is useless alone.
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[actix_web::test]
+    async fn test_unknown_key_ids_dont_refetch_keys_within_the_interval() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "keys": [{
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "kid": "known",
+                    "x": "vWSQpKX-CxrYcTAHG9al-9QXOZH8cvJQOa0JE9cBHL0",
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let provider = OidcProvider::new(OidcProviderSettings {
+            name: "school".to_string(),
+            issuer: Url::parse(&server.uri()).unwrap(),
+            client_id: "platform".to_string(),
+            client_secret: None,
+        });
+        let metadata = ProviderMetadata {
+            issuer: server.uri(),
+            authorization_endpoint: format!("{}/authorize", server.uri()),
+            token_endpoint: format!("{}/token", server.uri()),
+            jwks_uri: format!("{}/jwks", server.uri()),
+        };
+
+        assert!(provider
+            .decoding_key(&metadata, Some("known"))
+            .await
+            .is_ok());
+        for kid in ["forged-1", "forged-2", "forged-3"] {
+            assert!(provider.decoding_key(&metadata, Some(kid)).await.is_err());
+        }
+        assert!(provider
+            .decoding_key(&metadata, Some("known"))
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_discovery_must_be_for_the_configured_issuer() {
+        let server = MockServer::start().await;
+        let provider = |issuer: &str| {
+            OidcProvider::new(OidcProviderSettings {
+                name: "school".to_string(),
+                issuer: Url::parse(issuer).unwrap(),
+                client_id: "platform".to_string(),
+                client_secret: None,
+            })
+        };
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": "https://evil.example",
+                "authorization_endpoint": "https://evil.example/authorize",
+                "token_endpoint": "https://evil.example/token",
+                "jwks_uri": "https://evil.example/jwks",
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        assert!(provider(&server.uri())
+            .authorization_url("https://platform.example/callback", "s", "n", "v")
+            .await
+            .is_err());
+
+        Mock::given(method("GET"))
+            .and(path("/.well-known/openid-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "issuer": server.uri(),
+                "authorization_endpoint": format!("{}/authorize", server.uri()),
+                "token_endpoint": format!("{}/token", server.uri()),
+                "jwks_uri": format!("{}/jwks", server.uri()),
+            })))
+            .mount(&server)
+            .await;
+        assert!(provider(&server.uri())
+            .authorization_url("https://platform.example/callback", "s", "n", "v")
+            .await
+            .is_ok());
+    }
+}
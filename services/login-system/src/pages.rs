@@ -0,0 +1,802 @@
+//! Server-rendered pages for the browser, built with Leptos. The forms post
+//! JSON to the same endpoints API clients use, with relative URLs, and show
+//! validation errors next to the inputs they belong to.
+
+use leptos::*;
+
+/// Submits every `form[data-endpoint]` as JSON with the page's CSRF token and
+/// the access token, if there is one, and puts each field error from the
+/// error envelope next to its input. A form's `data-bearer` is sent instead of
+/// the access token. Pages react to success through the `form:success` event.
+const FORM_SCRIPT: &str = r#"
+(function () {
+    var csrfToken = document.body.dataset.csrfToken;
+    var status = document.getElementById("form-status");
+
+    function clearErrors(form) {
+        form.querySelectorAll(".field-error").forEach(function (el) { el.textContent = ""; });
+    }
+
+    function showError(form, error) {
+        var fields = error.fields || {};
+        Object.keys(fields).forEach(function (name) {
+            var el = form.querySelector('.field-error[data-field="' + name + '"]');
+            if (el) {
+                el.textContent = fields[name].join(" ");
+            }
+        });
+        status.textContent = error.message;
+    }
+
+    window.apiRequest = function (method, endpoint, body, bearer) {
+        var headers = { "Content-Type": "application/json", "X-CSRF-Token": csrfToken };
+        var accessToken = bearer || sessionStorage.getItem("access_token");
+        if (accessToken) {
+            headers["Authorization"] = "Bearer " + accessToken;
+        }
+        return fetch(endpoint, {
+            method: method,
+            headers: headers,
+            credentials: "same-origin",
+            body: body === undefined ? undefined : JSON.stringify(body),
+        }).then(function (response) {
+            return response.text().then(function (text) {
+                return { ok: response.ok, body: text ? JSON.parse(text) : null };
+            });
+        });
+    };
+
+    document.querySelectorAll("form[data-endpoint]").forEach(function (form) {
+        form.querySelectorAll("input, select, textarea").forEach(function (input) {
+            input.addEventListener("input", function () {
+                var el = form.querySelector('.field-error[data-field="' + input.name + '"]');
+                if (el) {
+                    el.textContent = "";
+                }
+            });
+        });
+
+        form.addEventListener("submit", function (event) {
+            event.preventDefault();
+            if (form.dataset.confirm && !window.confirm(form.dataset.confirm)) {
+                return;
+            }
+            clearErrors(form);
+            status.textContent = "";
+            var body = {};
+            new FormData(form).forEach(function (value, name) { body[name] = value; });
+
+            apiRequest(form.dataset.method || "POST", form.dataset.endpoint, body, form.dataset.bearer)
+                .then(function (result) {
+                    if (!result.ok) {
+                        showError(form, result.body.error);
+                        return;
+                    }
+                    status.textContent = form.dataset.success || "";
+                    form.dispatchEvent(new CustomEvent("form:success", { detail: result.body }));
+                })
+                .catch(function () {
+                    status.textContent = "Something went wrong, please try again.";
+                });
+        });
+    });
+})();
+"#;
+
+/// Tokens go to `sessionStorage`, so they are gone when the tab is closed. A
+/// role that requires 2FA enrolls here with the enrollment token, then logs in
+/// again with a code.
+const LOGIN_SCRIPT: &str = r#"
+(function () {
+    var login = document.getElementById("login");
+    var twoFactor = document.getElementById("two-factor");
+    var enroll = document.getElementById("enroll-two-factor");
+    var recoveryCodes = document.getElementById("recovery-codes");
+    var status = document.getElementById("form-status");
+
+    function signedIn(body) {
+        sessionStorage.setItem("access_token", body.access_token);
+        sessionStorage.setItem("refresh_token", body.refresh_token);
+        window.location.assign("profile");
+    }
+
+    login.addEventListener("form:success", function (event) {
+        var body = event.detail;
+        if (body.challenge === "totp") {
+            twoFactor.elements["mfa_token"].value = body.mfa_token;
+            login.hidden = true;
+            twoFactor.hidden = false;
+            status.textContent = "Enter the code from your authenticator app, or a recovery code.";
+        } else if (body.challenge === "totp_enrollment") {
+            startEnrollment(body.mfa_token);
+        } else {
+            signedIn(body);
+        }
+    });
+    twoFactor.addEventListener("form:success", function (event) {
+        signedIn(event.detail);
+    });
+
+    function startEnrollment(enrollmentToken) {
+        apiRequest("POST", "2fa/setup", undefined, enrollmentToken).then(function (result) {
+            if (!result.ok) {
+                status.textContent = result.body.error.message;
+                return;
+            }
+            document.getElementById("totp-secret").textContent = result.body.secret;
+            document.getElementById("totp-uri").href = result.body.provisioning_uri;
+            enroll.dataset.bearer = enrollmentToken;
+            login.hidden = true;
+            enroll.hidden = false;
+            status.textContent = "Your role requires two-factor authentication. "
+                + "Add the key to your authenticator app and enter the code it shows.";
+        });
+    }
+    enroll.addEventListener("form:success", function (event) {
+        recoveryCodes.textContent = "";
+        event.detail.recovery_codes.forEach(function (code) {
+            var item = document.createElement("li");
+            item.textContent = code;
+            recoveryCodes.appendChild(item);
+        });
+        delete enroll.dataset.bearer;
+        enroll.hidden = true;
+        login.elements["password"].value = "";
+        login.hidden = false;
+        status.textContent = "Two-factor authentication is on. Keep these recovery codes somewhere safe, "
+            + "each one signs you in once without your authenticator. Then log in again.";
+    });
+})();
+"#;
+
+const PROFILE_SCRIPT: &str = r#"
+(function () {
+    var status = document.getElementById("form-status");
+    var profile = document.getElementById("profile");
+
+    apiRequest("GET", "me").then(function (result) {
+        if (!result.ok) {
+            status.textContent = "Please log in first.";
+            return;
+        }
+        var me = result.body;
+        document.getElementById("account").textContent = me.username + " (" + me.email + ")";
+        ["display_name", "avatar_url", "preferred_language", "locale", "bio"].forEach(function (name) {
+            profile.elements[name].value = me[name] || "";
+        });
+    });
+
+    function signedOut() {
+        sessionStorage.removeItem("access_token");
+        sessionStorage.removeItem("refresh_token");
+    }
+
+    var sessions = document.getElementById("sessions");
+    function loadSessions() {
+        apiRequest("GET", "me/sessions").then(function (result) {
+            if (!result.ok) {
+                return;
+            }
+            sessions.textContent = "";
+            result.body.forEach(function (session) {
+                var row = document.createElement("li");
+                row.textContent = (session.device || "Unknown device")
+                    + (session.ip ? " from " + session.ip : "")
+                    + ", last used " + new Date(session.last_used_at * 1000).toLocaleString() + " ";
+                var button = document.createElement("button");
+                button.type = "button";
+                button.textContent = "Sign Out";
+                button.addEventListener("click", function () {
+                    apiRequest("DELETE", "me/sessions/" + session.id).then(function (result) {
+                        status.textContent = result.ok ? "Session signed out." : result.body.error.message;
+                        loadSessions();
+                    });
+                });
+                row.appendChild(button);
+                sessions.appendChild(row);
+            });
+        });
+    }
+    loadSessions();
+
+    document.getElementById("sign-out-everywhere").addEventListener("form:success", function () {
+        signedOut();
+        sessions.textContent = "";
+    });
+    function confirmErasure(token) {
+        apiRequest("POST", "me/erasure/confirm", { confirmation_token: token }).then(function (result) {
+            if (!result.ok) {
+                status.textContent = result.body.error.message;
+                return;
+            }
+            signedOut();
+            status.textContent = "Your account has been deleted.";
+        });
+    }
+
+    // The password only gets a confirmation token, the account goes with the second request.
+    // Without one, the token comes back here in the link that was emailed.
+    document.getElementById("delete-account").addEventListener("form:success", function (event) {
+        if (!event.detail) {
+            status.textContent = "We have emailed you a link to confirm deleting your account.";
+            return;
+        }
+        confirmErasure(event.detail.confirmation_token);
+    });
+    var emailedToken = new URLSearchParams(window.location.search).get("token");
+    if (emailedToken) {
+        history.replaceState(null, "", window.location.pathname);
+        if (window.confirm("Delete your account? This cannot be undone.")) {
+            confirmErasure(emailedToken);
+        }
+    }
+})();
+"#;
+
+/// Searches users and, for the one picked, shows their details with the
+/// actions admins can take. Everything is built with `textContent`, so names
+/// and emails can't inject markup.
+const ADMIN_SCRIPT: &str = r#"
+(function () {
+    var status = document.getElementById("form-status");
+    var search = document.getElementById("user-search");
+    var results = document.getElementById("users");
+    var more = document.getElementById("more-users");
+    var details = document.getElementById("user-details");
+    var nextAfter = null;
+    var selected = null;
+
+    function fail(result) {
+        status.textContent = result.body && result.body.error
+            ? result.body.error.message
+            : "Something went wrong, please try again.";
+    }
+
+    function searchUrl() {
+        var params = new URLSearchParams();
+        new FormData(search).forEach(function (value, name) {
+            if (value) {
+                params.set(name, value);
+            }
+        });
+        if (nextAfter !== null) {
+            params.set("after", nextAfter);
+        }
+        return "admin/users?" + params.toString();
+    }
+
+    function load(append) {
+        if (!append) {
+            nextAfter = null;
+            results.textContent = "";
+        }
+        apiRequest("GET", searchUrl()).then(function (result) {
+            if (!result.ok) {
+                fail(result);
+                return;
+            }
+            result.body.users.forEach(function (user) {
+                var row = document.createElement("li");
+                var button = document.createElement("button");
+                button.type = "button";
+                button.textContent = user.username;
+                button.addEventListener("click", function () { show(user.id); });
+                row.appendChild(button);
+                row.appendChild(document.createTextNode(
+                    " " + (user.email || "no email") + ", " + user.role
+                    + (user.disabled_at ? ", disabled" : "")
+                ));
+                results.appendChild(row);
+            });
+            nextAfter = result.body.next_after === undefined ? null : result.body.next_after;
+            more.hidden = nextAfter === null;
+        });
+    }
+
+    function field(label, value) {
+        var row = document.createElement("p");
+        row.textContent = label + ": " + value;
+        details.querySelector(".fields").appendChild(row);
+    }
+
+    function show(id) {
+        apiRequest("GET", "admin/users/" + id).then(function (result) {
+            if (!result.ok) {
+                fail(result);
+                return;
+            }
+            var user = result.body;
+            selected = user;
+            details.querySelector(".fields").textContent = "";
+            details.querySelector("h2").textContent = user.username;
+            field("Email", (user.email || "none") + (user.email_verified ? " (verified)" : ""));
+            field("Role", user.role);
+            field("Status", user.disabled_at ? "disabled" : "active");
+            field("Two-factor authentication", user.two_factor_enabled ? "on" : "off");
+            field("Failed logins", user.failed_logins);
+            field("Classes", user.classes.map(function (c) {
+                return c.name + " (" + c.role + ")";
+            }).join(", ") || "none");
+            field("Linked accounts", user.linked_identities.map(function (i) {
+                return i.provider;
+            }).join(", ") || "none");
+            var events = details.querySelector(".events");
+            events.textContent = "";
+            user.recent_events.forEach(function (event) {
+                var row = document.createElement("li");
+                row.textContent = new Date(event.created_at * 1000).toLocaleString()
+                    + " " + event.event + (event.ip ? " from " + event.ip : "");
+                events.appendChild(row);
+            });
+            showSessions(user.id);
+            details.querySelector("[name=role]").value = user.role;
+            details.querySelector("[data-action=disable]").hidden = !!user.disabled_at;
+            details.querySelector("[data-action=enable]").hidden = !user.disabled_at;
+            details.hidden = false;
+        });
+    }
+
+    function showSessions(id) {
+        var sessions = details.querySelector(".sessions");
+        apiRequest("GET", "admin/users/" + id + "/sessions").then(function (result) {
+            sessions.textContent = "";
+            if (!result.ok) {
+                fail(result);
+                return;
+            }
+            result.body.forEach(function (session) {
+                var row = document.createElement("li");
+                row.textContent = (session.device || session.user_agent || "Unknown device")
+                    + (session.ip ? " from " + session.ip : "")
+                    + ", last used " + new Date(session.last_used_at * 1000).toLocaleString() + " ";
+                var button = document.createElement("button");
+                button.type = "button";
+                button.textContent = "Revoke";
+                button.addEventListener("click", function () {
+                    apiRequest("DELETE", "admin/users/" + id + "/sessions/" + session.id)
+                        .then(function (result) {
+                            if (!result.ok) {
+                                fail(result);
+                                return;
+                            }
+                            status.textContent = "Session revoked.";
+                            showSessions(id);
+                        });
+                });
+                row.appendChild(button);
+                sessions.appendChild(row);
+            });
+        });
+    }
+
+    var actions = {
+        role: function () {
+            var role = details.querySelector("[name=role]").value;
+            return ["PUT", "role", { role: role }, "Role changed."];
+        },
+        disable: function () { return ["PUT", "disabled", undefined, "Account disabled."]; },
+        enable: function () { return ["DELETE", "disabled", undefined, "Account enabled."]; },
+        reset: function () { return ["POST", "password-reset", undefined, "Reset link sent."]; },
+        sessions: function () { return ["DELETE", "sessions", undefined, "Signed out everywhere."]; },
+    };
+
+    details.querySelectorAll("button[data-action]").forEach(function (button) {
+        button.addEventListener("click", function () {
+            if (button.dataset.confirm && !window.confirm(button.dataset.confirm)) {
+                return;
+            }
+            var action = actions[button.dataset.action]();
+            var id = selected.id;
+            status.textContent = "";
+            apiRequest(action[0], "admin/users/" + id + "/" + action[1], action[2])
+                .then(function (result) {
+                    if (!result.ok) {
+                        fail(result);
+                        return;
+                    }
+                    status.textContent = action[3];
+                    show(id);
+                    load(false);
+                });
+        });
+    });
+
+    search.addEventListener("submit", function (event) {
+        event.preventDefault();
+        load(false);
+    });
+    more.addEventListener("click", function () { load(true); });
+    load(false);
+})();
+"#;
+
+/// The languages the exercise compiler runs, as `(value, label)`.
+const PROGRAMMING_LANGUAGES: &[(&str, &str)] = &[
+    ("cpp", "C++"),
+    ("go", "Go"),
+    ("haskell", "Haskell"),
+    ("javascript", "JavaScript"),
+    ("python", "Python"),
+    ("rust", "Rust"),
+];
+
+/// Every role, for the admin page's pickers.
+const ROLES: &[&str] = &["student", "instructor", "admin"];
+
+/// Renders a whole document. Leptos escapes everything that isn't markup, so
+/// values from the request are safe to pass in.
+fn render<F, N>(view: F) -> String
+where
+    F: FnOnce() -> N + 'static,
+    N: IntoView,
+{
+    format!("<!DOCTYPE html>{}", leptos::ssr::render_to_string(view))
+}
+
+#[component]
+fn Layout(
+    #[prop(into)] title: String,
+    #[prop(into)] csrf_token: String,
+    #[prop(optional)] script: Option<&'static str>,
+    children: Children,
+) -> impl IntoView {
+    view! {
+        <html lang="en">
+            <head>
+                <meta charset="UTF-8"/>
+                <meta name="viewport" content="width=device-width, initial-scale=1"/>
+                <title>{title.clone()}</title>
+            </head>
+            <body data-csrf-token=csrf_token>
+                <h1>{title}</h1>
+                {children()}
+                <p id="form-status" role="status"></p>
+                <script inner_html=FORM_SCRIPT></script>
+                {script.map(|script| view! { <script inner_html=script></script> })}
+            </body>
+        </html>
+    }
+}
+
+/// An input with its label and the place its validation errors go.
+#[component]
+fn Field(
+    name: &'static str,
+    label: &'static str,
+    #[prop(default = "text")] input_type: &'static str,
+    #[prop(optional)] required: bool,
+    #[prop(optional)] autocomplete: Option<&'static str>,
+    #[prop(optional)] minlength: Option<u32>,
+    #[prop(optional)] maxlength: Option<u32>,
+) -> impl IntoView {
+    view! {
+        <p>
+            <label>
+                {label}
+                <input
+                    type=input_type
+                    name=name
+                    required=required
+                    autocomplete=autocomplete
+                    minlength=minlength.map(|n| n.to_string())
+                    maxlength=maxlength.map(|n| n.to_string())
+                />
+            </label>
+            <small class="field-error" data-field=name></small>
+        </p>
+    }
+}
+
+pub fn register_page(csrf_token: String) -> String {
+    render(move || {
+        view! {
+            <Layout title="Create an Account" csrf_token=csrf_token>
+                <form
+                    data-endpoint="register"
+                    data-success="Account created. Check your email to verify your address, then log in."
+                >
+                    <Field name="username" label="Username" required=true autocomplete="username" minlength=3 maxlength=32/>
+                    <Field name="email" label="Email" input_type="email" required=true autocomplete="email"/>
+                    <Field name="password" label="Password" input_type="password" required=true autocomplete="new-password" minlength=8/>
+                    <button type="submit">"Register"</button>
+                </form>
+                <p>"Already have an account? " <a href="login">"Log in"</a></p>
+            </Layout>
+        }
+    })
+}
+
+/// `providers` are the configured OpenID Connect providers, offered as
+/// alternatives to the password.
+pub fn login_page(csrf_token: String, providers: Vec<String>) -> String {
+    render(move || {
+        view! {
+            <Layout title="Log In" csrf_token=csrf_token script=LOGIN_SCRIPT>
+                <form id="login" data-endpoint="login">
+                    <Field name="username" label="Username" required=true autocomplete="username"/>
+                    <Field name="password" label="Password" input_type="password" required=true autocomplete="current-password"/>
+                    <button type="submit">"Log In"</button>
+                </form>
+                <form id="two-factor" data-endpoint="login/2fa" hidden=true>
+                    <input type="hidden" name="mfa_token"/>
+                    <Field name="code" label="Authentication code" autocomplete="one-time-code"/>
+                    <button type="submit">"Verify"</button>
+                </form>
+                <form id="enroll-two-factor" data-endpoint="2fa/confirm" hidden=true>
+                    <p>"Key: " <code id="totp-secret"></code></p>
+                    <p><a id="totp-uri">"Open in your authenticator app"</a></p>
+                    <Field name="code" label="Authentication code" required=true autocomplete="one-time-code"/>
+                    <button type="submit">"Turn On Two-Factor Authentication"</button>
+                </form>
+                <ul id="recovery-codes"></ul>
+                <ul>
+                    {providers
+                        .into_iter()
+                        .map(|name| {
+                            view! {
+                                <li>
+                                    <a href={format!("oidc/{}/login", name)}>
+                                        {format!("Sign in with {}", name)}
+                                    </a>
+                                </li>
+                            }
+                        })
+                        .collect_view()}
+                </ul>
+                <p>
+                    <a href="register">"Create an account"</a>
+                    " · "
+                    <a href="password-reset">"Forgot your password?"</a>
+                </p>
+            </Layout>
+        }
+    })
+}
+
+/// Without a token, asks for the email to send a reset link to. With the
+/// token from that link, asks for the new password.
+pub fn password_reset_page(csrf_token: String, reset_token: Option<String>) -> String {
+    render(move || {
+        let form = match reset_token {
+            Some(reset_token) => view! {
+                <form
+                    data-endpoint="password-reset/confirm"
+                    data-success="Your password has been changed. You can now log in."
+                >
+                    <input type="hidden" name="token" value=reset_token/>
+                    <Field name="password" label="New password" input_type="password" required=true autocomplete="new-password" minlength=8/>
+                    <button type="submit">"Reset Password"</button>
+                </form>
+            },
+            None => view! {
+                <form
+                    data-endpoint="password-reset"
+                    data-success="If an account uses that address, we've sent it a link to reset the password."
+                >
+                    <Field name="email" label="Email" input_type="email" required=true autocomplete="email"/>
+                    <button type="submit">"Send Reset Link"</button>
+                </form>
+            },
+        };
+
+        view! {
+            <Layout title="Reset Password" csrf_token=csrf_token>
+                {form}
+            </Layout>
+        }
+    })
+}
+
+pub fn profile_page(csrf_token: String) -> String {
+    render(move || {
+        view! {
+            <Layout title="Your Profile" csrf_token=csrf_token script=PROFILE_SCRIPT>
+                <p id="account"></p>
+                <form id="profile" data-endpoint="me" data-method="PATCH" data-success="Profile saved.">
+                    <Field name="display_name" label="Display name" maxlength=64/>
+                    <Field name="avatar_url" label="Avatar URL" input_type="url"/>
+                    <p>
+                        <label>
+                            "Preferred language"
+                            <select name="preferred_language">
+                                <option value="">"None"</option>
+                                {PROGRAMMING_LANGUAGES
+                                    .iter()
+                                    .map(|&(value, label)| view! { <option value=value>{label}</option> })
+                                    .collect_view()}
+                            </select>
+                        </label>
+                        <small class="field-error" data-field="preferred_language"></small>
+                    </p>
+                    <Field name="locale" label="Locale (e.g. pt-BR)"/>
+                    <p>
+                        <label>
+                            "Bio"
+                            <textarea name="bio" maxlength=1000></textarea>
+                        </label>
+                        <small class="field-error" data-field="bio"></small>
+                    </p>
+                    <button type="submit">"Save Profile"</button>
+                </form>
+
+                <h2>"Change Password"</h2>
+                <form
+                    data-endpoint="me/password"
+                    data-success="Password changed. Your other sessions have been signed out."
+                >
+                    <Field name="current_password" label="Current password" input_type="password" required=true autocomplete="current-password"/>
+                    <Field name="password" label="New password" input_type="password" required=true autocomplete="new-password" minlength=8/>
+                    <button type="submit">"Change Password"</button>
+                </form>
+
+                <h2>"Sessions"</h2>
+                <p>"Where you are signed in. Sign out of devices you no longer use, like lab machines."</p>
+                <ul id="sessions"></ul>
+                <form
+                    id="sign-out-everywhere"
+                    data-endpoint="me/sessions"
+                    data-method="DELETE"
+                    data-confirm="Sign out of every session, this one included?"
+                    data-success="You have been signed out everywhere."
+                >
+                    <button type="submit">"Sign Out Everywhere"</button>
+                </form>
+
+                <h2>"Delete Account"</h2>
+                <form
+                    id="delete-account"
+                    data-endpoint="me/erasure"
+                    data-confirm="Delete your account? This cannot be undone."
+                >
+                    <p>"If you sign in with your school account, leave the password empty and confirm through the link we email you."</p>
+                    <Field name="password" label="Password" input_type="password" autocomplete="current-password"/>
+                    <button type="submit">"Delete Account"</button>
+                </form>
+            </Layout>
+        }
+    })
+}
+
+/// User management for admins. The endpoints it calls check the role, so the
+/// page itself is served to anyone.
+pub fn admin_page(csrf_token: String) -> String {
+    render(move || {
+        view! {
+            <Layout title="Users" csrf_token=csrf_token script=ADMIN_SCRIPT>
+                <form id="user-search">
+                    <label>"Username or email " <input type="search" name="q"/></label>
+                    <label>
+                        " Role "
+                        <select name="role">
+                            <option value="">"Any"</option>
+                            {ROLES
+                                .iter()
+                                .map(|&role| view! { <option value=role>{role}</option> })
+                                .collect_view()}
+                        </select>
+                    </label>
+                    <label>
+                        " Status "
+                        <select name="disabled">
+                            <option value="">"Any"</option>
+                            <option value="false">"Active"</option>
+                            <option value="true">"Disabled"</option>
+                        </select>
+                    </label>
+                    <button type="submit">"Search"</button>
+                </form>
+                <ul id="users"></ul>
+                <button type="button" id="more-users" hidden=true>"More"</button>
+
+                <section id="user-details" hidden=true>
+                    <h2></h2>
+                    <div class="fields"></div>
+                    <p>
+                        <select name="role">
+                            {ROLES
+                                .iter()
+                                .map(|&role| view! { <option value=role>{role}</option> })
+                                .collect_view()}
+                        </select>
+                        <button type="button" data-action="role">"Change Role"</button>
+                    </p>
+                    <p>
+                        <button
+                            type="button"
+                            data-action="disable"
+                            data-confirm="Disable this account and sign the user out?"
+                        >
+                            "Disable Account"
+                        </button>
+                        <button type="button" data-action="enable">"Enable Account"</button>
+                        <button
+                            type="button"
+                            data-action="reset"
+                            data-confirm="Replace the user's password and email them a reset link?"
+                        >
+                            "Force Password Reset"
+                        </button>
+                        <button
+                            type="button"
+                            data-action="sessions"
+                            data-confirm="Sign the user out of every session?"
+                        >
+                            "Revoke All Sessions"
+                        </button>
+                    </p>
+                    <h3>"Sessions"</h3>
+                    <ul class="sessions"></ul>
+                    <h3>"Recent Activity"</h3>
+                    <ul class="events"></ul>
+                </section>
+            </Layout>
+        }
+    })
+}
+
+/// A page with a single message, e.g. the result of following an email link.
+pub fn message_page(title: &'static str, message: String) -> String {
+    render(move || {
+        view! {
+            <html lang="en">
+                <head>
+                    <meta charset="UTF-8"/>
+                    <title>{title}</title>
+                </head>
+                <body>
+                    <h1>{title}</h1>
+                    <p>{message}</p>
+                    <p><a href="login">"Log in"</a></p>
+                </body>
+            </html>
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_use_relative_urls_and_the_csrf_token() {
+        let page = register_page("csrf-123".to_string());
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("data-csrf-token=\"csrf-123\""));
+        assert!(page.contains("data-endpoint=\"register\""));
+        assert!(!page.contains("localhost"));
+        assert!(!page.contains("unpkg"));
+    }
+
+    #[test]
+    fn test_request_values_are_escaped() {
+        let page = password_reset_page(
+            "csrf".to_string(),
+            Some("\"><script>alert(1)</script>".to_string()),
+        );
+        assert!(!page.contains("<script>alert(1)"));
+
+        let page = message_page("Done", "<b>ada</b>".to_string());
+        assert!(page.contains("&lt;b&gt;ada"));
+        assert!(!page.contains("<b>"));
+    }
+
+    #[test]
+    fn test_admin_page_calls_the_admin_endpoints() {
+        let page = admin_page("csrf".to_string());
+        assert!(page.contains("\"admin/users?\""));
+        assert!(page.contains("data-action=\"sessions\""));
+    }
+
+    #[test]
+    fn test_login_page_offers_providers() {
+        let page = login_page("csrf".to_string(), vec!["school".to_string()]);
+        assert!(page.contains("href=\"oidc/school/login\""));
+    }
+
+    #[test]
+    fn test_login_page_enrolls_required_two_factor() {
+        let page = login_page("csrf".to_string(), Vec::new());
+        assert!(page.contains("data-endpoint=\"2fa/confirm\""));
+        assert!(page.contains("\"2fa/setup\""));
+    }
+}
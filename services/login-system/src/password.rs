@@ -0,0 +1,175 @@
+use actix_web::web;
+use argon2::password_hash::{
+    self, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::error::ApiError;
+use crate::tokens::random_token;
+
+/// bcrypt only looks at this many bytes. Longer passwords were hashed by their
+/// beginning, so that is what they are checked against.
+const BCRYPT_MAX_BYTES: usize = 72;
+
+/// Hashes passwords with Argon2id and verifies them against Argon2 and older
+/// bcrypt hashes. Hashing is slow on purpose, so it runs on the blocking
+/// thread pool rather than on the workers that serve requests.
+pub struct PasswordHasher {
+    params: Params,
+    /// Verified against when there is no account, see `verify_dummy`.
+    dummy_hash: String,
+}
+
+impl PasswordHasher {
+    /// `memory_kib` of memory, `iterations` passes over it and `parallelism`
+    /// lanes. Fails if Argon2 doesn't accept the combination.
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> anyhow::Result<Self> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+        let salt =
+            SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(anyhow::Error::msg)?;
+        let dummy_hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password(random_token().as_bytes(), &salt)
+            .map_err(anyhow::Error::msg)?
+            .to_string();
+        Ok(PasswordHasher { params, dummy_hash })
+    }
+
+    /// The cheapest parameters Argon2 allows, so tests don't wait on hashing.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        PasswordHasher::new(Params::MIN_M_COST, Params::MIN_T_COST, Params::MIN_P_COST).unwrap()
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// A PHC string, e.g. `$argon2id$v=19$m=19456,t=2,p=1$...`.
+    pub async fn hash(&self, password: String) -> Result<String, ApiError> {
+        let argon2 = self.argon2();
+        web::block(move || {
+            let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())?;
+            Ok(argon2
+                .hash_password(password.as_bytes(), &salt)?
+                .to_string())
+        })
+        .await
+        .map_err(|e| ApiError::internal("Error hashing password", e))?
+        .map_err(|e: password_hash::Error| ApiError::internal("Error hashing password", e))
+    }
+
+    pub async fn verify(&self, password: String, hash: String) -> Result<bool, ApiError> {
+        web::block(move || {
+            if is_bcrypt(&hash) {
+                let prefix = &password.as_bytes()[..password.len().min(BCRYPT_MAX_BYTES)];
+                return bcrypt::verify(prefix, &hash).map_err(anyhow::Error::from);
+            }
+            let parsed = PasswordHash::new(&hash).map_err(anyhow::Error::msg)?;
+            // The parameters come from the hash, not from the hasher.
+            match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+                Ok(()) => Ok(true),
+                Err(password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(anyhow::Error::msg(e)),
+            }
+        })
+        .await
+        .map_err(|e| ApiError::internal("Error verifying password", e))?
+        .map_err(|e| ApiError::internal("Error verifying password", e))
+    }
+
+    /// Takes as long as checking a password of an account with a current
+    /// hash, for logins to accounts that don't exist. Otherwise the quicker
+    /// answer would tell which usernames are taken.
+    pub async fn verify_dummy(&self, password: String) -> Result<(), ApiError> {
+        self.verify(password, self.dummy_hash.clone()).await?;
+        Ok(())
+    }
+
+    /// Whether the hash should be replaced next time the password is known:
+    /// it's bcrypt, or Argon2 with other parameters than the configured ones.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        parsed.algorithm != argon2::ARGON2ID_IDENT
+            || parsed.version != Some(Version::V0x13.into())
+            || Params::try_from(&parsed).map_or(true, |params| {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            })
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_verifies_argon2_and_bcrypt_hashes() {
+        let hasher = PasswordHasher::for_tests();
+        let argon2 = hasher.hash("analytical engine".to_string()).await.unwrap();
+        assert!(argon2.starts_with("$argon2id$"));
+        let bcrypt = bcrypt::hash("analytical engine", 4).unwrap();
+
+        for hash in [argon2, bcrypt] {
+            assert!(hasher
+                .verify("analytical engine".to_string(), hash.clone())
+                .await
+                .unwrap());
+            assert!(!hasher
+                .verify("difference engine".to_string(), hash)
+                .await
+                .unwrap());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_old_hashes_need_rehashing() {
+        let hasher = PasswordHasher::for_tests();
+        let current = hasher.hash("analytical engine".to_string()).await.unwrap();
+        let stronger = PasswordHasher::new(Params::MIN_M_COST * 2, 1, 1).unwrap();
+
+        assert!(!hasher.needs_rehash(&current));
+        assert!(stronger.needs_rehash(&current));
+        assert!(hasher.needs_rehash(&bcrypt::hash("analytical engine", 4).unwrap()));
+    }
+
+    #[actix_web::test]
+    async fn test_dummy_hash_has_the_configured_parameters() {
+        let hasher = PasswordHasher::for_tests();
+        assert!(!hasher.needs_rehash(&hasher.dummy_hash));
+        hasher
+            .verify_dummy("analytical engine".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_long_passwords_are_checked_by_their_bcrypt_prefix() {
+        let hasher = PasswordHasher::for_tests();
+        let password = "x".repeat(BCRYPT_MAX_BYTES) + "y";
+        let hash = bcrypt::hash(&password, 4).unwrap();
+
+        assert!(hasher.verify(password.clone(), hash.clone()).await.unwrap());
+        assert!(!hasher
+            .verify("y".repeat(BCRYPT_MAX_BYTES + 1), hash)
+            .await
+            .unwrap());
+
+        // Argon2 sees the whole password, so the rehash tells them apart.
+        let rehashed = hasher.hash(password.clone()).await.unwrap();
+        assert!(hasher.verify(password, rehashed.clone()).await.unwrap());
+        assert!(!hasher
+            .verify("x".repeat(BCRYPT_MAX_BYTES), rehashed)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,2485 @@
+use async_trait::async_trait;
+use libsql_client::{args, Client, Row, Statement, Value};
+use tokio::sync::Mutex;
+
+use crate::auth::{ApiScope, ClassRole, Role};
+use crate::db::run_in_transaction;
+
+use super::{
+    ApiKey, ApiKeyRepository, AuditEvent, AuditQuery, AuditRepository, Class, ClassMember,
+    ClassRepository, ExternalIdentityRepository, ImportedUser, LinkedIdentity,
+    LoginAttemptRepository, LoginFailures, NewApiKey, NewUser, OidcLoginState, OidcStateRepository,
+    OneTimeTokenRepository, Organization, OrganizationRepository, OrganizationSettings, Profile,
+    ProfileRepository, RecoveryCode, RefreshToken, RefreshTokenRepository, RepositoryError,
+    Session, SigningKeyRepository, StoredAuditEvent, StoredSigningKey, TotpCredential,
+    TwoFactorRepository, User, UserQuery, UserRepository,
+};
+
+/// Column list shared by every query that loads a whole user, in the order
+/// `user_from_row` expects.
+macro_rules! select_user {
+    ($rest:literal) => {
+        concat!(
+            "SELECT id, username, email, password, created_at, role, email_verified_at, disabled_at, organization_id FROM users ",
+            $rest
+        )
+    };
+}
+
+/// Columns of `classes` in the order `class_from_row` expects, for a query
+/// that calls the table `c`.
+macro_rules! class_columns {
+    () => {
+        "c.id, c.name, c.join_code, c.created_at, c.organization_id"
+    };
+}
+
+/// Columns of `organizations`, with the space-separated domains last, in the
+/// order `organization_from_row` expects, for a query that calls the table `o`.
+macro_rules! organization_columns {
+    () => {
+        "o.id, o.slug, o.name, o.sso_provider, o.password_min_length, o.created_at,
+         (SELECT group_concat(d.domain, ' ') FROM organization_domains d WHERE d.organization_id = o.id)"
+    };
+}
+
+/// Columns of `api_keys` in the order `api_key_from_row` expects.
+macro_rules! api_key_columns {
+    () => {
+        "id, name, prefix, scopes, created_by, created_at, expires_at, last_used_at, revoked_at"
+    };
+}
+
+/// Repository backed by a libsql client, either remote (Turso) or a local SQLite database.
+pub struct LibsqlRepository {
+    client: Mutex<Client>,
+}
+
+impl LibsqlRepository {
+    pub fn new(client: Client) -> Self {
+        LibsqlRepository {
+            client: Mutex::new(client),
+        }
+    }
+
+    /// A repository over a fresh in-memory database, for tests.
+    #[cfg(test)]
+    pub async fn in_memory() -> Self {
+        let client = Client::in_memory().unwrap();
+        crate::db::migrate(&client).await.unwrap();
+        LibsqlRepository::new(client)
+    }
+
+    /// A user in the default organization with the password `TEST_PASSWORD`
+    /// and the email `<username>@example.com`, not yet verified. The hash is
+    /// bcrypt, as accounts from before Argon2 have.
+    #[cfg(test)]
+    pub async fn create_test_user(&self, username: &str, role: crate::auth::Role) -> User {
+        let user = self
+            .create(crate::repository::NewUser {
+                username: username.to_string(),
+                email: Some(format!("{}@example.com", username)),
+                password_hash: bcrypt::hash(TEST_PASSWORD, 4).unwrap(),
+                organization_id: crate::repository::DEFAULT_ORGANIZATION_ID,
+            })
+            .await
+            .unwrap();
+        self.set_role(user.id, role).await.unwrap();
+        User { role, ..user }
+    }
+}
+
+/// The password of users from `LibsqlRepository::create_test_user`.
+#[cfg(test)]
+pub const TEST_PASSWORD: &str = "analytical engine";
+
+fn user_from_row(row: &Row) -> anyhow::Result<User> {
+    Ok(User {
+        id: row.try_get(0)?,
+        username: row.try_get::<&str>(1)?.to_string(),
+        email: optional_text(row, 2)?,
+        password_hash: row.try_get::<&str>(3)?.to_string(),
+        created_at: optional_integer(row, 4)?,
+        role: row
+            .try_get::<&str>(5)?
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        email_verified_at: optional_integer(row, 6)?,
+        disabled_at: optional_integer(row, 7)?,
+        organization_id: row.try_get(8)?,
+    })
+}
+
+fn class_from_row(row: &Row) -> anyhow::Result<Class> {
+    Ok(Class {
+        id: row.try_get(0)?,
+        name: row.try_get::<&str>(1)?.to_string(),
+        join_code: row.try_get::<&str>(2)?.to_string(),
+        created_at: row.try_get(3)?,
+        organization_id: row.try_get(4)?,
+    })
+}
+
+fn organization_from_row(row: &Row) -> anyhow::Result<Organization> {
+    let mut email_domains: Vec<String> = optional_text(row, 6)?
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    email_domains.sort();
+    Ok(Organization {
+        id: row.try_get(0)?,
+        slug: row.try_get::<&str>(1)?.to_string(),
+        name: row.try_get::<&str>(2)?.to_string(),
+        email_domains,
+        sso_provider: optional_text(row, 3)?,
+        password_min_length: row.try_get(4)?,
+        created_at: row.try_get(5)?,
+    })
+}
+
+fn class_role(row: &Row, index: usize) -> anyhow::Result<ClassRole> {
+    row.try_get::<&str>(index)?
+        .parse()
+        .map_err(anyhow::Error::msg)
+}
+
+fn api_key_from_row(row: &Row) -> anyhow::Result<ApiKey> {
+    Ok(ApiKey {
+        id: row.try_get(0)?,
+        name: row.try_get::<&str>(1)?.to_string(),
+        prefix: row.try_get::<&str>(2)?.to_string(),
+        scopes: row
+            .try_get::<&str>(3)?
+            .split_whitespace()
+            .map(|scope| scope.parse::<ApiScope>().map_err(anyhow::Error::msg))
+            .collect::<anyhow::Result<_>>()?,
+        created_by: optional_integer(row, 4)?,
+        created_at: row.try_get(5)?,
+        expires_at: optional_integer(row, 6)?,
+        last_used_at: optional_integer(row, 7)?,
+        revoked_at: optional_integer(row, 8)?,
+    })
+}
+
+fn optional_text(row: &Row, index: usize) -> anyhow::Result<Option<String>> {
+    match row.values.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => Ok(Some(row.try_get::<&str>(index)?.to_string())),
+    }
+}
+
+fn nullable_text(value: Option<&str>) -> Value {
+    match value {
+        Some(text) => text.into(),
+        None => Value::Null,
+    }
+}
+
+fn optional_integer(row: &Row, index: usize) -> anyhow::Result<Option<i64>> {
+    match row.values.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => Ok(Some(row.try_get(index)?)),
+    }
+}
+
+#[async_trait]
+impl UserRepository for LibsqlRepository {
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError> {
+        let created_at = chrono::Utc::now().timestamp();
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "INSERT INTO users (username, email, password, created_at, organization_id)
+                 VALUES (?, ?, ?, ?, ?) RETURNING id",
+                args!(
+                    user.username.as_str(),
+                    nullable_text(user.email.as_deref()),
+                    user.password_hash.as_str(),
+                    created_at,
+                    user.organization_id
+                ),
+            ))
+            .await?;
+        let id = match rs.rows.first() {
+            Some(row) => row.try_get(0)?,
+            None => return Err(anyhow::anyhow!("INSERT returned no id").into()),
+        };
+
+        Ok(User {
+            id,
+            username: user.username,
+            email: user.email,
+            password_hash: user.password_hash,
+            created_at: Some(created_at),
+            role: Role::Student,
+            email_verified_at: None,
+            disabled_at: None,
+            organization_id: user.organization_id,
+        })
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                select_user!("WHERE username = ? COLLATE NOCASE"),
+                args!(username),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(user_from_row).transpose()?)
+    }
+
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                select_user!("WHERE id = ?"),
+                args!(id),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(user_from_row).transpose()?)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                select_user!("WHERE email = ? COLLATE NOCASE"),
+                args!(email),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(user_from_row).transpose()?)
+    }
+
+    async fn set_role(&self, id: i64, role: Role) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE users SET role = ? WHERE id = ?",
+                args!(role.as_str(), id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn set_disabled(
+        &self,
+        id: i64,
+        disabled_at: Option<i64>,
+    ) -> Result<bool, RepositoryError> {
+        let disabled_at = match disabled_at {
+            Some(at) => Value::from(at),
+            None => Value::Null,
+        };
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE users SET disabled_at = ? WHERE id = ?",
+                args!(disabled_at, id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn search_users(&self, query: &UserQuery) -> Result<Vec<User>, RepositoryError> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(text) = &query.text {
+            // instr rather than LIKE, so % and _ in the query aren't wildcards.
+            conditions.push(
+                "(instr(lower(username), lower(?)) > 0 OR instr(lower(email), lower(?)) > 0)",
+            );
+            values.push(Value::from(text.as_str()));
+            values.push(Value::from(text.as_str()));
+        }
+        if let Some(role) = query.role {
+            conditions.push("role = ?");
+            values.push(Value::from(role.as_str()));
+        }
+        match query.disabled {
+            Some(true) => conditions.push("disabled_at IS NOT NULL"),
+            Some(false) => conditions.push("disabled_at IS NULL"),
+            None => {}
+        }
+        if let Some(organization_id) = query.organization_id {
+            conditions.push("organization_id = ?");
+            values.push(Value::from(organization_id));
+        }
+        if let Some(after_id) = query.after_id {
+            conditions.push("id > ?");
+            values.push(Value::from(after_id));
+        }
+        values.push(Value::from(query.limit));
+
+        let mut sql = String::from(select_user!(""));
+        if !conditions.is_empty() {
+            sql.push_str("WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id LIMIT ?");
+
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(sql, values.as_slice()))
+            .await?;
+        let users = rs
+            .rows
+            .iter()
+            .map(user_from_row)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(users)
+    }
+
+    async fn mark_email_verified(&self, id: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE users SET email_verified_at = COALESCE(email_verified_at, ?) WHERE id = ?",
+                args!(chrono::Utc::now().timestamp(), id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn set_password_hash(
+        &self,
+        id: i64,
+        password_hash: &str,
+    ) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE users SET password = ? WHERE id = ?",
+                args!(password_hash, id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn delete(&self, id: i64) -> Result<bool, RepositoryError> {
+        let statements = [
+            "DELETE FROM user_profiles WHERE user_id = ?",
+            "DELETE FROM refresh_tokens WHERE user_id = ?",
+            "DELETE FROM sessions WHERE user_id = ?",
+            "DELETE FROM totp_credentials WHERE user_id = ?",
+            "DELETE FROM recovery_codes WHERE user_id = ?",
+            "DELETE FROM external_identities WHERE user_id = ?",
+            "DELETE FROM oidc_login_states WHERE link_user_id = ?",
+            "DELETE FROM class_members WHERE user_id = ?",
+            "DELETE FROM class_invites WHERE email = (SELECT email FROM users WHERE id = ?)",
+            "UPDATE api_keys SET created_by = NULL WHERE created_by = ?",
+            "UPDATE audit_events SET ip = NULL, user_agent = NULL WHERE user_id = ?",
+            "DELETE FROM users WHERE id = ?",
+        ]
+        .map(|sql| Statement::with_args(sql, args!(id)));
+
+        let client = self.client.lock().await;
+        let results = run_in_transaction(&client, statements).await?;
+        Ok(results.last().is_some_and(|rs| rs.rows_affected == 1))
+    }
+
+    async fn import(&self, users: Vec<ImportedUser>) -> Result<Vec<User>, RepositoryError> {
+        let created_at = chrono::Utc::now().timestamp();
+        // All users or none: the statements run in one transaction. Each user's
+        // INSERT returns its id; the rows that go with it find the user by
+        // username.
+        let mut statements = Vec::new();
+        let mut id_positions = Vec::new();
+        for imported in &users {
+            let user = &imported.user;
+            id_positions.push(statements.len());
+            statements.push(Statement::with_args(
+                "INSERT INTO users (username, email, password, created_at, organization_id)
+                 VALUES (?, ?, ?, ?, ?) RETURNING id",
+                args!(
+                    user.username.as_str(),
+                    nullable_text(user.email.as_deref()),
+                    user.password_hash.as_str(),
+                    created_at,
+                    user.organization_id
+                ),
+            ));
+            if let Some(display_name) = &imported.display_name {
+                statements.push(Statement::with_args(
+                    "INSERT INTO user_profiles (user_id, display_name, updated_at)
+                     SELECT id, ?, ? FROM users WHERE username = ?",
+                    args!(display_name.as_str(), created_at, user.username.as_str()),
+                ));
+            }
+            if let Some(class_id) = imported.class_id {
+                statements.push(Statement::with_args(
+                    "INSERT INTO class_members (class_id, user_id, role, joined_at)
+                     SELECT ?, id, ?, ? FROM users WHERE username = ?",
+                    args!(
+                        class_id,
+                        ClassRole::Student.as_str(),
+                        created_at,
+                        user.username.as_str()
+                    ),
+                ));
+            }
+        }
+
+        let client = self.client.lock().await;
+        let results = run_in_transaction(&client, statements).await?;
+
+        let mut created = Vec::with_capacity(users.len());
+        for (imported, position) in users.into_iter().zip(id_positions) {
+            let id = match results.get(position).and_then(|rs| rs.rows.first()) {
+                Some(row) => row.try_get(0)?,
+                None => return Err(anyhow::anyhow!("INSERT returned no id").into()),
+            };
+            let user = imported.user;
+            created.push(User {
+                id,
+                username: user.username,
+                email: user.email,
+                password_hash: user.password_hash,
+                created_at: Some(created_at),
+                role: Role::Student,
+                email_verified_at: None,
+                disabled_at: None,
+                organization_id: user.organization_id,
+            });
+        }
+        Ok(created)
+    }
+}
+
+#[async_trait]
+impl ProfileRepository for LibsqlRepository {
+    async fn profile(&self, user_id: i64) -> Result<Profile, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT display_name, avatar_url, preferred_language, locale, bio
+                 FROM user_profiles WHERE user_id = ?",
+                args!(user_id),
+            ))
+            .await?;
+
+        let Some(row) = rs.rows.first() else {
+            return Ok(Profile::default());
+        };
+        Ok(Profile {
+            display_name: optional_text(row, 0)?,
+            avatar_url: optional_text(row, 1)?,
+            preferred_language: optional_text(row, 2)?,
+            locale: optional_text(row, 3)?,
+            bio: optional_text(row, 4)?,
+        })
+    }
+
+    async fn save_profile(&self, user_id: i64, profile: &Profile) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO user_profiles
+                     (user_id, display_name, avatar_url, preferred_language, locale, bio, updated_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)
+                 ON CONFLICT (user_id) DO UPDATE SET
+                     display_name = excluded.display_name,
+                     avatar_url = excluded.avatar_url,
+                     preferred_language = excluded.preferred_language,
+                     locale = excluded.locale,
+                     bio = excluded.bio,
+                     updated_at = excluded.updated_at",
+                args!(
+                    user_id,
+                    nullable_text(profile.display_name.as_deref()),
+                    nullable_text(profile.avatar_url.as_deref()),
+                    nullable_text(profile.preferred_language.as_deref()),
+                    nullable_text(profile.locale.as_deref()),
+                    nullable_text(profile.bio.as_deref()),
+                    chrono::Utc::now().timestamp()
+                ),
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepository for LibsqlRepository {
+    async fn store(
+        &self,
+        token_hash: &str,
+        user_id: i64,
+        family_id: &str,
+        expires_at: i64,
+    ) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO refresh_tokens (token_hash, user_id, family_id, expires_at) VALUES (?, ?, ?, ?)",
+                args!(token_hash, user_id, family_id, expires_at),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn find(&self, token_hash: &str) -> Result<Option<RefreshToken>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT user_id, family_id, expires_at FROM refresh_tokens WHERE token_hash = ?",
+                args!(token_hash),
+            ))
+            .await?;
+
+        let Some(row) = rs.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some(RefreshToken {
+            user_id: row.try_get(0)?,
+            family_id: row.try_get::<&str>(1)?.to_string(),
+            expires_at: row.try_get(2)?,
+        }))
+    }
+
+    async fn revoke(&self, token_hash: &str) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE refresh_tokens SET revoked_at = ? WHERE token_hash = ? AND revoked_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), token_hash),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn revoke_family(&self, family_id: &str) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), family_id),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_all_for_user(&self, user_id: i64) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "UPDATE refresh_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), user_id),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn record_session(
+        &self,
+        family_id: &str,
+        user_id: i64,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<(), RepositoryError> {
+        let now = chrono::Utc::now().timestamp();
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO sessions (family_id, user_id, created_at, last_used_at, ip, user_agent)
+                 VALUES (?, ?, ?, ?, ?, ?)
+                 ON CONFLICT (family_id) DO UPDATE SET
+                     last_used_at = excluded.last_used_at,
+                     ip = excluded.ip,
+                     user_agent = excluded.user_agent",
+                args!(
+                    family_id,
+                    user_id,
+                    now,
+                    now,
+                    nullable_text(ip),
+                    nullable_text(user_agent)
+                ),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn active_sessions(&self, user_id: i64) -> Result<Vec<Session>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT id, created_at, last_used_at, ip, user_agent FROM sessions s
+                 WHERE user_id = ? AND EXISTS (
+                     SELECT 1 FROM refresh_tokens t
+                     WHERE t.family_id = s.family_id AND t.revoked_at IS NULL AND t.expires_at > ?
+                 )
+                 ORDER BY last_used_at DESC, id DESC",
+                args!(user_id, chrono::Utc::now().timestamp()),
+            ))
+            .await?;
+
+        let sessions = rs
+            .rows
+            .iter()
+            .map(|row| {
+                Ok(Session {
+                    id: row.try_get(0)?,
+                    created_at: row.try_get(1)?,
+                    last_used_at: row.try_get(2)?,
+                    ip: optional_text(row, 3)?,
+                    user_agent: optional_text(row, 4)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(sessions)
+    }
+
+    async fn revoke_session(&self, user_id: i64, session_id: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT family_id FROM sessions WHERE id = ? AND user_id = ?",
+                args!(session_id, user_id),
+            ))
+            .await?;
+        let Some(row) = rs.rows.first() else {
+            return Ok(false);
+        };
+        let family_id = row.try_get::<&str>(0)?.to_string();
+        client
+            .execute(Statement::with_args(
+                "UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND revoked_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), family_id),
+            ))
+            .await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl OneTimeTokenRepository for LibsqlRepository {
+    async fn consume(&self, jti: &str, expires_at: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        // Expired entries can't be replayed anyway, so they are pruned as we go.
+        client
+            .execute(Statement::with_args(
+                "DELETE FROM used_tokens WHERE expires_at < ?",
+                args!(chrono::Utc::now().timestamp()),
+            ))
+            .await?;
+        match client
+            .execute(Statement::with_args(
+                "INSERT INTO used_tokens (jti, expires_at) VALUES (?, ?)",
+                args!(jti, expires_at),
+            ))
+            .await
+            .map_err(RepositoryError::from)
+        {
+            Ok(_) => Ok(true),
+            Err(RepositoryError::Conflict(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn insert_recovery_codes(user_id: i64, recovery_code_hashes: &[String]) -> Vec<Statement> {
+    recovery_code_hashes
+        .iter()
+        .map(|code_hash| {
+            Statement::with_args(
+                "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)",
+                args!(user_id, code_hash.as_str()),
+            )
+        })
+        .collect()
+}
+
+#[async_trait]
+impl TwoFactorRepository for LibsqlRepository {
+    async fn totp_credential(
+        &self,
+        user_id: i64,
+    ) -> Result<Option<TotpCredential>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT secret, confirmed_at FROM totp_credentials WHERE user_id = ?",
+                args!(user_id),
+            ))
+            .await?;
+
+        let Some(row) = rs.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some(TotpCredential {
+            secret: row.try_get::<&str>(0)?.to_string(),
+            confirmed: optional_integer(row, 1)?.is_some(),
+        }))
+    }
+
+    async fn begin_totp_enrollment(
+        &self,
+        user_id: i64,
+        secret: &str,
+    ) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "INSERT INTO totp_credentials (user_id, secret) VALUES (?, ?)
+                 ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, last_used_step = NULL
+                 WHERE confirmed_at IS NULL",
+                args!(user_id, secret),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn confirm_totp(
+        &self,
+        user_id: i64,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool, RepositoryError> {
+        let mut statements = vec![
+            Statement::with_args(
+                "UPDATE totp_credentials SET confirmed_at = ?, last_used_step = ?
+                 WHERE user_id = ? AND confirmed_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), step, user_id),
+            ),
+            Statement::with_args(
+                "DELETE FROM recovery_codes WHERE user_id = ?",
+                args!(user_id),
+            ),
+        ];
+        statements.extend(insert_recovery_codes(user_id, recovery_code_hashes));
+
+        let client = self.client.lock().await;
+        // Checked first, so a lost race doesn't replace the winner's recovery codes.
+        let pending = client
+            .execute(Statement::with_args(
+                "SELECT 1 FROM totp_credentials WHERE user_id = ? AND confirmed_at IS NULL",
+                args!(user_id),
+            ))
+            .await?;
+        if pending.rows.is_empty() {
+            return Ok(false);
+        }
+        run_in_transaction(&client, statements).await?;
+        Ok(true)
+    }
+
+    async fn record_totp_step(&self, user_id: i64, step: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE totp_credentials SET last_used_step = ?
+                 WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+                args!(step, user_id, step),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn disable_totp(&self, user_id: i64) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        run_in_transaction(
+            &client,
+            [
+                Statement::with_args(
+                    "DELETE FROM totp_credentials WHERE user_id = ?",
+                    args!(user_id),
+                ),
+                Statement::with_args(
+                    "DELETE FROM recovery_codes WHERE user_id = ?",
+                    args!(user_id),
+                ),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn unused_recovery_codes(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<RecoveryCode>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT id, code_hash FROM recovery_codes WHERE user_id = ? AND used_at IS NULL",
+                args!(user_id),
+            ))
+            .await?;
+
+        rs.rows
+            .iter()
+            .map(|row| {
+                Ok(RecoveryCode {
+                    id: row.try_get(0)?,
+                    code_hash: row.try_get::<&str>(1)?.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    async fn use_recovery_code(&self, id: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE recovery_codes SET used_at = ? WHERE id = ? AND used_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), RepositoryError> {
+        let mut statements = vec![Statement::with_args(
+            "DELETE FROM recovery_codes WHERE user_id = ?",
+            args!(user_id),
+        )];
+        statements.extend(insert_recovery_codes(user_id, recovery_code_hashes));
+
+        let client = self.client.lock().await;
+        run_in_transaction(&client, statements).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LoginAttemptRepository for LibsqlRepository {
+    async fn failures(&self, key: &str) -> Result<Option<LoginFailures>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT failures, last_failure_at FROM login_failures WHERE key = ?",
+                args!(key),
+            ))
+            .await?;
+
+        let Some(row) = rs.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some(LoginFailures {
+            failures: row.try_get(0)?,
+            last_failure_at: row.try_get(1)?,
+        }))
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        reset_before: i64,
+    ) -> Result<LoginFailures, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "INSERT INTO login_failures (key, failures, last_failure_at) VALUES (?, 1, ?)
+                 ON CONFLICT (key) DO UPDATE SET
+                     failures = CASE WHEN last_failure_at < ? THEN 1 ELSE failures + 1 END,
+                     last_failure_at = excluded.last_failure_at
+                 RETURNING failures, last_failure_at",
+                args!(key, now, reset_before),
+            ))
+            .await?;
+
+        match rs.rows.first() {
+            Some(row) => Ok(LoginFailures {
+                failures: row.try_get(0)?,
+                last_failure_at: row.try_get(1)?,
+            }),
+            None => Err(anyhow::anyhow!("UPSERT returned no row").into()),
+        }
+    }
+
+    async fn clear_failures(&self, key: &str) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "DELETE FROM login_failures WHERE key = ?",
+                args!(key),
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditRepository for LibsqlRepository {
+    async fn record(&self, event: AuditEvent) -> Result<(), RepositoryError> {
+        let user_id = match event.user_id {
+            Some(id) => Value::from(id),
+            None => Value::Null,
+        };
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO audit_events (created_at, event, user_id, ip, user_agent, outcome, detail)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+                args!(
+                    chrono::Utc::now().timestamp(),
+                    event.event,
+                    user_id,
+                    nullable_text(event.ip.as_deref()),
+                    nullable_text(event.user_agent.as_deref()),
+                    event.outcome.as_str(),
+                    nullable_text(event.detail.as_deref())
+                ),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn audit_events(
+        &self,
+        query: &AuditQuery,
+    ) -> Result<Vec<StoredAuditEvent>, RepositoryError> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        let mut filter = |condition: &'static str, value: Option<Value>| {
+            if let Some(value) = value {
+                conditions.push(condition);
+                values.push(value);
+            }
+        };
+        filter("event = ?", query.event.clone().map(Value::from));
+        filter("user_id = ?", query.user_id.map(Value::from));
+        filter("ip = ?", query.ip.clone().map(Value::from));
+        filter(
+            "outcome = ?",
+            query.outcome.map(|o| Value::from(o.as_str())),
+        );
+        filter("created_at >= ?", query.since.map(Value::from));
+        filter("created_at <= ?", query.until.map(Value::from));
+        filter(
+            "user_id IN (SELECT id FROM users WHERE organization_id = ?)",
+            query.organization_id.map(Value::from),
+        );
+        filter("id < ?", query.before_id.map(Value::from));
+        values.push(Value::from(query.limit));
+
+        let mut sql = String::from(
+            "SELECT id, created_at, event, user_id, ip, user_agent, outcome, detail FROM audit_events",
+        );
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id DESC LIMIT ?");
+
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(sql, values.as_slice()))
+            .await?;
+
+        let events = rs
+            .rows
+            .iter()
+            .map(|row| {
+                Ok(StoredAuditEvent {
+                    id: row.try_get(0)?,
+                    created_at: row.try_get(1)?,
+                    event: row.try_get::<&str>(2)?.to_string(),
+                    user_id: optional_integer(row, 3)?,
+                    ip: optional_text(row, 4)?,
+                    user_agent: optional_text(row, 5)?,
+                    outcome: row
+                        .try_get::<&str>(6)?
+                        .parse()
+                        .map_err(anyhow::Error::msg)?,
+                    detail: optional_text(row, 7)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl OidcStateRepository for LibsqlRepository {
+    async fn save(&self, state: OidcLoginState) -> Result<(), RepositoryError> {
+        let link_user_id = match state.link_user_id {
+            Some(id) => Value::from(id),
+            None => Value::Null,
+        };
+        let client = self.client.lock().await;
+        // Abandoned sign-ins are pruned as new ones start.
+        client
+            .execute(Statement::with_args(
+                "DELETE FROM oidc_login_states WHERE expires_at < ?",
+                args!(chrono::Utc::now().timestamp()),
+            ))
+            .await?;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO oidc_login_states (state, provider, code_verifier, nonce, link_user_id, expires_at)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                args!(
+                    state.state.as_str(),
+                    state.provider.as_str(),
+                    state.code_verifier.as_str(),
+                    state.nonce.as_str(),
+                    link_user_id,
+                    state.expires_at
+                ),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn take(&self, state: &str) -> Result<Option<OidcLoginState>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "DELETE FROM oidc_login_states WHERE state = ?
+                 RETURNING state, provider, code_verifier, nonce, link_user_id, expires_at",
+                args!(state),
+            ))
+            .await?;
+
+        let Some(row) = rs.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some(OidcLoginState {
+            state: row.try_get::<&str>(0)?.to_string(),
+            provider: row.try_get::<&str>(1)?.to_string(),
+            code_verifier: row.try_get::<&str>(2)?.to_string(),
+            nonce: row.try_get::<&str>(3)?.to_string(),
+            link_user_id: optional_integer(row, 4)?,
+            expires_at: row.try_get(5)?,
+        }))
+    }
+}
+
+#[async_trait]
+impl ExternalIdentityRepository for LibsqlRepository {
+    async fn find_user_id(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<i64>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT user_id FROM external_identities WHERE provider = ? AND subject = ?",
+                args!(provider, subject),
+            ))
+            .await?;
+
+        match rs.rows.first() {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn link(
+        &self,
+        provider: &str,
+        subject: &str,
+        user_id: i64,
+    ) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO external_identities (provider, subject, user_id, created_at) VALUES (?, ?, ?, ?)",
+                args!(provider, subject, user_id, chrono::Utc::now().timestamp()),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn create_linked_user(
+        &self,
+        user: NewUser,
+        email_verified: bool,
+        provider: &str,
+        subject: &str,
+    ) -> Result<User, RepositoryError> {
+        let created_at = chrono::Utc::now().timestamp();
+        // The rows after the INSERT find the new user by username, as in `import`.
+        let mut statements = vec![
+            Statement::with_args(
+                "INSERT INTO users (username, email, password, created_at, organization_id)
+                 VALUES (?, ?, ?, ?, ?) RETURNING id",
+                args!(
+                    user.username.as_str(),
+                    nullable_text(user.email.as_deref()),
+                    user.password_hash.as_str(),
+                    created_at,
+                    user.organization_id
+                ),
+            ),
+            Statement::with_args(
+                "INSERT INTO external_identities (provider, subject, user_id, created_at)
+                 SELECT ?, ?, id, ? FROM users WHERE username = ?",
+                args!(provider, subject, created_at, user.username.as_str()),
+            ),
+        ];
+        if email_verified {
+            statements.push(Statement::with_args(
+                "UPDATE users SET email_verified_at = ? WHERE username = ?",
+                args!(created_at, user.username.as_str()),
+            ));
+        }
+
+        let client = self.client.lock().await;
+        let results = run_in_transaction(&client, statements).await?;
+        let id = match results.first().and_then(|rs| rs.rows.first()) {
+            Some(row) => row.try_get(0)?,
+            None => return Err(anyhow::anyhow!("INSERT returned no id").into()),
+        };
+
+        Ok(User {
+            id,
+            username: user.username,
+            email: user.email,
+            password_hash: user.password_hash,
+            created_at: Some(created_at),
+            role: Role::Student,
+            email_verified_at: email_verified.then_some(created_at),
+            disabled_at: None,
+            organization_id: user.organization_id,
+        })
+    }
+
+    async fn identities_of(&self, user_id: i64) -> Result<Vec<LinkedIdentity>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT provider, subject, created_at FROM external_identities WHERE user_id = ? ORDER BY created_at",
+                args!(user_id),
+            ))
+            .await?;
+
+        rs.rows
+            .iter()
+            .map(|row| {
+                Ok(LinkedIdentity {
+                    provider: row.try_get::<&str>(0)?.to_string(),
+                    subject: row.try_get::<&str>(1)?.to_string(),
+                    linked_at: row.try_get(2)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ClassRepository for LibsqlRepository {
+    async fn create_class(
+        &self,
+        name: &str,
+        join_code: &str,
+        instructor_id: i64,
+    ) -> Result<Class, RepositoryError> {
+        let created_at = chrono::Utc::now().timestamp();
+        let client = self.client.lock().await;
+        let results = run_in_transaction(
+            &client,
+            [
+                Statement::with_args(
+                    "INSERT INTO classes (name, join_code, created_at, organization_id)
+                     SELECT ?, ?, ?, organization_id FROM users WHERE id = ?
+                     RETURNING id, organization_id",
+                    args!(name, join_code, created_at, instructor_id),
+                ),
+                Statement::with_args(
+                    "INSERT INTO class_members (class_id, user_id, role, joined_at)
+                     SELECT id, ?, ?, ? FROM classes WHERE join_code = ?",
+                    args!(
+                        instructor_id,
+                        ClassRole::Instructor.as_str(),
+                        created_at,
+                        join_code
+                    ),
+                ),
+            ],
+        )
+        .await?;
+        let (id, organization_id) = match results.first().and_then(|rs| rs.rows.first()) {
+            Some(row) => (row.try_get(0)?, row.try_get(1)?),
+            None => return Err(anyhow::anyhow!("INSERT returned no id").into()),
+        };
+
+        Ok(Class {
+            id,
+            name: name.to_string(),
+            join_code: join_code.to_string(),
+            created_at,
+            organization_id,
+        })
+    }
+
+    async fn find_class(&self, id: i64) -> Result<Option<Class>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    class_columns!(),
+                    " FROM classes c WHERE c.id = ?"
+                ),
+                args!(id),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(class_from_row).transpose()?)
+    }
+
+    async fn find_class_by_join_code(
+        &self,
+        join_code: &str,
+    ) -> Result<Option<Class>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    class_columns!(),
+                    " FROM classes c WHERE c.join_code = ?"
+                ),
+                args!(join_code),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(class_from_row).transpose()?)
+    }
+
+    async fn set_join_code(&self, class_id: i64, join_code: &str) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE classes SET join_code = ? WHERE id = ?",
+                args!(join_code, class_id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn classes_of(&self, user_id: i64) -> Result<Vec<(Class, ClassRole)>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    class_columns!(),
+                    ", m.role
+                     FROM class_members m JOIN classes c ON c.id = m.class_id
+                     WHERE m.user_id = ? ORDER BY c.id"
+                ),
+                args!(user_id),
+            ))
+            .await?;
+
+        let classes = rs
+            .rows
+            .iter()
+            .map(|row| Ok((class_from_row(row)?, class_role(row, 5)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(classes)
+    }
+
+    async fn member_role(
+        &self,
+        class_id: i64,
+        user_id: i64,
+    ) -> Result<Option<ClassRole>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT role FROM class_members WHERE class_id = ? AND user_id = ?",
+                args!(class_id, user_id),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(|row| class_role(row, 0)).transpose()?)
+    }
+
+    async fn add_member(
+        &self,
+        class_id: i64,
+        user_id: i64,
+        role: ClassRole,
+    ) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO class_members (class_id, user_id, role, joined_at) VALUES (?, ?, ?, ?)
+                 ON CONFLICT (class_id, user_id) DO UPDATE SET role = excluded.role",
+                args!(
+                    class_id,
+                    user_id,
+                    role.as_str(),
+                    chrono::Utc::now().timestamp()
+                ),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_member(&self, class_id: i64, user_id: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "DELETE FROM class_members WHERE class_id = ? AND user_id = ?",
+                args!(class_id, user_id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn roster(&self, class_id: i64) -> Result<Vec<ClassMember>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "SELECT m.user_id, u.username, m.role, m.joined_at
+                 FROM class_members m JOIN users u ON u.id = m.user_id
+                 WHERE m.class_id = ?
+                 ORDER BY m.role = 'student', u.username COLLATE NOCASE",
+                args!(class_id),
+            ))
+            .await?;
+
+        let members = rs
+            .rows
+            .iter()
+            .map(|row| {
+                Ok(ClassMember {
+                    user_id: row.try_get(0)?,
+                    username: row.try_get::<&str>(1)?.to_string(),
+                    role: class_role(row, 2)?,
+                    joined_at: row.try_get(3)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(members)
+    }
+
+    async fn invite(&self, class_id: i64, email: &str) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "INSERT INTO class_invites (class_id, email, created_at) VALUES (?, ?, ?)
+                 ON CONFLICT (class_id, email) DO UPDATE SET created_at = excluded.created_at",
+                args!(class_id, email, chrono::Utc::now().timestamp()),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    async fn invites_for(&self, email: &str) -> Result<Vec<Class>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    class_columns!(),
+                    " FROM class_invites i JOIN classes c ON c.id = i.class_id
+                     WHERE i.email = ? ORDER BY c.id"
+                ),
+                args!(email),
+            ))
+            .await?;
+
+        let classes = rs
+            .rows
+            .iter()
+            .map(class_from_row)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(classes)
+    }
+
+    async fn take_invite(&self, class_id: i64, email: &str) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "DELETE FROM class_invites WHERE class_id = ? AND email = ?",
+                args!(class_id, email),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+}
+
+#[async_trait]
+impl OrganizationRepository for LibsqlRepository {
+    async fn create_organization(
+        &self,
+        settings: &OrganizationSettings,
+    ) -> Result<Organization, RepositoryError> {
+        let created_at = chrono::Utc::now().timestamp();
+        // The domains find the new organization by its slug. They run in one
+        // transaction, so a taken domain undoes the whole thing.
+        let mut statements = vec![Statement::with_args(
+            "INSERT INTO organizations (slug, name, sso_provider, password_min_length, created_at)
+             VALUES (?, ?, ?, ?, ?) RETURNING id",
+            args!(
+                settings.slug.as_str(),
+                settings.name.as_str(),
+                nullable_text(settings.sso_provider.as_deref()),
+                settings.password_min_length,
+                created_at
+            ),
+        )];
+        statements.extend(settings.email_domains.iter().map(|domain| {
+            Statement::with_args(
+                "INSERT INTO organization_domains (domain, organization_id)
+                 SELECT ?, id FROM organizations WHERE slug = ?",
+                args!(domain.as_str(), settings.slug.as_str()),
+            )
+        }));
+
+        let client = self.client.lock().await;
+        let results = run_in_transaction(&client, statements).await?;
+        let id = match results.first().and_then(|rs| rs.rows.first()) {
+            Some(row) => row.try_get(0)?,
+            None => return Err(anyhow::anyhow!("INSERT returned no id").into()),
+        };
+
+        let mut email_domains = settings.email_domains.clone();
+        email_domains.sort();
+        Ok(Organization {
+            id,
+            slug: settings.slug.clone(),
+            name: settings.name.clone(),
+            email_domains,
+            sso_provider: settings.sso_provider.clone(),
+            password_min_length: settings.password_min_length,
+            created_at,
+        })
+    }
+
+    async fn find_organization(&self, id: i64) -> Result<Option<Organization>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    organization_columns!(),
+                    " FROM organizations o WHERE o.id = ?"
+                ),
+                args!(id),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(organization_from_row).transpose()?)
+    }
+
+    async fn find_organization_by_domain(
+        &self,
+        domain: &str,
+    ) -> Result<Option<Organization>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    organization_columns!(),
+                    " FROM organizations o
+                     JOIN organization_domains od ON od.organization_id = o.id
+                     WHERE od.domain = ?"
+                ),
+                args!(domain),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(organization_from_row).transpose()?)
+    }
+
+    async fn organizations(&self) -> Result<Vec<Organization>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(concat!(
+                "SELECT ",
+                organization_columns!(),
+                " FROM organizations o ORDER BY o.id"
+            ))
+            .await?;
+
+        let organizations = rs
+            .rows
+            .iter()
+            .map(organization_from_row)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(organizations)
+    }
+
+    async fn update_organization(
+        &self,
+        id: i64,
+        settings: &OrganizationSettings,
+    ) -> Result<bool, RepositoryError> {
+        let mut statements = vec![
+            Statement::with_args(
+                "UPDATE organizations SET slug = ?, name = ?, sso_provider = ?, password_min_length = ?
+                 WHERE id = ?",
+                args!(
+                    settings.slug.as_str(),
+                    settings.name.as_str(),
+                    nullable_text(settings.sso_provider.as_deref()),
+                    settings.password_min_length,
+                    id
+                ),
+            ),
+            Statement::with_args(
+                "DELETE FROM organization_domains WHERE organization_id = ?",
+                args!(id),
+            ),
+        ];
+        statements.extend(settings.email_domains.iter().map(|domain| {
+            Statement::with_args(
+                "INSERT INTO organization_domains (domain, organization_id)
+                 SELECT ?, id FROM organizations WHERE id = ?",
+                args!(domain.as_str(), id),
+            )
+        }));
+
+        let client = self.client.lock().await;
+        let results = run_in_transaction(&client, statements).await?;
+        Ok(results.first().is_some_and(|rs| rs.rows_affected == 1))
+    }
+}
+
+#[async_trait]
+impl ApiKeyRepository for LibsqlRepository {
+    async fn create_api_key(&self, key: NewApiKey) -> Result<ApiKey, RepositoryError> {
+        let scopes = key
+            .scopes
+            .iter()
+            .map(ApiScope::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let expires_at = match key.expires_at {
+            Some(at) => Value::from(at),
+            None => Value::Null,
+        };
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "INSERT INTO api_keys (name, prefix, key_hash, scopes, created_by, created_at, expires_at)
+                     VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING ",
+                    api_key_columns!()
+                ),
+                args!(
+                    key.name.as_str(),
+                    key.prefix.as_str(),
+                    key.key_hash.as_str(),
+                    scopes,
+                    key.created_by,
+                    chrono::Utc::now().timestamp(),
+                    expires_at
+                ),
+            ))
+            .await?;
+
+        let row = rs
+            .rows
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("INSERT returned no row"))?;
+        Ok(api_key_from_row(row)?)
+    }
+
+    async fn api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(concat!(
+                "SELECT ",
+                api_key_columns!(),
+                " FROM api_keys ORDER BY created_at DESC, id DESC"
+            ))
+            .await?;
+
+        let keys = rs
+            .rows
+            .iter()
+            .map(api_key_from_row)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(keys)
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                concat!(
+                    "SELECT ",
+                    api_key_columns!(),
+                    " FROM api_keys WHERE key_hash = ?"
+                ),
+                args!(key_hash),
+            ))
+            .await?;
+
+        Ok(rs.rows.first().map(api_key_from_row).transpose()?)
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "UPDATE api_keys SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+                args!(chrono::Utc::now().timestamp(), id),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn touch_api_key(&self, id: i64, now: i64) -> Result<(), RepositoryError> {
+        let client = self.client.lock().await;
+        client
+            .execute(Statement::with_args(
+                "UPDATE api_keys SET last_used_at = ? WHERE id = ?",
+                args!(now, id),
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SigningKeyRepository for LibsqlRepository {
+    async fn signing_keys(&self) -> Result<Vec<StoredSigningKey>, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(
+                "SELECT kid, private_key, activates_at FROM signing_keys ORDER BY activates_at",
+            )
+            .await?;
+
+        let keys = rs
+            .rows
+            .iter()
+            .map(|row| {
+                Ok(StoredSigningKey {
+                    kid: row.try_get::<&str>(0)?.to_string(),
+                    private_key: row.try_get::<&str>(1)?.to_string(),
+                    activates_at: row.try_get(2)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(keys)
+    }
+
+    async fn add_signing_key(
+        &self,
+        key: StoredSigningKey,
+        unless_after: i64,
+    ) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "INSERT INTO signing_keys (kid, private_key, activates_at, created_at)
+                 SELECT ?, ?, ?, ?
+                 WHERE NOT EXISTS (SELECT 1 FROM signing_keys WHERE activates_at > ?)",
+                args!(
+                    key.kid.as_str(),
+                    key.private_key.as_str(),
+                    key.activates_at,
+                    chrono::Utc::now().timestamp(),
+                    unless_after
+                ),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+
+    async fn delete_signing_key(&self, kid: &str) -> Result<bool, RepositoryError> {
+        let client = self.client.lock().await;
+        let rs = client
+            .execute(Statement::with_args(
+                "DELETE FROM signing_keys WHERE kid = ?",
+                args!(kid),
+            ))
+            .await?;
+        Ok(rs.rows_affected == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{AuditOutcome, DEFAULT_ORGANIZATION_ID};
+
+    fn new_user(username: &str, email: Option<&str>) -> NewUser {
+        NewUser {
+            username: username.to_string(),
+            email: email.map(str::to_string),
+            password_hash: "hash".to_string(),
+            organization_id: DEFAULT_ORGANIZATION_ID,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_create_and_find_user() {
+        let repository = LibsqlRepository::in_memory().await;
+        let created = repository.create(new_user("ada", None)).await.unwrap();
+
+        let user = repository.find_by_username("ada").await.unwrap().unwrap();
+        assert_eq!(user.id, created.id);
+        assert_eq!(user.password_hash, "hash");
+        assert!(repository.find_by_username("bob").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_new_users_are_students_until_promoted() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository.create(new_user("ada", None)).await.unwrap();
+        assert_eq!(user.role, Role::Student);
+
+        assert!(repository
+            .set_role(user.id, Role::Instructor)
+            .await
+            .unwrap());
+        let user = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert_eq!(user.role, Role::Instructor);
+
+        assert!(!repository.set_role(user.id + 1, Role::Admin).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_search_users_filters_and_pages() {
+        let repository = LibsqlRepository::in_memory().await;
+        let ada = repository
+            .create(new_user("ada", Some("ada@example.com")))
+            .await
+            .unwrap();
+        let grace = repository
+            .create(new_user("grace", Some("grace@navy.example")))
+            .await
+            .unwrap();
+        repository
+            .create(new_user("100%_real", None))
+            .await
+            .unwrap();
+        repository
+            .set_role(grace.id, Role::Instructor)
+            .await
+            .unwrap();
+        assert!(repository.set_disabled(ada.id, Some(1)).await.unwrap());
+
+        let search = |query: UserQuery| {
+            let repository = &repository;
+            async move {
+                repository
+                    .search_users(&UserQuery { limit: 10, ..query })
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|user| user.username)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            search(UserQuery {
+                text: Some("EXAMPLE".to_string()),
+                ..UserQuery::default()
+            })
+            .await,
+            ["ada", "grace"]
+        );
+        assert_eq!(
+            search(UserQuery {
+                text: Some("%_".to_string()),
+                ..UserQuery::default()
+            })
+            .await,
+            ["100%_real"]
+        );
+        assert_eq!(
+            search(UserQuery {
+                role: Some(Role::Instructor),
+                ..UserQuery::default()
+            })
+            .await,
+            ["grace"]
+        );
+        assert_eq!(
+            search(UserQuery {
+                disabled: Some(true),
+                ..UserQuery::default()
+            })
+            .await,
+            ["ada"]
+        );
+        assert_eq!(
+            search(UserQuery {
+                after_id: Some(ada.id),
+                ..UserQuery::default()
+            })
+            .await,
+            ["grace", "100%_real"]
+        );
+
+        let ada = repository.find_by_id(ada.id).await.unwrap().unwrap();
+        assert_eq!(ada.disabled_at, Some(1));
+        assert!(repository.set_disabled(ada.id, None).await.unwrap());
+        assert!(repository
+            .find_by_id(ada.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .disabled_at
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_username_is_conflict() {
+        let repository = LibsqlRepository::in_memory().await;
+        repository.create(new_user("ada", None)).await.unwrap();
+
+        let result = repository.create(new_user("ADA", None)).await;
+        assert!(matches!(result, Err(RepositoryError::Conflict(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_emails_are_unique_but_optional() {
+        let repository = LibsqlRepository::in_memory().await;
+        repository.create(new_user("ada", None)).await.unwrap();
+        repository.create(new_user("bob", None)).await.unwrap();
+        repository
+            .create(new_user("carol", Some("carol@example.com")))
+            .await
+            .unwrap();
+
+        let result = repository
+            .create(new_user("dave", Some("Carol@Example.com")))
+            .await;
+        assert!(matches!(result, Err(RepositoryError::Conflict(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_ids_are_generated() {
+        let repository = LibsqlRepository::in_memory().await;
+        let ada = repository.create(new_user("ada", None)).await.unwrap();
+        let bob = repository.create(new_user("bob", None)).await.unwrap();
+        assert_ne!(ada.id, bob.id);
+    }
+
+    #[actix_web::test]
+    async fn test_ids_of_deleted_users_are_not_reused() {
+        let repository = LibsqlRepository::in_memory().await;
+        repository.create(new_user("ada", None)).await.unwrap();
+        let bob = repository.create(new_user("bob", None)).await.unwrap();
+        assert!(repository.delete(bob.id).await.unwrap());
+
+        // Bob's access token must not start working for someone else.
+        let carol = repository.create(new_user("carol", None)).await.unwrap();
+        assert!(carol.id > bob.id);
+    }
+
+    #[actix_web::test]
+    async fn test_username_is_bound_not_interpolated() {
+        let repository = LibsqlRepository::in_memory().await;
+        let username = "x', 'y'); DROP TABLE users; --";
+        repository.create(new_user(username, None)).await.unwrap();
+
+        let user = repository
+            .find_by_username(username)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.username, username);
+    }
+
+    #[actix_web::test]
+    async fn test_refresh_token_can_only_be_revoked_once() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository.create(new_user("ada", None)).await.unwrap();
+        repository
+            .store("hash", user.id, "family", i64::MAX)
+            .await
+            .unwrap();
+
+        assert!(repository.revoke("hash").await.unwrap());
+        assert!(!repository.revoke("hash").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_sessions_are_active_until_their_tokens_are_revoked() {
+        let repository = LibsqlRepository::in_memory().await;
+        let ada = repository.create(new_user("ada", None)).await.unwrap();
+        let grace = repository.create(new_user("grace", None)).await.unwrap();
+        for (hash, family) in [("hash-1", "lab"), ("hash-2", "home")] {
+            repository
+                .store(hash, ada.id, family, i64::MAX)
+                .await
+                .unwrap();
+            repository
+                .record_session(family, ada.id, Some("10.0.0.1"), Some("Firefox"))
+                .await
+                .unwrap();
+        }
+        repository
+            .record_session("lab", ada.id, Some("10.0.0.2"), None)
+            .await
+            .unwrap();
+
+        let sessions = repository.active_sessions(ada.id).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let lab = sessions
+            .iter()
+            .find(|session| session.ip.as_deref() == Some("10.0.0.2"))
+            .unwrap();
+        assert!(lab.user_agent.is_none());
+
+        assert!(!repository.revoke_session(grace.id, lab.id).await.unwrap());
+        assert!(repository.revoke_session(ada.id, lab.id).await.unwrap());
+        let sessions = repository.active_sessions(ada.id).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_ne!(sessions[0].id, lab.id);
+
+        repository.revoke_all_for_user(ada.id).await.unwrap();
+        assert!(repository.active_sessions(ada.id).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_email_verification_and_lookup() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository
+            .create(new_user("ada", Some("ada@example.com")))
+            .await
+            .unwrap();
+        assert!(user.email_verified_at.is_none());
+
+        let found = repository
+            .find_by_email("ADA@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.id, user.id);
+
+        assert!(repository.mark_email_verified(user.id).await.unwrap());
+        let user = repository.find_by_id(user.id).await.unwrap().unwrap();
+        assert!(user.email_verified_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_one_time_token_can_only_be_consumed_once() {
+        let repository = LibsqlRepository::in_memory().await;
+        assert!(repository.consume("jti", i64::MAX).await.unwrap());
+        assert!(!repository.consume("jti", i64::MAX).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_login_failures_count_up_and_reset() {
+        let repository = LibsqlRepository::in_memory().await;
+        assert!(repository.failures("ip:1").await.unwrap().is_none());
+
+        repository.record_failure("ip:1", 100, 0).await.unwrap();
+        let failures = repository.record_failure("ip:1", 110, 0).await.unwrap();
+        assert_eq!(failures.failures, 2);
+        assert_eq!(failures.last_failure_at, 110);
+
+        // The last failure is older than the window, so counting starts over.
+        let failures = repository.record_failure("ip:1", 500, 200).await.unwrap();
+        assert_eq!(failures.failures, 1);
+
+        repository.clear_failures("ip:1").await.unwrap();
+        assert!(repository.failures("ip:1").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_totp_enrollment_and_replay_protection() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository.create(new_user("ada", None)).await.unwrap();
+        let codes = vec!["a".to_string(), "b".to_string()];
+
+        assert!(repository
+            .begin_totp_enrollment(user.id, "SECRET")
+            .await
+            .unwrap());
+        assert!(repository.confirm_totp(user.id, 10, &codes).await.unwrap());
+        assert!(!repository.confirm_totp(user.id, 11, &codes).await.unwrap());
+        // Confirmed secrets can't be replaced by starting over.
+        assert!(!repository
+            .begin_totp_enrollment(user.id, "OTHER")
+            .await
+            .unwrap());
+
+        let credential = repository.totp_credential(user.id).await.unwrap().unwrap();
+        assert_eq!(credential.secret, "SECRET");
+        assert!(credential.confirmed);
+
+        assert!(!repository.record_totp_step(user.id, 10).await.unwrap());
+        assert!(repository.record_totp_step(user.id, 11).await.unwrap());
+
+        let recovery_codes = repository.unused_recovery_codes(user.id).await.unwrap();
+        assert_eq!(recovery_codes.len(), 2);
+        assert!(repository
+            .use_recovery_code(recovery_codes[0].id)
+            .await
+            .unwrap());
+        assert!(!repository
+            .use_recovery_code(recovery_codes[0].id)
+            .await
+            .unwrap());
+        assert_eq!(
+            repository
+                .unused_recovery_codes(user.id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        repository.disable_totp(user.id).await.unwrap();
+        assert!(repository.totp_credential(user.id).await.unwrap().is_none());
+        assert!(repository
+            .unused_recovery_codes(user.id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_oidc_state_is_taken_once() {
+        let repository = LibsqlRepository::in_memory().await;
+        repository
+            .save(OidcLoginState {
+                state: "state".to_string(),
+                provider: "google".to_string(),
+                code_verifier: "verifier".to_string(),
+                nonce: "nonce".to_string(),
+                link_user_id: None,
+                expires_at: i64::MAX,
+            })
+            .await
+            .unwrap();
+
+        let state = repository.take("state").await.unwrap().unwrap();
+        assert_eq!(state.provider, "google");
+        assert_eq!(state.link_user_id, None);
+        assert!(repository.take("state").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_external_identity_links_to_one_user() {
+        let repository = LibsqlRepository::in_memory().await;
+        let ada = repository.create(new_user("ada", None)).await.unwrap();
+        let bob = repository.create(new_user("bob", None)).await.unwrap();
+
+        repository.link("google", "123", ada.id).await.unwrap();
+        assert_eq!(
+            repository.find_user_id("google", "123").await.unwrap(),
+            Some(ada.id)
+        );
+        assert!(repository
+            .find_user_id("microsoft", "123")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            repository.link("google", "123", bob.id).await,
+            Err(RepositoryError::Conflict(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_linked_user_is_not_created_when_the_link_fails() {
+        let repository = LibsqlRepository::in_memory().await;
+        let ada = repository
+            .create_linked_user(new_user("ada", None), true, "google", "123")
+            .await
+            .unwrap();
+        assert_eq!(
+            repository.find_user_id("google", "123").await.unwrap(),
+            Some(ada.id)
+        );
+        let stored = repository.find_by_id(ada.id).await.unwrap().unwrap();
+        assert!(stored.email_verified_at.is_some());
+
+        assert!(matches!(
+            repository
+                .create_linked_user(new_user("bob", None), false, "google", "123")
+                .await,
+            Err(RepositoryError::Conflict(_))
+        ));
+        assert!(repository.find_by_username("bob").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_profile_defaults_to_empty_and_saves() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository.create(new_user("ada", None)).await.unwrap();
+        assert_eq!(
+            repository.profile(user.id).await.unwrap(),
+            Profile::default()
+        );
+
+        let profile = Profile {
+            display_name: Some("Ada Lovelace".to_string()),
+            locale: Some("en-GB".to_string()),
+            ..Profile::default()
+        };
+        repository.save_profile(user.id, &profile).await.unwrap();
+        repository.save_profile(user.id, &profile).await.unwrap();
+        assert_eq!(repository.profile(user.id).await.unwrap(), profile);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user_removes_dependent_rows() {
+        let repository = LibsqlRepository::in_memory().await;
+        let user = repository.create(new_user("ada", None)).await.unwrap();
+        repository
+            .store("hash", user.id, "family", i64::MAX)
+            .await
+            .unwrap();
+        repository.link("school", "s-1", user.id).await.unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", user.id)
+            .await
+            .unwrap();
+
+        assert!(repository.delete(user.id).await.unwrap());
+        assert!(repository.roster(class.id).await.unwrap().is_empty());
+        assert!(repository.find_by_id(user.id).await.unwrap().is_none());
+        assert!(repository.find("hash").await.unwrap().is_none());
+        assert!(repository
+            .find_user_id("school", "s-1")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!repository.delete(user.id).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_class_membership_and_roster() {
+        let repository = LibsqlRepository::in_memory().await;
+        let grace = repository.create(new_user("grace", None)).await.unwrap();
+        let ada = repository.create(new_user("ada", None)).await.unwrap();
+
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace.id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            repository.create_class("Other", "ABCD2345", grace.id).await,
+            Err(RepositoryError::Conflict(_))
+        ));
+        assert_eq!(
+            repository
+                .find_class_by_join_code("ABCD2345")
+                .await
+                .unwrap()
+                .unwrap()
+                .id,
+            class.id
+        );
+
+        repository
+            .add_member(class.id, ada.id, ClassRole::Student)
+            .await
+            .unwrap();
+        repository
+            .add_member(class.id, ada.id, ClassRole::Student)
+            .await
+            .unwrap();
+        let roster = repository.roster(class.id).await.unwrap();
+        let names: Vec<_> = roster.iter().map(|m| m.username.as_str()).collect();
+        assert_eq!(names, ["grace", "ada"]);
+
+        let classes = repository.classes_of(ada.id).await.unwrap();
+        assert_eq!(classes.len(), 1);
+        assert_eq!(classes[0].1, ClassRole::Student);
+
+        assert!(repository.remove_member(class.id, ada.id).await.unwrap());
+        assert_eq!(
+            repository.member_role(class.id, ada.id).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            repository.member_role(class.id, grace.id).await.unwrap(),
+            Some(ClassRole::Instructor)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_class_invite_is_taken_once() {
+        let repository = LibsqlRepository::in_memory().await;
+        let grace = repository.create(new_user("grace", None)).await.unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace.id)
+            .await
+            .unwrap();
+
+        repository
+            .invite(class.id, "ada@example.com")
+            .await
+            .unwrap();
+        repository
+            .invite(class.id, "ada@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            repository
+                .invites_for("Ada@Example.com")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(repository
+            .take_invite(class.id, "ada@example.com")
+            .await
+            .unwrap());
+        assert!(!repository
+            .take_invite(class.id, "ada@example.com")
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_import_creates_all_users_or_none() {
+        let repository = LibsqlRepository::in_memory().await;
+        let grace = repository.create(new_user("grace", None)).await.unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", grace.id)
+            .await
+            .unwrap();
+        let imported = |username: &str| ImportedUser {
+            user: new_user(username, None),
+            display_name: Some(username.to_uppercase()),
+            class_id: Some(class.id),
+        };
+
+        let users = repository
+            .import(vec![imported("ada"), imported("alan")])
+            .await
+            .unwrap();
+        assert_eq!(users[1].username, "alan");
+        assert_eq!(
+            repository.profile(users[0].id).await.unwrap().display_name,
+            Some("ADA".to_string())
+        );
+        assert_eq!(repository.roster(class.id).await.unwrap().len(), 3);
+
+        assert!(matches!(
+            repository
+                .import(vec![imported("edsger"), imported("ada")])
+                .await,
+            Err(RepositoryError::Conflict(_))
+        ));
+        assert!(repository
+            .find_by_username("edsger")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_organizations_own_their_domains_and_classes() {
+        let repository = LibsqlRepository::in_memory().await;
+        let settings = |slug: &str, domains: &[&str]| OrganizationSettings {
+            slug: slug.to_string(),
+            name: slug.to_uppercase(),
+            email_domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            sso_provider: None,
+            password_min_length: 12,
+        };
+        let north = repository
+            .create_organization(&settings("north", &["north.edu", "alumni.north.edu"]))
+            .await
+            .unwrap();
+        assert_eq!(north.email_domains, ["alumni.north.edu", "north.edu"]);
+        assert!(matches!(
+            repository
+                .create_organization(&settings("south", &["NORTH.edu"]))
+                .await,
+            Err(RepositoryError::Conflict(_))
+        ));
+        // The failed insert left nothing behind.
+        assert!(repository
+            .organizations()
+            .await
+            .unwrap()
+            .iter()
+            .all(|organization| organization.slug != "south"));
+        assert_eq!(
+            repository
+                .find_organization_by_domain("North.EDU")
+                .await
+                .unwrap()
+                .map(|organization| organization.id),
+            Some(north.id)
+        );
+
+        assert!(repository
+            .update_organization(north.id, &settings("north", &["north.org"]))
+            .await
+            .unwrap());
+        assert!(repository
+            .find_organization_by_domain("north.edu")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!repository
+            .update_organization(999, &settings("nowhere", &[]))
+            .await
+            .unwrap());
+
+        let teacher = repository
+            .create(NewUser {
+                organization_id: north.id,
+                ..new_user("grace", Some("grace@north.org"))
+            })
+            .await
+            .unwrap();
+        let class = repository
+            .create_class("Compilers", "ABCD2345", teacher.id)
+            .await
+            .unwrap();
+        assert_eq!(class.organization_id, north.id);
+        let outsider = repository.create(new_user("ada", None)).await.unwrap();
+        let search = |organization_id| UserQuery {
+            organization_id,
+            limit: 10,
+            ..Default::default()
+        };
+        let found = repository
+            .search_users(&search(Some(north.id)))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, teacher.id);
+        assert_eq!(
+            repository.search_users(&search(None)).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            repository
+                .find_by_id(outsider.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .organization_id,
+            DEFAULT_ORGANIZATION_ID
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_signing_keys_are_added_once_per_rotation() {
+        let repository = LibsqlRepository::in_memory().await;
+        let key = |kid: &str, activates_at: i64| StoredSigningKey {
+            kid: kid.to_string(),
+            private_key: "pem".to_string(),
+            activates_at,
+        };
+
+        assert!(repository.add_signing_key(key("a", 100), 0).await.unwrap());
+        assert!(repository
+            .add_signing_key(key("b", 200), 100)
+            .await
+            .unwrap());
+        // Another instance saw only "a" and tries to add its own successor.
+        assert!(!repository
+            .add_signing_key(key("c", 200), 100)
+            .await
+            .unwrap());
+
+        assert!(repository.delete_signing_key("a").await.unwrap());
+        let kids: Vec<String> = repository
+            .signing_keys()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|key| key.kid)
+            .collect();
+        assert_eq!(kids, ["b"]);
+    }
+
+    #[actix_web::test]
+    async fn test_api_keys_are_found_by_hash_until_revoked() {
+        let repository = LibsqlRepository::in_memory().await;
+        let admin = repository.create(new_user("admin", None)).await.unwrap();
+        let key = repository
+            .create_api_key(NewApiKey {
+                name: "grader".to_string(),
+                prefix: "lsk_abcd".to_string(),
+                key_hash: "hash".to_string(),
+                scopes: vec![ApiScope::UsersRead, ApiScope::ClassesRead],
+                created_by: admin.id,
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+
+        let found = repository.find_api_key("hash").await.unwrap().unwrap();
+        assert_eq!(found.scopes, [ApiScope::UsersRead, ApiScope::ClassesRead]);
+        assert!(found.is_usable(chrono::Utc::now().timestamp()));
+
+        repository.touch_api_key(key.id, 1234).await.unwrap();
+        assert!(repository.revoke_api_key(key.id).await.unwrap());
+        assert!(!repository.revoke_api_key(key.id).await.unwrap());
+
+        // Deleting the admin keeps the key and its history.
+        repository.delete(admin.id).await.unwrap();
+        let keys = repository.api_keys().await.unwrap();
+        assert_eq!(keys[0].last_used_at, Some(1234));
+        assert_eq!(keys[0].created_by, None);
+        assert!(!keys[0].is_usable(0));
+    }
+
+    #[actix_web::test]
+    async fn test_audit_events_are_filtered_and_paged() {
+        let repository = LibsqlRepository::in_memory().await;
+        for (user_id, outcome) in [
+            (1, AuditOutcome::Failure),
+            (2, AuditOutcome::Failure),
+            (1, AuditOutcome::Success),
+            (1, AuditOutcome::Failure),
+        ] {
+            repository
+                .record(AuditEvent {
+                    event: "login",
+                    user_id: Some(user_id),
+                    ip: Some("203.0.113.7".to_string()),
+                    user_agent: Some("curl/8.0".to_string()),
+                    outcome,
+                    detail: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let query = AuditQuery {
+            user_id: Some(1),
+            outcome: Some(AuditOutcome::Failure),
+            limit: 1,
+            ..AuditQuery::default()
+        };
+        let page = repository.audit_events(&query).await.unwrap();
+        assert_eq!(page[0].id, 4);
+        let page = repository
+            .audit_events(&AuditQuery {
+                before_id: Some(4),
+                ..query
+            })
+            .await
+            .unwrap();
+        assert_eq!(page[0].id, 1);
+        assert_eq!(page[0].user_agent.as_deref(), Some("curl/8.0"));
+
+        // The log is append-only.
+        let client = repository.client.lock().await;
+        assert!(client.execute("DELETE FROM audit_events").await.is_err());
+        assert!(client
+            .execute("UPDATE audit_events SET user_id = NULL")
+            .await
+            .is_err());
+        assert!(client
+            .execute("UPDATE audit_events SET ip = '10.0.0.1'")
+            .await
+            .is_err());
+        // Except that erasing an account clears where its events came from.
+        client
+            .execute("UPDATE audit_events SET ip = NULL, user_agent = NULL")
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,673 @@
+pub mod libsql;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::auth::{ApiScope, ClassRole, Role};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A unique constraint was violated, e.g. the username is already taken.
+    Conflict(String),
+    Database(anyhow::Error),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Conflict(message) => write!(f, "Conflict: {}", message),
+            RepositoryError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<anyhow::Error> for RepositoryError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.to_string().contains("UNIQUE constraint failed") {
+            RepositoryError::Conflict(e.to_string())
+        } else {
+            RepositoryError::Database(e)
+        }
+    }
+}
+
+/// Everyone from before organizations existed belongs to this one. Its admins
+/// run the service for every school.
+pub const DEFAULT_ORGANIZATION_ID: i64 = 1;
+
+pub struct NewUser {
+    pub username: String,
+    pub email: Option<String>,
+    pub password_hash: String,
+    pub organization_id: i64,
+}
+
+/// An account created by a bulk import, with what else the import sets up.
+pub struct ImportedUser {
+    pub user: NewUser,
+    pub display_name: Option<String>,
+    /// Enrolled as a student.
+    pub class_id: Option<i64>,
+}
+
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub email: Option<String>,
+    pub password_hash: String,
+    pub created_at: Option<i64>,
+    pub role: Role,
+    pub email_verified_at: Option<i64>,
+    /// Set while an admin has the account disabled.
+    pub disabled_at: Option<i64>,
+    pub organization_id: i64,
+}
+
+/// What other users and clients are allowed to see about a user.
+#[derive(Serialize)]
+pub struct PublicProfile {
+    pub id: i64,
+    pub username: String,
+    pub email: Option<String>,
+    pub created_at: Option<i64>,
+    pub role: Role,
+    pub email_verified: bool,
+    pub organization_id: i64,
+}
+
+impl From<User> for PublicProfile {
+    fn from(user: User) -> Self {
+        PublicProfile {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            created_at: user.created_at,
+            role: user.role,
+            email_verified: user.email_verified_at.is_some(),
+            organization_id: user.organization_id,
+        }
+    }
+}
+
+/// What users can tell about themselves, all optional.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    /// One of the languages the exercise compiler runs, e.g. `python`.
+    pub preferred_language: Option<String>,
+    /// A BCP 47 language tag, e.g. `pt-BR`.
+    pub locale: Option<String>,
+    pub bio: Option<String>,
+}
+
+pub struct RefreshToken {
+    pub user_id: i64,
+    pub family_id: String,
+    pub expires_at: i64,
+}
+
+/// A login and every refresh token rotated from it, e.g. one browser on a lab
+/// machine.
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    /// Where the session was last refreshed from.
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A user's authenticator secret. 2FA is only enforced once it is confirmed.
+/// Replayed codes are caught by `record_totp_step`, not by reading this.
+pub struct TotpCredential {
+    pub secret: String,
+    pub confirmed: bool,
+}
+
+pub struct RecoveryCode {
+    pub id: i64,
+    pub code_hash: String,
+}
+
+pub struct LoginFailures {
+    pub failures: i64,
+    pub last_failure_at: i64,
+}
+
+/// Whether the attempt an audit event records went through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+        }
+    }
+}
+
+impl FromStr for AuditOutcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(AuditOutcome::Success),
+            "failure" => Ok(AuditOutcome::Failure),
+            _ => Err(format!("Unknown outcome: {}", s)),
+        }
+    }
+}
+
+/// Something security-relevant that happened, e.g. a failed login.
+pub struct AuditEvent {
+    pub event: &'static str,
+    pub user_id: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuditOutcome,
+    pub detail: Option<String>,
+}
+
+/// An audit event as recorded.
+#[derive(Debug, Serialize)]
+pub struct StoredAuditEvent {
+    pub id: i64,
+    pub created_at: i64,
+    pub event: String,
+    pub user_id: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuditOutcome,
+    pub detail: Option<String>,
+}
+
+/// Filters for reading the audit log. Every filter that is set must match.
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    pub event: Option<String>,
+    pub user_id: Option<i64>,
+    pub ip: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    /// Unix timestamps, inclusive.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    /// Only events about members of the organization.
+    pub organization_id: Option<i64>,
+    /// Only events older than this id, to page through the log newest first.
+    pub before_id: Option<i64>,
+    pub limit: i64,
+}
+
+/// Filters for searching users as an admin. Every filter that is set must match.
+#[derive(Debug, Default)]
+pub struct UserQuery {
+    /// Part of the username or email, regardless of case.
+    pub text: Option<String>,
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+    pub organization_id: Option<i64>,
+    /// Only users with a greater id, to page through the results.
+    pub after_id: Option<i64>,
+    pub limit: i64,
+}
+
+/// A sign-in started with an OpenID Connect provider, kept until the browser
+/// comes back with the authorization code.
+pub struct OidcLoginState {
+    pub state: String,
+    pub provider: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    /// Set when a signed-in user is linking the provider to their account
+    /// rather than signing in with it.
+    pub link_user_id: Option<i64>,
+    pub expires_at: i64,
+}
+
+/// An account at an OpenID Connect provider the user can sign in with.
+#[derive(Debug, Serialize)]
+pub struct LinkedIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub linked_at: i64,
+}
+
+/// A group of students with its instructors.
+#[derive(Debug, Clone, Serialize)]
+pub struct Class {
+    pub id: i64,
+    pub name: String,
+    /// Lets anyone who has it enroll as a student.
+    pub join_code: String,
+    pub created_at: i64,
+    /// The organization of the instructor who created the class. Only its
+    /// members can join.
+    pub organization_id: i64,
+}
+
+/// A class a user belongs to. Copied into access tokens, so other services can
+/// tell which classes the caller is in without asking this one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClassMembership {
+    /// The class id.
+    pub id: i64,
+    pub role: ClassRole,
+}
+
+/// A line of a class roster.
+#[derive(Debug, Serialize)]
+pub struct ClassMember {
+    pub user_id: i64,
+    pub username: String,
+    pub role: ClassRole,
+    pub joined_at: i64,
+}
+
+/// A school, with the settings that apply to its members.
+#[derive(Debug, Clone, Serialize)]
+pub struct Organization {
+    pub id: i64,
+    /// A short name for URLs and the admin dashboard, unique regardless of case.
+    pub slug: String,
+    pub name: String,
+    /// New accounts with an address at one of these domains join the
+    /// organization, and its members can't use addresses anywhere else.
+    pub email_domains: Vec<String>,
+    /// The OpenID Connect provider accounts must be created with, if the
+    /// school doesn't allow registering with a password.
+    pub sso_provider: Option<String>,
+    pub password_min_length: i64,
+    pub created_at: i64,
+}
+
+/// An organization's settings, when creating or changing it.
+#[derive(Debug)]
+pub struct OrganizationSettings {
+    pub slug: String,
+    pub name: String,
+    pub email_domains: Vec<String>,
+    pub sso_provider: Option<String>,
+    pub password_min_length: i64,
+}
+
+pub struct NewApiKey {
+    pub name: String,
+    /// The start of the key, shown so admins can tell keys apart.
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_by: i64,
+    pub expires_at: Option<i64>,
+}
+
+/// A key another service authenticates with. The key itself is never stored.
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    /// `None` once the admin who created it is deleted.
+    pub created_by: Option<i64>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+impl ApiKey {
+    pub fn is_usable(&self, now: i64) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+/// A key tokens are signed with, as stored.
+pub struct StoredSigningKey {
+    pub kid: String,
+    /// PKCS#8 PEM.
+    pub private_key: String,
+    pub activates_at: i64,
+}
+
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Creates the user with a server-generated id. Usernames and emails are
+    /// unique regardless of case.
+    async fn create(&self, user: NewUser) -> Result<User, RepositoryError>;
+
+    /// Case-insensitive lookup.
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, RepositoryError>;
+
+    async fn find_by_id(&self, id: i64) -> Result<Option<User>, RepositoryError>;
+
+    /// Case-insensitive lookup.
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError>;
+
+    /// Returns `false` if there is no user with that id.
+    async fn set_role(&self, id: i64, role: Role) -> Result<bool, RepositoryError>;
+
+    /// Disables the account at the given time, or enables it with `None`.
+    /// Returns `false` if there is no user with that id.
+    async fn set_disabled(
+        &self,
+        id: i64,
+        disabled_at: Option<i64>,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Users matching the query, ordered by id.
+    async fn search_users(&self, query: &UserQuery) -> Result<Vec<User>, RepositoryError>;
+
+    /// Returns `false` if there is no user with that id.
+    async fn mark_email_verified(&self, id: i64) -> Result<bool, RepositoryError>;
+
+    /// Returns `false` if there is no user with that id.
+    async fn set_password_hash(
+        &self,
+        id: i64,
+        password_hash: &str,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Deletes the user with their profile, sessions, 2FA and linked
+    /// identities, in one transaction. The audit log keeps its entries, minus
+    /// the addresses and user agents they were made from.
+    /// Returns `false` if there is no user with that id.
+    async fn delete(&self, id: i64) -> Result<bool, RepositoryError>;
+
+    /// Creates every user or, e.g. if one of the usernames is taken, none of
+    /// them. Returned in the same order.
+    async fn import(&self, users: Vec<ImportedUser>) -> Result<Vec<User>, RepositoryError>;
+}
+
+#[async_trait]
+pub trait ProfileRepository: Send + Sync {
+    /// An empty profile for users who never saved one.
+    async fn profile(&self, user_id: i64) -> Result<Profile, RepositoryError>;
+
+    async fn save_profile(&self, user_id: i64, profile: &Profile) -> Result<(), RepositoryError>;
+}
+
+/// Refresh tokens are looked up by the hash of the token, never the token itself.
+#[async_trait]
+pub trait RefreshTokenRepository: Send + Sync {
+    async fn store(
+        &self,
+        token_hash: &str,
+        user_id: i64,
+        family_id: &str,
+        expires_at: i64,
+    ) -> Result<(), RepositoryError>;
+
+    async fn find(&self, token_hash: &str) -> Result<Option<RefreshToken>, RepositoryError>;
+
+    /// Revokes a single token. Returns `false` if it was already revoked, so
+    /// two concurrent refreshes with the same token can't both succeed.
+    async fn revoke(&self, token_hash: &str) -> Result<bool, RepositoryError>;
+
+    async fn revoke_family(&self, family_id: &str) -> Result<(), RepositoryError>;
+
+    /// Signs the user out everywhere, e.g. after a password reset.
+    async fn revoke_all_for_user(&self, user_id: i64) -> Result<(), RepositoryError>;
+
+    /// Creates the session for the token family, or marks it as used now by
+    /// this client.
+    async fn record_session(
+        &self,
+        family_id: &str,
+        user_id: i64,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> Result<(), RepositoryError>;
+
+    /// Sessions with a refresh token that is neither revoked nor expired,
+    /// most recently used first.
+    async fn active_sessions(&self, user_id: i64) -> Result<Vec<Session>, RepositoryError>;
+
+    /// Revokes every token of the session. Returns `false` if the user has no
+    /// session with that id.
+    async fn revoke_session(&self, user_id: i64, session_id: i64) -> Result<bool, RepositoryError>;
+}
+
+/// Remembers which single-use tokens (verification and reset links) have been used.
+#[async_trait]
+pub trait OneTimeTokenRepository: Send + Sync {
+    /// Marks the token as used. Returns `false` if it already was, so the same
+    /// link can't be used twice, even concurrently.
+    async fn consume(&self, jti: &str, expires_at: i64) -> Result<bool, RepositoryError>;
+}
+
+#[async_trait]
+pub trait TwoFactorRepository: Send + Sync {
+    async fn totp_credential(
+        &self,
+        user_id: i64,
+    ) -> Result<Option<TotpCredential>, RepositoryError>;
+
+    /// Stores a new, unconfirmed secret, replacing an earlier unconfirmed one.
+    /// Returns `false` if 2FA is already confirmed, which leaves it untouched.
+    async fn begin_totp_enrollment(
+        &self,
+        user_id: i64,
+        secret: &str,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Turns 2FA on and replaces the recovery codes, in one transaction.
+    /// Returns `false` if there is no pending enrollment.
+    async fn confirm_totp(
+        &self,
+        user_id: i64,
+        step: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool, RepositoryError>;
+
+    /// Records that a code for `step` was used. Returns `false` if a code for
+    /// the same or a later step was already used, so codes can't be replayed.
+    async fn record_totp_step(&self, user_id: i64, step: i64) -> Result<bool, RepositoryError>;
+
+    /// Removes the secret and the recovery codes.
+    async fn disable_totp(&self, user_id: i64) -> Result<(), RepositoryError>;
+
+    async fn unused_recovery_codes(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<RecoveryCode>, RepositoryError>;
+
+    /// Returns `false` if the code was already used.
+    async fn use_recovery_code(&self, id: i64) -> Result<bool, RepositoryError>;
+
+    async fn replace_recovery_codes(
+        &self,
+        user_id: i64,
+        recovery_code_hashes: &[String],
+    ) -> Result<(), RepositoryError>;
+}
+
+/// Failed login counters, keyed by account or client address.
+#[async_trait]
+pub trait LoginAttemptRepository: Send + Sync {
+    async fn failures(&self, key: &str) -> Result<Option<LoginFailures>, RepositoryError>;
+
+    /// Counts a failure at `now` and returns the new total. Failures from before
+    /// `reset_before` are forgotten first, so old typos don't add up forever.
+    async fn record_failure(
+        &self,
+        key: &str,
+        now: i64,
+        reset_before: i64,
+    ) -> Result<LoginFailures, RepositoryError>;
+
+    async fn clear_failures(&self, key: &str) -> Result<(), RepositoryError>;
+}
+
+#[async_trait]
+pub trait AuditRepository: Send + Sync {
+    async fn record(&self, event: AuditEvent) -> Result<(), RepositoryError>;
+
+    /// Matching events, newest first.
+    async fn audit_events(
+        &self,
+        query: &AuditQuery,
+    ) -> Result<Vec<StoredAuditEvent>, RepositoryError>;
+}
+
+#[async_trait]
+pub trait OidcStateRepository: Send + Sync {
+    async fn save(&self, state: OidcLoginState) -> Result<(), RepositoryError>;
+
+    /// Removes and returns the state, so each one can be used only once.
+    async fn take(&self, state: &str) -> Result<Option<OidcLoginState>, RepositoryError>;
+}
+
+/// Accounts at OpenID Connect providers, linked to local users.
+#[async_trait]
+pub trait ExternalIdentityRepository: Send + Sync {
+    async fn find_user_id(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<i64>, RepositoryError>;
+
+    /// Fails with `Conflict` if the identity is already linked to a user.
+    async fn link(
+        &self,
+        provider: &str,
+        subject: &str,
+        user_id: i64,
+    ) -> Result<(), RepositoryError>;
+
+    /// Creates a user with the identity already linked, in one transaction, so
+    /// a failed link leaves no account behind. Fails with `Conflict` like
+    /// `UserRepository::create` and `link` do.
+    async fn create_linked_user(
+        &self,
+        user: NewUser,
+        email_verified: bool,
+        provider: &str,
+        subject: &str,
+    ) -> Result<User, RepositoryError>;
+
+    async fn identities_of(&self, user_id: i64) -> Result<Vec<LinkedIdentity>, RepositoryError>;
+}
+
+#[async_trait]
+pub trait ClassRepository: Send + Sync {
+    /// Creates the class with `instructor_id` as its first instructor, in
+    /// their organization. Fails with `Conflict` if the join code is taken.
+    async fn create_class(
+        &self,
+        name: &str,
+        join_code: &str,
+        instructor_id: i64,
+    ) -> Result<Class, RepositoryError>;
+
+    async fn find_class(&self, id: i64) -> Result<Option<Class>, RepositoryError>;
+
+    async fn find_class_by_join_code(
+        &self,
+        join_code: &str,
+    ) -> Result<Option<Class>, RepositoryError>;
+
+    /// Fails with `Conflict` if the join code is taken.
+    async fn set_join_code(&self, class_id: i64, join_code: &str) -> Result<bool, RepositoryError>;
+
+    /// Every class the user belongs to, by id.
+    async fn classes_of(&self, user_id: i64) -> Result<Vec<(Class, ClassRole)>, RepositoryError>;
+
+    async fn member_role(
+        &self,
+        class_id: i64,
+        user_id: i64,
+    ) -> Result<Option<ClassRole>, RepositoryError>;
+
+    /// Adds the user to the class, or changes their role if they already are a member.
+    async fn add_member(
+        &self,
+        class_id: i64,
+        user_id: i64,
+        role: ClassRole,
+    ) -> Result<(), RepositoryError>;
+
+    async fn remove_member(&self, class_id: i64, user_id: i64) -> Result<bool, RepositoryError>;
+
+    /// Instructors first, then students, each by username.
+    async fn roster(&self, class_id: i64) -> Result<Vec<ClassMember>, RepositoryError>;
+
+    /// Inviting an address again just renews the invite.
+    async fn invite(&self, class_id: i64, email: &str) -> Result<(), RepositoryError>;
+
+    /// Classes with an open invite for the address, by id.
+    async fn invites_for(&self, email: &str) -> Result<Vec<Class>, RepositoryError>;
+
+    /// Removes the invite, so each one can be accepted only once.
+    async fn take_invite(&self, class_id: i64, email: &str) -> Result<bool, RepositoryError>;
+}
+
+#[async_trait]
+pub trait OrganizationRepository: Send + Sync {
+    /// Fails with `Conflict` if the slug or one of the domains is taken.
+    async fn create_organization(
+        &self,
+        settings: &OrganizationSettings,
+    ) -> Result<Organization, RepositoryError>;
+
+    async fn find_organization(&self, id: i64) -> Result<Option<Organization>, RepositoryError>;
+
+    /// The organization that claimed the domain, regardless of case.
+    async fn find_organization_by_domain(
+        &self,
+        domain: &str,
+    ) -> Result<Option<Organization>, RepositoryError>;
+
+    /// Every organization, by id.
+    async fn organizations(&self) -> Result<Vec<Organization>, RepositoryError>;
+
+    /// Replaces the settings, domains included, in one transaction. Fails with
+    /// `Conflict` if the slug or one of the domains is taken. Returns `false`
+    /// if there is no organization with that id.
+    async fn update_organization(
+        &self,
+        id: i64,
+        settings: &OrganizationSettings,
+    ) -> Result<bool, RepositoryError>;
+}
+
+#[async_trait]
+pub trait SigningKeyRepository: Send + Sync {
+    /// Every stored key, in order of activation.
+    async fn signing_keys(&self) -> Result<Vec<StoredSigningKey>, RepositoryError>;
+
+    /// Stores the key unless one activating after `unless_after` already
+    /// exists, so instances rotating at the same time add only one key.
+    async fn add_signing_key(
+        &self,
+        key: StoredSigningKey,
+        unless_after: i64,
+    ) -> Result<bool, RepositoryError>;
+
+    async fn delete_signing_key(&self, kid: &str) -> Result<bool, RepositoryError>;
+}
+
+#[async_trait]
+pub trait ApiKeyRepository: Send + Sync {
+    async fn create_api_key(&self, key: NewApiKey) -> Result<ApiKey, RepositoryError>;
+
+    /// Every key, revoked and expired ones included, newest first.
+    async fn api_keys(&self) -> Result<Vec<ApiKey>, RepositoryError>;
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, RepositoryError>;
+
+    /// False if there is no such key or it was already revoked.
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, RepositoryError>;
+
+    async fn touch_api_key(&self, id: i64, now: i64) -> Result<(), RepositoryError>;
+}
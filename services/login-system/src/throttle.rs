@@ -0,0 +1,215 @@
+use actix_web::HttpRequest;
+
+use crate::audit::{client_ip, user_agent};
+use crate::error::ApiError;
+use crate::repository::{
+    AuditEvent, AuditOutcome, AuditRepository, LoginAttemptRepository, LoginFailures,
+};
+
+/// How quickly failed logins for one key slow down further attempts.
+pub struct ThrottlePolicy {
+    /// Failures allowed before any delay kicks in.
+    free_attempts: i64,
+    /// The delay doubles with every failure after the free ones, up to this.
+    max_backoff_secs: i64,
+    /// After this many failures the key is locked out until `lockout_secs`
+    /// pass or an admin unlocks it.
+    lockout_after: Option<i64>,
+    lockout_secs: i64,
+}
+
+/// Per account. Lockout stops slow guessing that stays under the backoff.
+pub const ACCOUNT_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 3,
+    max_backoff_secs: 15 * 60,
+    lockout_after: Some(10),
+    lockout_secs: 60 * 60,
+};
+
+/// Per client address. More lenient, since a whole classroom can share one
+/// address, and never a lockout, so one student can't lock out the rest.
+pub const IP_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 20,
+    max_backoff_secs: 15 * 60,
+    lockout_after: None,
+    lockout_secs: 0,
+};
+
+/// Failures older than this no longer count.
+const FAILURE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+impl ThrottlePolicy {
+    fn is_locked_out(&self, failures: i64) -> bool {
+        self.lockout_after
+            .is_some_and(|lockout_after| failures >= lockout_after)
+    }
+
+    /// How long after the last failure the next attempt is allowed.
+    fn delay_secs(&self, failures: i64) -> i64 {
+        if self.is_locked_out(failures) {
+            return self.lockout_secs;
+        }
+        let over = failures - self.free_attempts;
+        if over <= 0 {
+            return 0;
+        }
+        // 1s, 2s, 4s, ... without overflowing for large counts.
+        1i64.checked_shl(over as u32 - 1)
+            .unwrap_or(i64::MAX)
+            .min(self.max_backoff_secs)
+    }
+
+    /// Seconds until the next attempt is allowed, if it isn't yet.
+    fn retry_after(&self, failures: &LoginFailures, now: i64) -> Option<i64> {
+        let allowed_at = failures.last_failure_at + self.delay_secs(failures.failures);
+        (allowed_at > now).then(|| allowed_at - now)
+    }
+}
+
+/// Tracks failed logins for one attempt, by account and by client address.
+pub struct LoginThrottle<'a> {
+    attempts: &'a dyn LoginAttemptRepository,
+    audit: &'a dyn AuditRepository,
+    account_key: String,
+    ip_key: String,
+    ip: String,
+    user_agent: Option<String>,
+}
+
+impl<'a> LoginThrottle<'a> {
+    pub fn new(
+        attempts: &'a dyn LoginAttemptRepository,
+        audit: &'a dyn AuditRepository,
+        req: &HttpRequest,
+        username: &str,
+    ) -> Self {
+        let ip = client_ip(req);
+        LoginThrottle {
+            attempts,
+            audit,
+            account_key: account_key(username),
+            ip_key: format!("ip:{}", ip),
+            ip,
+            user_agent: user_agent(req),
+        }
+    }
+
+    /// Fails with 429 while either the account or the address is backing off.
+    /// Runs before the password is checked, so a locked account can't be
+    /// guessed at either.
+    pub async fn check(&self) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        let mut retry_after = None;
+
+        for (key, policy) in [
+            (&self.account_key, &ACCOUNT_POLICY),
+            (&self.ip_key, &IP_POLICY),
+        ] {
+            if let Some(failures) = self.attempts.failures(key).await? {
+                retry_after = retry_after.max(policy.retry_after(&failures, now));
+            }
+        }
+
+        match retry_after {
+            Some(secs) => Err(ApiError::too_many_attempts(secs)),
+            None => Ok(()),
+        }
+    }
+
+    /// `user_id` is `None` when the username doesn't exist; the attempt still
+    /// counts against it, so responses don't reveal which accounts exist.
+    pub async fn record_failure(&self, user_id: Option<i64>, reason: &str) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp();
+        let reset_before = now - FAILURE_WINDOW_SECS;
+
+        let account = self
+            .attempts
+            .record_failure(&self.account_key, now, reset_before)
+            .await?;
+        self.attempts
+            .record_failure(&self.ip_key, now, reset_before)
+            .await?;
+
+        self.audit(
+            user_id,
+            "login_failed",
+            AuditOutcome::Failure,
+            Some(reason.to_string()),
+        )
+        .await?;
+        if ACCOUNT_POLICY.lockout_after == Some(account.failures) {
+            self.audit(
+                user_id,
+                "account_locked",
+                AuditOutcome::Failure,
+                Some(format!("{} failed attempts", account.failures)),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Only the account's counter is cleared: one valid login from an address
+    /// shouldn't excuse the failures of everyone else behind it. `method` is
+    /// how the user signed in, e.g. `password`.
+    pub async fn record_success(&self, user_id: i64, method: &str) -> Result<(), ApiError> {
+        self.attempts.clear_failures(&self.account_key).await?;
+        self.audit(
+            Some(user_id),
+            "login_succeeded",
+            AuditOutcome::Success,
+            Some(method.to_string()),
+        )
+        .await
+    }
+
+    async fn audit(
+        &self,
+        user_id: Option<i64>,
+        event: &'static str,
+        outcome: AuditOutcome,
+        detail: Option<String>,
+    ) -> Result<(), ApiError> {
+        self.audit
+            .record(AuditEvent {
+                event,
+                user_id,
+                ip: Some(self.ip.clone()),
+                user_agent: self.user_agent.clone(),
+                outcome,
+                detail,
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Keyed by the normalized username rather than the user id, so attempts on
+/// usernames that don't exist are throttled the same way.
+pub fn account_key(username: &str) -> String {
+    format!("account:{}", username.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off_exponentially_then_locks_out() {
+        let delays: Vec<i64> = (1..=10)
+            .map(|failures| ACCOUNT_POLICY.delay_secs(failures))
+            .collect();
+        assert_eq!(delays, vec![0, 0, 0, 1, 2, 4, 8, 16, 32, 3600]);
+        assert_eq!(IP_POLICY.delay_secs(1000), 15 * 60);
+    }
+
+    #[test]
+    fn test_retry_after_counts_from_last_failure() {
+        let failures = LoginFailures {
+            failures: 6,
+            last_failure_at: 100,
+        };
+        assert_eq!(ACCOUNT_POLICY.retry_after(&failures, 101), Some(3));
+        assert_eq!(ACCOUNT_POLICY.retry_after(&failures, 104), None);
+    }
+}
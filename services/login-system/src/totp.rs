@@ -0,0 +1,125 @@
+use rand::{Rng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Shown next to the account name in authenticator apps.
+const ISSUER_NAME: &str = "Educational Platform";
+const STEP_SECS: u64 = 30;
+/// Codes from the previous and next step are accepted too, for clock drift.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A new random secret, base32-encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+fn totp(secret: &str, account_name: &str) -> anyhow::Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("Invalid TOTP secret: {}", e))?;
+    // Account names can't contain ':', which separates them from the issuer in the URI.
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECS,
+        secret,
+        Some(ISSUER_NAME.to_string()),
+        account_name.replace(':', "_"),
+    )?)
+}
+
+/// The `otpauth://` URI that authenticator apps read from a QR code.
+pub fn provisioning_uri(secret: &str, account_name: &str) -> anyhow::Result<String> {
+    Ok(totp(secret, account_name)?.get_url())
+}
+
+/// Returns the step the code is valid for, or `None` if it doesn't match any
+/// step within the allowed drift. Callers store the step and reject codes for
+/// the same or an earlier step, so each code works only once.
+pub fn verify_code(secret: &str, code: &str, now: i64) -> anyhow::Result<Option<i64>> {
+    let totp = totp(secret, "")?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let current_step = now / STEP_SECS as i64;
+
+    for step in current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS {
+        if totp.check(&code, step as u64 * STEP_SECS) {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/// The code an authenticator would show at `now`, for tests.
+#[cfg(test)]
+pub fn code_at(secret: &str, now: i64) -> String {
+    totp(secret, "").unwrap().generate(now as u64)
+}
+
+/// Single-use codes for when the authenticator is lost, e.g. `k7mq2-xp4rw`.
+/// The alphabet leaves out characters that are easy to misread.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char
+                })
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without the dash, spaces or case, since they
+/// are usually typed in by hand.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_accepted_within_drift() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+        let code = code_at(&secret, now);
+
+        assert_eq!(
+            verify_code(&secret, &code, now).unwrap(),
+            Some(now / STEP_SECS as i64)
+        );
+        assert!(verify_code(&secret, &code, now + STEP_SECS as i64)
+            .unwrap()
+            .is_some());
+        assert!(verify_code(&secret, &code, now + 3 * STEP_SECS as i64)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri(&generate_secret(), "ada").unwrap();
+        assert!(uri.starts_with("otpauth://totp/"));
+        assert!(uri.contains("ada"));
+    }
+
+    #[test]
+    fn test_recovery_codes_are_normalized() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(
+            normalize_recovery_code(&codes[0].to_uppercase()),
+            codes[0].replace('-', "")
+        );
+    }
+}
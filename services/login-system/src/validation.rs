@@ -0,0 +1,654 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::error::{ApiError, FieldErrors};
+use crate::repository::{OrganizationSettings, Profile};
+
+const USERNAME_MIN_CHARS: usize = 3;
+const USERNAME_MAX_CHARS: usize = 32;
+/// Organizations can ask for longer passwords, up to `PASSWORD_POLICY_MAX_CHARS`.
+pub const PASSWORD_MIN_CHARS: usize = 8;
+const PASSWORD_POLICY_MAX_CHARS: usize = 64;
+/// Long enough for any passphrase, short enough that nobody sends megabytes to hash.
+const PASSWORD_MAX_BYTES: usize = 1024;
+const EMAIL_MAX_CHARS: usize = 254;
+const DISPLAY_NAME_MAX_CHARS: usize = 64;
+const AVATAR_URL_MAX_CHARS: usize = 2048;
+const BIO_MAX_CHARS: usize = 1000;
+const CLASS_NAME_MAX_CHARS: usize = 100;
+const API_KEY_NAME_MAX_CHARS: usize = 100;
+const ORGANIZATION_NAME_MAX_CHARS: usize = 100;
+const SLUG_MAX_CHARS: usize = 32;
+const EMAIL_DOMAINS_MAX: usize = 20;
+/// The languages the exercise compiler runs.
+const PROGRAMMING_LANGUAGES: &[&str] = &["cpp", "go", "haskell", "javascript", "python", "rust"];
+
+/// Passwords that show up at the top of every leaked-password list.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// Registration input after normalization, ready to be stored.
+pub struct ValidRegistration {
+    pub username: String,
+    pub password: String,
+    pub email: String,
+}
+
+/// Validates every field and reports all problems at once, so a form can show
+/// them next to the inputs in a single round trip. Passwords need at least
+/// `min_password_chars`, the policy of the organization the user joins.
+pub fn validate_registration(
+    username: &str,
+    password: &str,
+    email: Option<&str>,
+    min_password_chars: usize,
+) -> Result<ValidRegistration, ApiError> {
+    let username = normalize_username(username);
+    let password = normalize_password(password);
+    let email = email.map(str::trim).unwrap_or_default();
+
+    let mut errors = FieldErrors::new();
+    add_errors(&mut errors, "username", username_errors(&username));
+    add_errors(
+        &mut errors,
+        "password",
+        password_errors(&password, &username, min_password_chars),
+    );
+    add_errors(&mut errors, "email", email_errors(email));
+
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+
+    Ok(ValidRegistration {
+        username,
+        password,
+        email: email.to_string(),
+    })
+}
+
+/// A row of a bulk student import after normalization.
+pub struct ValidStudent {
+    pub username: String,
+    pub email: String,
+    pub display_name: Option<String>,
+}
+
+/// Validates an imported student like a registration, except for the
+/// password, which the import makes up. The errors go into a per-row report.
+pub fn validate_student(
+    username: &str,
+    email: &str,
+    name: &str,
+) -> Result<ValidStudent, FieldErrors> {
+    let username = normalize_username(username);
+    let email = email.trim();
+    let name = name.trim();
+
+    let mut errors = FieldErrors::new();
+    add_errors(&mut errors, "username", username_errors(&username));
+    add_errors(&mut errors, "email", email_errors(email));
+    add_errors(
+        &mut errors,
+        "name",
+        text_errors("Name", name, DISPLAY_NAME_MAX_CHARS, false),
+    );
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(ValidStudent {
+        username,
+        email: email.to_string(),
+        display_name: (!name.is_empty()).then(|| name.to_string()),
+    })
+}
+
+/// Validates a new password for an existing account, e.g. on password reset.
+pub fn validate_password(
+    password: &str,
+    username: &str,
+    min_chars: usize,
+) -> Result<String, ApiError> {
+    let password = normalize_password(password);
+    let mut errors = FieldErrors::new();
+    add_errors(
+        &mut errors,
+        "password",
+        password_errors(&password, username, min_chars),
+    );
+
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    Ok(password)
+}
+
+/// Validates a whole profile the same way. Fields are trimmed, and empty ones
+/// are cleared.
+pub fn validate_profile(profile: Profile) -> Result<Profile, ApiError> {
+    let clean = |field: Option<String>| {
+        field
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let profile = Profile {
+        display_name: clean(profile.display_name),
+        avatar_url: clean(profile.avatar_url),
+        preferred_language: clean(profile.preferred_language),
+        locale: clean(profile.locale),
+        bio: clean(profile.bio),
+    };
+
+    let mut errors = FieldErrors::new();
+    if let Some(display_name) = &profile.display_name {
+        add_errors(
+            &mut errors,
+            "display_name",
+            text_errors("Display name", display_name, DISPLAY_NAME_MAX_CHARS, false),
+        );
+    }
+    if let Some(avatar_url) = &profile.avatar_url {
+        add_errors(&mut errors, "avatar_url", avatar_url_errors(avatar_url));
+    }
+    if let Some(language) = &profile.preferred_language {
+        if !PROGRAMMING_LANGUAGES.contains(&language.as_str()) {
+            errors.insert(
+                "preferred_language",
+                vec![format!(
+                    "Preferred language must be one of {}",
+                    PROGRAMMING_LANGUAGES.join(", ")
+                )],
+            );
+        }
+    }
+    if let Some(locale) = &profile.locale {
+        if !is_language_tag(locale) {
+            errors.insert(
+                "locale",
+                vec!["Locale must be a language tag like en or pt-BR".to_string()],
+            );
+        }
+    }
+    if let Some(bio) = &profile.bio {
+        add_errors(
+            &mut errors,
+            "bio",
+            text_errors("Bio", bio, BIO_MAX_CHARS, true),
+        );
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    Ok(profile)
+}
+
+/// Validates a class name, returning it trimmed.
+pub fn validate_class_name(name: &str) -> Result<String, ApiError> {
+    validate_name(name, CLASS_NAME_MAX_CHARS)
+}
+
+/// Validates the name an admin gives an API key, e.g. `grading-worker`,
+/// returning it trimmed.
+pub fn validate_api_key_name(name: &str) -> Result<String, ApiError> {
+    validate_name(name, API_KEY_NAME_MAX_CHARS)
+}
+
+fn validate_name(name: &str, max_chars: usize) -> Result<String, ApiError> {
+    let name = name.trim();
+    let mut messages = text_errors("Name", name, max_chars, false);
+    if name.is_empty() {
+        messages.push("Name is required".to_string());
+    }
+
+    if !messages.is_empty() {
+        return Err(ApiError::validation(FieldErrors::from([(
+            "name", messages,
+        )])));
+    }
+    Ok(name.to_string())
+}
+
+/// Validates an organization's settings, returning them trimmed, with the slug
+/// and domains in lowercase. `sso_providers` are the configured OpenID Connect
+/// providers the organization can pick from.
+pub fn validate_organization(
+    settings: OrganizationSettings,
+    sso_providers: &[String],
+) -> Result<OrganizationSettings, ApiError> {
+    let slug = settings.slug.trim().to_lowercase();
+    let name = settings.name.trim().to_string();
+    let mut email_domains: Vec<String> = settings
+        .email_domains
+        .iter()
+        .map(|domain| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    email_domains.sort();
+    email_domains.dedup();
+    let sso_provider = settings
+        .sso_provider
+        .map(|provider| provider.trim().to_string())
+        .filter(|provider| !provider.is_empty());
+
+    let mut errors = FieldErrors::new();
+    if !(2..=SLUG_MAX_CHARS).contains(&slug.len())
+        || !slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        errors.insert(
+            "slug",
+            vec![format!(
+                "Slug must be 2 to {} lowercase letters, digits or '-'",
+                SLUG_MAX_CHARS
+            )],
+        );
+    }
+    let mut name_errors = text_errors("Name", &name, ORGANIZATION_NAME_MAX_CHARS, false);
+    if name.is_empty() {
+        name_errors.push("Name is required".to_string());
+    }
+    add_errors(&mut errors, "name", name_errors);
+    let mut domain_errors: Vec<String> = email_domains
+        .iter()
+        .filter(|domain| !email_errors(&format!("user@{}", domain)).is_empty())
+        .map(|domain| format!("{} is not a valid domain", domain))
+        .collect();
+    if email_domains.len() > EMAIL_DOMAINS_MAX {
+        domain_errors.push(format!("At most {} domains", EMAIL_DOMAINS_MAX));
+    }
+    add_errors(&mut errors, "email_domains", domain_errors);
+    if let Some(provider) = &sso_provider {
+        if !sso_providers.contains(provider) {
+            errors.insert(
+                "sso_provider",
+                vec![format!("Unknown sign-in provider {}", provider)],
+            );
+        }
+    }
+    let password_min_length = settings.password_min_length;
+    if !(PASSWORD_MIN_CHARS as i64..=PASSWORD_POLICY_MAX_CHARS as i64)
+        .contains(&password_min_length)
+    {
+        errors.insert(
+            "password_min_length",
+            vec![format!(
+                "Minimum password length must be between {} and {}",
+                PASSWORD_MIN_CHARS, PASSWORD_POLICY_MAX_CHARS
+            )],
+        );
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    Ok(OrganizationSettings {
+        slug,
+        name,
+        email_domains,
+        sso_provider,
+        password_min_length,
+    })
+}
+
+/// Validates an address someone else gave, e.g. for an invite, returning it trimmed.
+pub fn validate_email(email: &str) -> Result<String, ApiError> {
+    let email = email.trim();
+    let messages = email_errors(email);
+
+    if !messages.is_empty() {
+        return Err(ApiError::validation(FieldErrors::from([(
+            "email", messages,
+        )])));
+    }
+    Ok(email.to_string())
+}
+
+/// NFKC folds look-alike forms (e.g. fullwidth `ａｄａ`) into one spelling, so
+/// they can't be used to register a username that looks like someone else's.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect()
+}
+
+/// Passwords are normalized too, so the same password typed on a different
+/// keyboard or OS still matches the stored hash.
+pub fn normalize_password(password: &str) -> String {
+    password.nfkc().collect()
+}
+
+fn add_errors(errors: &mut FieldErrors, field: &'static str, messages: Vec<String>) {
+    if !messages.is_empty() {
+        errors.insert(field, messages);
+    }
+}
+
+fn username_errors(username: &str) -> Vec<String> {
+    let mut messages = Vec::new();
+    let length = username.chars().count();
+
+    if !(USERNAME_MIN_CHARS..=USERNAME_MAX_CHARS).contains(&length) {
+        messages.push(format!(
+            "Username must be between {} and {} characters",
+            USERNAME_MIN_CHARS, USERNAME_MAX_CHARS
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        messages.push("Username may only contain letters, numbers, '_', '-' and '.'".to_string());
+    }
+    if username
+        .chars()
+        .next()
+        .is_some_and(|c| !c.is_alphanumeric())
+    {
+        messages.push("Username must start with a letter or number".to_string());
+    }
+
+    messages
+}
+
+fn password_errors(password: &str, username: &str, min_chars: usize) -> Vec<String> {
+    let mut messages = Vec::new();
+    let lowercase = password.to_lowercase();
+    let min_chars = min_chars.max(PASSWORD_MIN_CHARS);
+
+    if password.chars().count() < min_chars {
+        messages.push(format!(
+            "Password must be at least {} characters",
+            min_chars
+        ));
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        messages.push(format!(
+            "Password must be at most {} bytes",
+            PASSWORD_MAX_BYTES
+        ));
+    }
+    if password.chars().any(char::is_control) {
+        messages.push("Password must not contain control characters".to_string());
+    }
+    if COMMON_PASSWORDS.lines().any(|common| common == lowercase) {
+        messages.push("Password is too common".to_string());
+    } else if !password.is_empty() && password.chars().all(|c| password.starts_with(c)) {
+        messages.push("Password must not repeat a single character".to_string());
+    }
+    if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+        messages.push("Password must not contain the username".to_string());
+    }
+
+    messages
+}
+
+fn email_errors(email: &str) -> Vec<String> {
+    if email.is_empty() {
+        return vec!["Email address is required".to_string()];
+    }
+
+    let valid = email.chars().count() <= EMAIL_MAX_CHARS
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && match email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+            }
+            None => false,
+        };
+
+    if valid {
+        Vec::new()
+    } else {
+        vec!["Email address is not valid".to_string()]
+    }
+}
+
+fn text_errors(label: &str, text: &str, max_chars: usize, multiline: bool) -> Vec<String> {
+    let mut messages = Vec::new();
+    if text.chars().count() > max_chars {
+        messages.push(format!(
+            "{} must be at most {} characters",
+            label, max_chars
+        ));
+    }
+    if text
+        .chars()
+        .any(|c| c.is_control() && !(multiline && matches!(c, '\n' | '\r' | '\t')))
+    {
+        messages.push(format!("{} must not contain control characters", label));
+    }
+    messages
+}
+
+/// Only `https`, since the avatar is loaded by other users' browsers.
+fn avatar_url_errors(avatar_url: &str) -> Vec<String> {
+    if avatar_url.chars().count() > AVATAR_URL_MAX_CHARS {
+        return vec![format!(
+            "Avatar URL must be at most {} characters",
+            AVATAR_URL_MAX_CHARS
+        )];
+    }
+    match url::Url::parse(avatar_url) {
+        Ok(url) if url.scheme() == "https" && url.host().is_some() => Vec::new(),
+        _ => vec!["Avatar URL must be an https:// URL".to_string()],
+    }
+}
+
+/// A simplified BCP 47 check: a 2-3 letter language, then subtags of 2-8
+/// letters or digits, e.g. `en`, `pt-BR` or `zh-Hant-TW`.
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language_ok = subtags.next().is_some_and(|language| {
+        (2..=3).contains(&language.len()) && language.chars().all(|c| c.is_ascii_alphabetic())
+    });
+    language_ok
+        && tag.len() <= 35
+        && subtags.all(|subtag| {
+            (2..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_errors(result: Result<ValidRegistration, ApiError>) -> Vec<&'static str> {
+        match result {
+            Ok(_) => Vec::new(),
+            Err(e) => e.fields().keys().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn test_valid_registration_is_normalized() {
+        let registration = validate_registration(
+            "  ａｄａ_lovelace ",
+            "analytical engine",
+            Some(" ada@example.com "),
+            PASSWORD_MIN_CHARS,
+        )
+        .unwrap();
+        assert_eq!(registration.username, "ada_lovelace");
+        assert_eq!(registration.email, "ada@example.com");
+    }
+
+    #[test]
+    fn test_rejects_bad_usernames() {
+        for username in [
+            "",
+            "ab",
+            &"a".repeat(33),
+            "ada\u{0}",
+            "ada lovelace",
+            "_ada",
+        ] {
+            assert_eq!(
+                field_errors(validate_registration(
+                    username,
+                    "analytical engine",
+                    Some("ada@example.com"),
+                    PASSWORD_MIN_CHARS
+                )),
+                vec!["username"],
+                "username {:?} should be rejected",
+                username
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_weak_passwords() {
+        for password in [
+            "short",
+            "password123",
+            "aaaaaaaaaa",
+            "my-ada_lovelace-pw",
+            &"x".repeat(1025),
+        ] {
+            assert_eq!(
+                field_errors(validate_registration(
+                    "ada_lovelace",
+                    password,
+                    Some("ada@example.com"),
+                    PASSWORD_MIN_CHARS
+                )),
+                vec!["password"],
+                "password {:?} should be rejected",
+                password
+            );
+        }
+    }
+
+    #[test]
+    fn test_reports_every_invalid_field() {
+        assert_eq!(
+            field_errors(validate_registration(
+                "",
+                "",
+                Some("not-an-email"),
+                PASSWORD_MIN_CHARS
+            )),
+            vec!["email", "password", "username"]
+        );
+    }
+
+    #[test]
+    fn test_email_is_required() {
+        assert_eq!(
+            field_errors(validate_registration(
+                "ada_lovelace",
+                "analytical engine",
+                None,
+                PASSWORD_MIN_CHARS
+            )),
+            vec!["email"]
+        );
+    }
+
+    #[test]
+    fn test_validate_password_checks_against_username() {
+        assert!(validate_password("analytical engine", "ada_lovelace", PASSWORD_MIN_CHARS).is_ok());
+        assert!(validate_password("ada_lovelace!!", "ada_lovelace", PASSWORD_MIN_CHARS).is_err());
+    }
+
+    #[test]
+    fn test_organizations_can_require_longer_passwords() {
+        assert!(validate_password("analytical engine", "ada", 12).is_ok());
+        let error = validate_password("difference", "ada", 12).unwrap_err();
+        assert_eq!(
+            error.fields()["password"],
+            vec!["Password must be at least 12 characters"]
+        );
+        // The policy can't go below everyone's minimum.
+        assert!(validate_password("engine", "ada", 4).is_err());
+    }
+
+    #[test]
+    fn test_organization_settings_are_normalized_and_validated() {
+        let providers = vec!["google".to_string()];
+        let settings = validate_organization(
+            OrganizationSettings {
+                slug: " North-High ".to_string(),
+                name: " North High School ".to_string(),
+                email_domains: vec![
+                    "North.edu ".to_string(),
+                    "".to_string(),
+                    "north.edu".to_string(),
+                ],
+                sso_provider: Some("google".to_string()),
+                password_min_length: 12,
+            },
+            &providers,
+        )
+        .unwrap();
+        assert_eq!(settings.slug, "north-high");
+        assert_eq!(settings.name, "North High School");
+        assert_eq!(settings.email_domains, vec!["north.edu"]);
+
+        let error = validate_organization(
+            OrganizationSettings {
+                slug: "north high".to_string(),
+                name: " ".to_string(),
+                email_domains: vec!["north".to_string()],
+                sso_provider: Some("github".to_string()),
+                password_min_length: 4,
+            },
+            &providers,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.fields().keys().copied().collect::<Vec<_>>(),
+            vec![
+                "email_domains",
+                "name",
+                "password_min_length",
+                "slug",
+                "sso_provider"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_class_name_is_required() {
+        assert_eq!(validate_class_name("  Compilers ").unwrap(), "Compilers");
+        assert!(validate_class_name("   ").is_err());
+        assert!(validate_class_name(&"a".repeat(101)).is_err());
+    }
+
+    #[test]
+    fn test_profile_is_trimmed_and_validated() {
+        let profile = validate_profile(Profile {
+            display_name: Some("  Ada Lovelace ".to_string()),
+            avatar_url: Some("https://example.com/ada.png".to_string()),
+            preferred_language: Some("rust".to_string()),
+            locale: Some("en-GB".to_string()),
+            bio: Some("   ".to_string()),
+        })
+        .unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(profile.bio, None);
+
+        let errors = validate_profile(Profile {
+            display_name: Some("a".repeat(65)),
+            avatar_url: Some("javascript:alert(1)".to_string()),
+            preferred_language: Some("cobol".to_string()),
+            locale: Some("english".to_string()),
+            bio: Some("hi\u{7}".to_string()),
+        })
+        .unwrap_err();
+        let fields: Vec<&str> = errors.fields().keys().copied().collect();
+        assert_eq!(
+            fields,
+            vec![
+                "avatar_url",
+                "bio",
+                "display_name",
+                "locale",
+                "preferred_language"
+            ]
+        );
+    }
+}